@@ -0,0 +1,19 @@
+//! The log replayed by the benchmarks, `data/busy-channel.log` unless another
+//! one, recorded as raw lines, is given in `BOTON_BENCH_LOG`.
+
+use std::path::PathBuf;
+
+pub fn log() -> Vec<String> {
+    let path = std::env::var_os("BOTON_BENCH_LOG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("benches/data/busy-channel.log")
+        });
+    let text = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("could not read {}: {}", path.display(), e));
+    text.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
@@ -0,0 +1,27 @@
+//! Lines replayed over a real connection and dispatched, through the router,
+//! to more and more plugins.
+
+use boton::loadgen::Load;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+mod common;
+
+const PLUGINS: &[usize] = &[1, 8, 32];
+
+fn dispatch(c: &mut Criterion) {
+    let lines = common::log();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("dispatch");
+    group.throughput(Throughput::Elements(lines.len() as u64));
+    group.sample_size(10);
+    for &plugins in PLUGINS {
+        let mut load = runtime.block_on(Load::start(plugins)).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(plugins), &lines, |b, lines| {
+            b.iter(|| runtime.block_on(load.replay(lines)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
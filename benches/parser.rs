@@ -0,0 +1,88 @@
+//! The hot path of every received line, parsed on its own and taken out of a
+//! read buffer, and of every line sent.
+
+use boton::irc::{self, Message, Options};
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::io::BufWriter;
+
+mod common;
+
+const LINES: &[(&str, &str)] = &[
+    (
+        "privmsg",
+        ":nick!~user@host PRIVMSG #channel :hello there, how is everyone?",
+    ),
+    (
+        "tagged",
+        "@time=2024-03-14T14:00:00.828Z;msgid=m00001;account=nick :nick!~user@host PRIVMSG \
+         #channel :tagged hello",
+    ),
+    (
+        "names",
+        ":irc.example.net 353 bot = #channel :@op +voiced alice bob carol dave erin frank grace \
+         heidi",
+    ),
+    ("ping", "PING :irc.example.net"),
+];
+
+fn parse_line(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_line");
+    for (name, line) in LINES {
+        group.bench_with_input(BenchmarkId::from_parameter(name), line, |b, line| {
+            b.iter(|| irc::parse_line(black_box(line)))
+        });
+    }
+    group.finish();
+}
+
+fn process_buf(c: &mut Criterion) {
+    let lines = common::log();
+    let raw: String = lines.iter().map(|line| format!("{}\r\n", line)).collect();
+    let options = Options::default();
+    let mut group = c.benchmark_group("process_buf");
+    group.throughput(Throughput::Elements(lines.len() as u64));
+    group.bench_function("log", |b| {
+        b.iter(|| {
+            let mut buf = BytesMut::from(raw.as_bytes());
+            irc::process_buf(&mut buf, "bench", &options).unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn serialization(c: &mut Criterion) {
+    let messages: Vec<Message> = common::log()
+        .iter()
+        .map(|line| irc::parse_line(line).unwrap().1)
+        .collect();
+    let options = Options::default();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("serialization");
+    group.throughput(Throughput::Elements(messages.len() as u64));
+    group.bench_function("display", |b| {
+        b.iter(|| {
+            for msg in &messages {
+                black_box(msg.to_string());
+            }
+        })
+    });
+    group.bench_function("write_message", |b| {
+        let mut stream = BufWriter::new(tokio::io::sink());
+        b.iter(|| {
+            runtime.block_on(async {
+                for msg in &messages {
+                    irc::write_message(&mut stream, msg, true, &options.encodings)
+                        .await
+                        .unwrap();
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse_line, process_buf, serialization);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+use std::env;
+use std::process::Command;
+
+fn main() {
+    check_rust_version();
+
+    // Embed the current commit, when building from a git checkout
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=BOTON_GIT_HASH={}", hash.trim());
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+/// Fails the build on a compiler older than the `rust-version` of the
+/// manifest, which cargo otherwise lets through with `--ignore-rust-version`.
+fn check_rust_version() {
+    let wanted = match env::var("CARGO_PKG_RUST_VERSION") {
+        Ok(version) if !version.is_empty() => version,
+        _ => return,
+    };
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let found = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    // Like `rustc 1.88.0 (6b00bc388 2025-06-23)`
+    let found = match found.as_deref().and_then(|v| v.split_whitespace().nth(1)) {
+        Some(found) => found.to_string(),
+        None => return,
+    };
+    if parse_version(&found) < parse_version(&wanted) {
+        panic!(
+            "boton needs Rust {} or newer, but is being built with {}",
+            wanted, found
+        );
+    }
+}
+
+/// The numeric parts of a version, ignoring anything like `-nightly`.
+fn parse_version(version: &str) -> Vec<u32> {
+    version
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
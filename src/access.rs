@@ -0,0 +1,161 @@
+//! Access lists kept by ChanServ, in the FLAGS format of Atheme as on Libera
+//! and most networks running it. After joining a channel configured here the
+//! bot checks its own flags, and sets those of friends to what's configured,
+//! like `+Vv` to voice them or `+Oo` to op them as they join. Entries not
+//! configured are left alone.
+//!
+//! ChanServ only answers once the bot is logged in, so this is best used with
+//! SASL, rather than NickServ logins which may still be going on on joining.
+
+use anyhow::Result;
+use log::*;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::irc;
+use crate::services;
+
+/// Flags of the bot's account needed to change those of others, founders
+/// having every one
+const CHANGE_FLAGS: &[char] = &['f', 'F'];
+
+/// The access list wanted in a channel
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AccessConfig {
+    /// Flags each account should have, like `+Oo`
+    pub flags: HashMap<String, String>,
+    /// Whether to set flags differing from the configured ones, rather than
+    /// only logging them
+    pub sync:  bool,
+}
+
+/// Checks the access list of the channel, as `account`, syncing it if
+/// configured to.
+pub async fn check(
+    irc: &irc::IRC,
+    channel: &str,
+    config: &AccessConfig,
+    account: &str,
+) -> Result<()> {
+    let listing = format!("FLAGS {}", channel);
+    let answer = services::ask(
+        irc,
+        "ChanServ",
+        &listing,
+        &[
+            "end of",
+            "not authorized",
+            "not registered",
+            "not a registered",
+        ],
+    )
+    .await?;
+    if answer.matched != "end of" {
+        warn!(
+            "[{}] Could not list the access of {}: {:?}",
+            irc.server, channel, answer.lines
+        );
+        return Ok(());
+    }
+    let entries = parse(&answer.lines);
+
+    let ours = find(&entries, account).unwrap_or("");
+    info!(
+        "[{}] Access of {} in {}: {}",
+        irc.server,
+        account,
+        channel,
+        if ours.is_empty() { "none" } else { ours }
+    );
+    let sync = config.sync && ours.contains(CHANGE_FLAGS);
+    if config.sync && !sync {
+        warn!(
+            "[{}] Not syncing the access list of {}, which needs +f",
+            irc.server, channel
+        );
+    }
+
+    for (target, wanted) in &config.flags {
+        let current = find(&entries, target).unwrap_or("");
+        let change = difference(current, wanted);
+        if change.is_empty() {
+            continue;
+        }
+        if !sync {
+            warn!(
+                "[{}] {} has {:?} in {}, rather than {:?}",
+                irc.server, target, current, channel, wanted
+            );
+            continue;
+        }
+        let set = format!("FLAGS {} {} {}", channel, target, change);
+        let answer = services::ask(
+            irc,
+            "ChanServ",
+            &set,
+            &[
+                "were set",
+                "removed",
+                "not authorized",
+                "invalid",
+                "no such",
+                "not registered",
+            ],
+        )
+        .await?;
+        if matches!(answer.matched, "were set" | "removed") {
+            info!(
+                "[{}] Set {} on {} in {}",
+                irc.server, change, target, channel
+            );
+        } else {
+            warn!(
+                "[{}] Could not set {} on {} in {}: {:?}",
+                irc.server, change, target, channel, answer.lines
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Flags by lowercase account, from the lines of a FLAGS listing, like
+/// `1     wwared                 +AFRefiorstv`.
+fn parse(lines: &[String]) -> BTreeMap<String, String> {
+    let mut entries = BTreeMap::new();
+    for line in lines {
+        let line = line.replace(['\x02', '\x0f', '\x1d', '\x1f'], "");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if let [number, account, flags, ..] = words[..] {
+            if number.parse::<u32>().is_ok() && flags.starts_with('+') {
+                entries.insert(account.to_lowercase(), flags.into());
+            }
+        }
+    }
+    entries
+}
+
+fn find<'a>(entries: &'a BTreeMap<String, String>, account: &str) -> Option<&'a str> {
+    entries.get(&account.to_lowercase()).map(String::as_str)
+}
+
+/// The change taking `current` flags to `wanted`, like `+o-t`, empty if
+/// they're the same.
+fn difference(current: &str, wanted: &str) -> String {
+    let (current, wanted) = (
+        current.trim_start_matches('+'),
+        wanted.trim_start_matches('+'),
+    );
+    let added: String = wanted.chars().filter(|&c| !current.contains(c)).collect();
+    let removed: String = current.chars().filter(|&c| !wanted.contains(c)).collect();
+    let mut change = String::new();
+    if !added.is_empty() {
+        change.push('+');
+        change.push_str(&added);
+    }
+    if !removed.is_empty() {
+        change.push('-');
+        change.push_str(&removed);
+    }
+    change
+}
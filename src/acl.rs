@@ -0,0 +1,16 @@
+use serde::Deserialize;
+
+use crate::irc;
+
+/// A list of `nick!ident@host` masks, as matched by
+/// [`irc::User::matches_mask`].
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct Acl(Vec<String>);
+
+impl Acl {
+    /// Whether any of the masks matches the user.
+    pub fn allows(&self, user: &irc::User) -> bool {
+        self.0.iter().any(|mask| user.matches_mask(mask))
+    }
+}
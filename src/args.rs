@@ -0,0 +1,133 @@
+//! Splitting command arguments into words, flags and `key=value` options.
+//!
+//! `\feed add --quiet interval=30 "My Feed" https://example.org/feed` gives
+//! the positional words `add`, `My Feed` and `https://example.org/feed`, the
+//! flag `quiet` and the option `interval` set to `30`.
+
+use std::collections::HashMap;
+
+/// A positional word, along with where it started in the line.
+struct Word {
+    value: String,
+    start: usize,
+}
+
+/// Parsed arguments of a command.
+///
+/// Words can be quoted with `"` or `'` to include spaces, and `\` escapes the
+/// next character inside quotes. Anything after a lone `--` is positional.
+/// Parsing never fails: quotes that aren't closed are kept as they are.
+pub struct Args<'a> {
+    line:    &'a str,
+    words:   Vec<Word>,
+    /// `--flag` and `--flag=value`
+    flags:   HashMap<String, Option<String>>,
+    /// `key=value`
+    options: HashMap<String, String>,
+}
+
+impl<'a> Args<'a> {
+    pub fn parse(line: &'a str) -> Args<'a> {
+        let mut args = Args {
+            line,
+            words: vec![],
+            flags: HashMap::new(),
+            options: HashMap::new(),
+        };
+        let mut only_words = false;
+        for (start, raw, value) in split(line) {
+            if only_words || raw.starts_with(['"', '\'']) {
+                args.words.push(Word { value, start });
+            } else if raw == "--" {
+                only_words = true;
+            } else if let Some(flag) = raw.strip_prefix("--") {
+                let (name, value) = match flag.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None => (flag, None),
+                };
+                args.flags.insert(name.to_lowercase(), value);
+            } else if let Some((key, value)) = raw.split_once('=').filter(|(key, _)| is_key(key)) {
+                args.options.insert(key.to_lowercase(), value.to_string());
+            } else {
+                args.words.push(Word { value, start });
+            }
+        }
+        args
+    }
+
+    /// The positional word at `index`.
+    pub fn word(&self, index: usize) -> Option<&str> {
+        self.words.get(index).map(|w| w.value.as_str())
+    }
+
+    pub fn words(&self) -> impl Iterator<Item = &str> {
+        self.words.iter().map(|w| w.value.as_str())
+    }
+
+    /// The rest of the line as typed, starting at the positional word at
+    /// `index`.
+    pub fn rest(&self, index: usize) -> Option<&'a str> {
+        self.words
+            .get(index)
+            .map(|w| self.line[w.start ..].trim_end())
+    }
+
+    /// Whether `--name` was given, with or without a value.
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains_key(name)
+    }
+
+    /// The value given through either `--name=value` or `name=value`.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        match self.flags.get(name) {
+            Some(Some(value)) => Some(value),
+            _ => self.options.get(name).map(String::as_str),
+        }
+    }
+}
+
+/// Whether the text can be the key of a `key=value` option.
+fn is_key(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Splits the line into (start, raw text, unquoted value) for each word.
+fn split(line: &str) -> Vec<(usize, &str, String)> {
+    let mut words = vec![];
+    let mut pos = 0;
+    loop {
+        let rest = &line[pos ..];
+        let trimmed = rest.trim_start();
+        if trimmed.is_empty() {
+            return words;
+        }
+        let start = pos + rest.len() - trimmed.len();
+        let (len, value) = quoted(trimmed).unwrap_or_else(|| {
+            let len = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+            (len, trimmed[.. len].to_string())
+        });
+        pos = start + len;
+        words.push((start, &line[start .. pos], value));
+    }
+}
+
+/// If the text starts with a closed quoted string, its length including the
+/// quotes and its unescaped value. Only quotes followed by a space or the end
+/// of the line close the string, so apostrophes can be used inside it.
+fn quoted(text: &str) -> Option<(usize, String)> {
+    let quote = text.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        let at_end = chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+        match c {
+            '\\' => value.push(chars.next()?.1),
+            _ if c == quote && at_end => return Some((i + 1, value)),
+            _ => value.push(c),
+        }
+    }
+    None
+}
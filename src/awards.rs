@@ -0,0 +1,176 @@
+//! Points and achievements plugins award users for what they do in a channel,
+//! like trivia wins or karma milestones, ranked per channel.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::irc;
+use crate::links;
+use crate::runtime::Runtime;
+use crate::state;
+use crate::storage::{DataSet, Storage};
+
+const DB_NAME: &str = "awards";
+pub const DATA_SET: DataSet = DataSet::new::<Standings>(DB_NAME);
+
+/// Something users are awarded once per channel, declared as a const by the
+/// plugin awarding it.
+pub struct Achievement {
+    pub name:        &'static str,
+    /// What it takes, like `first to speak on 10 days`
+    pub description: &'static str,
+}
+
+/// A user's points and achievements in a channel.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Standing {
+    pub points:       i64,
+    /// When each achievement was unlocked, by name
+    pub achievements: BTreeMap<String, DateTime<Utc>>,
+}
+
+/// Standings by lowercase channel and user key, as linked across nick
+/// changes.
+type Standings = BTreeMap<String, BTreeMap<String, Standing>>;
+
+/// Points and achievements of one bot's users, saved whenever they change.
+#[derive(Clone)]
+pub struct Awards {
+    server:    String,
+    storage:   Storage,
+    outbox:    irc::Outbox,
+    runtime:   Runtime,
+    state:     state::Handle,
+    standings: Arc<Mutex<Standings>>,
+}
+
+impl Awards {
+    pub async fn load(
+        irc: &irc::IRC,
+        storage: Storage,
+        runtime: Runtime,
+        state: state::Handle,
+    ) -> Result<Self> {
+        let standings = storage.load(DB_NAME).await?.unwrap_or_default();
+        let standings = Arc::new(Mutex::new(standings));
+        storage.own(
+            DB_NAME,
+            standings.clone(),
+            |standings: &mut Standings, nick| {
+                let mut found = false;
+                for users in standings.values_mut() {
+                    found |= users.remove(nick).is_some();
+                }
+                standings.retain(|_, users| !users.is_empty());
+                found
+            },
+        );
+        Ok(Awards {
+            server: irc.server.clone(),
+            storage,
+            outbox: irc.send_messages.clone(),
+            runtime,
+            state,
+            standings,
+        })
+    }
+
+    /// The key the user's standings are kept by.
+    async fn key(&self, nick: &str) -> String {
+        self.state.read().await.links.key(nick)
+    }
+
+    /// Gives the user points in the channel, or takes them away if negative,
+    /// returning their new total.
+    pub async fn add_points(&self, channel: &str, nick: &str, points: i64) -> Result<i64> {
+        let key = self.key(nick).await;
+        let mut standings = self.standings.lock().await;
+        let standing = standings
+            .entry(channel.to_lowercase())
+            .or_default()
+            .entry(key)
+            .or_default();
+        standing.points += points;
+        let total = standing.points;
+        self.storage.save(DB_NAME, &*standings).await?;
+        Ok(total)
+    }
+
+    /// Awards the achievement to the user unless they have it already in the
+    /// channel, announcing it there outside of maintenance. Returns whether it
+    /// was new to them.
+    pub async fn unlock(
+        &self,
+        channel: &str,
+        nick: &str,
+        achievement: &Achievement,
+    ) -> Result<bool> {
+        let key = self.key(nick).await;
+        {
+            let mut standings = self.standings.lock().await;
+            let standing = standings
+                .entry(channel.to_lowercase())
+                .or_default()
+                .entry(key)
+                .or_default();
+            if standing.achievements.contains_key(achievement.name) {
+                return Ok(false);
+            }
+            standing
+                .achievements
+                .insert(achievement.name.into(), Utc::now());
+            self.storage.save(DB_NAME, &*standings).await?;
+        }
+
+        info!(
+            "[{}] {} unlocked {} in {}",
+            self.server, nick, achievement.name, channel
+        );
+        if self.runtime.maintenance().await {
+            return Ok(true);
+        }
+        let text = format!(
+            "{} unlocked {}: {}",
+            nick, achievement.name, achievement.description
+        );
+        self.outbox
+            .send_outgoing(irc::Outgoing::privmsg(channel, text.as_str()))
+            .await?;
+        Ok(true)
+    }
+
+    /// The user's standing in the channel along with their rank, 1 being the
+    /// most points, if they have one.
+    pub async fn standing(&self, channel: &str, nick: &str) -> Option<(usize, Standing)> {
+        let key = self.key(nick).await;
+        let standings = self.standings.lock().await;
+        let users = standings.get(&channel.to_lowercase())?;
+        let standing = users.get(&key)?;
+        let ahead = users
+            .values()
+            .filter(|other| other.points > standing.points)
+            .count();
+        Some((ahead + 1, standing.clone()))
+    }
+
+    /// The users with the most points in the channel, most first.
+    pub async fn leaderboard(&self, channel: &str, count: usize) -> Vec<(String, i64)> {
+        let standings = self.standings.lock().await;
+        let mut leaders: Vec<(String, i64)> = standings
+            .get(&channel.to_lowercase())
+            .into_iter()
+            .flatten()
+            .filter(|(_, standing)| standing.points > 0)
+            .map(|(key, standing)| (links::name(key).into(), standing.points))
+            .collect();
+        leaders.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        leaders.truncate(count);
+        leaders
+    }
+}
@@ -0,0 +1,70 @@
+//! Backfilling channels with what was said while the bot was away, on
+//! servers and bouncers supporting `draft/chathistory`: the time of the last
+//! message seen in each channel is kept, and the messages since are asked for
+//! when joining it again.
+
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::irc;
+use crate::storage::{DataSet, Storage};
+
+const DB_NAME: &str = "backfill";
+pub const DATA_SET: DataSet = DataSet::new::<LastSeen>(DB_NAME);
+/// Capability servers announce history support with
+pub const CAPABILITY: &str = "draft/chathistory";
+/// Most messages asked for per channel
+pub const LIMIT: usize = 50;
+
+/// Time of the last message seen, by lowercase channel.
+type LastSeen = BTreeMap<String, DateTime<Utc>>;
+
+/// When messages were last seen in each channel, saved on disconnecting.
+#[derive(Clone)]
+pub struct Backfill {
+    storage:   Storage,
+    last_seen: Arc<Mutex<LastSeen>>,
+}
+
+impl Backfill {
+    pub async fn load(storage: Storage) -> Result<Self> {
+        let last_seen = storage.load(DB_NAME).await?.unwrap_or_default();
+        Ok(Backfill {
+            storage,
+            last_seen: Arc::new(Mutex::new(last_seen)),
+        })
+    }
+
+    /// Remembers that a message sent at the given time was seen in the
+    /// channel, unless a later one was.
+    pub fn seen(&self, channel: &str, time: DateTime<Utc>) {
+        let mut last_seen = self.last_seen.lock().unwrap();
+        let last = last_seen.entry(channel.to_lowercase()).or_insert(time);
+        if *last < time {
+            *last = time;
+        }
+    }
+
+    /// A request for the messages sent to the channel since the last one
+    /// seen, if any ever was.
+    pub fn request(&self, channel: &str) -> Option<irc::Message> {
+        let since = *self
+            .last_seen
+            .lock()
+            .unwrap()
+            .get(&channel.to_lowercase())?;
+        let since = since.to_rfc3339_opts(SecondsFormat::Millis, true);
+        irc::Message::raw(&format!(
+            "CHATHISTORY AFTER {} timestamp={} {}",
+            channel, since, LIMIT
+        ))
+        .ok()
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        let last_seen = self.last_seen.lock().unwrap().clone();
+        self.storage.save(DB_NAME, &last_seen).await
+    }
+}
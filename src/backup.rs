@@ -0,0 +1,176 @@
+//! Backups of every bot's plugin data, taken periodically into timestamped
+//! files, of which only the newest few are kept. `boton restore <file>`
+//! brings one back, and is best run while the bot is stopped, as running
+//! plugins save over it.
+//!
+//! A backup holds the data sets of each server, like `export-data` prints
+//! them, and is encrypted with the storage key when one is configured.
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::*;
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::bot::Config;
+use crate::data;
+use crate::scheduler::Scheduler;
+use crate::storage::{self, Storage, StorageKey};
+use crate::tasks;
+
+/// Backup files are named like `boton-20240131T040000Z.json`
+const FILE_PREFIX: &str = "boton-";
+const FILE_EXTENSION: &str = "json";
+/// Authenticated along with encrypted backups, so data sets can't pass for
+/// one
+const LABEL: &str = "backup";
+
+const USAGE: &str = "usage: boton restore <backup>";
+
+/// When to take backups, and where to keep them
+#[derive(Debug, Deserialize, Clone)]
+pub struct BackupConfig {
+    /// Directory to keep backups in, `backups` by default
+    #[serde(default = "default_dir")]
+    pub dir:         String,
+    /// Hours between backups, 24 by default
+    #[serde(default = "default_every_hours")]
+    pub every_hours: u64,
+    /// Backups kept, the oldest being deleted, 7 by default
+    #[serde(default = "default_keep")]
+    pub keep:        usize,
+}
+
+fn default_dir() -> String {
+    "backups".into()
+}
+
+fn default_every_hours() -> u64 {
+    24
+}
+
+fn default_keep() -> usize {
+    7
+}
+
+/// Backs up the storages every `every_hours`, starting one period from now,
+/// until `shutdown` is set. Backups are encrypted with `key`, if any.
+pub fn schedule(
+    config: BackupConfig,
+    storages: Vec<Storage>,
+    key: Option<StorageKey>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    if config.every_hours == 0 || config.keep == 0 {
+        return Err(anyhow!("backups need every_hours and keep above 0"));
+    }
+    let period = Duration::from_secs(config.every_hours * 60 * 60);
+    let scheduler = Scheduler::new();
+    scheduler.every(period, move || {
+        let (config, storages, key) = (config.clone(), storages.clone(), key.clone());
+        async move {
+            match snapshot(&config, &storages, key.as_ref()).await {
+                Ok(path) => info!("Backed up plugin data to {}", path.display()),
+                Err(e) => error!("Could not back up plugin data: {}", e),
+            }
+        }
+    });
+    tasks::spawn("backups", async move {
+        while !*shutdown.borrow() {
+            if shutdown.changed().await.is_err() {
+                break;
+            }
+        }
+        scheduler.cancel_all();
+    });
+    Ok(())
+}
+
+/// Writes a backup of the storages, deleting the oldest ones past `keep`, and
+/// returns where it went.
+async fn snapshot(
+    config: &BackupConfig,
+    storages: &[Storage],
+    key: Option<&StorageKey>,
+) -> Result<PathBuf> {
+    let mut servers = serde_json::Map::new();
+    for storage in storages {
+        servers.insert(storage.server().into(), data::export_all(storage).await?);
+    }
+    let mut text = serde_json::to_string_pretty(&Value::Object(servers))?;
+    if let Some(key) = key {
+        text = storage::seal(key, LABEL, &text)?;
+    }
+
+    tokio::fs::create_dir_all(&config.dir).await?;
+    let name = format!(
+        "{}{}.{}",
+        FILE_PREFIX,
+        Utc::now().format("%Y%m%dT%H%M%SZ"),
+        FILE_EXTENSION
+    );
+    let path = Path::new(&config.dir).join(name);
+    // Written under another name first, so a backup cut short isn't kept
+    let partial = path.with_extension("partial");
+    tokio::fs::write(&partial, text).await?;
+    tokio::fs::rename(&partial, &path).await?;
+    prune(config).await?;
+    Ok(path)
+}
+
+/// Deletes the oldest backups past `keep`.
+async fn prune(config: &BackupConfig) -> Result<()> {
+    let mut backups = vec![];
+    let mut entries = tokio::fs::read_dir(&config.dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let is_backup = path.extension().is_some_and(|ext| ext == FILE_EXTENSION)
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(FILE_PREFIX));
+        if is_backup {
+            backups.push(path);
+        }
+    }
+    // Timestamps in the names sort oldest first
+    backups.sort();
+    let excess = backups.len().saturating_sub(config.keep);
+    for path in &backups[.. excess] {
+        tokio::fs::remove_file(path).await?;
+        info!("Deleted old backup {}", path.display());
+    }
+    Ok(())
+}
+
+/// Runs `boton restore`, replacing the data sets of every server in the
+/// backup given as argument.
+pub async fn restore(config: &Config, args: &[String]) -> Result<()> {
+    let path = match args {
+        [path] => path,
+        _ => return Err(anyhow!(USAGE)),
+    };
+    let mut text = tokio::fs::read_to_string(path).await?;
+    if storage::is_sealed(&text) {
+        let key = config
+            .storage_key()
+            .await?
+            .ok_or_else(|| anyhow!("{} is encrypted, but no storage key is configured", path))?;
+        text = storage::unseal(&key, LABEL, &text).await?;
+    }
+    let servers: serde_json::Map<String, Value> = serde_json::from_str(&text)?;
+    // Opening them all first fails before restoring anything if a server
+    // isn't configured anymore
+    let mut storages = vec![];
+    for server in servers.keys() {
+        storages.push(config.open_storage(server).await?);
+    }
+    for (storage, (server, data)) in storages.iter().zip(servers) {
+        let names = data::import_all(storage, data).await?;
+        eprintln!("Restored {} for {}", names.join(", "), server);
+    }
+    Ok(())
+}
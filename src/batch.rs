@@ -0,0 +1,102 @@
+//! IRCv3 batches: messages the server groups together, like the QUITs of a
+//! netsplit, replayed chat history or the lines of a multiline message,
+//! collected so plugins get each batch whole instead of as interleaved lines.
+
+use std::collections::HashMap;
+
+use crate::irc;
+
+/// Users leaving because servers split apart
+pub const NETSPLIT: &str = "netsplit";
+/// Users coming back once the servers rejoined
+pub const NETJOIN: &str = "netjoin";
+/// Messages replayed from a channel's history
+pub const CHATHISTORY: &str = "chathistory";
+/// Lines a user sent as a single message
+pub const MULTILINE: &str = "draft/multiline";
+/// Tag on a multiline line continuing the previous one without a line break
+const MULTILINE_CONCAT: &str = "draft/multiline-concat";
+
+/// A batch of messages, once the server has sent all of it.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    /// Type of batch, like [`NETSPLIT`]
+    pub kind:       String,
+    /// Parameters after the type, like the servers that split or the target
+    /// of a multiline message
+    pub parameters: Vec<String>,
+    /// Messages in the batch, in the order received
+    pub messages:   Vec<irc::Message>,
+    /// Batches nested in this one
+    pub batches:    Vec<Batch>,
+}
+
+impl Batch {
+    /// The lines of a multiline batch joined back into the PRIVMSG or NOTICE
+    /// they were sent as.
+    pub fn multiline(&self) -> Option<irc::Message> {
+        if self.kind != MULTILINE {
+            return None;
+        }
+        let mut lines = self.messages.iter();
+        let mut joined = lines.next()?.clone();
+        joined.tags.remove("batch");
+        for line in lines {
+            let text = line.parameters.first().map_or("", String::as_str);
+            let first = joined.parameters.first_mut()?;
+            if line.tag(MULTILINE_CONCAT).is_none() {
+                first.push('\n');
+            }
+            first.push_str(text);
+        }
+        Some(joined)
+    }
+}
+
+/// Batches still being received, by reference tag.
+#[derive(Default)]
+pub struct Batches {
+    open: HashMap<String, (Option<String>, Batch)>,
+}
+
+impl Batches {
+    /// Adds the message to the batch it's part of, if any. Returns the batch
+    /// the message ends, unless it's nested in another one still open.
+    pub fn collect(&mut self, msg: &irc::Message) -> Option<Batch> {
+        let parent = msg.tag("batch").map(String::from);
+        if msg.command == irc::Command::Batch {
+            // BATCH +reference type [parameters...] or BATCH -reference
+            let target = msg.target.as_deref()?;
+            if let Some(reference) = target.strip_prefix('+') {
+                let batch = Batch {
+                    kind:       msg.parameters.first()?.clone(),
+                    parameters: msg.parameters.iter().skip(1).cloned().collect(),
+                    messages:   vec![],
+                    batches:    vec![],
+                };
+                self.open.insert(reference.into(), (parent, batch));
+                return None;
+            }
+            let reference = target.strip_prefix('-')?;
+            let (parent, batch) = self.open.remove(reference)?;
+            return match parent.and_then(|parent| self.open.get_mut(&parent)) {
+                Some((_, outer)) => {
+                    outer.batches.push(batch);
+                    None
+                },
+                None => Some(batch),
+            };
+        }
+
+        if let Some((_, batch)) = parent.and_then(|parent| self.open.get_mut(&parent)) {
+            batch.messages.push(msg.clone());
+        }
+        None
+    }
+
+    /// Whether the message is part of a batch still being received.
+    pub fn contains(&self, msg: &irc::Message) -> bool {
+        msg.tag("batch")
+            .is_some_and(|reference| self.open.contains_key(reference))
+    }
+}
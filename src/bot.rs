@@ -1,14 +1,63 @@
 use std::{fs::File, path::Path};
 
 use anyhow::{anyhow, Result};
+use futures::future;
 use log::*;
 use ron::de::from_reader;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Semaphore};
 use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
 
+use crate::access::{self, AccessConfig};
+use crate::acl::Acl;
+use crate::awards::Awards;
+use crate::backfill::{self, Backfill};
+use crate::backup::{self, BackupConfig};
+use crate::bouncer::BouncerConfig;
+use crate::command::{self, Lifecycle};
+use crate::encoding::{EncodingConfig, Encodings};
+use crate::filter::{Filters, FiltersConfig};
+use crate::groups::{self, ChannelGroups};
+use crate::history::HistoryConfig;
+#[cfg(feature = "http-client")]
+use crate::http::{Http, HttpConfig};
+use crate::i18n::{I18n, I18nConfig};
+use crate::invite::{InviteConfig, Invites};
 use crate::irc;
+use crate::lag::{self, LagConfig};
+#[cfg(feature = "http-server")]
+use crate::listener::{self, ListenerConfig};
+use crate::messages::Messages;
+use crate::net::IpVersion;
+use crate::oper::{self, OperConfig};
+use crate::ops::{Ops, OpsConfig};
+#[cfg(feature = "http-client")]
+use crate::paste::{PasteConfig, Paster};
 use crate::plugins;
+use crate::prefs::Prefs;
+use crate::relay::{self, RelayConfig};
+use crate::runtime::Runtime;
+use crate::sasl::SaslConfig;
+use crate::scheduler::Scheduler;
+use crate::services::{self, ServicesConfig};
+#[cfg(feature = "http-client")]
+use crate::shortener::{Shortener, ShortenerConfig};
+use crate::state;
+use crate::status::{self, Phase};
+use crate::storage::{self, Backend, KeyConfig, Storage, StorageConfig, StorageKey};
+use crate::systemd;
+use crate::tasks::{self, TaskSet};
+use crate::watchdog::WatchdogConfig;
+
+/// How long to wait for the server to close the connection after QUIT
+const QUIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long plugins get to run their `disconnected` hook
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Arbitrary optional configuration for a given plugin
 pub type PluginConfig = HashMap<String, String>;
@@ -16,116 +65,816 @@ pub type PluginConfig = HashMap<String, String>;
 /// Global configuration, including possibly many bots
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
-    bots:    Vec<Bot>,
-    plugins: HashMap<String, PluginConfig>,
+    bots:        Vec<Bot>,
+    plugins:     HashMap<String, PluginConfig>,
+    /// Nicks and logins shared by bots, by name
+    #[serde(default)]
+    identities:  HashMap<String, Identity>,
+    /// How bots are started, all at once by default
+    #[serde(default)]
+    startup:     StartupConfig,
+    /// Named lists of channels, which bots' channels and the per-channel
+    /// settings of plugins can refer to as `@name`
+    #[serde(default)]
+    groups:      ChannelGroups,
+    /// HTTP listener for webhooks and health checks, disabled when omitted
+    #[cfg(feature = "http-server")]
+    #[serde(default)]
+    listener:    Option<ListenerConfig>,
+    /// Where plugin data is kept, files under `data/` by default
+    #[serde(default)]
+    storage:     StorageConfig,
+    /// Key encrypting plugin data before it's stored, left unencrypted when
+    /// omitted
+    #[serde(default)]
+    storage_key: Option<KeyConfig>,
+    /// Periodic backups of plugin data, disabled when omitted
+    #[serde(default)]
+    backup:      Option<BackupConfig>,
+    /// Address to serve tokio-console on, when built for it
+    #[serde(default)]
+    console:     Option<SocketAddr>,
+}
+
+/// Pacing of connections, for many bots going through the same bouncer or
+/// network not to get throttled
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+struct StartupConfig {
+    /// Seconds between starting each bot, 0 to start them all at once
+    stagger:        u64,
+    /// Bots connecting at once, until the server welcomes them, reconnections
+    /// included. 0 for no limit.
+    max_connecting: usize,
+}
+
+/// Who a bot is on a network, for bots naming it as their `identity`. Each
+/// bot still sets whatever it wants to use instead.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+struct Identity {
+    nick:      Option<String>,
+    ident:     Option<String>,
+    real_name: Option<String>,
+    sasl:      Option<SaslConfig>,
+    services:  Option<ServicesConfig>,
 }
 
 /// Configuration for one instance of the bot
 #[derive(Debug, Deserialize, Clone)]
-struct Bot {
+pub(crate) struct Bot {
     /// Hostname and port of IRC server
-    server:    (String, u16),
+    server:     (String, u16),
     /// Whether TLS should be used
-    use_tls:   bool,
+    use_tls:    bool,
+    /// Only connect over IPv4 (`V4`) or IPv6 (`V6`), rather than `Any`
+    #[serde(default)]
+    ip_version: IpVersion,
     // /// Whether the server TLS certificate should be validated (using system store)
     // validate_cert: bool, // TODO
+    /// Identity the nick, ident, real name and logins not set here come from
+    #[serde(default)]
+    identity:   Option<String>,
     /// Bot nickname
-    nick:      String,
+    #[serde(default)]
+    nick:       String,
     /// Bot ident/username
-    ident:     String,
+    #[serde(default)]
+    ident:      String,
     /// Bot realname
-    real_name: String,
+    #[serde(default)]
+    real_name:  String,
 
     /// Channls to join after connecting and remain joined
-    channels: Vec<String>,
+    channels:   Vec<String>,
+    /// User modes set after connecting, e.g. `+Bi`
+    #[serde(default)]
+    user_modes: Option<String>,
+    /// Raw commands sent after connecting, before joining channels. `{nick}`
+    /// and `{server}` get replaced by the bot's nick and the server name.
+    #[serde(default)]
+    perform:    Vec<String>,
+
+    /// Prefix or list of prefixes used to invoke commands, `\` by default
+    #[serde(default)]
+    command_prefix: command::Prefixes,
+    /// Which forms of addressing the bot are accepted as commands
+    #[serde(default)]
+    triggers:       command::Triggers,
+    /// Per-command cooldowns, keyed by command name
+    #[serde(default)]
+    cooldowns:      HashMap<String, command::Cooldown>,
+    /// Order in which plugins get to claim messages several of them would
+    /// answer, lowest first, 0 for plugins not listed
+    #[serde(default)]
+    priorities:     HashMap<String, i32>,
+    /// Masks of users allowed to use administrative commands
+    #[serde(default)]
+    admins:         Acl,
+    /// Masks of users allowed to use oper commands like \kill, when the bot
+    /// is an oper
+    #[serde(default)]
+    owners:         Acl,
+    /// Quit and part messages
+    #[serde(default)]
+    messages:       Messages,
+    /// Which invites to accept
+    #[serde(default)]
+    invites:        InviteConfig,
+    /// Account logged in to with SASL while registering
+    #[serde(default)]
+    sasl:           Option<SaslConfig>,
+    /// Operator block to OPER with after connecting, not done when omitted
+    #[serde(default)]
+    oper:           Option<OperConfig>,
+    /// NickServ/HostServ login, written without `Some`
+    #[serde(default, deserialize_with = "some")]
+    services:       Option<ServicesConfig>,
+    /// Connecting through a bouncer like ZNC or soju, directly when omitted
+    #[serde(default)]
+    bouncer:        Option<BouncerConfig>,
+    /// Accepting IRC clients to share the connection with, not done when
+    /// omitted
+    #[serde(default)]
+    relay:          Option<RelayConfig>,
+    /// Channels where ops are taken from ChanServ only to moderate
+    #[serde(default)]
+    ops:            OpsConfig,
+    /// ChanServ access lists checked after joining each of these channels,
+    /// and synced if configured to
+    #[serde(default)]
+    access:         HashMap<String, AccessConfig>,
+    /// Languages of the replies
+    #[serde(default)]
+    i18n:           I18nConfig,
+    /// Changes made to what plugins send to each channel
+    #[serde(default)]
+    filters:        FiltersConfig,
+    /// Seconds during which identical messages to the same target are only
+    /// sent once, 0 (the default) to always send them
+    #[serde(default)]
+    dedup_window:   u64,
+    /// Longest text sent in a PRIVMSG or NOTICE in bytes, 400 by default,
+    /// past which it's cut. 0 to never cut it.
+    #[serde(default = "default_max_length")]
+    max_length:     usize,
+    /// Character encodings of the network and its channels, UTF-8 by default
+    #[serde(default)]
+    encoding:       EncodingConfig,
+    /// What to do with lines from the server that can't be parsed, dropping
+    /// them by default
+    #[serde(default)]
+    malformed:      irc::Malformed,
+    /// How many recent messages per channel are kept for plugins to look at
+    #[serde(default)]
+    history:        HistoryConfig,
+    /// URL shortening service used by plugins posting links
+    #[cfg(feature = "http-client")]
+    #[serde(default)]
+    shortener:      ShortenerConfig,
+    /// Pastebin for outputs too long to post in a channel
+    #[cfg(feature = "http-client")]
+    #[serde(default)]
+    paste:          PasteConfig,
+    /// Timeouts, proxy and User-Agent for HTTP requests
+    #[cfg(feature = "http-client")]
+    #[serde(default)]
+    http:           HttpConfig,
+    /// When to reconnect because of lag
+    #[serde(default)]
+    lag:            LagConfig,
+    /// When plugin handlers are logged or aborted as stuck
+    #[serde(default)]
+    watchdog:       WatchdogConfig,
+    /// Settings of plugins for this bot only, laid over the global ones key
+    /// by key
+    #[serde(default)]
+    plugins:        HashMap<String, PluginConfig>,
+}
+
+fn default_max_length() -> usize {
+    400
+}
+
+/// The global settings of plugins, with those of a bot laid over them.
+fn overlay(
+    mut global: HashMap<String, PluginConfig>,
+    own: &HashMap<String, PluginConfig>,
+) -> HashMap<String, PluginConfig> {
+    for (name, config) in own {
+        let merged = global.entry(name.clone()).or_default();
+        merged.extend(config.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    global
+}
+
+/// Plugin settings whose names contain any of these hold secrets
+const SECRET_SETTINGS: &[&str] = &["password", "token", "secret", "apikey", "api-key"];
+
+/// Replaces the values of the plugin settings holding secrets.
+fn redact_settings(plugins: &mut HashMap<String, PluginConfig>) {
+    for (name, value) in plugins.values_mut().flatten() {
+        let name = name.to_lowercase();
+        if SECRET_SETTINGS.iter().any(|secret| name.contains(secret)) {
+            *value = "..".into();
+        }
+    }
+}
+
+fn some<'de, D: Deserializer<'de>, T: Deserialize<'de>>(d: D) -> Result<Option<T>, D::Error> {
+    T::deserialize(d).map(Some)
 }
 
 impl Bot {
+    /// Fills in what isn't set from the identity named, if any, checking
+    /// that the bot ends up with a nick, ident and real name.
+    fn take_identity(&mut self, identities: &HashMap<String, Identity>) -> Result<()> {
+        let server = &self.server.0;
+        let identity = match &self.identity {
+            Some(name) => identities
+                .get(name)
+                .ok_or_else(|| {
+                    anyhow!("{} uses identity {}, which isn't configured", server, name)
+                })?
+                .clone(),
+            None => Identity::default(),
+        };
+        for (field, value, name) in [
+            (&mut self.nick, identity.nick, "nick"),
+            (&mut self.ident, identity.ident, "ident"),
+            (&mut self.real_name, identity.real_name, "real_name"),
+        ] {
+            if field.is_empty() {
+                *field = value.ok_or_else(|| anyhow!("{} has no {}", server, name))?;
+            }
+        }
+        if self.sasl.is_none() {
+            self.sasl = identity.sasl;
+        }
+        if self.services.is_none() {
+            self.services = identity.services;
+        }
+        Ok(())
+    }
+
+    /// A bot with the defaults of every setting, for the synthetic load.
+    #[cfg(feature = "loadgen")]
+    pub(crate) fn with_defaults(server: &str, nick: &str) -> Result<Bot> {
+        let config = format!(
+            "(server: ({:?}, 0), use_tls: false, nick: {:?}, channels: [])",
+            server, nick
+        );
+        Ok(ron::from_str(&config)?)
+    }
+
+    /// What the plugins of a connection share, set up from this bot's
+    /// settings.
+    pub(crate) async fn core(
+        &self,
+        irc: &irc::IRC,
+        state: state::Handle,
+        storage: Storage,
+        runtime: Runtime,
+        prefs: Prefs,
+        i18n: I18n,
+    ) -> Result<plugins::Core> {
+        let scheduler = Scheduler::new();
+        #[cfg(feature = "http-client")]
+        let http = Http::new(self.http.clone())?;
+        let awards = Awards::load(irc, storage.clone(), runtime.clone(), state.clone()).await?;
+        // Aligned by hand, rustfmt leaving fields with attributes unaligned
+        #[rustfmt::skip]
+        let core = plugins::Core {
+            state:     state.clone(),
+            scheduler: scheduler.clone(),
+            admins:    self.admins.clone(),
+            owners:    self.owners.clone(),
+            messages:  self.messages.clone(),
+            ops:       Ops::new(self.ops.clone(), irc, state.clone(), scheduler),
+            #[cfg(feature = "http-client")]
+            shortener: Shortener::new(self.shortener.clone(), &http),
+            #[cfg(feature = "http-client")]
+            paster:    Paster::new(self.paste.clone(), &http),
+            #[cfg(feature = "http-client")]
+            http,
+            storage,
+            runtime,
+            awards,
+            i18n,
+            filters:   Filters::new(&self.filters, state)?,
+            prefs,
+            watchdog:  self.watchdog.clone(),
+        };
+        Ok(core)
+    }
+
     // TODO try to go back to old nick if changed
     // TODO handle kicks/parts/whatever and rejoin?
 
     pub async fn spawn_tasks(
         self,
         plugin_configs: HashMap<String, PluginConfig>,
+        registry: plugins::Registry,
+        backend: Arc<dyn Backend>,
+        delay: Duration,
+        connecting: Option<Arc<Semaphore>>,
+        mut shutdown: watch::Receiver<bool>,
     ) -> Result<JoinHandle<Result<()>>> {
         let server = self.server.0.clone();
         info!("[{}] Starting bot", server);
-        let handle = tokio::spawn((async move || -> Result<()> {
-            let (mut irc, irc_handle) = if self.use_tls {
-                irc::connect_tls(server.as_str(), &self.server, self.server.0.as_str()).await?
+        let name = format!("{} bot", server);
+        let handle = tasks::spawn(&name, async move {
+            status::set(&server, Phase::Connecting);
+            if !delay.is_zero() {
+                debug!("[{}] Waiting {:?} to connect", server, delay);
+                tokio::select! {
+                    () = sleep(delay) => {},
+                    _ = shutdown.changed() => return Ok(()),
+                }
+            }
+            // Held until the server welcomes us
+            let mut permit = match connecting {
+                Some(slots) => tokio::select! {
+                    permit = slots.acquire_owned() => Some(permit?),
+                    _ = shutdown.changed() => return Ok(()),
+                },
+                None => None,
+            };
+            let options = irc::Options {
+                dedup_window: Some(Duration::from_secs(self.dedup_window)).filter(|w| !w.is_zero()),
+                max_length:   self.max_length,
+                ip_version:   self.ip_version,
+                encodings:    Encodings::new(&self.encoding)?,
+                malformed:    self.malformed,
+            };
+            let (mut irc, mut irc_handle) = if self.use_tls {
+                irc::connect_tls(
+                    server.as_str(),
+                    &self.server,
+                    self.server.0.as_str(),
+                    options,
+                )
+                .await?
             } else {
-                irc::connect(server.as_str(), &self.server).await?
+                irc::connect(server.as_str(), &self.server, options).await?
             };
 
+            let state = state::State::new(self.nick.as_str(), &self.history);
+            let storage = Storage::new(server.as_str(), backend);
+            let prefs = Prefs::load(storage.clone()).await?;
+            let i18n = I18n::load(self.i18n.clone(), prefs.clone()).await?;
+            let backfill = Backfill::load(storage.clone()).await?;
+            let mut router = command::Router::new(
+                &irc,
+                state.clone(),
+                self.command_prefix.clone(),
+                self.triggers.clone(),
+                self.cooldowns.clone(),
+                i18n.clone(),
+                backfill.clone(),
+                self.bouncer.clone(),
+                prefs.clone(),
+                self.priorities.clone(),
+            );
+
             info!("[{}] Loading plugins", server);
-            let plugs = plugins::spawn_plugins(&irc, plugin_configs).await?;
+            let runtime = Runtime::load(storage.clone()).await?;
+            status::attach(
+                &server,
+                state.clone(),
+                irc.send_messages.clone(),
+                runtime.clone(),
+            );
+            let core = self
+                .core(
+                    &irc,
+                    state.clone(),
+                    storage.clone(),
+                    runtime.clone(),
+                    prefs,
+                    i18n,
+                )
+                .await?;
+            let plugin_configs = overlay(plugin_configs, &self.plugins);
+            let mut plugs =
+                plugins::spawn_plugins(&irc, &router, &core, plugin_configs, &registry).await?;
+            let lifecycle = router.lifecycle();
+
+            let mut invites = Invites::new(self.invites.clone(), storage.clone()).await?;
+
+            let relay = self.relay.clone().map(|config| {
+                let (server, relay) = (
+                    server.clone(),
+                    relay::serve(config, irc.clone(), state.clone()),
+                );
+                tasks::spawn(&format!("{} relay", server), async move {
+                    if let Err(e) = relay.await {
+                        error!("[{}] Client relay failed: {}", server, e);
+                    }
+                })
+            });
+
+            let quit = (
+                server.clone(),
+                irc.send_messages.clone(),
+                self.messages.clone(),
+            );
+            // Asks for a reconnection when the server lags too much
+            let (cycle, mut cycle_requests) = mpsc::channel(1);
+            // Saved once disconnected, whatever the reason
+            let last_seen = backfill.clone();
+
+            let name = format!("{} events", server);
+            let send_handle: JoinHandle<Result<()>> = tasks::spawn(&name, async move {
+                let nick = self.nick.clone();
+                let (ident, password) = match &self.bouncer {
+                    Some(bouncer) => (bouncer.username(&self.ident), bouncer.password.clone()),
+                    None => (self.ident, None),
+                };
+                irc.authenticate(
+                    self.nick,
+                    ident,
+                    self.real_name,
+                    password,
+                    self.sasl.as_ref(),
+                )
+                .await?;
+                // Bouncers keep the bot in its channels and may log in to
+                // services themselves
+                let (join, login) = match &self.bouncer {
+                    Some(bouncer) => (bouncer.join, bouncer.services_login),
+                    None => (true, true),
+                };
 
-            let send_handle = tokio::spawn((async move || -> Result<()> {
-                irc.authenticate(self.nick, self.ident, self.real_name)
-                    .await?;
+                // What ChanServ lists the bot as
+                let account = match &self.sasl {
+                    Some(sasl) => sasl.username.clone(),
+                    None => nick.clone(),
+                };
 
+                // Services logins, lag measurement and access checks,
+                // aborted if the connection goes away
+                let mut tasks = TaskSet::new();
+                let mut nick_taken = false;
                 loop {
                     while let Ok(msg) = irc.received_messages.recv().await {
+                        state.write().await.update(&msg);
+                        router.route(&msg).await?;
                         match msg.command {
                             irc::Command::Ping => irc.reply_pong(msg).await?,
-                            irc::Command::ErrNicknameInUse => irc.reply_nick_in_use(msg).await?,
-                            irc::Command::RplWelcome => irc.join(&self.channels).await?,
+                            irc::Command::ErrNicknameInUse => {
+                                nick_taken |= msg.parameters.first() == Some(&nick);
+                                irc.reply_nick_in_use(msg).await?
+                            },
+                            irc::Command::RplWelcome => {
+                                status::set(&server, Phase::Connected);
+                                permit.take();
+                                tasks.spawn(
+                                    "lag",
+                                    lag::measure(
+                                        server.clone(),
+                                        state.clone(),
+                                        irc.send_messages.clone(),
+                                        self.lag.clone(),
+                                        cycle.clone(),
+                                    ),
+                                );
+                                let services = self.services.clone().unwrap_or_default();
+                                if login && services.password.is_some() {
+                                    let login = services::login(
+                                        irc.clone(),
+                                        services,
+                                        nick.clone(),
+                                        nick_taken,
+                                    );
+                                    let server = server.clone();
+                                    tasks.spawn("login", async move {
+                                        if let Err(e) = login.await {
+                                            error!("[{}] Services login failed: {}", server, e);
+                                        }
+                                    });
+                                }
+                                let current_nick = state.read().await.nick.clone();
+                                if let Some(oper) = &self.oper {
+                                    let (irc, oper) = (irc.clone(), oper.clone());
+                                    let (server, nick) = (server.clone(), current_nick.clone());
+                                    tasks.spawn("oper", async move {
+                                        if let Err(e) = oper::up(&irc, &oper, &nick).await {
+                                            error!("[{}] OPER failed: {}", server, e);
+                                        }
+                                    });
+                                }
+                                if let Some(modes) = &self.user_modes {
+                                    let mode = irc::Message::mode(current_nick.as_str(), modes);
+                                    irc.send_messages
+                                        .send_with(mode, irc::Priority::Control)
+                                        .await?;
+                                }
+                                perform(&irc, &self.perform, &current_nick, &server).await?;
+                                if join {
+                                    irc.join_keyed(&runtime.channels(&self.channels).await)
+                                        .await?;
+                                    irc.join(invites.remembered()).await?;
+                                }
+                            },
+                            irc::Command::Join => {
+                                // Ban lists aren't sent on join, so ask for it
+                                let ours = match msg.source_nick() {
+                                    Some(nick) => state.read().await.is_me(nick),
+                                    None => false,
+                                };
+                                if let (true, Some(channel)) = (ours, &msg.target) {
+                                    let bans = irc::Message::mode(channel.as_str(), "+b");
+                                    irc.send_messages
+                                        .send_with(bans, irc::Priority::Bulk)
+                                        .await?;
+                                    // Catch up on what was said since we left
+                                    let history =
+                                        state.read().await.caps.contains(backfill::CAPABILITY);
+                                    if let Some(request) =
+                                        backfill.request(channel).filter(|_| history)
+                                    {
+                                        irc.send_messages
+                                            .send_with(request, irc::Priority::Bulk)
+                                            .await?;
+                                    }
+                                    let access = self
+                                        .access
+                                        .iter()
+                                        .find(|(name, _)| name.eq_ignore_ascii_case(channel));
+                                    if let Some((_, config)) = access {
+                                        let (irc, server) = (irc.clone(), server.clone());
+                                        let (channel, config) = (channel.clone(), config.clone());
+                                        let account = account.clone();
+                                        tasks.spawn("access", async move {
+                                            let check =
+                                                access::check(&irc, &channel, &config, &account);
+                                            if let Err(e) = check.await {
+                                                error!(
+                                                    "[{}] Access check of {} failed: {}",
+                                                    server, channel, e
+                                                );
+                                            }
+                                        });
+                                    }
+                                }
+                            },
+                            irc::Command::Invite => invites.handle(&mut irc, &msg).await?,
+                            irc::Command::ErrNoPrivileges => {
+                                warn!(
+                                    "[{}] Not privileged enough: {:?}",
+                                    server,
+                                    msg.parameters.last()
+                                );
+                            },
                             _ => trace!("[{}] Ignoring {:?}", server, msg),
                         }
                     }
                     debug!("bot task got a recv_msgs error =========loop=========");
                 }
-            })());
+            });
 
-            let res = irc_handle.await?;
+            let res = tokio::select! {
+                res = &mut irc_handle => res?,
+                Ok(()) = shutdown.changed() => {
+                    let (server, send_messages, messages) = &quit;
+                    info!("[{}] Disconnecting", server);
+                    disconnect(send_messages, messages, &mut irc_handle).await?;
+                    Ok(())
+                },
+                Some(()) = cycle_requests.recv() => {
+                    let (server, send_messages, messages) = &quit;
+                    disconnect(send_messages, messages, &mut irc_handle).await?;
+                    Err(anyhow!("{} is lagging too much", server))
+                },
+            };
             debug!("irc task exited: {:?}", res);
+            status::set(&quit.0, Phase::Disconnected);
+            if lifecycle.send(Lifecycle::Disconnected).is_ok() {
+                let stopped = future::join_all(plugs.values_mut());
+                if timeout(STOP_TIMEOUT, stopped).await.is_err() {
+                    warn!("[{}] Stopping plugins that didn't stop in time", quit.0);
+                }
+            }
             for (_, handle) in plugs.iter() {
                 handle.abort();
             }
             send_handle.abort();
+            if let Some(relay) = relay {
+                relay.abort();
+            }
+            core.scheduler.cancel_all();
+            if let Err(e) = last_seen.save().await {
+                error!("[{}] Could not save backfill times: {}", quit.0, e);
+            }
             res
-        })());
+        });
         Ok(handle)
     }
 }
 
+/// Sends the configured raw commands, skipping (and logging) invalid ones.
+async fn perform(irc: &irc::IRC, commands: &[String], nick: &str, server: &str) -> Result<()> {
+    for command in commands {
+        let line = command.replace("{nick}", nick).replace("{server}", server);
+        match irc::Message::raw(&line) {
+            Ok(msg) => {
+                irc.send_messages
+                    .send_with(msg, irc::Priority::Control)
+                    .await?
+            },
+            Err(e) => warn!("[{}] Not performing {:?}: {}", server, command, e),
+        }
+    }
+    Ok(())
+}
+
+/// Sends QUIT, giving the server a chance to close the connection itself
+/// before closing it ourselves.
+async fn disconnect(
+    send_messages: &irc::Outbox,
+    messages: &Messages,
+    irc_handle: &mut JoinHandle<Result<()>>,
+) -> Result<()> {
+    let quit = irc::Message::quit(messages.quit_message());
+    send_messages
+        .send_with(quit, irc::Priority::Control)
+        .await?;
+    if timeout(QUIT_TIMEOUT, &mut *irc_handle).await.is_err() {
+        irc_handle.abort();
+    }
+    Ok(())
+}
+
 impl Config {
     pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Config> {
         let file = File::open(&path)?;
-        let config: Config = from_reader(file)?;
+        let mut config: Config = from_reader(file)?;
+        let groups = &config.groups;
+        for bot in &mut config.bots {
+            bot.take_identity(&config.identities)?;
+            bot.channels = groups::channels(groups, &bot.channels)?;
+            for settings in bot.plugins.values_mut() {
+                *settings = groups::settings(groups, settings)?;
+            }
+        }
+        for settings in config.plugins.values_mut() {
+            *settings = groups::settings(groups, settings)?;
+        }
         Ok(config)
     }
 
-    pub async fn spawn_tasks(&self) -> Result<Vec<JoinHandle<Result<()>>>> {
+    /// The configuration as fit to be logged: the settings of plugins named
+    /// like secrets and the arguments of the commands performed after
+    /// connecting, which may log in somewhere, are replaced.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        redact_settings(&mut config.plugins);
+        for bot in &mut config.bots {
+            redact_settings(&mut bot.plugins);
+            for command in &mut bot.perform {
+                if let Some((name, _)) = command.split_once(' ') {
+                    *command = format!("{} ..", name);
+                }
+            }
+        }
+        config
+    }
+
+    /// Servers of the configured bots, with their ports.
+    pub fn servers(&self) -> impl Iterator<Item = String> + '_ {
+        self.bots
+            .iter()
+            .map(|bot| format!("{}:{}", bot.server.0, bot.server.1))
+    }
+
+    /// Where to serve tokio-console, if anywhere.
+    pub fn console(&self) -> Option<SocketAddr> {
+        self.console
+    }
+
+    /// The key encrypting plugin data, if one is configured.
+    pub async fn storage_key(&self) -> Result<Option<StorageKey>> {
+        match &self.storage_key {
+            Some(key) => Ok(Some(key.derive().await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Opens the storage of the bot connecting to `server`, bringing its data
+    /// to the latest format.
+    pub async fn open_storage(&self, server: &str) -> Result<Storage> {
+        if !self.bots.iter().any(|bot| bot.server.0 == server) {
+            return Err(anyhow!("no bot connects to {}", server));
+        }
+        let backend = storage::open(&self.storage, self.storage_key().await?).await?;
+        let storage = Storage::new(server, backend);
+        storage.migrate().await?;
+        Ok(storage)
+    }
+
+    /// Starts every bot with the plugins of the registry, reconnecting them
+    /// whenever their connection closes, until `shutdown` is set.
+    pub async fn spawn_tasks(
+        &self,
+        registry: &plugins::Registry,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<Vec<JoinHandle<Result<()>>>> {
+        #[cfg(feature = "http-server")]
+        if let Some(config) = self.listener.clone() {
+            let (servers, shutdown) = (self.bots.len(), shutdown.clone());
+            tasks::spawn("listener", async move {
+                if let Err(e) = listener::serve(config, servers, shutdown).await {
+                    error!("Webhook listener failed: {}", e);
+                }
+            });
+        }
+        tasks::spawn("systemd", systemd::run(self.bots.len(), shutdown.clone()));
+        // Derived once, as that's slow on purpose
+        let key = self.storage_key().await?;
+        let backend = storage::open(&self.storage, key.clone()).await?;
+        for bot in &self.bots {
+            Storage::new(bot.server.0.as_str(), backend.clone())
+                .migrate()
+                .await?;
+        }
+        if let Some(config) = self.backup.clone() {
+            let storages = self
+                .bots
+                .iter()
+                .map(|bot| Storage::new(bot.server.0.as_str(), backend.clone()))
+                .collect();
+            backup::schedule(config, storages, key, shutdown.clone())?;
+        }
+        let connecting = (self.startup.max_connecting > 0)
+            .then(|| Arc::new(Semaphore::new(self.startup.max_connecting)));
         let mut handles = vec![];
-        for bot in self.bots.clone() {
+        for (i, bot) in self.bots.clone().into_iter().enumerate() {
+            let delay = Duration::from_secs(self.startup.stagger * i as u64);
             handles.push((
                 bot.server.0.clone(),
-                bot.spawn_tasks(self.plugins.clone()).await?,
+                bot.spawn_tasks(
+                    self.plugins.clone(),
+                    registry.clone(),
+                    backend.clone(),
+                    delay,
+                    connecting.clone(),
+                    shutdown.clone(),
+                )
+                .await?,
             ));
         }
         let mut reconnection_handles = vec![];
         for (server, mut handle) in handles {
             let bots = self.clone();
-            reconnection_handles.push(tokio::spawn((async move || -> Result<()> {
-                while handle.await?.is_err() {
+            let backend = backend.clone();
+            let registry = registry.clone();
+            let connecting = connecting.clone();
+            let shutdown = shutdown.clone();
+            let name = format!("{} reconnection", server);
+            reconnection_handles.push(tasks::spawn(&name, async move {
+                while handle.await?.is_err() && !*shutdown.borrow() {
                     info!("[{}] Connection closed, restarting bot...", server);
-                    handle = bots.spawn_task(&server).await?;
+                    handle = bots
+                        .spawn_task(
+                            &server,
+                            &registry,
+                            backend.clone(),
+                            connecting.clone(),
+                            shutdown.clone(),
+                        )
+                        .await?;
                 }
                 info!("[{}] Closed cleanly, shutting down bot...", server);
                 Ok(())
-            })()));
+            }));
         }
         Ok(reconnection_handles)
     }
 
-    pub async fn spawn_task(&self, server: &str) -> Result<JoinHandle<Result<()>>> {
+    async fn spawn_task(
+        &self,
+        server: &str,
+        registry: &plugins::Registry,
+        backend: Arc<dyn Backend>,
+        connecting: Option<Arc<Semaphore>>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<JoinHandle<Result<()>>> {
         let bot = self
             .bots
             .iter()
             .find(|b| b.server.0 == server)
             .map(Ok)
             .unwrap_or_else(|| Err(anyhow!("could not find server {}", server)))?;
-        Ok(bot.clone().spawn_tasks(self.plugins.clone()).await?)
+        bot.clone()
+            .spawn_tasks(
+                self.plugins.clone(),
+                registry.clone(),
+                backend,
+                Duration::ZERO,
+                connecting,
+                shutdown,
+            )
+            .await
     }
 }
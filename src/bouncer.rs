@@ -0,0 +1,79 @@
+//! Running behind a bouncer like ZNC or soju, which stays connected to the
+//! network for the bot, keeps it in its channels and plays back what it
+//! missed when it reconnects.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use std::fmt;
+
+/// Nick soju's own commands are sent to and answered from
+const SOJU_SERVICE: &str = "BouncerServ";
+/// How much older than the connection a message must be to be taken as
+/// playback, in seconds, allowing for the bouncer's clock being off
+const PLAYBACK_SLACK: i64 = 30;
+
+/// How to connect through a bouncer
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct BouncerConfig {
+    /// Network to connect to on bouncers serving several, sent along the
+    /// username as `ident/network`
+    pub network:        Option<String>,
+    /// Password of the bouncer user, sent with PASS
+    pub password:       Option<String>,
+    /// Whether to still join the configured channels, which the bouncer
+    /// otherwise keeps the bot in
+    pub join:           bool,
+    /// Whether to still log in to NickServ, which the bouncer otherwise does
+    pub services_login: bool,
+    /// What ZNC's module nicks start with, like `*status`
+    pub status_prefix:  String,
+}
+
+// Keeps the password out of the logged configuration
+impl fmt::Debug for BouncerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BouncerConfig")
+            .field("network", &self.network)
+            .field("password", &self.password.as_ref().map(|_| ".."))
+            .field("join", &self.join)
+            .field("services_login", &self.services_login)
+            .field("status_prefix", &self.status_prefix)
+            .finish()
+    }
+}
+
+impl Default for BouncerConfig {
+    fn default() -> Self {
+        BouncerConfig {
+            network:        None,
+            password:       None,
+            join:           false,
+            services_login: false,
+            status_prefix:  "*".into(),
+        }
+    }
+}
+
+impl BouncerConfig {
+    /// The username to connect with, choosing the network if configured.
+    pub fn username(&self, ident: &str) -> String {
+        match &self.network {
+            Some(network) => format!("{}/{}", ident, network),
+            None => ident.into(),
+        }
+    }
+
+    /// Whether the nick is the bouncer itself talking, like ZNC's `*status`
+    /// or soju's `BouncerServ`, rather than a user.
+    pub fn is_service(&self, nick: &str) -> bool {
+        (!self.status_prefix.is_empty() && nick.starts_with(&self.status_prefix))
+            || nick.eq_ignore_ascii_case(SOJU_SERVICE)
+    }
+}
+
+/// Whether a message sent at the given time is being played back, having been
+/// sent before the bot connected at `connected`.
+pub fn is_playback(time: DateTime<Utc>, connected: DateTime<Utc>) -> bool {
+    time < connected - Duration::seconds(PLAYBACK_SLACK)
+}
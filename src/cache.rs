@@ -0,0 +1,246 @@
+//! Cache of HTTP responses, for requests asking for one. Responses are kept in
+//! memory, and mirrored on disk when configured so they survive restarts. A
+//! response is fresh for as long as its request asked, then answered with
+//! while stale for a while longer as it's fetched again in the background, so
+//! lookups made over and over don't wait on the provider every time.
+//!
+//! Responses are cached by URL, with the query parameters carrying
+//! credentials left out so API keys don't end up on disk.
+
+use anyhow::Result;
+use log::*;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Cached responses by URL, shared by all bots
+static ENTRIES: Mutex<BTreeMap<String, Entry>> = Mutex::new(BTreeMap::new());
+/// URLs being fetched again in the background
+static REFRESHING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Query parameters left out of the URLs responses are cached by
+const CREDENTIALS: &[&str] = &[
+    "access_token",
+    "api_key",
+    "api-key",
+    "apikey",
+    "appid",
+    "client_secret",
+    "key",
+    "password",
+    "secret",
+    "token",
+];
+
+/// How responses are cached
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Responses kept, the oldest being dropped, 0 to cache nothing
+    pub entries:   usize,
+    /// Directory to mirror cached responses in, if any
+    pub dir:       Option<String>,
+    /// Seconds a response is still answered with after it expires, while
+    /// it's fetched again
+    pub stale_for: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            entries:   1000,
+            dir:       None,
+            stale_for: 3600,
+        }
+    }
+}
+
+/// A successful response, as cached
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    url:          String,
+    status:       u16,
+    content_type: Option<String>,
+    /// Seconds since the epoch the response was fetched at
+    fetched:      u64,
+    /// Base64 encoded
+    body:         String,
+}
+
+impl Entry {
+    /// Reads the whole response into an entry.
+    pub async fn read(url: &str, response: reqwest::Response) -> Result<Entry> {
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let body = response.bytes().await?;
+        Ok(Entry {
+            url: url.into(),
+            status,
+            content_type,
+            fetched: now(),
+            body: base64::encode(body),
+        })
+    }
+
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(now().saturating_sub(self.fetched))
+    }
+
+    /// The response as it was fetched.
+    pub fn response(&self) -> Result<reqwest::Response> {
+        let mut response = hyper::Response::builder().status(self.status);
+        if let Some(content_type) = &self.content_type {
+            response = response.header(CONTENT_TYPE, content_type);
+        }
+        Ok(response.body(base64::decode(&self.body)?)?.into())
+    }
+}
+
+/// What the response to `url` is cached by: the URL without the query
+/// parameters carrying credentials.
+pub fn key(url: &Url) -> String {
+    let is_credential = |name: &str| CREDENTIALS.contains(&name.to_lowercase().as_str());
+    if !url.query_pairs().any(|(name, _)| is_credential(&name)) {
+        return url.to_string();
+    }
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| !is_credential(name))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    let mut url = url.clone();
+    if kept.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(kept);
+    }
+    url.to_string()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Where the entry for `url` is mirrored, named after its hash.
+fn path(dir: &str, url: &str) -> PathBuf {
+    let hash: String = openssl::sha::sha256(url.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    PathBuf::from(dir).join(format!("{}.json", hash))
+}
+
+/// The cached entry for `url`, from memory or otherwise from disk.
+pub async fn get(config: &CacheConfig, url: &str) -> Option<Entry> {
+    if let Some(entry) = ENTRIES.lock().unwrap().get(url) {
+        return Some(entry.clone());
+    }
+    let path = path(config.dir.as_ref()?, url);
+    let text = tokio::fs::read_to_string(&path).await.ok()?;
+    match serde_json::from_str::<Entry>(&text) {
+        // A different URL with the same hash isn't the one wanted
+        Ok(entry) if entry.url == url => {
+            remember(config, entry.clone()).await;
+            Some(entry)
+        },
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Ignoring cached response {}: {}", path.display(), e);
+            None
+        },
+    }
+}
+
+/// Caches the entry, mirroring it on disk if configured.
+pub async fn put(config: &CacheConfig, entry: Entry) {
+    if let Some(dir) = &config.dir {
+        let written = async {
+            tokio::fs::create_dir_all(dir).await?;
+            let text = serde_json::to_string(&entry)?;
+            tokio::fs::write(path(dir, &entry.url), text).await?;
+            Ok::<_, anyhow::Error>(())
+        };
+        if let Err(e) = written.await {
+            warn!(
+                "Could not cache the response of {} on disk: {}",
+                entry.url, e
+            );
+        }
+    }
+    remember(config, entry).await;
+}
+
+/// Keeps the entry in memory, dropping the oldest ones past `entries`, on
+/// disk too.
+async fn remember(config: &CacheConfig, entry: Entry) {
+    let dropped = {
+        let mut entries = ENTRIES.lock().unwrap();
+        entries.insert(entry.url.clone(), entry);
+        let mut dropped = vec![];
+        while entries.len() > config.entries {
+            let oldest = entries
+                .values()
+                .min_by_key(|entry| entry.fetched)
+                .map(|entry| entry.url.clone());
+            match oldest {
+                Some(url) => {
+                    entries.remove(&url);
+                    dropped.push(url);
+                },
+                None => break,
+            }
+        }
+        dropped
+    };
+    if let Some(dir) = &config.dir {
+        for url in dropped {
+            let _ = tokio::fs::remove_file(path(dir, &url)).await;
+        }
+    }
+}
+
+/// Marks `url` as being fetched again, returning whether it wasn't already.
+pub fn start_refresh(url: &str) -> bool {
+    REFRESHING.lock().unwrap().insert(url.into())
+}
+
+pub fn finish_refresh(url: &str) {
+    REFRESHING.lock().unwrap().remove(url);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_credentials_out_of_keys() {
+        let key = |url: &str| super::key(&Url::parse(url).unwrap());
+        assert_eq!(
+            key("https://api.example.org/search?api_key=hunter2&query=dune"),
+            "https://api.example.org/search?query=dune"
+        );
+        assert_eq!(
+            key("https://api.example.org/weather?q=Paris&APPID=hunter2"),
+            "https://api.example.org/weather?q=Paris"
+        );
+        assert_eq!(
+            key("https://api.example.org/?token=hunter2"),
+            "https://api.example.org/"
+        );
+        assert_eq!(
+            key("https://api.example.org/?q=a%20b&page=2"),
+            "https://api.example.org/?q=a%20b&page=2"
+        );
+    }
+}
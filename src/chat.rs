@@ -0,0 +1,111 @@
+//! PRIVMSGs as plugins get them, parsed once by the core: where they were
+//! sent, who by, and whether they're `/me` actions or other CTCP requests.
+
+use chrono::{DateTime, Utc};
+
+use crate::irc;
+
+/// Wraps CTCP requests, including actions
+const CTCP_DELIMITER: char = '\x01';
+const CTCP_ACTION: &str = "ACTION";
+
+/// Where a message was sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Place {
+    /// A channel, named as the sender wrote it
+    Channel(String),
+    /// A private message to the bot
+    Query,
+}
+
+/// A message a user sent to a channel or to the bot.
+#[derive(Debug, Clone)]
+pub struct ChatEvent {
+    pub place:      Place,
+    pub sender:     irc::User,
+    /// Services account the sender is logged in to, on servers supporting
+    /// `account-tag`
+    pub account:    Option<String>,
+    /// Text of the message, without the CTCP wrapping of actions. For other
+    /// CTCP requests, the request itself, like `VERSION` or `PING 1234`.
+    pub body:       String,
+    /// Whether this was a `/me` action
+    pub is_action:  bool,
+    /// Whether this was a CTCP request other than an action
+    pub is_ctcp:    bool,
+    /// Whether the sender is marked as a bot, on servers tagging the messages
+    /// of bots with `bot`
+    pub is_bot:     bool,
+    /// When it was sent, as told by servers supporting `server-time`, or
+    /// else when it was received
+    pub time:       DateTime<Utc>,
+    /// Whether this was replayed from the channel's history, said while the
+    /// bot was away
+    pub is_history: bool,
+    /// Id of the message, on servers supporting `message-tags`, for replies
+    /// to point at
+    pub msgid:      Option<String>,
+    /// Plugin that claimed the message to answer it, the others that would
+    /// have leaving it alone
+    pub claimed_by: Option<String>,
+}
+
+impl ChatEvent {
+    /// Parses a PRIVMSG sent by a user, with its text as only parameter. It's
+    /// taken as live chat, not history.
+    pub fn parse(msg: &irc::Message) -> Option<ChatEvent> {
+        if msg.command != irc::Command::Privmsg || msg.parameters.len() != 1 {
+            return None;
+        }
+        let target = msg.target.as_ref()?;
+        let sender = msg.source_as_user()?;
+        let place = if irc::is_channel(target) {
+            Place::Channel(target.clone())
+        } else {
+            Place::Query
+        };
+
+        let text = &msg.parameters[0];
+        let (body, is_action, is_ctcp) = match text.strip_prefix(CTCP_DELIMITER) {
+            Some(ctcp) => {
+                let ctcp = ctcp.strip_suffix(CTCP_DELIMITER).unwrap_or(ctcp);
+                match ctcp.split_once(' ').unwrap_or((ctcp, "")) {
+                    (CTCP_ACTION, action) => (action, true, false),
+                    _ => (ctcp, false, true),
+                }
+            },
+            None => (text.as_str(), false, false),
+        };
+        let time = msg
+            .tag("time")
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map_or_else(Utc::now, |time| time.with_timezone(&Utc));
+        Some(ChatEvent {
+            place,
+            sender,
+            account: msg.tag("account").map(String::from),
+            body: body.into(),
+            is_action,
+            is_ctcp,
+            is_bot: msg.tag("bot").is_some(),
+            time,
+            is_history: false,
+            msgid: msg.tag("msgid").map(String::from),
+            claimed_by: None,
+        })
+    }
+
+    /// The channel the message was sent to, unless it was private.
+    pub fn channel(&self) -> Option<&str> {
+        match &self.place {
+            Place::Channel(channel) => Some(channel),
+            Place::Query => None,
+        }
+    }
+
+    /// Where replies should go: the channel, or the sender for private
+    /// messages.
+    pub fn reply_to(&self) -> &str {
+        self.channel().unwrap_or(&self.sender.nick)
+    }
+}
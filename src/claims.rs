@@ -0,0 +1,57 @@
+//! Settling which plugin answers a message several of them could, like a
+//! GitHub URL also matching a trigger. Plugins are asked in the order of the
+//! bot's `priorities`, lowest first, and the first one claiming the message
+//! is the only one of those claiming it to answer it.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::chat::ChatEvent;
+
+/// Whether a plugin would answer a message
+pub(crate) type Claim = Box<dyn Fn(&ChatEvent) -> bool + Send + Sync>;
+
+struct Claimant {
+    priority: i32,
+    plugin:   String,
+    claim:    Claim,
+}
+
+/// Plugins taking part in claiming messages, in the order they're asked.
+#[derive(Clone)]
+pub(crate) struct Claims {
+    /// Priorities by plugin name, 0 for plugins not listed
+    priorities: Arc<HashMap<String, i32>>,
+    claimants:  Arc<RwLock<Vec<Claimant>>>,
+}
+
+impl Claims {
+    pub fn new(priorities: HashMap<String, i32>) -> Self {
+        Claims {
+            priorities: Arc::new(priorities),
+            claimants:  Arc::default(),
+        }
+    }
+
+    /// Adds a plugin to the ones asked, after those with the same priority.
+    pub fn register(&self, plugin: &str, claim: Claim) {
+        let priority = self.priorities.get(plugin).copied().unwrap_or_default();
+        let mut claimants = self.claimants.write().unwrap();
+        let at = claimants.partition_point(|c| c.priority <= priority);
+        claimants.insert(
+            at,
+            Claimant {
+                priority,
+                plugin: plugin.into(),
+                claim,
+            },
+        );
+    }
+
+    /// The plugin that gets to answer the message, if any would.
+    pub fn claim(&self, event: &ChatEvent) -> Option<String> {
+        let claimants = self.claimants.read().unwrap();
+        let claimant = claimants.iter().find(|c| (c.claim)(event))?;
+        Some(claimant.plugin.clone())
+    }
+}
@@ -0,0 +1,697 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::*;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+use crate::args::Args;
+use crate::backfill::{self, Backfill};
+use crate::batch::{self, Batch, Batches};
+use crate::bouncer::{self, BouncerConfig};
+use crate::chat::{ChatEvent, Place};
+use crate::claims::Claims;
+use crate::i18n::I18n;
+use crate::irc;
+use crate::netsplit::{self, Gatherer};
+use crate::notices::ServerEvent;
+use crate::plugins::Subscription;
+use crate::prefs::Prefs;
+use crate::state;
+use crate::tasks;
+
+/// Prefix used to invoke commands when none is configured, e.g. `\w london`
+pub const DEFAULT_PREFIX: &str = "\\";
+
+const INVOCATION_CHAN: usize = 16;
+/// Room for the history backfilled for a channel on top of live chat
+const CHAT_CHAN: usize = 16 + backfill::LIMIT;
+const BATCH_CHAN: usize = 16;
+/// Room for bursts of server notices, like opers get on a busy network
+const NOTICE_CHAN: usize = 64;
+const LIFECYCLE_CHAN: usize = 16;
+/// Per plugin subscribed to messages from the server
+const MESSAGE_CHAN: usize = 16;
+
+/// A plugin subscribed to messages from the server, and where to send them
+type Subscriber = (Subscription, broadcast::Sender<irc::Message>);
+
+/// Commands from a user within this long of the bot speaking where they
+/// came from count as reactions to it, as another bot's would
+const REACTION_WINDOW: Duration = Duration::from_secs(3);
+/// Reactions in a row taken as another bot and this one triggering each
+/// other's commands
+const LOOP_REACTIONS: u32 = 5;
+/// How long commands from a user caught in a loop are ignored
+const LOOP_MUTE: Duration = Duration::from_secs(10 * 60);
+
+/// Which ways of addressing the bot are accepted as commands
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Triggers {
+    /// `\weather london`
+    pub prefix:  bool,
+    /// `boton: weather london` or `boton, weather london`
+    pub address: bool,
+    /// `weather london` sent in a private message, without any prefix
+    pub private: bool,
+}
+
+impl Default for Triggers {
+    fn default() -> Self {
+        Triggers {
+            prefix:  true,
+            address: true,
+            private: true,
+        }
+    }
+}
+
+/// Prefixes accepted for invoking commands, configured either as a single
+/// string or as a list. The first one is used when mentioning commands in
+/// replies.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(from = "PrefixConfig")]
+pub struct Prefixes(Vec<String>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PrefixConfig {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<PrefixConfig> for Prefixes {
+    fn from(config: PrefixConfig) -> Self {
+        let prefixes = match config {
+            PrefixConfig::One(prefix) => vec![prefix],
+            PrefixConfig::Many(prefixes) => prefixes,
+        };
+        let prefixes: Vec<String> = prefixes.into_iter().filter(|p| !p.is_empty()).collect();
+        if prefixes.is_empty() {
+            return Prefixes::default();
+        }
+        Prefixes(prefixes)
+    }
+}
+
+impl Default for Prefixes {
+    fn default() -> Self {
+        Prefixes(vec![DEFAULT_PREFIX.into()])
+    }
+}
+
+impl Prefixes {
+    /// The prefix used when mentioning commands in replies.
+    pub fn primary(&self) -> &str {
+        &self.0[0]
+    }
+
+    /// Strips any of the prefixes from the text, returning the prefix that
+    /// matched and the rest of the text.
+    pub fn strip<'a>(&self, text: &'a str) -> Option<(&str, &'a str)> {
+        self.0
+            .iter()
+            .find_map(|prefix| Some((prefix.as_str(), text.strip_prefix(prefix.as_str())?)))
+    }
+}
+
+/// Minimum time between invocations of a single command, in seconds
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Cooldown {
+    /// How long each user has to wait before using the command again
+    pub user:    u64,
+    /// How long anyone has to wait before using the command again in the same
+    /// channel
+    pub channel: u64,
+}
+
+/// State for the per-command cooldowns, keyed by command and user/channel.
+struct Cooldowns {
+    config: HashMap<String, Cooldown>,
+    /// When each key becomes usable again, and whether the user was already
+    /// told about it
+    until:  HashMap<(String, String), (Instant, bool)>,
+}
+
+/// Outcome of checking an invocation against the cooldowns.
+enum Throttle {
+    Allowed,
+    /// Throttled, and the user should be told to wait this long
+    Notify(Duration),
+    /// Throttled, but the user was already told about it
+    Silent,
+}
+
+impl Cooldowns {
+    fn new(config: HashMap<String, Cooldown>) -> Self {
+        Cooldowns {
+            config,
+            until: HashMap::new(),
+        }
+    }
+
+    fn check(&mut self, invocation: &Invocation) -> Throttle {
+        let cooldown = match self.config.get(&invocation.name) {
+            Some(cooldown) => cooldown,
+            None => return Throttle::Allowed,
+        };
+        let now = Instant::now();
+        self.until.retain(|_, (until, _)| *until > now);
+
+        let mut keys = vec![];
+        if cooldown.user > 0 {
+            let key = format!("user:{}", invocation.user.nick.to_lowercase());
+            keys.push((key, cooldown.user));
+        }
+        if cooldown.channel > 0 && irc::is_channel(&invocation.reply_to) {
+            let key = format!("channel:{}", invocation.reply_to.to_lowercase());
+            keys.push((key, cooldown.channel));
+        }
+
+        for (key, _) in &keys {
+            let key = (invocation.name.clone(), key.clone());
+            if let Some((until, notified)) = self.until.get_mut(&key) {
+                if *notified {
+                    return Throttle::Silent;
+                }
+                *notified = true;
+                return Throttle::Notify(*until - now);
+            }
+        }
+        for (key, secs) in keys {
+            let until = now + Duration::from_secs(secs);
+            self.until
+                .insert((invocation.name.clone(), key), (until, false));
+        }
+        Throttle::Allowed
+    }
+}
+
+/// Catches another bot and this one triggering each other's commands
+/// endlessly: commands from a user who keeps answering the bot right after it
+/// speaks get ignored for a while.
+struct LoopGuard {
+    /// When the bot last spoke to each lowercase target, kept by a task
+    /// watching what's sent
+    spoke:     Arc<Mutex<HashMap<String, Instant>>>,
+    /// Reactions in a row of each lowercase nick
+    reactions: HashMap<String, u32>,
+    /// Lowercase nicks whose commands are ignored, until when
+    muted:     HashMap<String, Instant>,
+}
+
+impl LoopGuard {
+    fn new(irc: &irc::IRC) -> Self {
+        let spoke = Arc::new(Mutex::new(HashMap::new()));
+        let mut sent = irc.subscribe_sent();
+        let spoken = spoke.clone();
+        tasks::spawn(&format!("{} loop guard", irc.server), async move {
+            loop {
+                let msg = match sent.recv().await {
+                    Ok(msg) => msg,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if !matches!(msg.command, irc::Command::Privmsg | irc::Command::Notice) {
+                    continue;
+                }
+                if let Some(target) = msg.target {
+                    let mut spoke = spoken.lock().unwrap();
+                    spoke.insert(target.to_lowercase(), Instant::now());
+                }
+            }
+        });
+        LoopGuard {
+            spoke,
+            reactions: HashMap::new(),
+            muted: HashMap::new(),
+        }
+    }
+
+    /// Whether the invocation is to be ignored, its user being caught in a
+    /// loop with the bot.
+    fn is_loop(&mut self, invocation: &Invocation) -> bool {
+        let now = Instant::now();
+        let nick = invocation.user.nick.to_lowercase();
+        self.muted.retain(|_, until| *until > now);
+        if self.muted.contains_key(&nick) {
+            return true;
+        }
+
+        let spoke = self
+            .spoke
+            .lock()
+            .unwrap()
+            .get(&invocation.reply_to.to_lowercase())
+            .copied();
+        if spoke.is_none_or(|spoke| now.duration_since(spoke) >= REACTION_WINDOW) {
+            self.reactions.remove(&nick);
+            return false;
+        }
+        let reactions = self.reactions.entry(nick.clone()).or_default();
+        *reactions += 1;
+        if *reactions < LOOP_REACTIONS {
+            return false;
+        }
+        warn!(
+            "Ignoring commands from {} for {}s, as they keep answering us like a bot in a loop",
+            invocation.user.nick,
+            LOOP_MUTE.as_secs()
+        );
+        self.reactions.remove(&nick);
+        self.muted.insert(nick, now + LOOP_MUTE);
+        true
+    }
+}
+
+/// Usage information for a command provided by a plugin
+pub struct Help {
+    pub name:  &'static str,
+    /// Command syntax without the prefix, followed by a short description
+    pub usage: &'static str,
+}
+
+/// How a given command was invoked.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Trigger {
+    Prefix,
+    Address,
+    Private,
+}
+
+/// A command addressed to the bot, already stripped of its trigger.
+#[derive(Clone, Debug)]
+pub struct Invocation {
+    /// Lowercase command name
+    pub name:     String,
+    /// Rest of the line after the command name, if any
+    pub args:     Option<String>,
+    /// User who sent the command
+    pub user:     irc::User,
+    /// Where replies should go: the channel, or the user for private messages
+    pub reply_to: String,
+    pub trigger:  Trigger,
+    /// Prefix to use when mentioning commands in replies: the one used to
+    /// invoke this command, if any
+    pub prefix:   String,
+    /// The message the command came in
+    pub chat:     ChatEvent,
+}
+
+impl Invocation {
+    /// Splits the arguments into words, flags and options.
+    pub fn parse_args(&self) -> Args<'_> {
+        Args::parse(self.args.as_deref().unwrap_or_default())
+    }
+
+    /// Builds a PRIVMSG replying to wherever this command came from, and to
+    /// the message it came in.
+    pub fn reply<S: Into<String>>(&self, text: S) -> irc::Outgoing {
+        irc::Outgoing::privmsg(self.reply_to.clone(), text.into())
+            .in_reply_to(self.chat.msgid.as_deref())
+    }
+}
+
+pub fn split_first_word(text: &str) -> (&str, Option<&str>) {
+    if let Some(space) = text.find(' ') {
+        (&text[.. space], Some(&text[space + 1 ..]))
+    } else {
+        (text, None)
+    }
+}
+
+/// Strips a leading `nick:` or `nick,` from the text, if present.
+fn strip_address<'a>(text: &'a str, nick: &str) -> Option<&'a str> {
+    let addressed = text.get(.. nick.len())?;
+    if !addressed.eq_ignore_ascii_case(nick) {
+        return None;
+    }
+    let rest = &text[nick.len() ..];
+    let rest = rest.strip_prefix(':').or_else(|| rest.strip_prefix(','))?;
+    Some(rest.trim_start())
+}
+
+/// Tries to interpret a chat message as a command, according to the enabled
+/// triggers. Actions, CTCP requests and messages from bots never are.
+pub fn parse(
+    event: &ChatEvent,
+    nick: &str,
+    prefixes: &Prefixes,
+    triggers: &Triggers,
+) -> Option<Invocation> {
+    if event.is_action || event.is_ctcp || event.is_bot {
+        return None;
+    }
+    let private = event.place == Place::Query;
+    let text = event.body.trim();
+
+    let mut prefix = prefixes.primary();
+    let (trigger, line) =
+        if let Some((used, line)) = prefixes.strip(text).filter(|_| triggers.prefix) {
+            prefix = used;
+            (Trigger::Prefix, line)
+        } else if let Some(line) = strip_address(text, nick).filter(|_| triggers.address) {
+            (Trigger::Address, line)
+        } else if private && triggers.private {
+            (Trigger::Private, text)
+        } else {
+            return None;
+        };
+
+    let (name, args) = split_first_word(line);
+    if name.is_empty() {
+        return None;
+    }
+    let args = args.map(str::trim).filter(|a| !a.is_empty());
+
+    Some(Invocation {
+        name: name.to_lowercase(),
+        args: args.map(String::from),
+        user: event.sender.clone(),
+        reply_to: event.reply_to().into(),
+        trigger,
+        prefix: prefix.into(),
+        chat: event.clone(),
+    })
+}
+
+/// Points in the life of a connection that plugins are told about.
+#[derive(Debug, Clone)]
+pub(crate) enum Lifecycle {
+    /// Registered with the server
+    Connected,
+    /// In the channel, with its NAMES in
+    Joined(String),
+    /// The connection is gone, and the plugins are about to be stopped
+    Disconnected,
+}
+
+/// Turns incoming PRIVMSGs into chat events and command invocations for the
+/// plugins, enforcing the configured cooldowns, and collects batches of
+/// messages for them. The bot's own messages, echoed back by the server, are
+/// dropped.
+pub struct Router {
+    state:         state::Handle,
+    prefixes:      Prefixes,
+    triggers:      Triggers,
+    cooldowns:     Cooldowns,
+    loop_guard:    LoopGuard,
+    invocations:   broadcast::Sender<Invocation>,
+    chat:          broadcast::Sender<ChatEvent>,
+    /// Which plugin answers live chat several of them could
+    claims:        Claims,
+    batches:       Batches,
+    batched:       broadcast::Sender<Batch>,
+    /// WALLOPS and server notices
+    notices:       broadcast::Sender<ServerEvent>,
+    lifecycle:     broadcast::Sender<Lifecycle>,
+    /// Plugins subscribed to messages from the server, sent only those
+    /// they want
+    messages:      Arc<RwLock<Vec<Subscriber>>>,
+    /// Lowercase channels joined whose NAMES aren't all in yet
+    joining:       HashSet<String>,
+    /// Gathers netsplits from servers not sending them in batches
+    netsplits:     Gatherer,
+    backfill:      Backfill,
+    bouncer:       Option<BouncerConfig>,
+    /// When the connection was made, to tell what a bouncer plays back
+    connected:     DateTime<Utc>,
+    /// Told which accounts users are logged in to
+    prefs:         Prefs,
+    send_messages: irc::Outbox,
+    i18n:          I18n,
+}
+
+impl Router {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        irc: &irc::IRC,
+        state: state::Handle,
+        prefixes: Prefixes,
+        triggers: Triggers,
+        cooldowns: HashMap<String, Cooldown>,
+        i18n: I18n,
+        backfill: Backfill,
+        bouncer: Option<BouncerConfig>,
+        prefs: Prefs,
+        priorities: HashMap<String, i32>,
+    ) -> Self {
+        let (invocations, _) = broadcast::channel(INVOCATION_CHAN);
+        let (chat, _) = broadcast::channel(CHAT_CHAN);
+        let (batched, _) = broadcast::channel(BATCH_CHAN);
+        let (notices, _) = broadcast::channel(NOTICE_CHAN);
+        let (lifecycle, _) = broadcast::channel(LIFECYCLE_CHAN);
+        Router {
+            state,
+            prefixes,
+            triggers,
+            cooldowns: Cooldowns::new(cooldowns),
+            loop_guard: LoopGuard::new(irc),
+            invocations,
+            chat,
+            claims: Claims::new(priorities),
+            batches: Batches::default(),
+            netsplits: Gatherer::new(&irc.server, batched.clone()),
+            batched,
+            notices,
+            lifecycle,
+            messages: Arc::default(),
+            joining: HashSet::new(),
+            backfill,
+            bouncer,
+            connected: Utc::now(),
+            prefs,
+            send_messages: irc.send_messages.clone(),
+            i18n,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Invocation> {
+        self.invocations.subscribe()
+    }
+
+    pub fn subscribe_chat(&self) -> broadcast::Receiver<ChatEvent> {
+        self.chat.subscribe()
+    }
+
+    pub(crate) fn claims(&self) -> &Claims {
+        &self.claims
+    }
+
+    pub fn subscribe_batches(&self) -> broadcast::Receiver<Batch> {
+        self.batched.subscribe()
+    }
+
+    pub fn subscribe_notices(&self) -> broadcast::Receiver<ServerEvent> {
+        self.notices.subscribe()
+    }
+
+    /// Messages from the server the subscription wants, or `None` when it
+    /// wants none of them.
+    pub(crate) fn subscribe_messages(
+        &self,
+        subscription: Subscription,
+    ) -> Option<broadcast::Receiver<irc::Message>> {
+        if let Subscription::None = subscription {
+            return None;
+        }
+        let (sender, receiver) = broadcast::channel(MESSAGE_CHAN);
+        self.messages.write().unwrap().push((subscription, sender));
+        Some(receiver)
+    }
+
+    pub(crate) fn subscribe_lifecycle(&self) -> broadcast::Receiver<Lifecycle> {
+        self.lifecycle.subscribe()
+    }
+
+    /// For telling plugins the connection is gone, once the router is too.
+    pub(crate) fn lifecycle(&self) -> broadcast::Sender<Lifecycle> {
+        self.lifecycle.clone()
+    }
+
+    /// Handles any message received. PRIVMSGs in a batch are only handled
+    /// once it ends: multiline ones as the single message they make up, and
+    /// replayed history as chat marked as such, never taken as commands.
+    pub async fn route(&mut self, msg: &irc::Message) -> Result<()> {
+        for (subscription, sender) in self.messages.read().unwrap().iter() {
+            if subscription.wants(msg) {
+                // Only failing once the plugin is gone
+                let _ = sender.send(msg.clone());
+            }
+        }
+        match (&msg.command, msg.source_nick(), &msg.target) {
+            (irc::Command::Nick, Some(old), Some(new)) => self.prefs.renamed(old, new),
+            (irc::Command::Quit, Some(nick), _) => self.prefs.left(nick),
+            _ => {},
+        }
+        self.track_lifecycle(msg).await;
+        if let Some(event) = ServerEvent::parse(msg) {
+            if self.notices.send(event).is_err() {
+                debug!("No plugins listening for server notices");
+            }
+        }
+        if !self.batches.contains(msg) {
+            self.gather_netsplit(msg).await;
+        }
+        if let Some(batch) = self.batches.collect(msg) {
+            trace!("Got batch {:?}", batch);
+            if let Some(msg) = batch.multiline() {
+                self.route_chat(&msg).await?;
+            }
+            if batch.kind == batch::CHATHISTORY {
+                self.replay(&batch);
+            }
+            if self.batched.send(batch).is_err() {
+                debug!("No plugins listening for batches");
+            }
+            return Ok(());
+        }
+        if msg.command != irc::Command::Privmsg || self.batches.contains(msg) {
+            return Ok(());
+        }
+        self.route_chat(msg).await
+    }
+
+    /// Tells plugins about registering, and about joining channels once
+    /// their NAMES are in.
+    async fn track_lifecycle(&mut self, msg: &irc::Message) {
+        let event = match (&msg.command, msg.source_nick(), &msg.target) {
+            (irc::Command::RplWelcome, _, _) => Some(Lifecycle::Connected),
+            (irc::Command::Join, Some(nick), Some(channel)) => {
+                if self.state.read().await.is_me(nick) {
+                    self.joining.insert(channel.to_lowercase());
+                }
+                None
+            },
+            // :server 366 ournick #channel :End of /NAMES list.
+            (irc::Command::Other(numeric), _, _) if numeric == "366" => msg
+                .parameters
+                .first()
+                .filter(|channel| self.joining.remove(&channel.to_lowercase()))
+                .map(|channel| Lifecycle::Joined(channel.clone())),
+            _ => None,
+        };
+        if let Some(event) = event {
+            if self.lifecycle.send(event).is_err() {
+                debug!("No plugins listening for the connection's lifecycle");
+            }
+        }
+    }
+
+    /// Gathers the message into a netsplit batch if it's the QUIT of a user
+    /// lost in a split, or the JOIN of one coming back.
+    async fn gather_netsplit(&self, msg: &irc::Message) {
+        if let Some((first, second)) = netsplit::servers(msg) {
+            let servers = [first.into(), second.into()];
+            self.netsplits.add(batch::NETSPLIT, &servers, msg);
+        } else if let (irc::Command::Join, Some(nick)) = (&msg.command, msg.source_nick()) {
+            if let Some(servers) = self.state.read().await.netsplits.rejoining(nick) {
+                self.netsplits.add(batch::NETJOIN, servers, msg);
+            }
+        }
+    }
+
+    /// Passes on the messages of a chathistory batch as history.
+    fn replay(&self, batch: &Batch) {
+        let multiline = batch.batches.iter().filter_map(Batch::multiline);
+        let messages = batch.messages.iter().cloned().chain(multiline);
+        for mut event in messages.filter_map(|msg| ChatEvent::parse(&msg)) {
+            event.is_history = true;
+            if let Some(channel) = event.channel() {
+                self.backfill.seen(channel, event.time);
+            }
+            if self.chat.send(event).is_err() {
+                debug!("No plugins listening for chat");
+            }
+        }
+    }
+
+    async fn route_chat(&mut self, msg: &irc::Message) -> Result<()> {
+        let mut event = match ChatEvent::parse(msg) {
+            Some(event) => event,
+            None => return Ok(()),
+        };
+        if let Some(bouncer) = &self.bouncer {
+            if bouncer.is_service(&event.sender.nick) {
+                info!("{} says: {}", event.sender.nick, event.body);
+                return Ok(());
+            }
+            // Played back by the bouncer, so taken as history
+            event.is_history = bouncer::is_playback(event.time, self.connected);
+        }
+        if let Some(channel) = event.channel() {
+            self.backfill.seen(channel, event.time);
+        }
+        let (nick, is_me) = {
+            let state = self.state.read().await;
+            (state.nick.clone(), state.is_me(&event.sender.nick))
+        };
+        if is_me {
+            trace!("Dropping our own message: {}", event.body);
+            return Ok(());
+        }
+        if !event.is_history {
+            let account = event.account.as_deref();
+            self.state
+                .write()
+                .await
+                .links
+                .seen(&event.sender.nick, account);
+            if let Err(e) = self.prefs.seen(&event.sender.nick, account).await {
+                warn!("Could not move preferences to an account: {}", e);
+            }
+        }
+        let invocation = if event.is_history {
+            None
+        } else {
+            event.claimed_by = self.claims.claim(&event);
+            parse(&event, &nick, &self.prefixes, &self.triggers)
+        };
+        if self.chat.send(event).is_err() {
+            debug!("No plugins listening for chat");
+        }
+        let invocation = match invocation {
+            Some(invocation) => invocation,
+            None => return Ok(()),
+        };
+
+        if self.loop_guard.is_loop(&invocation) {
+            debug!("Ignoring {:?} as part of a loop", invocation);
+            return Ok(());
+        }
+        match self.cooldowns.check(&invocation) {
+            Throttle::Allowed => {},
+            Throttle::Notify(wait) => {
+                debug!("Throttling {:?}", invocation);
+                let notice = self.i18n.tr(
+                    &invocation.user.nick,
+                    &invocation.reply_to,
+                    "Please wait {0}s before using {1}{2} again",
+                    &[
+                        &(wait.as_secs() + 1).to_string(),
+                        &invocation.prefix,
+                        &invocation.name,
+                    ],
+                );
+                let notice = irc::Outgoing::notice(invocation.user.nick, notice);
+                self.send_messages.send_outgoing(notice).await?;
+                return Ok(());
+            },
+            Throttle::Silent => {
+                trace!("Throttling {:?} silently", invocation);
+                return Ok(());
+            },
+        }
+
+        trace!("Routing {:?}", invocation);
+        if self.invocations.send(invocation).is_err() {
+            debug!("No plugins listening for commands");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+//! `boton export-data` and `boton import-data`, which move plugin data out
+//! and in as JSON, to switch storage backends or bring data over from other
+//! bots once converted.
+//!
+//! `export-data --server <server>` prints every data set of the bot
+//! connecting to the server as a JSON object by name, and `--plugin <name>`
+//! prints only the named one. `import-data` takes the same options and reads
+//! the same JSON from standard input, replacing the data sets it has.
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::io::Read;
+
+use crate::bot::Config;
+use crate::storage::{DataSet, Storage};
+use crate::{awards, backfill, invite, prefs, runtime};
+
+/// Every data set whose format is known
+const DATA_SETS: &[DataSet] = &[
+    awards::DATA_SET,
+    backfill::DATA_SET,
+    invite::DATA_SET,
+    prefs::DATA_SET,
+    runtime::DATA_SET,
+    #[cfg(feature = "alertmanager")]
+    crate::plugins::alertmanager::DATA_SET,
+    #[cfg(feature = "bans")]
+    crate::plugins::bans::DATA_SET,
+    #[cfg(feature = "chanstats")]
+    crate::plugins::chanstats::DATA_SET,
+    #[cfg(feature = "football")]
+    crate::plugins::football::DATA_SET,
+    #[cfg(feature = "greet")]
+    crate::plugins::greet::DATA_SET,
+    #[cfg(feature = "notify")]
+    crate::plugins::notify::DATA_SET,
+    #[cfg(feature = "rank")]
+    crate::plugins::rank::DATA_SET,
+    #[cfg(feature = "recap")]
+    crate::plugins::recap::DATA_SET,
+    #[cfg(feature = "scripting")]
+    crate::plugins::script::DATA_SET,
+    #[cfg(feature = "triggers")]
+    crate::plugins::triggers::DATA_SET,
+    #[cfg(feature = "twitch")]
+    crate::plugins::twitch::DATA_SET,
+    #[cfg(feature = "wasm")]
+    crate::plugins::wasm::DATA_SET,
+];
+
+/// Every data set saved in the storage, as JSON objects by name.
+pub async fn export_all(storage: &Storage) -> Result<Value> {
+    let mut all = serde_json::Map::new();
+    for data_set in DATA_SETS {
+        if let Some(value) = storage.export(data_set).await? {
+            all.insert(data_set.name.into(), value);
+        }
+    }
+    Ok(Value::Object(all))
+}
+
+/// Replaces the data sets in the storage with those of a JSON object by name,
+/// as exported, returning their names.
+pub async fn import_all(storage: &Storage, json: Value) -> Result<Vec<&'static str>> {
+    let all = match json {
+        Value::Object(all) => all,
+        _ => return Err(anyhow!("expected an object of data sets by name")),
+    };
+    let data = all
+        .into_iter()
+        .map(|(name, value)| Ok((find(&name)?, value)))
+        .collect::<Result<Vec<_>>>()?;
+    let names = data.iter().map(|(data_set, _)| data_set.name).collect();
+    storage.import(data).await?;
+    Ok(names)
+}
+
+const USAGE: &str = "usage: boton export-data|import-data --server <server> [--plugin <name>]";
+
+fn find(name: &str) -> Result<&'static DataSet> {
+    DATA_SETS
+        .iter()
+        .find(|data_set| data_set.name == name)
+        .ok_or_else(|| {
+            let names: Vec<&str> = DATA_SETS.iter().map(|data_set| data_set.name).collect();
+            anyhow!("no data set named {}, try: {}", name, names.join(", "))
+        })
+}
+
+/// Runs the command given as arguments.
+pub async fn run(config: &Config, args: &[String]) -> Result<()> {
+    let (command, options) = args.split_first().ok_or_else(|| anyhow!(USAGE))?;
+    let (mut server, mut plugin) = (None, None);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options
+            .next()
+            .ok_or_else(|| anyhow!("{} needs a value", option));
+        match option.as_str() {
+            "--server" => server = Some(value?.as_str()),
+            "--plugin" => plugin = Some(find(value?)?),
+            _ => return Err(anyhow!(USAGE)),
+        }
+    }
+    let server = server.ok_or_else(|| anyhow!(USAGE))?;
+
+    match command.as_str() {
+        "export-data" => {
+            let storage = config.open_storage(server).await?;
+            let json = match plugin {
+                Some(data_set) => storage
+                    .export(data_set)
+                    .await?
+                    .ok_or_else(|| anyhow!("no {} data saved for {}", data_set.name, server))?,
+                None => export_all(&storage).await?,
+            };
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        },
+        "import-data" => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            let json: Value = serde_json::from_str(&input)?;
+            let storage = config.open_storage(server).await?;
+            let names = match plugin {
+                Some(data_set) => {
+                    storage.import(vec![(data_set, json)]).await?;
+                    vec![data_set.name]
+                },
+                None => import_all(&storage, json).await?,
+            };
+            eprintln!("Imported {} for {}", names.join(", "), server);
+        },
+        _ => return Err(anyhow!(USAGE)),
+    }
+    Ok(())
+}
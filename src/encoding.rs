@@ -0,0 +1,91 @@
+//! Character encodings of networks and channels still on something else than
+//! UTF-8, like Latin-1 or Shift-JIS. Text received that isn't valid UTF-8 is
+//! decoded with the encoding of the channel it's for, and text sent is
+//! encoded with it.
+
+use anyhow::{anyhow, Result};
+use encoding_rs::{Encoding, UTF_8};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Encodings by label, like `latin1`, `iso-8859-15` or `shift_jis`
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct EncodingConfig {
+    /// Encoding of the network, UTF-8 when omitted
+    pub default:  Option<String>,
+    /// Encodings of specific channels, overriding `default`
+    pub channels: HashMap<String, String>,
+}
+
+/// The configured encodings, ready to use.
+#[derive(Clone, Debug)]
+pub struct Encodings {
+    default:  &'static Encoding,
+    /// Encodings by lowercase channel
+    channels: HashMap<String, &'static Encoding>,
+}
+
+impl Default for Encodings {
+    fn default() -> Self {
+        Encodings {
+            default:  UTF_8,
+            channels: HashMap::new(),
+        }
+    }
+}
+
+fn lookup(label: &str) -> Result<&'static Encoding> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| anyhow!("unknown encoding {:?}", label))
+}
+
+impl Encodings {
+    pub fn new(config: &EncodingConfig) -> Result<Self> {
+        let default = match &config.default {
+            Some(label) => lookup(label)?,
+            None => UTF_8,
+        };
+        let mut channels = HashMap::new();
+        for (channel, label) in &config.channels {
+            channels.insert(channel.to_lowercase(), lookup(label)?);
+        }
+        Ok(Encodings { default, channels })
+    }
+
+    /// The encoding of the channel, or of the network for anything else.
+    fn of(&self, target: Option<&str>) -> &'static Encoding {
+        target
+            .and_then(|target| self.channels.get(&target.to_lowercase()))
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Decodes a line received. Valid UTF-8 is taken as such, since clients
+    /// on legacy networks often send it anyway. Anything else is decoded
+    /// with the encoding of the first channel with its own among the
+    /// parameters, or else of the network.
+    pub fn decode<'a>(&self, line: &'a [u8]) -> Cow<'a, str> {
+        if let Ok(text) = std::str::from_utf8(line) {
+            return Cow::Borrowed(text);
+        }
+        let channel = if self.channels.is_empty() {
+            None
+        } else {
+            // Tags, source and command come before the parameters, and the
+            // trailing one can't be a channel
+            line.split(|&b| b == b' ')
+                .skip_while(|word| word.starts_with(b"@") || word.starts_with(b":"))
+                .skip(1)
+                .take_while(|word| !word.starts_with(b":"))
+                .filter_map(|word| std::str::from_utf8(word).ok())
+                .find(|word| self.channels.contains_key(&word.to_lowercase()))
+        };
+        self.of(channel).decode_without_bom_handling(line).0
+    }
+
+    /// Encodes a line sent to the target.
+    pub fn encode<'a>(&self, target: Option<&str>, line: &'a str) -> Cow<'a, [u8]> {
+        self.of(target).encode(line).0
+    }
+}
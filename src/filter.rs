@@ -0,0 +1,126 @@
+//! Filters applied to what plugins send to a channel, configured per channel
+//! to suit its audience, on top of stripping colors where the channel's `+c`
+//! mode would have the server block them.
+
+use anyhow::{anyhow, Result};
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::irc;
+use crate::state;
+
+/// Channel mode blocking messages with colors
+const NO_COLORS_MODE: char = 'c';
+/// Formatting codes, with the foreground and background numbers of colors
+const FORMATTING: &str = r"\x03(\d{1,2}(,\d{1,2})?)?|[\x02\x0f\x11\x16\x1d\x1e\x1f]";
+/// Links, along with any `<>` already around them
+const LINK: &str = r"<?\bhttps?://[^\s<>]+>?";
+
+/// Filters for messages sent to each channel
+pub type FiltersConfig = HashMap<String, Vec<Filter>>;
+
+/// A change made to messages sent to a channel
+#[derive(Debug, Deserialize, Clone)]
+pub enum Filter {
+    /// Replaces these words with asterisks, whatever their case
+    Mask(Vec<String>),
+    /// Drops colors, bold, italics and other formatting
+    StripFormatting,
+    /// Wraps links in `<>`, which bridges to Discord and similar chats take
+    /// as not to show a preview
+    SuppressEmbeds,
+}
+
+/// The filters of a channel, ready to apply.
+#[derive(Default)]
+struct ChannelFilters {
+    mask:            Option<Regex>,
+    strip:           bool,
+    suppress_embeds: bool,
+}
+
+/// Applies the filters of the channel a message is sent to.
+#[derive(Clone)]
+pub struct Filters {
+    state:      state::Handle,
+    /// Filters by lowercase channel
+    channels:   Arc<HashMap<String, ChannelFilters>>,
+    formatting: Regex,
+    link:       Regex,
+}
+
+impl Filters {
+    pub fn new(config: &FiltersConfig, state: state::Handle) -> Result<Self> {
+        let mut channels = HashMap::new();
+        for (channel, filters) in config {
+            let mut compiled = ChannelFilters::default();
+            for filter in filters {
+                match filter {
+                    Filter::Mask(words) => {
+                        let words: Vec<String> = words.iter().map(|w| regex::escape(w)).collect();
+                        let mask = Regex::new(&format!(r"(?i)\b({})\b", words.join("|")))
+                            .map_err(|e| anyhow!("Invalid mask for {}: {}", channel, e))?;
+                        compiled.mask = Some(mask);
+                    },
+                    Filter::StripFormatting => compiled.strip = true,
+                    Filter::SuppressEmbeds => compiled.suppress_embeds = true,
+                }
+            }
+            channels.insert(channel.to_lowercase(), compiled);
+        }
+        Ok(Filters {
+            state,
+            channels: Arc::new(channels),
+            formatting: Regex::new(FORMATTING).unwrap(),
+            link: Regex::new(LINK).unwrap(),
+        })
+    }
+
+    /// The message with the filters of the channel it's for applied, if it's
+    /// a PRIVMSG or NOTICE to one.
+    pub async fn apply(&self, mut msg: irc::Message) -> irc::Message {
+        let channel = match &msg.target {
+            Some(target)
+                if matches!(msg.command, irc::Command::Privmsg | irc::Command::Notice)
+                    && irc::is_channel(target) =>
+            {
+                target.to_lowercase()
+            },
+            _ => return msg,
+        };
+        let no_colors = self
+            .state
+            .read()
+            .await
+            .channel(&channel)
+            .is_some_and(|c| c.modes.contains_key(&NO_COLORS_MODE));
+        let filters = self.channels.get(&channel);
+        let text = match msg.parameters.last_mut() {
+            Some(text) => text,
+            None => return msg,
+        };
+
+        if no_colors || filters.is_some_and(|f| f.strip) {
+            *text = self.formatting.replace_all(text, "").into_owned();
+        }
+        if let Some(filters) = filters {
+            if let Some(mask) = &filters.mask {
+                *text = mask
+                    .replace_all(text, |word: &Captures| "*".repeat(word[0].chars().count()))
+                    .into_owned();
+            }
+            if filters.suppress_embeds {
+                *text = self
+                    .link
+                    .replace_all(text, |link: &Captures| {
+                        let link = link[0].trim_start_matches('<').trim_end_matches('>');
+                        format!("<{}>", link)
+                    })
+                    .into_owned();
+            }
+        }
+        msg
+    }
+}
@@ -0,0 +1,57 @@
+//! Named groups of channels, which the channels of bots and the per-channel
+//! settings of plugins refer to as `@name` rather than listing them all, on
+//! whichever network.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+use crate::bot::PluginConfig;
+
+/// Channels of each group, by name
+pub type ChannelGroups = HashMap<String, Vec<String>>;
+
+const PREFIX: char = '@';
+
+/// The channels, with the groups among them replaced by theirs.
+pub fn channels(groups: &ChannelGroups, channels: &[String]) -> Result<Vec<String>> {
+    let mut expanded = vec![];
+    for channel in channels {
+        for channel in members(groups, channel)? {
+            if !expanded.iter().any(|c| c == channel) {
+                expanded.push(channel.into());
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// The settings of a plugin, with those for a group, like `greet @dev`, set
+/// for each of its channels instead. Settings for one of those channels in
+/// particular are kept over the group's.
+pub fn settings(groups: &ChannelGroups, config: &PluginConfig) -> Result<PluginConfig> {
+    let mut expanded = PluginConfig::new();
+    let mut own = vec![];
+    for (key, value) in config {
+        match key.rsplit_once(' ') {
+            Some((setting, group)) if group.starts_with(PREFIX) => {
+                for channel in members(groups, group)? {
+                    expanded.insert(format!("{} {}", setting, channel), value.clone());
+                }
+            },
+            _ => own.push((key.clone(), value.clone())),
+        }
+    }
+    expanded.extend(own);
+    Ok(expanded)
+}
+
+/// The channels of the group, if `channel` names one, or else itself.
+fn members<'a>(groups: &'a ChannelGroups, channel: &'a str) -> Result<Vec<&'a str>> {
+    match channel.strip_prefix(PREFIX) {
+        Some(name) => groups
+            .get(name)
+            .map(|channels| channels.iter().map(String::as_str).collect())
+            .ok_or_else(|| anyhow!("no channel group called {}", name)),
+        None => Ok(vec![channel]),
+    }
+}
@@ -0,0 +1,95 @@
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::SystemTime;
+
+use crate::chat::ChatEvent;
+use crate::irc;
+
+/// How much of each channel's history to keep
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Messages kept per channel, 0 to keep none
+    pub size: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig { size: 100 }
+    }
+}
+
+/// A message someone sent to a channel.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub user:   irc::User,
+    /// Text of the message, without the CTCP wrapping for actions
+    pub text:   String,
+    /// Whether this was a `/me` action
+    pub action: bool,
+    pub time:   SystemTime,
+}
+
+/// The last few messages sent to each channel, shared by the plugins that
+/// need some context instead of each of them keeping its own.
+#[derive(Debug, Default)]
+pub struct History {
+    size:     usize,
+    /// Oldest messages first, keyed by lowercase channel name
+    channels: HashMap<String, VecDeque<Line>>,
+}
+
+impl History {
+    pub fn new(config: &HistoryConfig) -> Self {
+        History {
+            size:     config.size,
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Keeps the message if it is a PRIVMSG to a channel. CTCP requests other
+    /// than actions are ignored.
+    pub fn record(&mut self, msg: &irc::Message) {
+        if self.size == 0 {
+            return;
+        }
+        let event = match ChatEvent::parse(msg) {
+            Some(event) if !event.is_ctcp => event,
+            _ => return,
+        };
+        let channel = match event.channel() {
+            Some(channel) => channel.to_lowercase(),
+            None => return,
+        };
+
+        let lines = self.channels.entry(channel).or_default();
+        if lines.len() == self.size {
+            lines.pop_front();
+        }
+        lines.push_back(Line {
+            user:   event.sender,
+            text:   event.body,
+            action: event.is_action,
+            time:   SystemTime::now(),
+        });
+    }
+
+    /// Messages sent to the channel, most recent first.
+    pub fn recent(&self, channel: &str) -> impl Iterator<Item = &Line> {
+        self.channels
+            .get(&channel.to_lowercase())
+            .into_iter()
+            .flat_map(|lines| lines.iter().rev())
+    }
+
+    /// The most recent message sent to the channel by the given nick.
+    pub fn last_from(&self, channel: &str, nick: &str) -> Option<&Line> {
+        self.recent(channel)
+            .find(|line| line.user.nick.eq_ignore_ascii_case(nick))
+    }
+
+    /// Forgets everything about a channel, e.g. after leaving it.
+    pub fn forget(&mut self, channel: &str) {
+        self.channels.remove(&channel.to_lowercase());
+    }
+}
@@ -0,0 +1,420 @@
+//! The HTTP client plugins make requests with. Requests to each host go
+//! through a circuit breaker, so a provider that keeps failing is given a rest
+//! instead of every plugin command waiting on it, and through an optional
+//! budget of requests per minute. Requests can also ask for their response
+//! to be cached, see `cache`.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use reqwest::{IntoUrl, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cache::{self, CacheConfig};
+use crate::tasks;
+use crate::version;
+
+/// How long request budgets last
+const BUDGET_WINDOW: Duration = Duration::from_secs(60);
+
+/// State of every host requested so far, shared by all bots
+static PROVIDERS: Mutex<BTreeMap<String, Provider>> = Mutex::new(BTreeMap::new());
+
+/// How the bot makes HTTP requests
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Seconds before giving up on a whole request
+    pub timeout:          u64,
+    /// Seconds before giving up on connecting
+    pub connect_timeout:  u64,
+    /// HTTP(S) proxy every request goes through, e.g. `http://proxy:3128`
+    pub proxy:            Option<String>,
+    /// User-Agent header, identifying the bot and its version by default
+    pub user_agent:       Option<String>,
+    /// Failed requests in a row before a host is given a rest, 0 to never
+    pub breaker_failures: u32,
+    /// Seconds requests to a host are refused for once it's given a rest,
+    /// before one is let through to see whether it's back
+    pub breaker_cooldown: u64,
+    /// Requests per minute to each host, 0 for no limit
+    pub budget:           u32,
+    /// Requests per minute to specific hosts, overriding `budget`
+    pub budgets:          HashMap<String, u32>,
+    /// How responses are cached for requests asking for it
+    pub cache:            CacheConfig,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            timeout:          30,
+            connect_timeout:  10,
+            proxy:            None,
+            user_agent:       None,
+            breaker_failures: 5,
+            breaker_cooldown: 60,
+            budget:           0,
+            budgets:          HashMap::new(),
+            cache:            CacheConfig::default(),
+        }
+    }
+}
+
+/// Where a host's circuit breaker is at
+#[derive(Clone, Copy, Debug)]
+enum Breaker {
+    /// Requests go through, counting the failures in a row
+    Closed { failures: u32 },
+    /// Requests are refused until the time comes to try again
+    Open { until: Instant },
+    /// A request went through to see whether the host is back, and others
+    /// are refused until it's done
+    HalfOpen { since: Instant },
+}
+
+/// Requests to a host in the current budget window
+#[derive(Clone, Copy, Debug)]
+struct Window {
+    started: Instant,
+    made:    u32,
+    /// Requests refused for going over budget
+    refused: u32,
+}
+
+struct Provider {
+    breaker: Breaker,
+    window:  Window,
+}
+
+impl Default for Provider {
+    fn default() -> Self {
+        Provider {
+            breaker: Breaker::Closed { failures: 0 },
+            window:  Window {
+                started: Instant::now(),
+                made:    0,
+                refused: 0,
+            },
+        }
+    }
+}
+
+/// The breaker and budget settings of the configuration
+#[derive(Debug)]
+struct Limits {
+    failures: u32,
+    cooldown: Duration,
+    budget:   u32,
+    budgets:  HashMap<String, u32>,
+}
+
+impl Limits {
+    /// Lets a request to `host` through, or refuses it if the host is resting
+    /// or out of budget.
+    fn admit(&self, host: &str) -> Result<()> {
+        let mut providers = PROVIDERS.lock().unwrap();
+        let provider = providers.entry(host.into()).or_default();
+        let now = Instant::now();
+
+        let budget = self.budgets.get(host).copied().unwrap_or(self.budget);
+        if now.duration_since(provider.window.started) >= BUDGET_WINDOW {
+            provider.window = Window {
+                started: now,
+                made:    0,
+                refused: 0,
+            };
+        }
+        if budget > 0 && provider.window.made >= budget {
+            provider.window.refused += 1;
+            return Err(anyhow!(
+                "out of the budget of {} requests a minute to {}",
+                budget,
+                host
+            ));
+        }
+
+        match provider.breaker {
+            Breaker::Closed { .. } => {},
+            Breaker::Open { until } if now < until => {
+                return Err(anyhow!(
+                    "{} keeps failing, not trying it again for {}s",
+                    host,
+                    (until - now).as_secs() + 1
+                ));
+            },
+            Breaker::HalfOpen { since } if now.duration_since(since) < self.cooldown => {
+                return Err(anyhow!("{} keeps failing, already trying it again", host));
+            },
+            // A request trying the host again for too long is taken as
+            // dropped, letting this one try instead
+            Breaker::Open { .. } | Breaker::HalfOpen { .. } => {
+                debug!("Trying {} again", host);
+                provider.breaker = Breaker::HalfOpen { since: now };
+            },
+        }
+        provider.window.made += 1;
+        Ok(())
+    }
+
+    /// Records how a request to `host` went, giving the host a rest after
+    /// too many failures.
+    fn record(&self, host: &str, ok: bool) {
+        let mut providers = PROVIDERS.lock().unwrap();
+        let provider = providers.entry(host.into()).or_default();
+        let open = Breaker::Open {
+            until: Instant::now() + self.cooldown,
+        };
+        provider.breaker = match (provider.breaker, ok) {
+            (Breaker::HalfOpen { .. }, true) => {
+                info!("{} is back, resuming requests to it", host);
+                Breaker::Closed { failures: 0 }
+            },
+            (_, true) => Breaker::Closed { failures: 0 },
+            (Breaker::Closed { failures }, false) => {
+                let failures = failures + 1;
+                if self.failures == 0 || failures < self.failures {
+                    Breaker::Closed { failures }
+                } else {
+                    warn!(
+                        "Requests to {} failed {} times in a row, refusing them for {}s",
+                        host,
+                        failures,
+                        self.cooldown.as_secs()
+                    );
+                    open
+                }
+            },
+            (Breaker::HalfOpen { .. }, false) => {
+                warn!(
+                    "Requests to {} still fail, refusing them for {}s",
+                    host,
+                    self.cooldown.as_secs()
+                );
+                open
+            },
+            // Requests made before it opened failing too change nothing
+            (Breaker::Open { .. }, false) => return,
+        };
+    }
+}
+
+/// Hands out the HTTP client set up according to the bot's configuration,
+/// shared so connections get reused across everything using it.
+#[derive(Clone)]
+pub struct Http {
+    client: Client,
+}
+
+impl Http {
+    pub fn new(config: HttpConfig) -> Result<Self> {
+        let user_agent = match config.user_agent {
+            Some(user_agent) => user_agent,
+            None => format!(
+                "boton/{} (+https://github.com/wwared/boton)",
+                version::VERSION
+            ),
+        };
+        let mut builder = reqwest::Client::builder()
+            .user_agent(user_agent)
+            .timeout(Duration::from_secs(config.timeout))
+            .connect_timeout(Duration::from_secs(config.connect_timeout));
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        let limits = Limits {
+            failures: config.breaker_failures,
+            cooldown: Duration::from_secs(config.breaker_cooldown),
+            budget:   config.budget,
+            budgets:  config.budgets,
+        };
+        Ok(Http {
+            client: Client {
+                inner:  builder.build()?,
+                limits: Arc::new(limits),
+                cache:  Arc::new(config.cache),
+            },
+        })
+    }
+
+    /// The client shared by the whole bot.
+    pub fn client(&self) -> Client {
+        self.client.clone()
+    }
+}
+
+/// A `reqwest::Client` whose requests go through the breaker and budget of
+/// their host, and through the cache if they ask for it.
+#[derive(Clone)]
+pub struct Client {
+    inner:  reqwest::Client,
+    limits: Arc<Limits>,
+    cache:  Arc<CacheConfig>,
+}
+
+impl Client {
+    pub fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::POST, url)
+    }
+
+    pub fn request<U: IntoUrl>(&self, method: Method, url: U) -> RequestBuilder {
+        RequestBuilder {
+            client:  self.clone(),
+            request: self.inner.request(method, url),
+            ttl:     None,
+        }
+    }
+
+    /// Sends the request, unless its host is resting or out of budget. Server
+    /// errors and rate limiting count as failures of the host, like requests
+    /// that don't get a response at all.
+    async fn execute(&self, request: reqwest::Request) -> Result<reqwest::Response> {
+        let host = request.url().host_str().unwrap_or_default().to_string();
+        self.limits.admit(&host)?;
+        let response = self.inner.execute(request).await;
+        let ok = match &response {
+            Ok(response) => {
+                let status = response.status();
+                !(status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS)
+            },
+            Err(_) => false,
+        };
+        self.limits.record(&host, ok);
+        Ok(response?)
+    }
+
+    /// Sends the request, caching its response if successful.
+    async fn fetch(&self, url: &str, request: reqwest::Request) -> Result<reqwest::Response> {
+        let response = self.execute(request).await?;
+        if !response.status().is_success() {
+            return Ok(response);
+        }
+        let entry = cache::Entry::read(url, response).await?;
+        let response = entry.response()?;
+        cache::put(&self.cache, entry).await;
+        Ok(response)
+    }
+}
+
+/// Builds a request like `reqwest::RequestBuilder`, for the methods the bot
+/// uses.
+pub struct RequestBuilder {
+    client:  Client,
+    request: reqwest::RequestBuilder,
+    /// How long the response is fresh for, if it's to be cached
+    ttl:     Option<Duration>,
+}
+
+impl RequestBuilder {
+    fn map(self, f: impl FnOnce(reqwest::RequestBuilder) -> reqwest::RequestBuilder) -> Self {
+        RequestBuilder {
+            client:  self.client,
+            request: f(self.request),
+            ttl:     self.ttl,
+        }
+    }
+
+    pub fn query<T: Serialize + ?Sized>(self, query: &T) -> Self {
+        self.map(|request| request.query(query))
+    }
+
+    pub fn header(self, name: &'static str, value: &str) -> Self {
+        self.map(|request| request.header(name, value))
+    }
+
+    pub fn bearer_auth<T: Display>(self, token: T) -> Self {
+        self.map(|request| request.bearer_auth(token))
+    }
+
+    pub fn form<T: Serialize + ?Sized>(self, form: &T) -> Self {
+        self.map(|request| request.form(form))
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.map(|request| request.timeout(timeout))
+    }
+
+    /// Answers GET requests from the cache while the response is younger
+    /// than `ttl`, and for the configured while after, while it's fetched
+    /// again in the background.
+    pub fn cache(self, ttl: Duration) -> Self {
+        RequestBuilder {
+            ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    /// Sends the request, unless its host is resting or out of budget, or
+    /// answers it from the cache if it asked for it.
+    pub async fn send(self) -> Result<reqwest::Response> {
+        let client = self.client;
+        let request = self.request.build()?;
+        let ttl = match self.ttl {
+            Some(ttl) if request.method() == Method::GET && client.cache.entries > 0 => ttl,
+            _ => return client.execute(request).await,
+        };
+        let url = cache::key(request.url());
+        let entry = match cache::get(&client.cache, &url).await {
+            Some(entry) => entry,
+            None => return client.fetch(&url, request).await,
+        };
+        let age = entry.age();
+        if age < ttl {
+            return entry.response();
+        }
+        if age >= ttl + Duration::from_secs(client.cache.stale_for) {
+            return client.fetch(&url, request).await;
+        }
+        if cache::start_refresh(&url) {
+            let url = url.clone();
+            tasks::spawn("http cache refresh", async move {
+                if let Err(e) = client.fetch(&url, request).await {
+                    debug!("Could not refresh the cached response of {}: {}", url, e);
+                }
+                cache::finish_refresh(&url);
+            });
+        }
+        entry.response()
+    }
+}
+
+/// One line per host that is failing or went over budget lately.
+pub fn report() -> Vec<String> {
+    let providers = PROVIDERS.lock().unwrap();
+    let now = Instant::now();
+    let mut lines = vec![];
+    for (host, provider) in providers.iter() {
+        let mut trouble = vec![];
+        match provider.breaker {
+            Breaker::Closed { failures: 0 } => {},
+            Breaker::Closed { failures } => {
+                trouble.push(format!("failed {} times in a row", failures))
+            },
+            Breaker::Open { until } if now < until => trouble.push(format!(
+                "failing, requests refused for {}s",
+                (until - now).as_secs() + 1
+            )),
+            Breaker::Open { .. } => trouble.push("failing, to be tried again".into()),
+            Breaker::HalfOpen { .. } => trouble.push("failing, being tried again".into()),
+        }
+        let window = provider.window;
+        if window.refused > 0 && now.duration_since(window.started) < BUDGET_WINDOW {
+            trouble.push(format!(
+                "over budget, {} requests refused in the last {}s",
+                window.refused,
+                now.duration_since(window.started).as_secs()
+            ));
+        }
+        if !trouble.is_empty() {
+            lines.push(format!("HTTP {}: {}", host, trouble.join(", ")));
+        }
+    }
+    lines
+}
@@ -0,0 +1,135 @@
+//! Translations of the bot's replies, gettext style: the English text is
+//! looked up in the catalog of the language wanted, and kept when missing.
+//!
+//! Catalogs are RON maps from the English text to the translated one, one
+//! `<language>.ron` file per language in the configured directory. Texts take
+//! their arguments as `{0}`, `{1}`..., which translations can reorder.
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::prefs::{self, Prefs};
+use crate::template;
+
+/// The language texts are written in, which needs no catalog
+pub const ENGLISH: &str = "en";
+
+/// Which languages the bot replies in
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct I18nConfig {
+    /// Language for channels and users that didn't pick one
+    pub default:  String,
+    /// Language of each channel, unless its users picked one
+    pub channels: HashMap<String, String>,
+    /// Directory with the catalogs
+    pub dir:      String,
+}
+
+impl Default for I18nConfig {
+    fn default() -> Self {
+        I18nConfig {
+            default:  ENGLISH.into(),
+            channels: HashMap::new(),
+            dir:      "locales".into(),
+        }
+    }
+}
+
+/// Translates replies into the language of whoever they're for.
+#[derive(Clone)]
+pub struct I18n {
+    default:  String,
+    /// Languages by lowercase channel
+    channels: Arc<HashMap<String, String>>,
+    catalogs: Arc<HashMap<String, HashMap<String, String>>>,
+    /// Where the languages users picked are saved
+    prefs:    Prefs,
+}
+
+impl I18n {
+    pub async fn load(config: I18nConfig, prefs: Prefs) -> Result<Self> {
+        let mut catalogs = HashMap::new();
+        if let Ok(entries) = std::fs::read_dir(&config.dir) {
+            for path in entries.flatten().map(|entry| entry.path()) {
+                let language = match path.file_stem().and_then(|stem| stem.to_str()) {
+                    Some(language) if path.extension().is_some_and(|e| e == "ron") => {
+                        language.to_string()
+                    },
+                    _ => continue,
+                };
+                let text = std::fs::read_to_string(&path)
+                    .map_err(|e| anyhow!("Could not read catalog {}: {}", path.display(), e))?;
+                let catalog: HashMap<String, String> = ron::de::from_str(&text)
+                    .map_err(|e| anyhow!("Invalid catalog {}: {}", path.display(), e))?;
+                catalogs.insert(language, catalog);
+            }
+        }
+        for language in config.channels.values().chain(Some(&config.default)) {
+            if language != ENGLISH && !catalogs.contains_key(language) {
+                return Err(anyhow!("No {} catalog in {}", language, config.dir));
+            }
+        }
+
+        Ok(I18n {
+            default: config.default,
+            channels: Arc::new(
+                config
+                    .channels
+                    .into_iter()
+                    .map(|(channel, language)| (channel.to_lowercase(), language))
+                    .collect(),
+            ),
+            catalogs: Arc::new(catalogs),
+            prefs,
+        })
+    }
+
+    /// The languages there are catalogs for, besides English.
+    pub fn languages(&self) -> Vec<&str> {
+        let mut languages: Vec<&str> = self.catalogs.keys().map(String::as_str).collect();
+        languages.sort_unstable();
+        languages
+    }
+
+    /// The language picked by the user, if any.
+    pub fn picked(&self, nick: &str) -> Option<String> {
+        self.prefs.get(&prefs::LANGUAGE, nick)
+    }
+
+    /// Remembers the language picked by the user, or forgets it with `None`.
+    pub async fn pick(&self, nick: &str, language: Option<&str>) -> Result<()> {
+        self.prefs
+            .set(&prefs::LANGUAGE, nick, language.map(String::from))
+            .await
+    }
+
+    /// The language to reply in to the user, where replies go to the target:
+    /// the one they picked, or else the channel's, or else the default.
+    pub fn language(&self, nick: &str, target: &str) -> String {
+        self.picked(nick)
+            .or_else(|| self.channels.get(&target.to_lowercase()).cloned())
+            .unwrap_or_else(|| self.default.clone())
+    }
+
+    /// The text in the language, or as is if it has no translation.
+    pub fn translate<'a>(&'a self, language: &str, text: &'a str) -> &'a str {
+        self.catalogs
+            .get(language)
+            .and_then(|catalog| catalog.get(text))
+            .map_or(text, String::as_str)
+    }
+
+    /// Translates the text for the user, filling in its arguments.
+    pub fn tr(&self, nick: &str, target: &str, text: &str, args: &[&str]) -> String {
+        let language = self.language(nick, target);
+        let args = args.iter().map(|arg| Value::String(arg.to_string()));
+        template::render(
+            self.translate(&language, text),
+            &Value::Array(args.collect()),
+        )
+    }
+}
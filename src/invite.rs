@@ -0,0 +1,72 @@
+use anyhow::Result;
+use log::*;
+use serde::Deserialize;
+
+use crate::acl::Acl;
+use crate::irc;
+use crate::storage::{DataSet, Storage};
+
+const DB_NAME: &str = "invited-channels";
+pub const DATA_SET: DataSet = DataSet::new::<Vec<String>>(DB_NAME);
+
+/// What to do when invited to a channel
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct InviteConfig {
+    /// Masks of users whose invites are accepted
+    pub allow:    Acl,
+    /// Whether to also join the channel again on every connect
+    pub remember: bool,
+}
+
+/// Joins channels the bot gets invited to by allowed users, keeping track of
+/// the ones to rejoin on connect.
+pub struct Invites {
+    config:     InviteConfig,
+    storage:    Storage,
+    /// Channels joined through invites that should be rejoined
+    remembered: Vec<String>,
+}
+
+impl Invites {
+    pub async fn new(config: InviteConfig, storage: Storage) -> Result<Self> {
+        let remembered = storage.load(DB_NAME).await?.unwrap_or_default();
+        Ok(Invites {
+            config,
+            storage,
+            remembered,
+        })
+    }
+
+    /// Channels joined through past invites.
+    pub fn remembered(&self) -> &[String] {
+        &self.remembered
+    }
+
+    pub async fn handle(&mut self, irc: &mut irc::IRC, msg: &irc::Message) -> Result<()> {
+        // :nick!user@host INVITE ournick :#channel
+        let (user, channel) = match (msg.source_as_user(), msg.parameters.first()) {
+            (Some(user), Some(channel)) if irc::is_channel(channel) => (user, channel),
+            _ => return Ok(()),
+        };
+        if !self.config.allow.allows(&user) {
+            info!(
+                "[{}] Ignoring invite to {} from {}",
+                irc.server, channel, user
+            );
+            return Ok(());
+        }
+
+        info!("[{}] Invited to {} by {}", irc.server, channel, user.nick);
+        irc.join(std::slice::from_ref(channel)).await?;
+        let known = self
+            .remembered
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(channel));
+        if self.config.remember && !known {
+            self.remembered.push(channel.clone());
+            self.storage.save(DB_NAME, &self.remembered).await?;
+        }
+        Ok(())
+    }
+}
@@ -8,32 +8,69 @@ use nom::{
     multi::many0,
     IResult,
 };
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tokio::{
     io::{split, AsyncReadExt, AsyncWriteExt, BufWriter, ReadHalf, WriteHalf},
-    net::{TcpStream, ToSocketAddrs},
     task::JoinHandle,
 };
+#[cfg(feature = "tls")]
 use tokio_native_tls::TlsConnector;
 
+use std::time::Duration;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
 
-fn process_buf(src: &mut BytesMut) -> Vec<Message> {
+use crate::encoding::Encodings;
+use crate::metrics;
+use crate::net;
+use crate::sasl::{self, SaslConfig};
+use crate::tasks;
+
+/// Takes the complete lines out of the buffer, parsed, leaving any partial
+/// one at the end for the next read.
+pub fn process_buf(src: &mut BytesMut, server: &str, options: &Options) -> Result<Vec<Message>> {
     let mut res = vec![];
     let mut start = 0;
     for (pos, win) in src.windows(2).enumerate() {
         if win == b"\r\n" {
-            let decoded = String::from_utf8_lossy(&src[start .. pos]);
+            let decoded = options.encodings.decode(&src[start .. pos]);
             debug!("<- \"{}\"", decoded);
 
-            // FIXME: can't ? here
-            let msg = parse_line(&decoded);
-            if let Ok((_, msg)) = msg {
-                res.push(msg);
-            } else {
-                error!("Parse failed for line: {}", decoded);
-                error!("Error: {:?}", msg);
+            match parse_line(&decoded) {
+                Ok((_, msg)) => res.push(msg),
+                Err(e) => {
+                    metrics::increment(metrics::MALFORMED_LINES, server);
+                    match options.malformed {
+                        Malformed::Drop => {
+                            warn!("[{}] Dropping malformed line {:?}: {}", server, decoded, e)
+                        },
+                        Malformed::Lenient => match parse_lenient(&decoded) {
+                            Some(msg) => {
+                                debug!(
+                                    "[{}] Made {:?} of malformed line {:?}",
+                                    server, msg, decoded
+                                );
+                                res.push(msg);
+                            },
+                            None => {
+                                warn!("[{}] Dropping malformed line {:?}: {}", server, decoded, e)
+                            },
+                        },
+                        Malformed::Disconnect => {
+                            error!(
+                                "[{}] Disconnecting over malformed line {:?}",
+                                server, decoded
+                            );
+                            return Err(anyhow!("malformed line {:?}: {}", decoded, e));
+                        },
+                    }
+                },
             }
 
             start = pos + 2;
@@ -41,7 +78,7 @@ fn process_buf(src: &mut BytesMut) -> Vec<Message> {
     }
     // trace!("Advancing buf by {}:\n{:?}", start, &src[..start]);
     src.advance(start);
-    res
+    Ok(res)
 }
 
 fn is_space(ch: char) -> bool {
@@ -73,7 +110,65 @@ fn parse_parameter(input: &str) -> IResult<&str, &str> {
     }
 }
 
-fn parse_line(input: &str) -> IResult<&str, Message> {
+/// Parses the tags of a message, after the `@`: `key=value;other`.
+fn parse_tags(input: &str) -> HashMap<String, String> {
+    input
+        .split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+            (key.to_string(), unescape_tag(value))
+        })
+        .collect()
+}
+
+/// Escapes a tag value, the other way around from [`unescape_tag`].
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Unescapes a tag value, where `\:` stands for `;` and `\s` for a space.
+fn unescape_tag(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {},
+        }
+    }
+    unescaped
+}
+
+/// Parses a line, as received without its CRLF.
+pub fn parse_line(input: &str) -> IResult<&str, Message> {
+    let (input, tags) = match input.strip_prefix('@') {
+        Some(input) => {
+            let (input, tags) = take_till1(is_space)(input)?;
+            let (input, _) = skip_space(input)?;
+            (input, parse_tags(tags))
+        },
+        None => (input, HashMap::new()),
+    };
     let (input, has_source) = starts_with_colon(input)?;
     let (input, source) = if has_source {
         let (input, source) = take_till1(is_space)(input)?;
@@ -102,7 +197,7 @@ fn parse_line(input: &str) -> IResult<&str, Message> {
     };
     trace!("target: {:?}", target);
 
-    let parameters: Vec<String> = params[1 ..].iter().map(|s| s.to_string()).collect();
+    let parameters: Vec<String> = params.iter().skip(1).map(|s| s.to_string()).collect();
     trace!("params as strings: {:?}", parameters);
 
     Ok((
@@ -112,10 +207,47 @@ fn parse_line(input: &str) -> IResult<&str, Message> {
             command,
             target,
             parameters,
+            tags,
         },
     ))
 }
 
+/// Makes what can be made of a line [`parse_line`] fails on: its first word
+/// as command, after any tags and source, and the rest as parameters.
+fn parse_lenient(input: &str) -> Option<Message> {
+    let mut rest = input.trim();
+    let mut tags = HashMap::new();
+    if let Some(tagged) = rest.strip_prefix('@') {
+        let (raw, after) = tagged.split_once(' ').unwrap_or((tagged, ""));
+        tags = parse_tags(raw);
+        rest = after.trim_start();
+    }
+    let mut source = None;
+    if let Some(sourced) = rest.strip_prefix(':') {
+        let (raw, after) = sourced.split_once(' ').unwrap_or((sourced, ""));
+        source = Some(raw.to_string()).filter(|source| !source.is_empty());
+        rest = after.trim_start();
+    }
+    let (middle, trailing) = match rest.split_once(" :") {
+        Some((middle, trailing)) => (middle, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = middle.split_whitespace();
+    let command = Command::try_from(words.next()?).ok()?;
+    let mut parameters: Vec<String> = words
+        .map(String::from)
+        .chain(trailing.map(String::from))
+        .collect();
+    let target = (!parameters.is_empty()).then(|| parameters.remove(0));
+    Some(Message {
+        source,
+        command,
+        target,
+        parameters,
+        tags,
+    })
+}
+
 impl<'a> TryFrom<&'a str> for Command {
     type Error = anyhow::Error;
 
@@ -124,11 +256,27 @@ impl<'a> TryFrom<&'a str> for Command {
             return Err(anyhow!("empty string as command"));
         }
         match value {
+            "BATCH" => Ok(Command::Batch),
+            "INVITE" => Ok(Command::Invite),
+            "JOIN" => Ok(Command::Join),
+            "KICK" => Ok(Command::Kick),
+            "MODE" => Ok(Command::Mode),
+            "NICK" => Ok(Command::Nick),
+            "PART" => Ok(Command::Part),
             "PING" => Ok(Command::Ping),
+            "PONG" => Ok(Command::Pong),
             "NOTICE" => Ok(Command::Notice),
             "PRIVMSG" => Ok(Command::Privmsg),
+            "QUIT" => Ok(Command::Quit),
             "001" => Ok(Command::RplWelcome),
+            "005" => Ok(Command::RplISupport),
+            "353" => Ok(Command::RplNamReply),
+            "367" => Ok(Command::RplBanList),
+            "381" => Ok(Command::RplYoureOper),
             "433" => Ok(Command::ErrNicknameInUse),
+            "464" => Ok(Command::ErrPasswdMismatch),
+            "481" => Ok(Command::ErrNoPrivileges),
+            "491" => Ok(Command::ErrNoOperHost),
             _ => Ok(Command::Other(value.into())),
         }
     }
@@ -139,14 +287,28 @@ impl TryFrom<&Command> for String {
 
     fn try_from(cmd: &Command) -> Result<Self> {
         match cmd {
+            Command::Batch => Ok("BATCH".into()),
+            Command::Invite => Ok("INVITE".into()),
             Command::Join => Ok("JOIN".into()),
+            Command::Kick => Ok("KICK".into()),
+            Command::Mode => Ok("MODE".into()),
             Command::Nick => Ok("NICK".into()),
             Command::Notice => Ok("NOTICE".into()),
-            Command::Ping => Ok("PONG".into()),
+            Command::Part => Ok("PART".into()),
+            Command::Ping | Command::Pong => Ok("PONG".into()),
             Command::Privmsg => Ok("PRIVMSG".into()),
+            Command::Quit => Ok("QUIT".into()),
             Command::Other(val) => Ok(val.clone()),
 
-            Command::ErrNicknameInUse | Command::RplWelcome => {
+            Command::ErrNicknameInUse
+            | Command::ErrPasswdMismatch
+            | Command::ErrNoPrivileges
+            | Command::ErrNoOperHost
+            | Command::RplWelcome
+            | Command::RplISupport
+            | Command::RplNamReply
+            | Command::RplBanList
+            | Command::RplYoureOper => {
                 error!("Tried to send {:?} to server", cmd);
                 Err(anyhow!("invalid command"))
             },
@@ -154,24 +316,118 @@ impl TryFrom<&Command> for String {
     }
 }
 
+/// Matches text against a pattern with `*` and `?` wildcards.
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Where to resume from if the current attempt after a `*` fails
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p ..].iter().all(|&c| c == '*')
+}
+
+/// Whether the given message target is a channel rather than a nick.
+pub fn is_channel(target: &str) -> bool {
+    target.starts_with(['#', '&', '+', '!'])
+}
+
 const READ_BUF_SIZE: usize = 4 * 1024;
 const RECV_MSG_CHAN: usize = 16;
 const SEND_MSG_CHAN: usize = 16;
+const SENT_MSG_CHAN: usize = 16;
+/// Capabilities requested, one at a time since servers refuse the whole
+/// request if they lack one: every status prefix of users in NAMES replies,
+/// not just the highest one, batches grouping messages like netsplits and
+/// multiline messages, the time messages were sent, channel history, the
+/// accounts senders are logged in to, and tags like the ids of messages and
+/// which one a reply answers
+const CAPABILITIES: &[&str] = &[
+    "multi-prefix",
+    "batch",
+    "draft/multiline",
+    "server-time",
+    "draft/chathistory",
+    "account-tag",
+    MESSAGE_TAGS,
+];
+const MESSAGE_TAGS: &str = "message-tags";
+/// Client tag pointing at the `msgid` of the message answered
+const REPLY_TAG: &str = "+draft/reply";
+
+/// Messages that can be sent at once before throttling kicks in
+const SEND_BURST: u32 = 5;
+/// Time between throttled messages, in line with what clients usually do to
+/// avoid getting disconnected for flooding
+const SEND_INTERVAL: Duration = Duration::from_millis(2200);
+
+/// What to do with lines from the server that can't be parsed
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+pub enum Malformed {
+    /// Drop them
+    #[default]
+    Drop,
+    /// Make what can be made of them, taking their first word as command and
+    /// the rest as parameters
+    Lenient,
+    /// Disconnect, reconnecting as after any other error
+    Disconnect,
+}
+
+/// Settings for a single connection
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Drop PRIVMSGs and NOTICEs identical to one already sent to the same
+    /// target within this long
+    pub dedup_window: Option<Duration>,
+    /// Longest text of a PRIVMSG or NOTICE in bytes, cutting the rest, or 0
+    /// to send it whole
+    pub max_length:   usize,
+    /// Which IP versions to connect over
+    pub ip_version:   net::IpVersion,
+    /// Character encodings of the network and its channels
+    pub encodings:    Encodings,
+    /// What to do with lines that can't be parsed, all counted as
+    /// `malformed_lines`
+    pub malformed:    Malformed,
+}
 
-pub async fn connect<A: ToSocketAddrs>(
+pub async fn connect(
     server: &str,
-    addr: A,
+    (host, port): &(String, u16),
+    options: Options,
 ) -> Result<(IRC, JoinHandle<Result<()>>)> {
-    let stream = TcpStream::connect(addr).await?;
+    let stream = net::connect(host, *port, options.ip_version).await?;
 
-    let conn = Connection::from_socket(server.into(), stream);
+    let conn = Connection::from_socket(server.into(), stream, options);
     conn.spawn_tasks().await
 }
 
-pub async fn connect_tls<A: ToSocketAddrs>(
+#[cfg(feature = "tls")]
+pub async fn connect_tls(
     server: &str,
-    addr: A,
+    (host, port): &(String, u16),
     domain: &str,
+    options: Options,
 ) -> Result<(IRC, JoinHandle<Result<()>>)> {
     let connector = tokio_native_tls::native_tls::TlsConnector::builder()
         .danger_accept_invalid_certs(true)
@@ -179,92 +435,169 @@ pub async fn connect_tls<A: ToSocketAddrs>(
         .build()?;
     let connector = TlsConnector::from(connector);
 
-    let stream = TcpStream::connect(addr).await?;
+    let stream = net::connect(host, *port, options.ip_version).await?;
 
     let stream = connector.connect(domain, stream).await?;
 
-    let conn = Connection::from_socket(server.into(), stream);
+    let conn = Connection::from_socket(server.into(), stream, options);
     conn.spawn_tasks().await
 }
 
+#[cfg(not(feature = "tls"))]
+pub async fn connect_tls(
+    _server: &str,
+    _address: &(String, u16),
+    _domain: &str,
+    _options: Options,
+) -> Result<(IRC, JoinHandle<Result<()>>)> {
+    Err(anyhow!("built without the tls feature, needed for use_tls"))
+}
+
+/// Writes the message, with its client tags if the server takes them.
+pub async fn write_message<W: AsyncWriteExt + Unpin>(
+    stream: &mut BufWriter<W>,
+    msg: &Message,
+    message_tags: bool,
+    encodings: &Encodings,
+) -> Result<()> {
+    trace!("Sending message: {:?}", msg);
+    let mut tags: Vec<String> = msg
+        .tags
+        .iter()
+        .filter(|(key, _)| message_tags && key.starts_with('+'))
+        .map(|(key, value)| match value.as_str() {
+            "" => key.clone(),
+            value => format!("{}={}", key, escape_tag(value)),
+        })
+        .collect();
+    if !tags.is_empty() {
+        tags.sort_unstable();
+        stream.write_all(b"@").await?;
+        stream.write_all(tags.join(";").as_bytes()).await?;
+        stream.write_all(b" ").await?;
+    }
+    let cmd = String::try_from(&msg.command)?;
+    stream.write_all(cmd.as_bytes()).await?;
+
+    // Encoded as the channel the message is for, if any
+    let channel = msg.target.as_deref();
+    if let Some(target) = &msg.target {
+        stream.write_all(b" ").await?;
+        if msg.parameters.is_empty() && target.contains(' ') {
+            stream.write_all(b":").await?;
+        }
+        stream.write_all(&encodings.encode(channel, target)).await?;
+    }
+
+    if !msg.parameters.is_empty() {
+        for (idx, param) in msg.parameters.iter().enumerate() {
+            stream.write_all(b" ").await?;
+            if idx == msg.parameters.len() - 1 {
+                stream.write_all(b":").await?;
+            }
+            stream.write_all(&encodings.encode(channel, param)).await?;
+        }
+    }
+
+    stream.write_all(b"\r\n").await?;
+    debug!("-> {:?}", String::from_utf8_lossy(stream.buffer()));
+    stream.flush().await?;
+    Ok(())
+}
+
 impl<S: 'static + AsyncReadExt + AsyncWriteExt + Unpin + Send> Connection<S> {
-    fn from_socket(server: String, socket: S) -> Self {
+    fn from_socket(server: String, socket: S, options: Options) -> Self {
         let (recv_half, write_half) = split(socket);
         let write_half = BufWriter::new(write_half);
         let recv_buffer: BytesMut = BytesMut::with_capacity(READ_BUF_SIZE);
         let (received_messages, rx) = broadcast::channel(RECV_MSG_CHAN);
         drop(rx);
-        let sent_messages = mpsc::channel(SEND_MSG_CHAN);
+        let (outbox, sent_messages) = Outbox::new(options.max_length);
+        let (sent_tap, rx) = broadcast::channel(SENT_MSG_CHAN);
+        drop(rx);
         Self {
             server,
+            options,
             write_half,
             recv_half,
             recv_buffer,
             received_messages,
+            outbox,
             sent_messages,
+            sent_tap,
+            message_tags: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    async fn send_message(stream: &mut BufWriter<WriteHalf<S>>, msg: &Message) -> Result<()> {
-        trace!("Sending message: {:?}", msg);
-        let cmd = String::try_from(&msg.command)?;
-        stream.write_all(cmd.as_bytes()).await?;
-
-        if let Some(target) = &msg.target {
-            stream.write_all(b" ").await?;
-            if msg.parameters.is_empty() && target.contains(' ') {
-                stream.write_all(b":").await?;
-            }
-            stream.write_all(target.as_bytes()).await?;
-        }
-
-        if !msg.parameters.is_empty() {
-            for (idx, param) in msg.parameters.iter().enumerate() {
-                stream.write_all(b" ").await?;
-                if idx == msg.parameters.len() - 1 {
-                    stream.write_all(b":").await?;
-                }
-                stream.write_all(param.as_bytes()).await?;
-            }
-        }
-
-        stream.write_all(b"\r\n").await?;
-        debug!("-> {:?}", String::from_utf8_lossy(stream.buffer()));
-        stream.flush().await?;
-        Ok(())
-    }
-
     async fn spawn_tasks(self) -> Result<(IRC, JoinHandle<Result<()>>)> {
         trace!("Spawning connection tasks...");
         let irc = self.get_channels();
-        let join_handle = tokio::spawn(async move {
-            let (mut send_channel_rx, mut write_half) = (self.sent_messages.1, self.write_half);
+        let server = irc.server.clone();
+        let join_handle = tasks::spawn(&format!("{} connection", server), async move {
+            let (mut lanes, mut write_half) = (self.sent_messages, self.write_half);
+            let sent_tap = self.sent_tap;
+            let mut dedup = self.options.dedup_window.map(Dedup::new);
+            let max_length = self.options.max_length;
+            let message_tags = self.message_tags;
+            let options = self.options;
 
             let (recv_channel_tx, mut recv_half, mut recv_buffer) =
                 (self.received_messages, self.recv_half, self.recv_buffer);
 
             // Read messages
-            let read_handle = tokio::spawn((async move || -> Result<()> {
+            let name = format!("{} read", server);
+            let (acked_tags, read_options) = (message_tags.clone(), options.clone());
+            let read_server = server.clone();
+            let read_handle: JoinHandle<Result<()>> = tasks::spawn(&name, async move {
                 loop {
                     Connection::receive_messages(
                         &mut recv_half,
                         &mut recv_buffer,
                         &recv_channel_tx,
+                        &acked_tags,
+                        &read_server,
+                        &read_options,
                     )
                     .await?;
                     trace!("Processed a batch of received messages");
                 }
-            })());
+            });
             trace!("Spawned read task: {:?}", read_handle);
 
-            // Send messages
-            let send_handle = tokio::spawn((async move || -> Result<()> {
-                while let Some(msg) = send_channel_rx.recv().await {
+            // Send messages, control ones right away and the rest throttled
+            let name = format!("{} write", server);
+            let send_handle: JoinHandle<Result<()>> = tasks::spawn(&name, async move {
+                let mut throttle = Throttle::new();
+                loop {
+                    let (msg, throttled) = tokio::select! {
+                        biased;
+                        msg = lanes.control.recv() => (msg, false),
+                        _ = throttle.wait() => tokio::select! {
+                            biased;
+                            msg = lanes.control.recv() => (msg, false),
+                            msg = lanes.replies.recv() => (msg, true),
+                            msg = lanes.bulk.recv() => (msg, true),
+                        },
+                    };
+                    let msg = match msg {
+                        Some(msg) => msg,
+                        None => return Ok(()),
+                    };
+                    let msg = sanitize(msg, max_length);
+                    if dedup.as_mut().is_some_and(|d| d.is_duplicate(&msg)) {
+                        debug!("Dropping duplicate message: {:?}", msg);
+                        continue;
+                    }
+                    if throttled {
+                        throttle.take();
+                    }
                     trace!("Got message to send");
-                    Connection::send_message(&mut write_half, &msg).await?;
+                    let tags = message_tags.load(Ordering::Relaxed);
+                    write_message(&mut write_half, &msg, tags, &options.encodings).await?;
+                    // Nobody listening is fine
+                    let _ = sent_tap.send(msg);
                 }
-                Ok(())
-            })());
+            });
             trace!("Spawned send task: {:?}", send_handle);
 
             let res = read_handle.await?;
@@ -283,6 +616,9 @@ impl<S: 'static + AsyncReadExt + AsyncWriteExt + Unpin + Send> Connection<S> {
         stream: &mut ReadHalf<S>,
         buffer: &mut BytesMut,
         recv_messages_tx: &broadcast::Sender<Message>,
+        message_tags: &AtomicBool,
+        server: &str,
+        options: &Options,
     ) -> Result<()> {
         if stream.read_buf(buffer).await? == 0 {
             if buffer.is_empty() {
@@ -293,8 +629,18 @@ impl<S: 'static + AsyncReadExt + AsyncWriteExt + Unpin + Send> Connection<S> {
             }
         }
 
-        let messages = process_buf(buffer);
+        let messages = process_buf(buffer, server, options)?;
         for msg in messages {
+            // :server CAP ournick ACK :message-tags
+            if msg.command == Command::Other("CAP".into())
+                && msg.parameters.first().is_some_and(|sub| sub == "ACK")
+                && msg
+                    .parameters
+                    .get(1)
+                    .is_some_and(|caps| caps.split_whitespace().any(|cap| cap == MESSAGE_TAGS))
+            {
+                message_tags.store(true, Ordering::Relaxed);
+            }
             recv_messages_tx.send(msg)?;
         }
 
@@ -306,18 +652,176 @@ impl<S: 'static + AsyncReadExt + AsyncWriteExt + Unpin + Send> Connection<S> {
             server:                   self.server.clone(),
             received_messages_sender: self.received_messages.clone(),
             received_messages:        self.received_messages.subscribe(),
-            send_messages:            self.sent_messages.0.clone(),
+            send_messages:            self.outbox.clone(),
+            sent_messages_sender:     self.sent_tap.clone(),
         }
     }
 }
 
+/// Token bucket limiting how fast non-control messages are sent.
+struct Throttle {
+    tokens: u32,
+    /// When the next token gets added
+    refill: Instant,
+}
+
+impl Throttle {
+    fn new() -> Self {
+        Throttle {
+            tokens: SEND_BURST,
+            refill: Instant::now() + SEND_INTERVAL,
+        }
+    }
+
+    /// Waits until a message may be sent.
+    async fn wait(&mut self) {
+        let now = Instant::now();
+        while self.refill <= now && self.tokens < SEND_BURST {
+            self.tokens += 1;
+            self.refill += SEND_INTERVAL;
+        }
+        if self.tokens == SEND_BURST {
+            self.refill = now + SEND_INTERVAL;
+        }
+        if self.tokens == 0 {
+            sleep_until(self.refill).await;
+            self.tokens += 1;
+            self.refill += SEND_INTERVAL;
+        }
+    }
+
+    /// Uses up a token for sending a message.
+    fn take(&mut self) {
+        self.tokens -= 1;
+    }
+}
+
+/// Remembers recently sent PRIVMSGs and NOTICEs, to catch duplicates.
+struct Dedup {
+    window: Duration,
+    /// When each (command, target, text) was last sent
+    sent:   HashMap<(String, String, String), Instant>,
+}
+
+impl Dedup {
+    fn new(window: Duration) -> Self {
+        Dedup {
+            window,
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Whether the message was already sent within the window. Otherwise,
+    /// records it as sent now.
+    fn is_duplicate(&mut self, msg: &Message) -> bool {
+        if !matches!(msg.command, Command::Privmsg | Command::Notice) {
+            return false;
+        }
+        let now = Instant::now();
+        let window = self.window;
+        self.sent
+            .retain(|_, sent| now.duration_since(*sent) < window);
+
+        let key = (
+            String::try_from(&msg.command).unwrap_or_default(),
+            msg.target.clone().unwrap_or_default(),
+            msg.parameters.join(" "),
+        );
+        if self.sent.contains_key(&key) {
+            return true;
+        }
+        self.sent.insert(key, now);
+        false
+    }
+}
+
+/// Makes an outgoing message safe to send, whatever text plugins put in it
+/// from users or APIs: line breaks become spaces, so they can't start a
+/// command of their own, control characters other than CTCP and formatting
+/// codes are dropped, and the text of PRIVMSGs and NOTICEs is cut to
+/// `max_length` bytes unless that's 0.
+fn sanitize(mut msg: Message, max_length: usize) -> Message {
+    for param in msg.parameters.iter_mut().chain(msg.target.as_mut()) {
+        if param.chars().any(is_unsafe) {
+            *param = strip_controls(param);
+        }
+    }
+    let text = match msg.parameters.last_mut() {
+        Some(text) if matches!(msg.command, Command::Privmsg | Command::Notice) => text,
+        _ => return msg,
+    };
+    if max_length > 0 && text.len() > max_length {
+        debug!("Cutting message to {} bytes: {:?}", max_length, text);
+        *text = cut(text, max_length);
+    }
+    msg
+}
+
+/// Characters never sent: line breaks, and control characters that aren't
+/// CTCP delimiters or bold, color, italics and other formatting codes.
+fn is_unsafe(c: char) -> bool {
+    c.is_control()
+        && !matches!(
+            c,
+            '\x01' | '\x02' | '\x03' | '\x0f' | '\x11' | '\x16' | '\x1d' | '\x1e' | '\x1f'
+        )
+}
+
+/// Replaces each run of line breaks, with the spaces around it, by a single
+/// space, and tabs by spaces, dropping any other unsafe character.
+fn strip_controls(text: &str) -> String {
+    let mut clean = String::with_capacity(text.len());
+    let mut line_break = false;
+    for c in text.chars() {
+        match c {
+            '\r' | '\n' => line_break = true,
+            ' ' | '\t' if line_break => {},
+            '\t' => clean.push(' '),
+            c if is_unsafe(c) => {},
+            c => {
+                if line_break {
+                    clean.truncate(clean.trim_end().len());
+                    if !clean.is_empty() {
+                        clean.push(' ');
+                    }
+                    line_break = false;
+                }
+                clean.push(c);
+            },
+        }
+    }
+    if line_break {
+        clean.truncate(clean.trim_end().len());
+    }
+    clean
+}
+
+/// Cuts the text to at most `max` bytes, ending it with `…`, and keeping the
+/// closing delimiter of a CTCP message.
+fn cut(text: &str, max: usize) -> String {
+    const ELLIPSIS: &str = "…";
+    let ctcp = text.starts_with('\x01') && text.len() > 1 && text.ends_with('\x01');
+    let room = max.saturating_sub(ELLIPSIS.len() + usize::from(ctcp));
+    let mut end = room.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut cut = text[.. end].trim_end().to_string();
+    cut.push_str(ELLIPSIS);
+    if ctcp {
+        cut.push('\x01');
+    }
+    cut
+}
+
 impl Message {
-    fn single_argument<S: Into<String>>(cmd: Command, arg: S) -> Message {
+    pub(crate) fn single_argument<S: Into<String>>(cmd: Command, arg: S) -> Message {
         Message {
             source:     None,
             command:    cmd,
             target:     Some(arg.into()),
             parameters: Vec::with_capacity(0),
+            tags:       HashMap::new(),
         }
     }
 
@@ -327,21 +831,103 @@ impl Message {
             command:    cmd,
             target:     Some(target.into()),
             parameters: vec![arg.into()],
+            tags:       HashMap::new(),
         }
     }
 
-    fn nick<S: Into<String>>(new_nick: S) -> Message {
+    pub fn nick<S: Into<String>>(new_nick: S) -> Message {
         Message::single_argument(Command::Nick, new_nick)
     }
 
-    fn join<S: Into<String>>(channel: S) -> Message {
+    pub fn join<S: Into<String>>(channel: S) -> Message {
         Message::single_argument(Command::Join, channel)
     }
 
-    pub fn privmsg<S: Into<String>>(target: S, message: S) -> Message {
+    pub fn join_with_key<S: Into<String>>(channel: S, key: S) -> Message {
+        Message::double_argument(Command::Join, channel, key)
+    }
+
+    pub fn part<S: Into<String>>(channel: S, message: S) -> Message {
+        Message::double_argument(Command::Part, channel, message)
+    }
+
+    /// Parses a raw line like `MODE boton +B`, to be sent exactly as given.
+    pub fn raw(line: &str) -> Result<Message> {
+        let (_, mut msg) =
+            parse_line(line.trim()).map_err(|e| anyhow!("invalid line {:?}: {}", line, e))?;
+        // Keep the command as written, since some are only meant to be
+        // received and would be sent differently (like PING as PONG)
+        let command = line.split_whitespace().find(|word| !word.starts_with(':'));
+        msg.command = Command::Other(command.unwrap_or_default().to_uppercase());
+        Ok(msg)
+    }
+
+    pub fn mode<S: Into<String>>(target: S, modes: S) -> Message {
+        Message::double_argument(Command::Mode, target, modes)
+    }
+
+    /// A channel mode change taking parameters, like `+o` and a nick.
+    pub fn channel_mode<S: Into<String>>(channel: S, modes: S, parameters: &[&str]) -> Message {
+        Message {
+            source:     None,
+            command:    Command::Mode,
+            target:     Some(channel.into()),
+            parameters: std::iter::once(modes.into())
+                .chain(parameters.iter().map(|p| p.to_string()))
+                .collect(),
+            tags:       HashMap::new(),
+        }
+    }
+
+    /// A PING of our own, which the server answers with a PONG carrying the
+    /// same token.
+    pub fn ping<S: Into<String>>(token: S) -> Message {
+        Message::single_argument(Command::Other("PING".into()), token)
+    }
+
+    pub fn quit<S: Into<String>>(message: S) -> Message {
+        Message {
+            source:     None,
+            command:    Command::Quit,
+            target:     None,
+            parameters: vec![message.into()],
+            tags:       HashMap::new(),
+        }
+    }
+
+    pub fn oper<S: Into<String>>(name: S, password: S) -> Message {
+        Message::double_argument(Command::Other("OPER".into()), name, password)
+    }
+
+    /// Forces the user into the channel, for opers on servers supporting it.
+    pub fn sajoin<S: Into<String>>(nick: S, channel: S) -> Message {
+        Message::double_argument(Command::Other("SAJOIN".into()), nick, channel)
+    }
+
+    /// Disconnects the user from the network, for opers.
+    pub fn kill<S: Into<String>>(nick: S, reason: S) -> Message {
+        Message::double_argument(Command::Other("KILL".into()), nick, reason)
+    }
+
+    fn privmsg<S: Into<String>>(target: S, message: S) -> Message {
         Message::double_argument(Command::Privmsg, target, message)
     }
 
+    fn notice<S: Into<String>>(target: S, message: S) -> Message {
+        Message::double_argument(Command::Notice, target, message)
+    }
+
+    /// Value of the tag, if the message has it.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(String::as_str)
+    }
+
+    /// Nick part of the message source, if it came from a user.
+    pub fn source_nick(&self) -> Option<&str> {
+        let src = self.source.as_deref()?;
+        src.find('!').map(|bang| &src[.. bang])
+    }
+
     pub fn source_as_user(&self) -> Option<User> {
         // TODO gross
         if let Some(src) = self.source.clone() {
@@ -371,16 +957,55 @@ impl IRC {
         nick: String,
         ident: String,
         real_name: String,
+        password: Option<String>,
+        sasl: Option<&SaslConfig>,
     ) -> Result<()> {
+        // Subscribed before anything is sent, not to miss any answer
+        let mut messages = self.clone().received_messages;
+        if let Some(password) = password {
+            self.send_messages
+                .send_with(
+                    Message::single_argument(Command::Other("PASS".into()), password),
+                    Priority::Control,
+                )
+                .await?;
+        }
+        let sasl_capability = sasl.map(|_| "sasl");
+        for capability in CAPABILITIES.iter().chain(&sasl_capability) {
+            self.send_messages
+                .send_with(
+                    Message::double_argument(Command::Other("CAP".into()), "REQ", *capability),
+                    Priority::Control,
+                )
+                .await?;
+        }
         self.send_messages
-            .send(Message {
-                source:     None,
-                command:    Command::Other("USER".into()),
-                target:     None,
-                parameters: vec![ident, "0".into(), "*".into(), real_name],
-            })
+            .send_with(
+                Message {
+                    source:     None,
+                    command:    Command::Other("USER".into()),
+                    target:     None,
+                    parameters: vec![ident, "0".into(), "*".into(), real_name],
+                    tags:       HashMap::new(),
+                },
+                Priority::Control,
+            )
+            .await?;
+        self.send_messages
+            .send_with(Message::nick(nick), Priority::Control)
+            .await?;
+        if let Some(config) = sasl {
+            // Registering without the account beats not registering
+            if let Err(e) = sasl::login(self, &mut messages, config).await {
+                error!("[{}] SASL login failed: {}", self.server, e);
+            }
+        }
+        self.send_messages
+            .send_with(
+                Message::single_argument(Command::Other("CAP".into()), "END"),
+                Priority::Control,
+            )
             .await?;
-        self.send_messages.send(Message::nick(nick)).await?;
         Ok(())
     }
 
@@ -391,18 +1016,33 @@ impl IRC {
         Ok(())
     }
 
-    pub async fn reply_pong(&mut self, msg: Message) -> Result<()> {
-        self.send_messages.send(msg).await?;
+    /// Joins channels along with their keys, for those that need one.
+    pub async fn join_keyed(&mut self, channels: &[(String, Option<String>)]) -> Result<()> {
+        for (ch, key) in channels {
+            let join = match key {
+                Some(key) => Message::join_with_key(ch, key),
+                None => Message::join(ch),
+            };
+            self.send_messages.send(join).await?;
+        }
         Ok(())
     }
 
+    pub async fn reply_pong(&mut self, msg: Message) -> Result<()> {
+        self.send_messages.send_with(msg, Priority::Control).await
+    }
+
     pub async fn reply_nick_in_use(&mut self, msg: Message) -> Result<()> {
         assert!(msg.command == Command::ErrNicknameInUse);
         assert!(!msg.parameters.is_empty());
-        self.send_messages
-            .send(Message::nick(format!("{}_", msg.parameters[0])))
-            .await?;
-        Ok(())
+        let nick = Message::nick(format!("{}_", msg.parameters[0]));
+        self.send_messages.send_with(nick, Priority::Control).await
+    }
+
+    /// Subscribes to the messages sent to the server from now on, whoever
+    /// queued them, once they're actually sent.
+    pub fn subscribe_sent(&self) -> broadcast::Receiver<Message> {
+        self.sent_messages_sender.subscribe()
     }
 }
 
@@ -412,7 +1052,209 @@ pub struct IRC {
 
     received_messages_sender: broadcast::Sender<Message>,
     pub received_messages:    broadcast::Receiver<Message>,
-    pub send_messages:        mpsc::Sender<Message>,
+    pub send_messages:        Outbox,
+    sent_messages_sender:     broadcast::Sender<Message>,
+}
+
+/// How urgently an outgoing message should be sent.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Priority {
+    /// Protocol upkeep like PONG and QUIT, never throttled
+    Control,
+    /// Replies to users
+    Reply,
+    /// Long outputs that can wait for everything else
+    Bulk,
+}
+
+/// Queues messages for sending, in separate lanes per priority.
+#[derive(Clone, Debug)]
+pub struct Outbox {
+    control:    mpsc::Sender<Message>,
+    replies:    mpsc::Sender<Message>,
+    bulk:       mpsc::Sender<Message>,
+    /// Longest text of a PRIVMSG or NOTICE in bytes, or 0 for no limit
+    max_length: usize,
+}
+
+/// Receiving ends of the outbox lanes, read by the send task.
+struct Lanes {
+    control: mpsc::Receiver<Message>,
+    replies: mpsc::Receiver<Message>,
+    bulk:    mpsc::Receiver<Message>,
+}
+
+impl Outbox {
+    fn new(max_length: usize) -> (Outbox, Lanes) {
+        let (control, control_rx) = mpsc::channel(SEND_MSG_CHAN);
+        let (replies, replies_rx) = mpsc::channel(SEND_MSG_CHAN);
+        let (bulk, bulk_rx) = mpsc::channel(SEND_MSG_CHAN);
+        let lanes = Lanes {
+            control: control_rx,
+            replies: replies_rx,
+            bulk:    bulk_rx,
+        };
+        (
+            Outbox {
+                control,
+                replies,
+                bulk,
+                max_length,
+            },
+            lanes,
+        )
+    }
+
+    /// Queues a message with the default, reply priority.
+    pub async fn send(&self, msg: Message) -> Result<()> {
+        self.send_with(msg, Priority::Reply).await
+    }
+
+    pub async fn send_with(&self, msg: Message, priority: Priority) -> Result<()> {
+        self.lane(priority)
+            .send(msg)
+            .await
+            .map_err(|_| anyhow!("connection closed"))
+    }
+
+    /// Queues the messages carrying the text, with its priority.
+    pub async fn send_outgoing(&self, outgoing: Outgoing) -> Result<()> {
+        for msg in outgoing.messages(self.max_length) {
+            self.send_with(msg, outgoing.priority).await?;
+        }
+        Ok(())
+    }
+
+    /// Longest text of a PRIVMSG or NOTICE in bytes, or 0 for no limit.
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    /// How many messages of the given priority are waiting to be sent.
+    pub fn queued(&self, priority: Priority) -> usize {
+        SEND_MSG_CHAN - self.lane(priority).capacity()
+    }
+
+    fn lane(&self, priority: Priority) -> &mpsc::Sender<Message> {
+        match priority {
+            Priority::Control => &self.control,
+            Priority::Reply => &self.replies,
+            Priority::Bulk => &self.bulk,
+        }
+    }
+}
+
+/// What to do with text too long for a single PRIVMSG or NOTICE
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Split {
+    /// Cut it, ending with `…`
+    Cut,
+    /// Send it over as many messages as it takes, broken between words
+    Lines,
+}
+
+/// Text to send to a channel or user, along with how: as a PRIVMSG or a
+/// NOTICE, in reply to which message, how urgently, and what to do if it's
+/// too long. Turned into messages as it's queued.
+#[derive(Clone, Debug)]
+pub struct Outgoing {
+    pub target:   String,
+    pub text:     String,
+    pub notice:   bool,
+    /// `msgid` of the message this answers, sent along as `+draft/reply` on
+    /// servers taking client tags
+    pub reply_to: Option<String>,
+    pub priority: Priority,
+    pub split:    Split,
+}
+
+impl Outgoing {
+    pub fn privmsg<S: Into<String>>(target: S, text: S) -> Outgoing {
+        Outgoing {
+            target:   target.into(),
+            text:     text.into(),
+            notice:   false,
+            reply_to: None,
+            priority: Priority::Reply,
+            split:    Split::Cut,
+        }
+    }
+
+    pub fn notice<S: Into<String>>(target: S, text: S) -> Outgoing {
+        Outgoing {
+            notice: true,
+            ..Outgoing::privmsg(target, text)
+        }
+    }
+
+    /// Marks this as answering the message with the given `msgid`, if any.
+    pub fn in_reply_to(self, msgid: Option<&str>) -> Outgoing {
+        Outgoing {
+            reply_to: msgid.map(String::from),
+            ..self
+        }
+    }
+
+    pub fn with_priority(self, priority: Priority) -> Outgoing {
+        Outgoing { priority, ..self }
+    }
+
+    pub fn with_split(self, split: Split) -> Outgoing {
+        Outgoing { split, ..self }
+    }
+
+    /// The messages to send, more than one when splitting text longer than
+    /// `max_length` bytes into lines. Text to cut is left whole, for the
+    /// connection to cut when sending it.
+    pub fn messages(&self, max_length: usize) -> Vec<Message> {
+        let ctcp = self.text.starts_with('\x01');
+        let lines = if self.split == Split::Lines && max_length > 0 && !ctcp {
+            split_lines(&self.text, max_length)
+        } else {
+            vec![self.text.clone()]
+        };
+        lines
+            .into_iter()
+            .map(|line| {
+                let mut msg = if self.notice {
+                    Message::notice(self.target.as_str(), line.as_str())
+                } else {
+                    Message::privmsg(self.target.as_str(), line.as_str())
+                };
+                if let Some(msgid) = &self.reply_to {
+                    msg.tags.insert(REPLY_TAG.into(), msgid.clone());
+                }
+                msg
+            })
+            .collect()
+    }
+}
+
+/// Breaks the text into lines of at most `max` bytes, between words where
+/// possible.
+fn split_lines(text: &str, max: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut rest = text.trim();
+    while rest.len() > max {
+        let mut end = max;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            // Not even one character fits, so it goes alone
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        let end = match rest[.. end].rfind(' ') {
+            Some(space) if space > 0 => space,
+            _ => end,
+        };
+        lines.push(rest[.. end].trim_end().to_string());
+        rest = rest[end ..].trim_start();
+    }
+    if !rest.is_empty() || lines.is_empty() {
+        lines.push(rest.to_string());
+    }
+    lines
 }
 
 impl Clone for IRC {
@@ -422,30 +1264,64 @@ impl Clone for IRC {
             received_messages_sender: self.received_messages_sender.clone(),
             received_messages:        self.received_messages_sender.subscribe(),
             send_messages:            self.send_messages.clone(),
+            sent_messages_sender:     self.sent_messages_sender.clone(),
         }
     }
 }
 
 /// Inner type used by tasks for reading and writing to the underlying socket S.
 struct Connection<S> {
-    server: String,
+    server:  String,
+    options: Options,
 
     write_half:  BufWriter<WriteHalf<S>>,
     recv_half:   ReadHalf<S>,
     recv_buffer: BytesMut,
 
     received_messages: broadcast::Sender<Message>,
-    sent_messages:     (mpsc::Sender<Message>, mpsc::Receiver<Message>),
+    outbox:            Outbox,
+    sent_messages:     Lanes,
+    /// Messages once sent, for whoever wants to know
+    sent_tap:          broadcast::Sender<Message>,
+    /// Whether the server acknowledged `message-tags`, so client tags can be
+    /// sent
+    message_tags:      Arc<AtomicBool>,
 }
 
 /// Type identifying a single user.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct User {
     pub nick:  String,
     pub ident: String,
     pub host:  String,
 }
 
+impl User {
+    /// Whether the user matches a `nick!ident@host` mask, where `*` matches
+    /// any run of characters and `?` any single character. Matching is
+    /// case-insensitive.
+    pub fn matches_mask(&self, mask: &str) -> bool {
+        glob(&mask.to_lowercase(), &self.to_string().to_lowercase())
+    }
+
+    /// A mask matching anyone connecting from the same host: `*!*@host`.
+    pub fn host_mask(&self) -> String {
+        format!("*!*@{}", self.host)
+    }
+
+    /// A mask matching the same ident from the same host, whether or not it
+    /// was verified through identd: `*!*ident@host`.
+    pub fn ident_mask(&self) -> String {
+        format!("*!*{}@{}", self.ident.trim_start_matches('~'), self.host)
+    }
+}
+
+impl fmt::Display for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}!{}@{}", self.nick, self.ident, self.host)
+    }
+}
+
 /// Type describing single IRC message.
 #[derive(Clone, Debug)]
 pub struct Message {
@@ -453,17 +1329,95 @@ pub struct Message {
     pub command:    Command,
     pub target:     Option<String>,
     pub parameters: Vec<String>,
+    /// IRCv3 tags, like `batch`, with their values unescaped and empty for
+    /// tags without one. Only client tags, starting with `+`, are sent, and
+    /// only to servers supporting `message-tags`.
+    pub tags:       HashMap<String, String>,
+}
+
+/// The message as a line in the protocol, without the line break nor tags.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(source) = &self.source {
+            write!(f, ":{} ", source)?;
+        }
+        write!(f, "{}", self.command)?;
+        if let Some(target) = &self.target {
+            if self.parameters.is_empty() && (target.is_empty() || target.contains(' ')) {
+                write!(f, " :{}", target)?;
+            } else {
+                write!(f, " {}", target)?;
+            }
+        }
+        if let Some((last, rest)) = self.parameters.split_last() {
+            for param in rest {
+                write!(f, " {}", param)?;
+            }
+            write!(f, " :{}", last)?;
+        }
+        Ok(())
+    }
 }
 
 /// List of recognized IRC commands.
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Command {
+    Batch,
+    Invite,
     Join,
+    Kick,
+    Mode,
     Nick,
     Notice,
+    Part,
     Privmsg,
     Ping,
+    Pong,
+    Quit,
     RplWelcome,
+    RplISupport,
+    RplNamReply,
+    RplBanList,
+    RplYoureOper,
     ErrNicknameInUse,
+    ErrPasswdMismatch,
+    ErrNoPrivileges,
+    ErrNoOperHost,
     Other(String),
 }
+
+impl Command {
+    /// The command as received, numerics included.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Command::Batch => "BATCH",
+            Command::Invite => "INVITE",
+            Command::Join => "JOIN",
+            Command::Kick => "KICK",
+            Command::Mode => "MODE",
+            Command::Nick => "NICK",
+            Command::Notice => "NOTICE",
+            Command::Part => "PART",
+            Command::Privmsg => "PRIVMSG",
+            Command::Ping => "PING",
+            Command::Pong => "PONG",
+            Command::Quit => "QUIT",
+            Command::RplWelcome => "001",
+            Command::RplISupport => "005",
+            Command::RplNamReply => "353",
+            Command::RplBanList => "367",
+            Command::RplYoureOper => "381",
+            Command::ErrNicknameInUse => "433",
+            Command::ErrPasswdMismatch => "464",
+            Command::ErrNoPrivileges => "481",
+            Command::ErrNoOperHost => "491",
+            Command::Other(name) => name,
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
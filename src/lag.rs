@@ -0,0 +1,146 @@
+use log::*;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+
+use crate::irc;
+use crate::state;
+
+/// How often the server gets PINGed to measure lag
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the lag is checked against the configured limit
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const TOKEN_PREFIX: &str = "boton-lag-";
+
+/// When to give up on a lagging server and reconnect
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LagConfig {
+    /// Seconds of lag considered too much, 0 to never reconnect because of
+    /// lag
+    pub max:       u64,
+    /// Seconds the lag has to stay over `max` before reconnecting
+    pub sustained: u64,
+    /// Channel told about reconnections because of lag
+    pub announce:  Option<String>,
+}
+
+impl Default for LagConfig {
+    fn default() -> Self {
+        LagConfig {
+            max:       0,
+            sustained: 60,
+            announce:  None,
+        }
+    }
+}
+
+/// Lag to the server, measured as the round trip time of our own PINGs.
+#[derive(Debug, Default)]
+pub struct Lag {
+    /// Token of the PING still waiting for its PONG
+    pending: Option<String>,
+    /// When the last PING was sent
+    sent_at: Option<Instant>,
+    /// Round trip time of the last answered PING
+    last:    Option<Duration>,
+    sent:    u64,
+}
+
+impl Lag {
+    /// Builds the next PING to send, if it's time for one and the last one
+    /// was answered.
+    fn ping(&mut self) -> Option<irc::Message> {
+        let recent = self.sent_at.is_some_and(|at| at.elapsed() < PING_INTERVAL);
+        if self.pending.is_some() || recent {
+            return None;
+        }
+        self.sent += 1;
+        let token = format!("{}{}", TOKEN_PREFIX, self.sent);
+        self.pending = Some(token.clone());
+        self.sent_at = Some(Instant::now());
+        Some(irc::Message::ping(token))
+    }
+
+    /// Records the answer to one of our PINGs.
+    pub fn pong(&mut self, token: &str) {
+        if self.pending.as_deref() == Some(token) {
+            self.last = self.sent_at.map(|at| at.elapsed());
+            self.pending = None;
+        }
+    }
+
+    /// The current lag: the last round trip time, or how long the pending
+    /// PING has been waiting if that's longer. `None` until measured.
+    pub fn current(&self) -> Option<Duration> {
+        let waiting = self
+            .pending
+            .as_ref()
+            .and(self.sent_at)
+            .map(|at| at.elapsed());
+        self.last.max(waiting)
+    }
+}
+
+/// PINGs the server periodically to keep the lag in the state up to date.
+/// If the lag stays too high for too long, announces it and asks for a
+/// reconnection through `cycle`. Returns once the connection is gone.
+pub async fn measure(
+    server: String,
+    state: state::Handle,
+    outbox: irc::Outbox,
+    config: LagConfig,
+    cycle: mpsc::Sender<()>,
+) {
+    let max = Duration::from_secs(config.max);
+    let sustained = Duration::from_secs(config.sustained);
+    // Since when the lag has been over the limit
+    let mut lagging: Option<Instant> = None;
+
+    let mut ticks = interval(CHECK_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let (ping, lag) = {
+            let mut state = state.write().await;
+            (state.lag.ping(), state.lag.current())
+        };
+        if let Some(ping) = ping {
+            if outbox
+                .send_with(ping, irc::Priority::Control)
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+
+        let lag = match lag {
+            Some(lag) if config.max > 0 && lag > max => lag,
+            _ => {
+                lagging = None;
+                continue;
+            },
+        };
+        let since = *lagging.get_or_insert_with(Instant::now);
+        if since.elapsed() < sustained {
+            continue;
+        }
+        warn!(
+            "[{}] Lag has been over {}s for {}s ({}s now), reconnecting",
+            server,
+            config.max,
+            since.elapsed().as_secs(),
+            lag.as_secs()
+        );
+        if let Some(channel) = &config.announce {
+            let text = format!("Lag to {} is {}s, reconnecting", server, lag.as_secs());
+            let _ = outbox
+                .send_outgoing(irc::Outgoing::privmsg(channel.clone(), text))
+                .await;
+        }
+        let _ = cycle.send(()).await;
+        return;
+    }
+}
@@ -0,0 +1,155 @@
+//! An IRC bot, and the pieces to build one with plugins of your own.
+//!
+//! The `boton` binary is [`run`] with the [bundled](plugins::Registry::bundled)
+//! plugins. A binary of your own can add to them, or start from none:
+//!
+//! ```no_run
+//! use anyhow::Result;
+//! use async_trait::async_trait;
+//! use boton::command::{Help, Invocation};
+//! use boton::plugins::{Plugin, PluginBuilder, PluginContext, Registry};
+//!
+//! const HELLO: Help = Help {
+//!     name:  "hello",
+//!     usage: "hello - says hello",
+//! };
+//!
+//! struct HelloPlugin;
+//!
+//! #[async_trait]
+//! impl PluginBuilder for HelloPlugin {
+//!     type Plugin = HelloPlugin;
+//!
+//!     const COMMANDS: &'static [Help] = &[HELLO];
+//!     const NAME: &'static str = "hello";
+//!
+//!     async fn new(_ctx: &PluginContext) -> Result<HelloPlugin> {
+//!         Ok(HelloPlugin)
+//!     }
+//! }
+//!
+//! #[async_trait]
+//! impl Plugin for HelloPlugin {
+//!     async fn command(&self, ctx: &PluginContext, cmd: &Invocation) -> Result<()> {
+//!         if cmd.name == HELLO.name {
+//!             ctx.reply(cmd, format!("Hello, {}!", cmd.user.nick)).await?;
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<()> {
+//!     env_logger::init();
+//!     let mut plugins = Registry::bundled();
+//!     plugins.add::<HelloPlugin>();
+//!     boton::run(plugins).await
+//! }
+//! ```
+//!
+//! Plugins get their section of the `plugins` map of the configuration, by
+//! name, in [`PluginContext::config`](plugins::PluginContext::config).
+
+use anyhow::Result;
+use log::*;
+use tokio::signal;
+use tokio::sync::watch;
+
+mod access;
+pub mod acl;
+pub mod args;
+pub mod awards;
+mod backfill;
+mod backup;
+pub mod batch;
+pub mod bot;
+mod bouncer;
+#[cfg(feature = "http-client")]
+mod cache;
+pub mod chat;
+mod claims;
+pub mod command;
+mod data;
+mod encoding;
+mod filter;
+mod groups;
+mod history;
+#[cfg(feature = "http-client")]
+pub mod http;
+pub mod i18n;
+mod invite;
+pub mod irc;
+mod lag;
+pub mod links;
+#[cfg(feature = "http-server")]
+mod listener;
+#[cfg(feature = "loadgen")]
+#[doc(hidden)]
+pub mod loadgen;
+pub mod messages;
+mod metrics;
+mod net;
+mod netsplit;
+pub mod notices;
+mod oper;
+pub mod ops;
+#[cfg(feature = "http-client")]
+pub mod paste;
+pub mod plugins;
+pub mod prefs;
+mod relay;
+pub mod reply;
+pub mod runtime;
+pub mod sasl;
+pub mod scheduler;
+mod services;
+#[cfg(feature = "http-client")]
+pub mod shortener;
+pub mod state;
+mod status;
+pub mod storage;
+mod systemd;
+mod tasks;
+pub mod template;
+mod version;
+mod watchdog;
+
+/// Runs the bots configured in `config`, each with the plugins of the
+/// registry, until interrupted. Given arguments, runs the `restore` or data
+/// command they name instead.
+pub async fn run(plugins: plugins::Registry) -> Result<()> {
+    metrics::start();
+
+    let bots = bot::Config::load_from("config")?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("restore") => return backup::restore(&bots, &args[1 ..]).await,
+        Some(_) => return data::run(&bots, &args).await,
+        None => {},
+    }
+    let servers: Vec<String> = bots.servers().collect();
+    println!("Loaded config for {}", servers.join(", "));
+    // Passwords, secret plugin settings and performed commands are redacted,
+    // but the rest is only of use when debugging
+    debug!("Config: {:#?}", bots.redacted());
+    if let Some(address) = bots.console() {
+        tasks::serve_console(address)?;
+    }
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let bot_handles = bots.spawn_tasks(&plugins, shutdown_rx).await?;
+
+    tokio::spawn(async move {
+        if signal::ctrl_c().await.is_ok() {
+            info!("Interrupted, disconnecting...");
+            let _ = shutdown.send(true);
+            shutdown.closed().await;
+        }
+    });
+
+    for h in bot_handles {
+        h.await??;
+    }
+
+    info!("All connections closed, exiting...");
+    Ok(())
+}
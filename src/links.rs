@@ -0,0 +1,82 @@
+//! Users followed across nick changes, so plugins keeping data about them key
+//! it by who they are rather than by whichever nick they have at the moment.
+//! For the session, users are known by the nick the bot first saw them with,
+//! and those logged in to services, on servers telling accounts through the
+//! `account` tag, by their account, which lasts across sessions.
+
+use std::collections::HashMap;
+
+use crate::prefs::ACCOUNT_PREFIX;
+
+/// Keys of the users whose nick changed or who are logged in.
+#[derive(Debug, Default)]
+pub struct Links {
+    /// Key of each such user, by lowercase current nick
+    keys: HashMap<String, String>,
+}
+
+impl Links {
+    /// The key of the user with the nick: `account:` and their lowercase
+    /// account if they're logged in, or else the lowercase nick they had
+    /// first.
+    pub fn key(&self, nick: &str) -> String {
+        let nick = nick.to_lowercase();
+        self.keys.get(&nick).cloned().unwrap_or(nick)
+    }
+
+    /// The lowercase nick the user with the key has now, as far as the bot
+    /// knows, if they're around.
+    pub fn nick(&self, key: &str) -> Option<String> {
+        if let Some((nick, _)) = self.keys.iter().find(|(_, linked)| *linked == key) {
+            return Some(nick.clone());
+        }
+        (!key.starts_with(ACCOUNT_PREFIX) && !self.keys.contains_key(key)).then(|| key.into())
+    }
+
+    /// Follows a user changing nicks.
+    pub fn renamed(&mut self, old: &str, new: &str) {
+        let key = self.key(old);
+        self.keys.remove(&old.to_lowercase());
+        let new = new.to_lowercase();
+        if key != new {
+            self.keys.insert(new, key);
+        }
+    }
+
+    /// Records which account the user is logged in to, if any, as seen on a
+    /// message of theirs.
+    pub fn seen(&mut self, nick: &str, account: Option<&str>) {
+        let nick = nick.to_lowercase();
+        match account {
+            Some(account) => {
+                let key = format!("{}{}", ACCOUNT_PREFIX, account.to_lowercase());
+                self.keys.insert(nick, key);
+            },
+            None => {
+                if self
+                    .keys
+                    .get(&nick)
+                    .is_some_and(|key| key.starts_with(ACCOUNT_PREFIX))
+                {
+                    self.keys.remove(&nick);
+                }
+            },
+        }
+    }
+
+    /// Forgets a user who left the network.
+    pub fn left(&mut self, nick: &str) {
+        self.keys.remove(&nick.to_lowercase());
+    }
+
+    /// Forgets every user, on a new session.
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+/// How to call the user with the key in replies: their account, or the nick
+/// they had first.
+pub fn name(key: &str) -> &str {
+    key.strip_prefix(ACCOUNT_PREFIX).unwrap_or(key)
+}
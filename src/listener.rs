@@ -0,0 +1,141 @@
+//! HTTP listener shared by every server, through which plugins receive
+//! webhooks. Plugins route paths to handlers when they start, replacing the
+//! handlers of the connection they replace. `/healthz` tells whether the
+//! servers are connected, for container health checks.
+
+use anyhow::{anyhow, Result};
+use bytes::{Bytes, BytesMut};
+use futures::future::BoxFuture;
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+use crate::status::{self, Phase};
+
+/// Largest request body accepted
+const MAX_BODY: usize = 1024 * 1024;
+
+/// Handles the body of a POST request to a routed path
+pub type Handler = Arc<dyn Fn(Bytes) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+static ROUTES: Mutex<BTreeMap<String, Handler>> = Mutex::new(BTreeMap::new());
+
+/// Where to listen for webhooks and health checks
+#[derive(Debug, Deserialize, Clone)]
+pub struct ListenerConfig {
+    /// Address and port to listen on, e.g. `127.0.0.1:8080`
+    pub address: SocketAddr,
+    /// Whether `/healthz` needs `Any` (the default) or `All` of the servers
+    /// to be connected to report the bot as healthy
+    #[serde(default)]
+    pub healthy: Healthy,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Healthy {
+    #[default]
+    Any,
+    All,
+}
+
+/// Sends POST requests for the path to the handler from now on.
+#[cfg(any(feature = "webhook", feature = "alertmanager"))]
+pub fn route(path: String, handler: Handler) {
+    ROUTES.lock().unwrap().insert(path, handler);
+}
+
+/// Serves requests until `shutdown` is set. `servers` is how many servers
+/// the bot is configured to connect to.
+pub async fn serve(
+    config: ListenerConfig,
+    servers: usize,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let healthy = config.healthy;
+    let service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request| handle(request, healthy, servers)))
+    });
+    let server = Server::try_bind(&config.address)?.serve(service);
+    info!("Listening for webhooks on {}", config.address);
+    server
+        .with_graceful_shutdown(async move {
+            while !*shutdown.borrow() {
+                if shutdown.changed().await.is_err() {
+                    break;
+                }
+            }
+        })
+        .await?;
+    Ok(())
+}
+
+/// Responds whether enough servers are connected, saying how many are.
+fn health(healthy: Healthy, servers: usize) -> Response<Body> {
+    let connected = status::phases()
+        .iter()
+        .filter(|(_, phase, _)| *phase == Phase::Connected)
+        .count();
+    let ok = match healthy {
+        Healthy::Any => connected > 0,
+        Healthy::All => connected >= servers,
+    };
+    let mut response = Response::new(Body::from(format!(
+        "{}/{} servers connected\n",
+        connected, servers
+    )));
+    if !ok {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    response
+}
+
+async fn handle(
+    request: Request<Body>,
+    healthy: Healthy,
+    servers: usize,
+) -> Result<Response<Body>, Infallible> {
+    if request.uri().path() == "/healthz" {
+        return Ok(health(healthy, servers));
+    }
+    let status = match dispatch(request).await {
+        Ok(status) => status,
+        Err(e) => {
+            warn!("Webhook failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        },
+    };
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    Ok(response)
+}
+
+async fn dispatch(request: Request<Body>) -> Result<StatusCode> {
+    let handler = ROUTES.lock().unwrap().get(request.uri().path()).cloned();
+    let handler = match handler {
+        Some(handler) => handler,
+        None => return Ok(StatusCode::NOT_FOUND),
+    };
+    if request.method() != Method::POST {
+        return Ok(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let mut body = request.into_body();
+    let mut bytes = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk?);
+        if bytes.len() > MAX_BODY {
+            return Ok(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+    handler(bytes.freeze())
+        .await
+        .map_err(|e| anyhow!("handler failed: {}", e))?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,181 @@
+//! Synthetic load for the benchmarks: raw lines, like those of a log recorded
+//! from a busy channel, replayed over a real connection to the router and any
+//! number of plugins, each doing nothing but counting what it's handed.
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::backfill::Backfill;
+use crate::bot::Bot;
+use crate::chat::ChatEvent;
+use crate::command::{self, Prefixes, Triggers};
+use crate::history::HistoryConfig;
+use crate::i18n::{I18n, I18nConfig};
+use crate::irc;
+use crate::plugins::{self, Plugin, PluginContext};
+use crate::prefs::Prefs;
+use crate::runtime::Runtime;
+use crate::state;
+use crate::storage::{Backend, Storage};
+use crate::tasks;
+
+const SERVER: &str = "load";
+const NICK: &str = "loadbot";
+/// Lines sent at once, before waiting for them to be handled, few enough for
+/// the channels of received messages not to lag behind
+const CHUNK: usize = 8;
+/// How long plugins get to handle a chunk before the load is given up on
+const STALL: Duration = Duration::from_secs(10);
+
+/// Keeps data sets in memory, so the load doesn't touch the disk
+#[derive(Debug, Default)]
+struct Memory(Mutex<HashMap<String, String>>);
+
+#[async_trait]
+impl Backend for Memory {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.0.lock().unwrap().get(key).cloned())
+    }
+
+    async fn set(&self, key: &str, data: &str) -> Result<()> {
+        self.0.lock().unwrap().insert(key.into(), data.into());
+        Ok(())
+    }
+}
+
+/// Counts the messages and chat events it's handed
+struct Counter {
+    handled: Arc<watch::Sender<usize>>,
+}
+
+#[async_trait]
+impl Plugin for Counter {
+    async fn chat(&self, _ctx: &PluginContext, _event: &ChatEvent) -> Result<()> {
+        self.handled.send_modify(|handled| *handled += 1);
+        Ok(())
+    }
+
+    async fn message(&self, _ctx: &PluginContext, _msg: &irc::Message) -> Result<()> {
+        self.handled.send_modify(|handled| *handled += 1);
+        Ok(())
+    }
+}
+
+/// A bot connected to a server of our own, with its plugins.
+pub struct Load {
+    /// Our end of the connection
+    server:   TcpStream,
+    plugins:  usize,
+    handled:  watch::Receiver<usize>,
+    /// Messages and chat events the plugins were handed so far, together
+    expected: usize,
+    tasks:    Vec<JoinHandle<Result<()>>>,
+}
+
+impl Load {
+    /// Connects a bot with the given number of plugins, with the defaults of
+    /// every setting.
+    pub async fn start(plugins: usize) -> Result<Load> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = ("127.0.0.1".into(), listener.local_addr()?.port());
+        let (irc, connection) = irc::connect(SERVER, &address, irc::Options::default()).await?;
+        let (server, _) = listener.accept().await?;
+
+        let state = state::State::new(NICK, &HistoryConfig::default());
+        let storage = Storage::new(SERVER, Arc::new(Memory::default()));
+        let prefs = Prefs::load(storage.clone()).await?;
+        let i18n = I18n::load(I18nConfig::default(), prefs.clone()).await?;
+        let mut router = command::Router::new(
+            &irc,
+            state.clone(),
+            Prefixes::default(),
+            Triggers::default(),
+            HashMap::new(),
+            i18n.clone(),
+            Backfill::load(storage.clone()).await?,
+            None,
+            prefs.clone(),
+            HashMap::new(),
+        );
+        let runtime = Runtime::load(storage.clone()).await?;
+        let core = Bot::with_defaults(SERVER, NICK)?
+            .core(&irc, state.clone(), storage, runtime, prefs, i18n)
+            .await?;
+
+        let (handled, handled_rx) = watch::channel(0);
+        let handled = Arc::new(handled);
+        let mut tasks = vec![connection];
+        for _ in 0 .. plugins {
+            let counter = Counter {
+                handled: handled.clone(),
+            };
+            let ctx = core.context(&irc, "counter", None);
+            tasks.push(plugins::spawn_plugin(
+                counter,
+                ctx,
+                &router,
+                false,
+                plugins::Subscription::All,
+            ));
+        }
+        let mut received = irc.received_messages;
+        tasks.push(tasks::spawn("load events", async move {
+            while let Ok(msg) = received.recv().await {
+                state.write().await.update(&msg);
+                router.route(&msg).await?;
+            }
+            Ok(())
+        }));
+
+        Ok(Load {
+            server,
+            plugins,
+            handled: handled_rx,
+            expected: 0,
+            tasks,
+        })
+    }
+
+    /// Sends the lines, a few at a time, waiting for every plugin to have
+    /// handled each of them, and the chat events of the PRIVMSGs among them.
+    pub async fn replay(&mut self, lines: &[String]) -> Result<()> {
+        for chunk in lines.chunks(CHUNK) {
+            let mut raw = String::new();
+            for line in chunk {
+                raw.push_str(line);
+                raw.push_str("\r\n");
+                let is_chat = irc::parse_line(line)
+                    .ok()
+                    .is_some_and(|(_, msg)| ChatEvent::parse(&msg).is_some());
+                self.expected += self.plugins * if is_chat { 2 } else { 1 };
+            }
+            self.server.write_all(raw.as_bytes()).await?;
+
+            let expected = self.expected;
+            let handled = tokio::time::timeout(STALL, self.handled.wait_for(|n| *n >= expected));
+            if !matches!(handled.await, Ok(Ok(_))) {
+                return Err(anyhow!(
+                    "plugins stopped at {} of {} messages and chat events",
+                    *self.handled.borrow(),
+                    expected
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Load {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
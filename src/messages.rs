@@ -0,0 +1,63 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::metrics;
+use crate::version;
+
+/// Messages sent by the bot itself, like when leaving channels or the server.
+///
+/// `{version}` and `{uptime}` in them get replaced by the bot's version and
+/// how long it has been running.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Messages {
+    /// Sent when disconnecting, on shutdown or when reconnecting
+    quit:    String,
+    /// Sent when leaving a channel through `\part`
+    part:    String,
+    /// Sent in reply to CTCP VERSION
+    version: String,
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Messages {
+            quit:    "boton {version}".into(),
+            part:    "Bye!".into(),
+            version: "boton {version} - https://github.com/wwared/boton".into(),
+        }
+    }
+}
+
+impl Messages {
+    pub fn quit_message(&self) -> String {
+        render(&self.quit)
+    }
+
+    pub fn part_message(&self) -> String {
+        render(&self.part)
+    }
+
+    pub fn version_reply(&self) -> String {
+        render(&self.version)
+    }
+}
+
+fn render(template: &str) -> String {
+    template
+        .replace("{version}", version::VERSION)
+        .replace("{uptime}", &format_duration(metrics::uptime()))
+}
+
+/// Formats a duration as e.g. `2d 3h 15m`, leaving out leading zero units.
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
@@ -0,0 +1,46 @@
+//! Process-wide event counters, kept per server.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Plugin handlers that panicked
+pub const PLUGIN_PANICS: &str = "plugin_panics";
+/// Plugin handlers that ran longer than the watchdog allows
+pub const PLUGIN_STALLS: &str = "plugin_stalls";
+/// Plugin handlers the watchdog aborted
+pub const PLUGIN_ABORTS: &str = "plugin_aborts";
+/// Lines from the server that couldn't be parsed
+pub const MALFORMED_LINES: &str = "malformed_lines";
+/// PINGs sent through `\ping` the server never answered
+#[cfg(feature = "diagnostics")]
+pub const LOST_PINGS: &str = "lost_pings";
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static COUNTERS: Mutex<BTreeMap<(&'static str, String), u64>> = Mutex::new(BTreeMap::new());
+
+/// Increments the counter `name` for the given server.
+pub fn increment(name: &'static str, server: &str) {
+    let mut counters = COUNTERS.lock().unwrap();
+    *counters.entry((name, server.into())).or_default() += 1;
+}
+
+/// The counters of the given server that went up, by name.
+pub fn counters(server: &str) -> Vec<(&'static str, u64)> {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|((_, counted), _)| counted == server)
+        .map(|((name, _), count)| (*name, *count))
+        .collect()
+}
+
+/// Marks the time the bot started at, used for its uptime.
+pub fn start() {
+    STARTED.get_or_init(Instant::now);
+}
+
+pub fn uptime() -> Duration {
+    STARTED.get().map(Instant::elapsed).unwrap_or_default()
+}
@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Error, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::*;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::{sleep, timeout};
+
+/// How long to wait for one address before also trying the next one
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// How long a single connection attempt may take
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which IP versions to connect over
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpVersion {
+    /// Whatever the server resolves to, alternating between both
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    fn allows(self, addr: &SocketAddr) -> bool {
+        match self {
+            IpVersion::Any => true,
+            IpVersion::V4 => addr.is_ipv4(),
+            IpVersion::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Resolves the host, keeping the addresses of the wanted IP versions. When
+/// both are allowed they are interleaved, starting with whichever came first,
+/// so one broken IP version doesn't hold up the other for long.
+pub async fn resolve(host: &str, port: u16, version: IpVersion) -> Result<Vec<SocketAddr>> {
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) = {
+        let mut addrs = lookup_host((host, port))
+            .await?
+            .filter(|addr| version.allows(addr))
+            .peekable();
+        let v6_first = addrs.peek().is_some_and(SocketAddr::is_ipv6);
+        addrs.partition(|addr| addr.is_ipv6() == v6_first)
+    };
+
+    let mut addrs = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+    if addrs.is_empty() {
+        return Err(anyhow!("{} has no {:?} addresses", host, version));
+    }
+    Ok(addrs)
+}
+
+async fn attempt(addr: SocketAddr) -> (SocketAddr, Result<TcpStream>) {
+    let result = match timeout(ATTEMPT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(result) => result.map_err(Error::from),
+        Err(_) => Err(anyhow!("timed out")),
+    };
+    (addr, result)
+}
+
+/// Connects to the host, trying each of its addresses in turn. An attempt
+/// that takes a while doesn't stop the next address from being tried, and
+/// whichever connects first is used (happy eyeballs).
+pub async fn connect(host: &str, port: u16, version: IpVersion) -> Result<TcpStream> {
+    let mut addrs = resolve(host, port, version).await?.into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = anyhow!("could not connect to {}", host);
+
+    while addrs.peek().is_some() || !attempts.is_empty() {
+        if let Some(addr) = addrs.next() {
+            debug!("Connecting to {} ({})", host, addr);
+            attempts.push(attempt(addr));
+        }
+        // Wait for an attempt to finish, or until it's time to try the next
+        // address. A failed attempt also moves on to the next address.
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => {
+                    info!("Connected to {} ({})", host, addr);
+                    return Ok(stream);
+                },
+                Err(e) => {
+                    warn!("Could not connect to {} ({}): {}", host, addr, e);
+                    last_error = e;
+                },
+            },
+            _ = sleep(ATTEMPT_DELAY), if addrs.peek().is_some() => {},
+        }
+    }
+    Err(last_error)
+}
@@ -0,0 +1,161 @@
+//! Netsplits, told apart from other QUITs by their reason naming the two
+//! servers that split, like `irc.example.org hub.example.org`, or `*.net
+//! *.split` on networks hiding their servers. Users lost in one are
+//! remembered until they come back, so plugins can tell their rejoins from
+//! arrivals, and splits and rejoins are gathered for plugins into one batch
+//! each, like those of servers sending netsplit batches.
+
+use log::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::{sleep_until, Instant};
+
+use crate::batch::{self, Batch};
+use crate::irc;
+use crate::tasks;
+
+/// How long users lost in a split are waited for
+const RETURN_WINDOW: Duration = Duration::from_secs(30 * 60);
+/// How long users back from a split still count as rejoining, as the servers
+/// sync up
+const REJOIN_WINDOW: Duration = Duration::from_secs(60);
+/// Quiet time after which a split or rejoin is taken as over
+const SETTLE: Duration = Duration::from_secs(3);
+
+/// The two servers named by the reason of a QUIT, if it's that of a split.
+pub fn servers(msg: &irc::Message) -> Option<(&str, &str)> {
+    if msg.command != irc::Command::Quit {
+        return None;
+    }
+    // :nick!user@host QUIT :irc.example.org hub.example.org
+    let reason = msg.target.as_deref()?;
+    let (first, second) = reason.split_once(' ')?;
+    let is_server = |name: &str| {
+        name.contains('.')
+            && !name.starts_with('.')
+            && !name.ends_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '*' | '_'))
+    };
+    (is_server(first) && is_server(second) && first != second).then_some((first, second))
+}
+
+/// Users lost in splits, and back from them, by lowercase nick.
+#[derive(Debug, Default)]
+pub struct Netsplits {
+    /// Since when, and between which servers
+    lost:     HashMap<String, (Instant, Vec<String>)>,
+    /// Since when, and between which servers they had split
+    rejoined: HashMap<String, (Instant, Vec<String>)>,
+}
+
+impl Netsplits {
+    /// Remembers the user quitting in a split, if it is one.
+    pub fn quit(&mut self, msg: &irc::Message) {
+        if let (Some((first, second)), Some(nick)) = (servers(msg), msg.source_nick()) {
+            let servers = vec![first.into(), second.into()];
+            self.lost
+                .insert(nick.to_lowercase(), (Instant::now(), servers));
+        }
+    }
+
+    /// Notes the user coming back from a split, if they were lost in one.
+    pub fn join(&mut self, nick: &str) {
+        self.lost.retain(|_, (at, _)| at.elapsed() < RETURN_WINDOW);
+        self.rejoined
+            .retain(|_, (at, _)| at.elapsed() < REJOIN_WINDOW);
+        let key = nick.to_lowercase();
+        if let Some((_, servers)) = self.lost.remove(&key) {
+            self.rejoined.insert(key, (Instant::now(), servers));
+        }
+    }
+
+    /// The servers that had split, if the user is rejoining after it.
+    pub fn rejoining(&self, nick: &str) -> Option<&[String]> {
+        match self.rejoined.get(&nick.to_lowercase()) {
+            Some((at, servers)) if at.elapsed() < REJOIN_WINDOW => Some(servers),
+            _ => None,
+        }
+    }
+
+    /// Whether the user is rejoining after a split, or still lost in one and
+    /// so about to, rather than arriving.
+    pub fn is_returning(&self, nick: &str) -> bool {
+        self.lost.contains_key(&nick.to_lowercase()) || self.rejoining(nick).is_some()
+    }
+}
+
+/// Gathers the QUITs of splits and the JOINs after them, from servers not
+/// sending them in batches, into batches passed on once things settle.
+pub struct Gatherer {
+    server:  String,
+    batched: broadcast::Sender<Batch>,
+    /// Batches being gathered by kind, with the time of their last message
+    open:    Arc<Mutex<HashMap<&'static str, (Instant, Batch)>>>,
+}
+
+impl Gatherer {
+    pub fn new(server: &str, batched: broadcast::Sender<Batch>) -> Self {
+        Gatherer {
+            server: server.into(),
+            batched,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Adds the message to the batch of its kind being gathered, starting
+    /// one if there's none.
+    pub fn add(&self, kind: &'static str, servers: &[String], msg: &irc::Message) {
+        let mut gathering = self.open.lock().unwrap();
+        if let Some((last, batch)) = gathering.get_mut(kind) {
+            *last = Instant::now();
+            batch.messages.push(msg.clone());
+            return;
+        }
+        let batch = Batch {
+            kind:       kind.into(),
+            parameters: servers.to_vec(),
+            messages:   vec![msg.clone()],
+            batches:    vec![],
+        };
+        gathering.insert(kind, (Instant::now(), batch));
+
+        let (open, batched) = (self.open.clone(), self.batched.clone());
+        let server = self.server.clone();
+        tasks::spawn(&format!("{} {}", server, kind), async move {
+            loop {
+                let last = match open.lock().unwrap().get(kind) {
+                    Some((last, _)) => *last,
+                    None => return,
+                };
+                if last.elapsed() < SETTLE {
+                    sleep_until(last + SETTLE).await;
+                    continue;
+                }
+                let batch = match open.lock().unwrap().remove(kind) {
+                    Some((_, batch)) => batch,
+                    None => return,
+                };
+                let what = if kind == batch::NETSPLIT {
+                    "lost"
+                } else {
+                    "back"
+                };
+                info!(
+                    "[{}] Netsplit between {}, {} users {}",
+                    server,
+                    batch.parameters.join(" and "),
+                    batch.messages.len(),
+                    what
+                );
+                if batched.send(batch).is_err() {
+                    debug!("No plugins listening for batches");
+                }
+                return;
+            }
+        });
+    }
+}
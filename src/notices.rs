@@ -0,0 +1,44 @@
+//! Messages from the network itself rather than from users: WALLOPS, sent to
+//! users with the `w` mode, and NOTICEs from servers, like the connection and
+//! kill notices opers get with the `s` mode. They're passed to plugins on
+//! their own, apart from everything else received.
+
+use crate::irc;
+
+/// A WALLOPS or server NOTICE received.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// A WALLOPS, from the oper or server that sent it
+    Wallops { from: String, text: String },
+    /// A NOTICE from a server, empty if it didn't say which
+    Notice { server: String, text: String },
+}
+
+impl ServerEvent {
+    /// The event the message is, if it's a WALLOPS or a server NOTICE.
+    pub fn parse(msg: &irc::Message) -> Option<Self> {
+        match &msg.command {
+            // :oper!user@host WALLOPS :text
+            irc::Command::Other(command) if command == "WALLOPS" => {
+                let source = msg.source.as_deref()?;
+                Some(ServerEvent::Wallops {
+                    from: msg.source_nick().unwrap_or(source).into(),
+                    text: msg.target.clone()?,
+                })
+            },
+            // :irc.example.org NOTICE ournick :*** Notice -- Client connecting: ...
+            irc::Command::Notice => {
+                // Server names have dots, which nicks can't
+                let server = msg.source.as_deref().unwrap_or_default();
+                if !server.is_empty() && (!server.contains('.') || server.contains(['!', '@'])) {
+                    return None;
+                }
+                Some(ServerEvent::Notice {
+                    server: server.into(),
+                    text:   msg.parameters.last()?.clone(),
+                })
+            },
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Becoming an IRC operator after connecting, for bots providing network
+//! services, which can then force users into channels or disconnect them.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{timeout_at, Instant};
+
+use crate::irc::{self, Command, Message};
+
+/// How long to wait for the server to answer OPER
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Operator block the bot opers up with
+#[derive(Deserialize, Clone)]
+pub struct OperConfig {
+    pub name:     String,
+    pub password: String,
+    /// User modes set once opered, like `+ws` for WALLOPS and server notices
+    #[serde(default)]
+    pub modes:    Option<String>,
+}
+
+// Keeps the password out of the logged configuration
+impl fmt::Debug for OperConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OperConfig")
+            .field("name", &self.name)
+            .field("password", &"..")
+            .field("modes", &self.modes)
+            .finish()
+    }
+}
+
+/// Sends OPER and waits for the server to accept it, then sets the
+/// configured modes.
+pub async fn up(irc: &irc::IRC, config: &OperConfig, nick: &str) -> Result<()> {
+    let mut messages = irc.clone().received_messages;
+    let oper = Message::oper(config.name.as_str(), config.password.as_str());
+    irc.send_messages
+        .send_with(oper, irc::Priority::Control)
+        .await?;
+
+    let deadline = Instant::now() + ANSWER_TIMEOUT;
+    loop {
+        let msg = match timeout_at(deadline, messages.recv()).await {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) => return Err(anyhow!("connection closed")),
+            Err(_) => return Err(anyhow!("no answer from the server")),
+        };
+        match msg.command {
+            Command::RplYoureOper => break,
+            Command::ErrPasswdMismatch => return Err(anyhow!("wrong password")),
+            Command::ErrNoOperHost => return Err(anyhow!("not allowed from this host")),
+            _ => {},
+        }
+    }
+    info!("[{}] Opered up as {}", irc.server, config.name);
+
+    if let Some(modes) = &config.modes {
+        irc.send_messages
+            .send_with(Message::mode(nick, modes), irc::Priority::Control)
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+//! Operator status for moderating, taken from ChanServ only when needed in
+//! channels where the bot doesn't sit opped.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+use crate::irc;
+use crate::scheduler::Scheduler;
+use crate::state;
+
+/// How long to wait for ChanServ to op the bot
+const OP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often to look whether ChanServ did
+const OP_POLL: Duration = Duration::from_millis(250);
+
+/// Where to get ops from ChanServ on demand
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct OpsConfig {
+    /// Channels where ChanServ is asked for ops before moderating
+    pub chanserv: Vec<String>,
+    /// Seconds to keep ops after moderating before dropping them, so actions
+    /// in a row only ask once
+    pub keep:     u64,
+}
+
+impl Default for OpsConfig {
+    fn default() -> Self {
+        OpsConfig {
+            chanserv: vec![],
+            keep:     60,
+        }
+    }
+}
+
+/// Gets the bot opped for plugins that moderate channels, dropping the ops it
+/// got from ChanServ once they're done.
+#[derive(Clone)]
+pub struct Ops {
+    config:    OpsConfig,
+    server:    String,
+    state:     state::Handle,
+    outbox:    irc::Outbox,
+    scheduler: Scheduler,
+    /// Channels where ChanServ opped us, by lowercase name, with a count of
+    /// the times ops were needed there so pending releases can tell they're
+    /// outdated
+    taken:     Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Ops {
+    pub fn new(
+        config: OpsConfig,
+        irc: &irc::IRC,
+        state: state::Handle,
+        scheduler: Scheduler,
+    ) -> Self {
+        Ops {
+            config,
+            server: irc.server.clone(),
+            state,
+            outbox: irc.send_messages.clone(),
+            scheduler,
+            taken: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Makes sure the bot is opped in the channel, asking ChanServ if it's
+    /// configured to. Call `release` once done moderating.
+    pub async fn acquire(&self, channel: &str) -> Result<()> {
+        let key = channel.to_lowercase();
+        if let Some(uses) = self.taken.lock().unwrap().get_mut(&key) {
+            *uses += 1;
+        }
+        if self.state.read().await.bot_has_op(channel) {
+            return Ok(());
+        }
+        let configured = self
+            .config
+            .chanserv
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(channel));
+        if !configured {
+            return Err(anyhow!("not opped in {}", channel));
+        }
+
+        info!("[{}] Asking ChanServ for ops in {}", self.server, channel);
+        let op = format!("OP {}", channel);
+        self.outbox
+            .send_outgoing(irc::Outgoing::privmsg("ChanServ", op.as_str()))
+            .await?;
+        let deadline = Instant::now() + OP_TIMEOUT;
+        while !self.state.read().await.bot_has_op(channel) {
+            if Instant::now() >= deadline {
+                return Err(anyhow!("ChanServ didn't op us in {}", channel));
+            }
+            sleep(OP_POLL).await;
+        }
+        *self.taken.lock().unwrap().entry(key).or_default() += 1;
+        Ok(())
+    }
+
+    /// Drops the ops ChanServ gave for the channel after a while, unless
+    /// they're needed again meanwhile. Ops the bot had anyway are kept.
+    pub fn release(&self, channel: &str) {
+        let key = channel.to_lowercase();
+        let uses = match self.taken.lock().unwrap().get(&key) {
+            Some(uses) => *uses,
+            None => return,
+        };
+        let ops = self.clone();
+        let channel = channel.to_string();
+        let keep = Duration::from_secs(self.config.keep);
+        self.scheduler.after(keep, async move {
+            {
+                let mut taken = ops.taken.lock().unwrap();
+                if taken.get(&key) != Some(&uses) {
+                    return;
+                }
+                taken.remove(&key);
+            }
+            let nick = ops.state.read().await.nick.clone();
+            info!("[{}] Dropping ops in {}", ops.server, channel);
+            let deop = irc::Message::channel_mode(channel.as_str(), "-o", &[&nick]);
+            if let Err(e) = ops.outbox.send(deop).await {
+                warn!("[{}] Could not drop ops in {}: {}", ops.server, channel, e);
+            }
+        });
+    }
+}
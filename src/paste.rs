@@ -0,0 +1,69 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::http::{self, Http};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pastebin used for outputs too long to post in a channel
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PasteConfig {
+    /// URL the content gets POSTed to as a form. It must answer with the
+    /// link to the paste as plain text. Nothing is uploaded when unset.
+    pub endpoint: Option<String>,
+    /// Name of the form field holding the content
+    pub field:    String,
+}
+
+impl Default for PasteConfig {
+    fn default() -> Self {
+        PasteConfig {
+            endpoint: None,
+            field:    "content".into(),
+        }
+    }
+}
+
+/// Uploads long outputs to a pastebin, so only a link has to be posted.
+#[derive(Clone)]
+pub struct Paster {
+    config: PasteConfig,
+    client: http::Client,
+}
+
+impl Paster {
+    pub fn new(config: PasteConfig, http: &Http) -> Self {
+        Paster {
+            config,
+            client: http.client(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.endpoint.is_some()
+    }
+
+    /// Uploads the content, returning the link to it.
+    pub async fn upload(&self, content: &str) -> Result<String> {
+        let endpoint = self
+            .config
+            .endpoint
+            .as_ref()
+            .ok_or_else(|| anyhow!("no paste endpoint configured"))?;
+        let response = self
+            .client
+            .post(endpoint)
+            .timeout(TIMEOUT)
+            .form(&[(self.config.field.as_str(), content)])
+            .send()
+            .await?
+            .error_for_status()?;
+        let link = response.text().await?.trim().to_string();
+        if !link.starts_with("http") {
+            return Err(anyhow!("unexpected response {:?}", link));
+        }
+        Ok(link)
+    }
+}
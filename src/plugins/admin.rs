@@ -0,0 +1,240 @@
+use crate::command;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext};
+use crate::reply::Reply;
+use crate::status;
+use anyhow::Result;
+use async_trait::async_trait;
+use log::*;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// How long `\debugtap` relays messages unless told otherwise, in minutes
+const DEFAULT_TAP_MINUTES: u64 = 10;
+const MAX_TAP_MINUTES: u64 = 60;
+
+/// Commands for managing the bot itself, restricted to the configured admins.
+pub struct AdminPlugin {
+    /// Until when messages about each lowercase channel are relayed to each
+    /// admin, by their nick
+    taps: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl AdminPlugin {
+    /// Handles `\debugtap`, returning the reply.
+    async fn tap(&self, ctx: &PluginContext, cmd: &command::Invocation) -> String {
+        let nick = &cmd.user.nick;
+        let args = cmd.parse_args();
+        let (channel, on, minutes) = match (args.word(0), args.word(1), args.word(2)) {
+            (Some(channel), Some("off"), None) => (channel, false, 0),
+            (Some(channel), Some("on"), minutes) => match minutes.map(str::parse) {
+                None => (channel, true, DEFAULT_TAP_MINUTES),
+                Some(Ok(minutes)) if (1 ..= MAX_TAP_MINUTES).contains(&minutes) => {
+                    (channel, true, minutes)
+                },
+                Some(_) => {
+                    return format!(
+                        "{}: Taps last between 1 and {} minutes",
+                        nick, MAX_TAP_MINUTES
+                    )
+                },
+            },
+            _ => return ctx.usage(cmd, "debugtap #channel on [minutes] | off"),
+        };
+        if !irc::is_channel(channel) {
+            return ctx.tr(cmd, "{0}: Which channel?", &[nick]);
+        }
+
+        let key = (channel.to_lowercase(), nick.clone());
+        let mut taps = self.taps.lock().await;
+        if !on {
+            if taps.remove(&key).is_none() {
+                return format!("{}: {} isn't tapped", nick, channel);
+            }
+            info!("[{}] {} stopped tapping {}", ctx.server, cmd.user, channel);
+            return format!("{}: No longer relaying {}", nick, channel);
+        }
+        taps.insert(key, Instant::now() + Duration::from_secs(minutes * 60));
+        info!(
+            "[{}] {} tapping {} for {} minutes",
+            ctx.server, cmd.user, channel, minutes
+        );
+        format!(
+            "{}: Relaying messages about {} to you for {} minutes",
+            nick, channel, minutes
+        )
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for AdminPlugin {
+    type Plugin = AdminPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[
+        command::Help {
+            name:  "join",
+            usage: "join #channel [key] - joins the channel, also after restarts (admins only)",
+        },
+        command::Help {
+            name:  "part",
+            usage: "part [#channel] - leaves the channel, also after restarts (admins only)",
+        },
+        command::Help {
+            name:  "maintenance",
+            usage: "maintenance [on|off] - keeps plugins from saying anything while still \
+                    tracking everything, or shows whether they are (admins only)",
+        },
+        command::Help {
+            name:  "reconnect",
+            usage: "reconnect - quits and reconnects to the server (admins only)",
+        },
+        command::Help {
+            name:  "debugtap",
+            usage: "debugtap #channel on [minutes] | off - sends you every message received about \
+                    the channel, as parsed, for a while (admins only)",
+        },
+        command::Help {
+            name:  "status",
+            usage: "status - shows the connection to each server and HTTP providers in trouble \
+                    (admins only)",
+        },
+    ];
+    const NAME: &'static str = "admin";
+
+    async fn new(_ctx: &PluginContext) -> Result<AdminPlugin> {
+        Ok(AdminPlugin {
+            taps: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for AdminPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if !matches!(
+            cmd.name.as_str(),
+            "join" | "part" | "maintenance" | "reconnect" | "debugtap" | "status"
+        ) {
+            return Ok(());
+        }
+        if !ctx.admins.allows(&cmd.user) {
+            info!("[{}] Denied {} to {}", ctx.server, cmd.name, cmd.user);
+            return ctx
+                .reply(
+                    cmd,
+                    ctx.tr(cmd, "{0}: You're not allowed to do that", &[&cmd.user.nick]),
+                )
+                .await;
+        }
+
+        match cmd.name.as_str() {
+            "join" => {
+                let args = cmd.parse_args();
+                let channel = match args.word(0) {
+                    Some(channel) if irc::is_channel(channel) => channel,
+                    _ => {
+                        return ctx
+                            .reply(cmd, ctx.tr(cmd, "{0}: Which channel?", &[&cmd.user.nick]))
+                            .await
+                    },
+                };
+                let key = args.word(1);
+                ctx.runtime.joined(channel, key).await?;
+                match key {
+                    Some(key) => ctx.send(irc::Message::join_with_key(channel, key)).await,
+                    None => ctx.send(irc::Message::join(channel)).await,
+                }
+            },
+            "part" => {
+                let channel = cmd.args.as_deref().unwrap_or(&cmd.reply_to);
+                if !irc::is_channel(channel) {
+                    return ctx
+                        .reply(cmd, ctx.tr(cmd, "{0}: Which channel?", &[&cmd.user.nick]))
+                        .await;
+                }
+                ctx.runtime.parted(channel).await?;
+                let part = irc::Message::part(channel, &ctx.messages.part_message());
+                ctx.send(part).await
+            },
+            "maintenance" => {
+                let maintenance = match cmd.args.as_deref() {
+                    Some("on") => true,
+                    Some("off") => false,
+                    Some(_) => return ctx.reply(cmd, ctx.usage(cmd, "maintenance [on|off]")).await,
+                    None => ctx.runtime.maintenance().await,
+                };
+                if cmd.args.is_some() {
+                    info!(
+                        "[{}] Maintenance turned {} by {}",
+                        ctx.server,
+                        if maintenance { "on" } else { "off" },
+                        cmd.user.nick
+                    );
+                    ctx.runtime.set_maintenance(maintenance).await?;
+                }
+                let text = if maintenance {
+                    "{0}: In maintenance, plugins are quiet"
+                } else {
+                    "{0}: Not in maintenance, plugins are talking"
+                };
+                ctx.reply(cmd, ctx.tr(cmd, text, &[&cmd.user.nick])).await
+            },
+            "debugtap" => {
+                let reply = self.tap(ctx, cmd).await;
+                ctx.reply(cmd, reply).await
+            },
+            "status" => {
+                Reply::paginated(cmd, status::report().await)
+                    .send(ctx)
+                    .await
+            },
+            _ => {
+                info!(
+                    "[{}] Reconnecting as asked by {}",
+                    ctx.server, cmd.user.nick
+                );
+                // The connection closing makes the bot reconnect
+                let quit = irc::Message::quit(ctx.messages.quit_message());
+                ctx.send_with(quit, irc::Priority::Control).await
+            },
+        }
+    }
+
+    /// Relays messages naming a tapped channel, as their target or in their
+    /// parameters like those of NAMES, to the admins tapping it.
+    async fn message(&self, ctx: &PluginContext, msg: &irc::Message) -> Result<()> {
+        let admins: Vec<(String, String)> = {
+            let mut taps = self.taps.lock().await;
+            if taps.is_empty() {
+                return Ok(());
+            }
+            let now = Instant::now();
+            let mut ended = vec![];
+            taps.retain(|(channel, nick), until| {
+                if *until > now {
+                    return true;
+                }
+                ended.push((nick.clone(), format!("Tap on {} ended", channel)));
+                false
+            });
+            let names = |channel: &str| {
+                msg.target
+                    .iter()
+                    .chain(&msg.parameters)
+                    .any(|p| p.eq_ignore_ascii_case(channel))
+            };
+            taps.keys()
+                .filter(|(channel, _)| names(channel))
+                .map(|(channel, nick)| (nick.clone(), format!("[{}] {:?}", channel, msg)))
+                .chain(ended)
+                .collect()
+        };
+        for (nick, text) in admins {
+            let relay = irc::Outgoing::privmsg(nick.as_str(), text.as_str())
+                .with_priority(irc::Priority::Bulk);
+            ctx.send_outgoing(relay).await?;
+        }
+        Ok(())
+    }
+}
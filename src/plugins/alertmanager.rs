@@ -0,0 +1,352 @@
+//! Posts the notifications Prometheus Alertmanager sends to
+//! `/alertmanager/<token>`, one line per group of alerts colored by severity.
+//! `\ack` silences the re-announcements of an alert for a while. Needs the
+//! `listener` to be configured.
+//!
+//! Configuration:
+//! - `alerts <token>`: the server and channel to post to, separated by a space
+//! - `ack-minutes`: how long `\ack` silences an alert by default, 60 by default
+
+use crate::command;
+use crate::irc;
+use crate::listener;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::reply::truncate;
+use crate::storage::DataSet;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use log::*;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const ACK: command::Help = command::Help {
+    name:  "ack",
+    usage: "ack [<alert> [minutes]] - silences an alert's re-announcements here, or lists the \
+            silenced ones",
+};
+
+const DB_NAME: &str = "alertmanager";
+pub const DATA_SET: DataSet = DataSet::new::<Acks>(DB_NAME);
+const DEFAULT_ACK_MINUTES: i64 = 60;
+/// Longest an alert can be silenced for, a week
+const MAX_ACK_MINUTES: i64 = 7 * 24 * 60;
+/// Most alerts named in a group's line
+const MAX_NAMES: usize = 5;
+/// Longest summary posted, in characters
+const MAX_SUMMARY: usize = 250;
+
+const BOLD: char = '\x02';
+const COLOR: char = '\x03';
+const RED: &str = "04";
+const ORANGE: &str = "07";
+const GREEN: &str = "03";
+const BLUE: &str = "12";
+
+/// When each silenced alert may be announced again, by lowercase channel and
+/// lowercase alert name.
+type Acks = BTreeMap<String, BTreeMap<String, DateTime<Utc>>>;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Notification {
+    status:             String,
+    #[serde(default)]
+    group_labels:       HashMap<String, String>,
+    #[serde(default)]
+    common_labels:      HashMap<String, String>,
+    #[serde(default)]
+    common_annotations: HashMap<String, String>,
+    #[serde(default)]
+    alerts:             Vec<Alert>,
+}
+
+#[derive(Deserialize)]
+struct Alert {
+    status:      String,
+    #[serde(default)]
+    labels:      HashMap<String, String>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+impl Alert {
+    /// What tells this alert apart from the others in its group.
+    fn name(&self) -> &str {
+        ["instance", "job", "alertname"]
+            .iter()
+            .find_map(|label| self.labels.get(*label))
+            .map_or("?", String::as_str)
+    }
+}
+
+impl Notification {
+    fn alertname(&self) -> String {
+        let from_alerts = self.alerts.iter().find_map(|a| a.labels.get("alertname"));
+        self.group_labels
+            .get("alertname")
+            .or_else(|| self.common_labels.get("alertname"))
+            .or(from_alerts)
+            .cloned()
+            .unwrap_or_else(|| "alert".into())
+    }
+
+    fn summary(&self) -> Option<&String> {
+        let annotations = std::iter::once(&self.common_annotations)
+            .chain(self.alerts.iter().map(|alert| &alert.annotations));
+        for annotations in annotations {
+            if let Some(summary) = annotations
+                .get("summary")
+                .or_else(|| annotations.get("description"))
+            {
+                return Some(summary);
+            }
+        }
+        None
+    }
+
+    fn describe(&self) -> String {
+        let (firing, resolved): (Vec<&Alert>, Vec<&Alert>) = self
+            .alerts
+            .iter()
+            .partition(|alert| alert.status == "firing");
+        let names = |alerts: &[&Alert]| {
+            let mut names: Vec<&str> = alerts.iter().take(MAX_NAMES).map(|a| a.name()).collect();
+            let more = format!("+{} more", alerts.len().saturating_sub(MAX_NAMES));
+            if alerts.len() > MAX_NAMES {
+                names.push(&more);
+            }
+            names.join(", ")
+        };
+
+        let mut line = if self.status == "firing" {
+            let severity = self.common_labels.get("severity").map(String::as_str);
+            let color = match severity {
+                Some("critical" | "page" | "error") => RED,
+                Some("warning") => ORANGE,
+                _ => BLUE,
+            };
+            let mut tag = format!("[FIRING:{}]", firing.len());
+            if let Some(severity) = severity {
+                tag += &format!(" {}", severity);
+            }
+            let mut line = format!(
+                "{}{}{}{}{}{} {}: {}",
+                BOLD,
+                COLOR,
+                color,
+                tag,
+                COLOR,
+                BOLD,
+                self.alertname(),
+                names(&firing)
+            );
+            if !resolved.is_empty() {
+                line += &format!(" (resolved: {})", names(&resolved));
+            }
+            line
+        } else {
+            format!(
+                "{}{}{}[RESOLVED]{}{} {}: {}",
+                BOLD,
+                COLOR,
+                GREEN,
+                COLOR,
+                BOLD,
+                self.alertname(),
+                names(&resolved)
+            )
+        };
+        if let Some(summary) = self.summary() {
+            line += &format!(" 〜 {}", truncate(summary.trim(), MAX_SUMMARY));
+        }
+        line
+    }
+}
+
+/// Posts the notifications for one token to its channel.
+struct Receiver {
+    ctx:     PluginContext,
+    channel: String,
+    acks:    Arc<Mutex<Acks>>,
+}
+
+impl Receiver {
+    async fn post(&self, body: Bytes) -> Result<()> {
+        let notification: Notification = serde_json::from_slice(&body)?;
+        let alertname = notification.alertname();
+        let channel = self.channel.to_lowercase();
+        {
+            let mut acks = self.acks.lock().await;
+            let acked = acks.get_mut(&channel);
+            if notification.status == "firing" {
+                let until = acked.and_then(|acked| acked.get(&alertname.to_lowercase()).copied());
+                if until.is_some_and(|until| until > Utc::now()) {
+                    debug!("[{}] Not announcing acked {}", self.ctx.server, alertname);
+                    return Ok(());
+                }
+            } else if let Some(acked) = acked {
+                // Once resolved, firing again is news again
+                if acked.remove(&alertname.to_lowercase()).is_some() {
+                    self.ctx.storage.save(DB_NAME, &*acks).await?;
+                }
+            }
+        }
+
+        let text = notification.describe();
+        self.ctx
+            .send_outgoing(irc::Outgoing::privmsg(self.channel.as_str(), text.as_str()))
+            .await
+    }
+}
+
+pub struct AlertmanagerPlugin {
+    /// Channels alerts are posted to on this server, in lowercase
+    channels:    Vec<String>,
+    ack_minutes: i64,
+    acks:        Arc<Mutex<Acks>>,
+}
+
+impl AlertmanagerPlugin {
+    async fn ack(
+        &self,
+        ctx: &PluginContext,
+        channel: &str,
+        alert: &str,
+        minutes: i64,
+    ) -> Result<()> {
+        let until = Utc::now() + Duration::minutes(minutes);
+        let mut acks = self.acks.lock().await;
+        // Forget about the silences that are over while at it
+        for acked in acks.values_mut() {
+            acked.retain(|_, until| *until > Utc::now());
+        }
+        acks.retain(|_, acked| !acked.is_empty());
+        acks.entry(channel.into())
+            .or_default()
+            .insert(alert.to_lowercase(), until);
+        ctx.storage.save(DB_NAME, &*acks).await
+    }
+
+    async fn list(&self, channel: &str) -> Option<String> {
+        let acks = self.acks.lock().await;
+        let now = Utc::now();
+        let acked: Vec<String> = acks
+            .get(channel)?
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(alert, until)| format!("{} ({}m left)", alert, (*until - now).num_minutes()))
+            .collect();
+        Some(acked.join(", ")).filter(|acked| !acked.is_empty())
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for AlertmanagerPlugin {
+    type Plugin = AlertmanagerPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[ACK];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "alertmanager";
+
+    async fn new(ctx: &PluginContext) -> Result<AlertmanagerPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let ack_minutes = match config.get("ack-minutes") {
+            Some(minutes) => minutes
+                .parse()
+                .map_err(|_| anyhow!("[Alertmanager] Invalid ack-minutes: {}", minutes))?,
+            None => DEFAULT_ACK_MINUTES,
+        };
+        let acks: Acks = ctx.storage.load(DB_NAME).await?.unwrap_or_default();
+        let acks = Arc::new(Mutex::new(acks));
+
+        let mut channels = vec![];
+        for (key, target) in &config {
+            let token = match key.strip_prefix("alerts ") {
+                Some(token) => token.trim(),
+                None => continue,
+            };
+            let (server, channel) = match target.split_whitespace().collect::<Vec<_>>()[..] {
+                [server, channel] if irc::is_channel(channel) => (server, channel),
+                _ => return Err(anyhow!("[Alertmanager] Invalid target: {}", target)),
+            };
+            // Alerts for other servers are routed by their own connections
+            if server != ctx.server {
+                continue;
+            }
+
+            channels.push(channel.to_lowercase());
+            let receiver = Arc::new(Receiver {
+                ctx:     ctx.clone(),
+                channel: channel.into(),
+                acks:    acks.clone(),
+            });
+            listener::route(
+                format!("/alertmanager/{}", token),
+                Arc::new(move |body| {
+                    let receiver = receiver.clone();
+                    Box::pin(async move { receiver.post(body).await })
+                }),
+            );
+        }
+
+        Ok(AlertmanagerPlugin {
+            channels,
+            ack_minutes,
+            acks,
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for AlertmanagerPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != ACK.name {
+            return Ok(());
+        }
+        let channel = cmd.reply_to.to_lowercase();
+        if !self.channels.contains(&channel) {
+            let reply = format!("{}: No alerts are posted here", cmd.user.nick);
+            return ctx.reply(cmd, reply).await;
+        }
+
+        let args = cmd.parse_args();
+        let words: Vec<&str> = args.words().collect();
+        let (alert, minutes) = match words[..] {
+            [] => {
+                let reply = match self.list(&channel).await {
+                    Some(acked) => format!("{}: Silenced here: {}", cmd.user.nick, acked),
+                    None => format!("{}: No alerts are silenced here", cmd.user.nick),
+                };
+                return ctx.reply(cmd, reply).await;
+            },
+            [alert] => (alert, Some(self.ack_minutes)),
+            [alert, minutes] => (
+                alert,
+                minutes
+                    .parse()
+                    .ok()
+                    .filter(|m| (1 ..= MAX_ACK_MINUTES).contains(m)),
+            ),
+            _ => ("", None),
+        };
+        let reply = match minutes {
+            Some(minutes) => {
+                self.ack(ctx, &channel, alert, minutes).await?;
+                info!(
+                    "[{}] {} acked {} in {}",
+                    ctx.server, cmd.user, alert, channel
+                );
+                format!(
+                    "{}: Silencing {} here for {} minutes",
+                    cmd.user.nick, alert, minutes
+                )
+            },
+            None => ctx.usage(cmd, ACK.usage),
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
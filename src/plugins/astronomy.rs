@@ -0,0 +1,269 @@
+//! Where the International Space Station is, when it next passes over the
+//! requester's location saved through `\wset`, and NASA's Astronomy Picture of
+//! the Day.
+//!
+//! Configuration:
+//! - `nasa-apikey`: from <https://api.nasa.gov>, `DEMO_KEY` by default
+//! - `n2yo-apikey`: from <https://www.n2yo.com/api/>, needed for ISS passes
+
+use crate::command;
+use crate::http;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::prefs;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use log::*;
+use serde::Deserialize;
+
+const ISS: command::Help = command::Help {
+    name:  "iss",
+    usage: "iss - shows where the ISS is, and when it next passes over your saved location",
+};
+const APOD: command::Help = command::Help {
+    name:  "apod",
+    usage: "apod - shows NASA's Astronomy Picture of the Day",
+};
+
+const WHERETHEISS_URL: &str = "https://api.wheretheiss.at/v1/satellites";
+const ISS_NORAD_ID: u32 = 25544;
+const N2YO_URL: &str = "https://api.n2yo.com/rest/v1/satellite";
+const NOMINATIM_URL: &str = "https://nominatim.openstreetmap.org/search";
+const APOD_URL: &str = "https://api.nasa.gov/planetary/apod";
+/// How many days ahead to look for visible passes, at most 10
+const PASS_DAYS: u32 = 10;
+/// Minimum time a pass must be visible for, in seconds
+const PASS_MIN_VISIBILITY: u32 = 60;
+
+#[derive(Deserialize)]
+struct IssPosition {
+    latitude:   f64,
+    longitude:  f64,
+    /// Kilometers
+    altitude:   f64,
+    /// Kilometers per hour
+    velocity:   f64,
+    visibility: String,
+}
+
+#[derive(Deserialize)]
+struct Place {
+    lat:          String,
+    lon:          String,
+    display_name: String,
+}
+
+#[derive(Deserialize)]
+struct Passes {
+    #[serde(default)]
+    passes: Vec<Pass>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Pass {
+    #[serde(rename = "startUTC")]
+    start_utc:        i64,
+    start_az_compass: String,
+    end_az_compass:   String,
+    max_el:           f64,
+    /// Seconds
+    duration:         u64,
+}
+
+#[derive(Deserialize)]
+struct Picture {
+    title: String,
+    date:  String,
+}
+
+/// Formats a latitude and longitude like `12.34°N, 56.78°W`.
+fn coordinates(latitude: f64, longitude: f64) -> String {
+    let ns = if latitude < 0.0 { 'S' } else { 'N' };
+    let ew = if longitude < 0.0 { 'W' } else { 'E' };
+    format!(
+        "{:.2}°{}, {:.2}°{}",
+        latitude.abs(),
+        ns,
+        longitude.abs(),
+        ew
+    )
+}
+
+pub struct AstronomyPlugin {
+    nasa_apikey: String,
+    n2yo_apikey: Option<String>,
+    client:      http::Client,
+}
+
+impl AstronomyPlugin {
+    async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+    ) -> Result<T> {
+        Ok(self
+            .client
+            .get(url)
+            .query(query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Finds the coordinates of a location saved through `\wset`.
+    async fn geocode(&self, location: &str) -> Result<Option<Place>> {
+        if location.starts_with("id:") {
+            return Err(anyhow!("OpenWeatherMap ids can't be geocoded"));
+        }
+        let mut query = vec![("format", "json"), ("limit", "1")];
+        // Weather locations made only of digits are US zip codes
+        if location.chars().all(|c| c.is_ascii_digit()) {
+            query.extend([("postalcode", location), ("country", "us")]);
+        } else {
+            query.push(("q", location));
+        }
+        let places: Vec<Place> = self.get(NOMINATIM_URL, &query).await?;
+        Ok(places.into_iter().next())
+    }
+
+    /// Describes the next visible ISS pass over the user's saved location,
+    /// if possible.
+    async fn next_pass(&self, ctx: &PluginContext, nick: &str) -> Result<Option<String>> {
+        let apikey = match &self.n2yo_apikey {
+            Some(apikey) => apikey,
+            None => return Ok(None),
+        };
+        let location = match ctx.prefs.get(&prefs::LOCATION, nick) {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        let place = match self.geocode(&location).await? {
+            Some(place) => place,
+            None => return Ok(Some(format!("Could not find {} on the map", location))),
+        };
+
+        let url = format!(
+            "{}/visualpasses/{}/{}/{}/0/{}/{}/",
+            N2YO_URL, ISS_NORAD_ID, place.lat, place.lon, PASS_DAYS, PASS_MIN_VISIBILITY
+        );
+        let passes: Passes = self.get(&url, &[("apiKey", apikey)]).await?;
+        let place_name = place.display_name.split(',').next().unwrap_or(&location);
+        let pass = match passes.passes.into_iter().next() {
+            Some(pass) => pass,
+            None => {
+                return Ok(Some(format!(
+                    "No visible passes over {} in the next {} days",
+                    place_name, PASS_DAYS
+                )))
+            },
+        };
+
+        let start = Utc.timestamp(pass.start_utc, 0);
+        let until = start - Utc::now();
+        Ok(Some(format!(
+            "Next visible pass over {}: {} (in {}h{:02}m), {} min long, up to {:.0}°, {} to {}",
+            place_name,
+            start.format("%Y-%m-%d %H:%M UTC"),
+            until.num_hours(),
+            until.num_minutes() % 60,
+            pass.duration / 60,
+            pass.max_el,
+            pass.start_az_compass,
+            pass.end_az_compass
+        )))
+    }
+
+    async fn iss(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        let url = format!("{}/{}", WHERETHEISS_URL, ISS_NORAD_ID);
+        let position: IssPosition = match self.get(&url, &[]).await {
+            Ok(position) => position,
+            Err(e) => {
+                warn!("[{}] Could not get the ISS position: {}", ctx.server, e);
+                let reply = format!("{}: Could not find the ISS, try again later", cmd.user.nick);
+                return ctx.reply(cmd, reply).await;
+            },
+        };
+        let mut reply = format!(
+            "The ISS is over {}, {:.0} km up, moving at {:.0} km/h ({})",
+            coordinates(position.latitude, position.longitude),
+            position.altitude,
+            position.velocity,
+            position.visibility
+        );
+        match self.next_pass(ctx, &cmd.user.nick).await {
+            Ok(Some(pass)) => reply += &format!(" 〜 {}", pass),
+            Ok(None) => {},
+            Err(e) => warn!(
+                "[{}] Could not get ISS passes for {}: {}",
+                ctx.server, cmd.user.nick, e
+            ),
+        }
+        ctx.reply(cmd, reply).await
+    }
+
+    async fn apod(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        let picture: Result<Picture> = self
+            .get(APOD_URL, &[("api_key", self.nasa_apikey.as_str())])
+            .await;
+        let reply = match picture {
+            Ok(picture) => {
+                // The picture's page, like apod.nasa.gov/apod/ap240501.html
+                let day: String = picture
+                    .date
+                    .chars()
+                    .filter(char::is_ascii_digit)
+                    .skip(2)
+                    .collect();
+                let link = format!("https://apod.nasa.gov/apod/ap{}.html", day);
+                let link = ctx.shortener.shorten(&cmd.reply_to, &link).await;
+                format!(
+                    "Astronomy Picture of the Day for {}: {} 〜 {}",
+                    picture.date, picture.title, link
+                )
+            },
+            Err(e) => {
+                warn!("[{}] Could not get the APOD: {}", ctx.server, e);
+                format!(
+                    "{}: Could not get today's picture, try again later",
+                    cmd.user.nick
+                )
+            },
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for AstronomyPlugin {
+    type Plugin = AstronomyPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[ISS, APOD];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "astronomy";
+
+    async fn new(ctx: &PluginContext) -> Result<AstronomyPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        Ok(AstronomyPlugin {
+            nasa_apikey: config
+                .get("nasa-apikey")
+                .cloned()
+                .unwrap_or_else(|| "DEMO_KEY".into()),
+            n2yo_apikey: config.get("n2yo-apikey").cloned(),
+            client:      ctx.http.client(),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for AstronomyPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        match cmd.name.as_str() {
+            "iss" => self.iss(ctx, cmd).await,
+            "apod" => self.apod(ctx, cmd).await,
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! Channel bans: `\ban` for good or for a while, `\unban` and `\bans`,
+//! getting ops from ChanServ where configured to. Bans placed for a while are
+//! lifted once they expire, also across restarts.
+
+use crate::command;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::reply::Reply;
+use crate::storage::DataSet;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use log::*;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const BAN: command::Help = command::Help {
+    name:  "ban",
+    usage: "ban <nick|mask> [duration] - bans from this channel, for a while if given a duration \
+            like 30m, 12h or 7d (admins only)",
+};
+const UNBAN: command::Help = command::Help {
+    name:  "unban",
+    usage: "unban <nick|mask> - lifts a ban from this channel (admins only)",
+};
+const BANS: command::Help = command::Help {
+    name:  "bans",
+    usage: "bans - lists the bans of this channel",
+};
+
+const DB_NAME: &str = "bans";
+pub const DATA_SET: DataSet = DataSet::new::<Expiries>(DB_NAME);
+/// How often to look for expired bans
+const EXPIRE_PERIOD: std::time::Duration = std::time::Duration::from_secs(60);
+/// Bans listed per line by `\bans`
+const BANS_PER_LINE: usize = 8;
+
+/// When each ban the bot placed for a while expires, by lowercase channel and
+/// mask.
+type Expiries = BTreeMap<String, BTreeMap<String, DateTime<Utc>>>;
+
+/// Parses a duration like `30m`, `12h`, `7d` or `2w`.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let unit = text.chars().last()?;
+    let amount: i64 = text[.. text.len() - unit.len_utf8()].parse().ok()?;
+    let duration = match unit {
+        'm' => Duration::minutes(amount),
+        'h' => Duration::hours(amount),
+        'd' => Duration::days(amount),
+        'w' => Duration::weeks(amount),
+        _ => return None,
+    };
+    Some(duration).filter(|d| *d > Duration::zero())
+}
+
+/// Describes a duration like `parse_duration` takes it, rounded down.
+fn describe_duration(duration: Duration) -> String {
+    if duration.num_days() > 0 {
+        format!("{}d", duration.num_days())
+    } else if duration.num_hours() > 0 {
+        format!("{}h", duration.num_hours())
+    } else {
+        format!("{}m", duration.num_minutes().max(1))
+    }
+}
+
+/// The mask to ban for a nick, or the mask itself.
+fn mask_for(target: &str) -> String {
+    if target.contains(['!', '@']) {
+        target.into()
+    } else {
+        format!("{}!*@*", target)
+    }
+}
+
+/// Sets or removes a ban, getting opped to do it if needed.
+async fn set_ban(ctx: &PluginContext, channel: &str, change: &str, mask: &str) -> Result<()> {
+    ctx.ops.acquire(channel).await?;
+    let result = ctx
+        .send(irc::Message::channel_mode(channel, change, &[mask]))
+        .await;
+    ctx.ops.release(channel);
+    result
+}
+
+/// Lifts the bans that expired.
+#[derive(Clone)]
+struct Expirer {
+    ctx:      PluginContext,
+    expiries: Arc<Mutex<Expiries>>,
+}
+
+impl Expirer {
+    async fn expire(self) {
+        let now = Utc::now();
+        let expired: Vec<(String, String)> = self
+            .expiries
+            .lock()
+            .await
+            .iter()
+            .flat_map(|(channel, masks)| {
+                masks
+                    .iter()
+                    .filter(|(_, until)| **until <= now)
+                    .map(move |(mask, _)| (channel.clone(), mask.clone()))
+            })
+            .collect();
+
+        let mut lifted = vec![];
+        for (channel, mask) in expired {
+            // Bans can only be lifted from inside the channel
+            if self.ctx.state.read().await.channel(&channel).is_none() {
+                continue;
+            }
+            match set_ban(&self.ctx, &channel, "-b", &mask).await {
+                Ok(()) => {
+                    info!(
+                        "[{}] Ban on {} in {} expired",
+                        self.ctx.server, mask, channel
+                    );
+                    lifted.push((channel, mask));
+                },
+                Err(e) => warn!(
+                    "[{}] Could not lift the ban on {} in {}: {}",
+                    self.ctx.server, mask, channel, e
+                ),
+            }
+        }
+        if lifted.is_empty() {
+            return;
+        }
+
+        let mut expiries = self.expiries.lock().await;
+        for (channel, mask) in lifted {
+            if let Some(masks) = expiries.get_mut(&channel) {
+                masks.remove(&mask);
+            }
+        }
+        expiries.retain(|_, masks| !masks.is_empty());
+        if let Err(e) = self.ctx.storage.save(DB_NAME, &*expiries).await {
+            warn!("[{}] Could not save ban expiries: {}", self.ctx.server, e);
+        }
+    }
+}
+
+pub struct BansPlugin {
+    expiries: Arc<Mutex<Expiries>>,
+}
+
+impl BansPlugin {
+    /// Remembers when the ban expires, or that it doesn't with `None`.
+    async fn set_expiry(
+        &self,
+        ctx: &PluginContext,
+        channel: &str,
+        mask: &str,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let mut expiries = self.expiries.lock().await;
+        let masks = expiries.entry(channel.to_lowercase()).or_default();
+        match until {
+            Some(until) => masks.insert(mask.to_lowercase(), until),
+            None => masks.remove(&mask.to_lowercase()),
+        };
+        expiries.retain(|_, masks| !masks.is_empty());
+        ctx.storage.save(DB_NAME, &*expiries).await
+    }
+
+    async fn list(&self, ctx: &PluginContext, channel: &str) -> Vec<String> {
+        let expiries = self.expiries.lock().await;
+        let expiries = expiries.get(&channel.to_lowercase());
+        let state = ctx.state.read().await;
+        let bans = match state.channel(channel) {
+            Some(channel) => &channel.bans,
+            None => return vec![],
+        };
+
+        let now = Utc::now();
+        let described: Vec<String> = bans
+            .iter()
+            .map(|ban| {
+                let mut details = vec![];
+                if let Some(by) = &ban.by {
+                    details.push(format!("by {}", by.split('!').next().unwrap_or(by)));
+                }
+                if let Some(at) = ban.at {
+                    details.push(format!("{} ago", describe_duration(now - at)));
+                }
+                let until = expiries.and_then(|e| e.get(&ban.mask.to_lowercase()));
+                if let Some(until) = until {
+                    details.push(format!("{} left", describe_duration(*until - now)));
+                }
+                if details.is_empty() {
+                    ban.mask.clone()
+                } else {
+                    format!("{} ({})", ban.mask, details.join(", "))
+                }
+            })
+            .collect();
+        described
+            .chunks(BANS_PER_LINE)
+            .map(|bans| bans.join(" 〜 "))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for BansPlugin {
+    type Plugin = BansPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[BAN, UNBAN, BANS];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "bans";
+
+    async fn new(ctx: &PluginContext) -> Result<BansPlugin> {
+        let expiries: Expiries = ctx.storage.load(DB_NAME).await?.unwrap_or_default();
+        let expiries = Arc::new(Mutex::new(expiries));
+
+        let expirer = Expirer {
+            ctx:      ctx.clone(),
+            expiries: expiries.clone(),
+        };
+        ctx.scheduler
+            .every(EXPIRE_PERIOD, move || expirer.clone().expire());
+
+        Ok(BansPlugin { expiries })
+    }
+}
+
+#[async_trait]
+impl Plugin for BansPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if ![BAN.name, UNBAN.name, BANS.name].contains(&cmd.name.as_str()) {
+            return Ok(());
+        }
+        let channel = cmd.reply_to.as_str();
+        if !irc::is_channel(channel) {
+            let reply = ctx.tr(cmd, "{0}: Only works in channels", &[&cmd.user.nick]);
+            return ctx.reply(cmd, reply).await;
+        }
+        if cmd.name == BANS.name {
+            let lines = self.list(ctx, channel).await;
+            if lines.is_empty() {
+                let reply = format!("{}: No bans here", cmd.user.nick);
+                return ctx.reply(cmd, reply).await;
+            }
+            return Reply::paginated(cmd, lines).send(ctx).await;
+        }
+        if !ctx.admins.allows(&cmd.user) {
+            info!("[{}] Denied {} to {}", ctx.server, cmd.name, cmd.user);
+            let reply = ctx.tr(cmd, "{0}: You're not allowed to do that", &[&cmd.user.nick]);
+            return ctx.reply(cmd, reply).await;
+        }
+
+        let args = cmd.parse_args();
+        let words: Vec<&str> = args.words().collect();
+        let (target, duration) = match (cmd.name == BAN.name, &words[..]) {
+            (_, [target]) => (*target, None),
+            (true, [target, duration]) => match parse_duration(duration) {
+                Some(duration) => (*target, Some(duration)),
+                None => {
+                    let reply = format!("{}: Invalid duration {}", cmd.user.nick, duration);
+                    return ctx.reply(cmd, reply).await;
+                },
+            },
+            _ => {
+                let usage = if cmd.name == BAN.name { BAN } else { UNBAN }.usage;
+                let reply = ctx.usage(cmd, usage);
+                return ctx.reply(cmd, reply).await;
+            },
+        };
+        let mask = mask_for(target);
+
+        let change = if cmd.name == BAN.name { "+b" } else { "-b" };
+        if let Err(e) = set_ban(ctx, channel, change, &mask).await {
+            warn!("[{}] Could not {} {}: {}", ctx.server, cmd.name, mask, e);
+            let reply = format!("{}: Could not {} {}: {}", cmd.user.nick, cmd.name, mask, e);
+            return ctx.reply(cmd, reply).await;
+        }
+        let until = duration.map(|duration| Utc::now() + duration);
+        self.set_expiry(ctx, channel, &mask, until).await?;
+        info!(
+            "[{}] {} {}ned {} in {}",
+            ctx.server, cmd.user, cmd.name, mask, channel
+        );
+
+        let reply = match duration {
+            Some(duration) => format!(
+                "{}: Banned {} for {}",
+                cmd.user.nick,
+                mask,
+                describe_duration(duration)
+            ),
+            None if cmd.name == BAN.name => format!("{}: Banned {}", cmd.user.nick, mask),
+            None => format!("{}: Unbanned {}", cmd.user.nick, mask),
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
@@ -0,0 +1,414 @@
+//! Channel statistics: who talks the most, at what hours and about what,
+//! tallied by day and summarized with `\chanstats`. What was said while the
+//! bot was away counts too, on servers that replay channel history.
+//!
+//! Configuration:
+//! - `keep-days`: how many days of stats are kept, 30 by default
+//! - `report-dir`: a directory to write an HTML report of each channel to, like
+//!   `reports/libera-boton.html`, refreshed whenever stats are saved
+
+use crate::chat::ChatEvent;
+use crate::command;
+use crate::irc;
+use crate::links;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::storage::DataSet;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDate, Timelike, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const CHANSTATS: command::Help = command::Help {
+    name:  "chanstats",
+    usage: "chanstats [#channel] [days] - who talks the most, when and about what over the last \
+            few days, 7 by default",
+};
+
+const DB_NAME: &str = "chanstats";
+pub const DATA_SET: DataSet = DataSet::new::<Stats>(DB_NAME);
+const DEFAULT_KEEP_DAYS: i64 = 30;
+const DEFAULT_DAYS: i64 = 7;
+/// How often stats are saved, and reports written, besides on disconnecting
+const SAVE_PERIOD: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// Entries in each ranking of `\chanstats`
+const SUMMARY_TOP: usize = 5;
+/// Entries in each ranking of the reports
+const REPORT_TOP: usize = 25;
+/// Words kept for each past day, the rarest being dropped
+const MAX_WORDS_PER_DAY: usize = 500;
+/// Shortest word counted, in characters
+const MIN_WORD: usize = 4;
+/// Words too common to say anything about a channel
+const STOP_WORDS: &[&str] = &[
+    "about", "also", "been", "could", "does", "dont", "from", "have", "just", "know", "like",
+    "more", "only", "really", "should", "some", "than", "that", "them", "then", "there", "they",
+    "thing", "think", "this", "want", "were", "what", "when", "where", "which", "will", "with",
+    "would", "yeah", "your",
+];
+/// Looks of the reports
+const REPORT_STYLE: &str = "body { font-family: sans-serif; max-width: 50em; margin: auto; }
+td { padding: 0 0.5em; }
+.bar { background: #69c; height: 1em; }
+";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Day {
+    /// Messages by user key, as linked across nick changes
+    messages: BTreeMap<String, u64>,
+    /// Messages by hour, in UTC
+    hours:    [u64; 24],
+    /// Uses of each lowercase word
+    words:    BTreeMap<String, u64>,
+}
+
+impl Day {
+    fn add(&mut self, other: &Day) {
+        for (nick, count) in &other.messages {
+            *self.messages.entry(nick.clone()).or_default() += count;
+        }
+        for (hour, count) in other.hours.iter().enumerate() {
+            self.hours[hour] += count;
+        }
+        for (word, count) in &other.words {
+            *self.words.entry(word.clone()).or_default() += count;
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.messages.values().sum()
+    }
+}
+
+/// Stats by lowercase channel and day, in UTC.
+type Stats = BTreeMap<String, BTreeMap<NaiveDate, Day>>;
+
+/// The words of a message worth counting, lowercase.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split_whitespace()
+        .filter(|word| !word.contains("://"))
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|word| word.chars().count() >= MIN_WORD && word.chars().all(char::is_alphabetic))
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+}
+
+/// The highest counts, most first, users by name.
+fn top(counts: &BTreeMap<String, u64>, n: usize) -> Vec<(&str, u64)> {
+    let mut top: Vec<(&str, u64)> = counts.iter().map(|(k, v)| (links::name(k), *v)).collect();
+    top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    top.truncate(n);
+    top
+}
+
+/// The busiest hours, most first.
+fn busiest(hours: &[u64; 24], n: usize) -> Vec<usize> {
+    let mut busiest: Vec<usize> = (0 .. 24).filter(|hour| hours[*hour] > 0).collect();
+    busiest.sort_by(|a, b| hours[*b].cmp(&hours[*a]).then(a.cmp(b)));
+    busiest.truncate(n);
+    busiest
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn html_table(rows: &[(&str, u64)]) -> String {
+    let mut html = String::from("<table>\n");
+    for (name, count) in rows {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td></tr>\n",
+            escape(name),
+            count
+        ));
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+/// A standalone HTML page showing the channel's stats.
+fn report(server: &str, channel: &str, since: NaiveDate, totals: &Day) -> String {
+    let title = escape(&format!("{} on {}", channel, server));
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>{}</title>\n", title));
+    html.push_str(&format!("<style>\n{}</style>\n", REPORT_STYLE));
+    html.push_str(&format!("</head>\n<body>\n<h1>{}</h1>\n", title));
+    html.push_str(&format!(
+        "<p>{} messages since {}, as of {}</p>\n",
+        totals.total(),
+        since,
+        Utc::now().format("%Y-%m-%d %H:%M UTC")
+    ));
+    html.push_str("<h2>Most active</h2>\n");
+    html.push_str(&html_table(&top(&totals.messages, REPORT_TOP)));
+
+    html.push_str("<h2>Messages by hour (UTC)</h2>\n<table>\n");
+    let most = totals.hours.iter().max().map_or(1, |most| *most.max(&1));
+    for (hour, count) in totals.hours.iter().enumerate() {
+        let width = *count as f64 / most as f64 * 30.0;
+        html.push_str(&format!("<tr><td>{:02}h</td>", hour));
+        html.push_str(&format!(
+            "<td><div class=\"bar\" style=\"width: {:.1}em\"></div></td>",
+            width
+        ));
+        html.push_str(&format!("<td>{}</td></tr>\n", count));
+    }
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Top words</h2>\n");
+    html.push_str(&html_table(&top(&totals.words, REPORT_TOP)));
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Prunes and saves the stats, and writes the reports.
+#[derive(Clone)]
+struct Saver {
+    ctx:        PluginContext,
+    stats:      Arc<Mutex<Stats>>,
+    keep_days:  i64,
+    report_dir: Option<PathBuf>,
+}
+
+impl Saver {
+    async fn save(self) {
+        let today = Utc::today().naive_utc();
+        let oldest = today - Duration::days(self.keep_days - 1);
+        let mut stats = self.stats.lock().await;
+        for days in stats.values_mut() {
+            days.retain(|date, _| *date >= oldest);
+            for (_, day) in days.range_mut(.. today) {
+                if day.words.len() > MAX_WORDS_PER_DAY {
+                    let kept: Vec<String> = top(&day.words, MAX_WORDS_PER_DAY)
+                        .into_iter()
+                        .map(|(word, _)| word.to_string())
+                        .collect();
+                    day.words.retain(|word, _| kept.contains(word));
+                }
+            }
+        }
+        stats.retain(|_, days| !days.is_empty());
+        if let Err(e) = self.ctx.storage.save(DB_NAME, &*stats).await {
+            warn!("[{}] Could not save channel stats: {}", self.ctx.server, e);
+        }
+
+        let dir = match &self.report_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        let reports: Vec<(String, String)> = stats
+            .iter()
+            .filter_map(|(channel, days)| {
+                let since = *days.keys().next()?;
+                let mut totals = Day::default();
+                days.values().for_each(|day| totals.add(day));
+                let name = format!("{}-{}", self.ctx.server, channel.trim_start_matches('#'))
+                    .replace(|c: char| !c.is_alphanumeric() && !"-_.".contains(c), "_");
+                let html = report(&self.ctx.server, channel, since, &totals);
+                Some((format!("{}.html", name), html))
+            })
+            .collect();
+        drop(stats);
+
+        if let Err(e) = tokio::fs::create_dir_all(dir).await {
+            warn!(
+                "[{}] Could not create {}: {}",
+                self.ctx.server,
+                dir.display(),
+                e
+            );
+            return;
+        }
+        for (name, html) in reports {
+            let path = dir.join(name);
+            if let Err(e) = tokio::fs::write(&path, html).await {
+                warn!(
+                    "[{}] Could not write {}: {}",
+                    self.ctx.server,
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+pub struct ChanstatsPlugin {
+    stats:     Arc<Mutex<Stats>>,
+    keep_days: i64,
+    saver:     Saver,
+}
+
+impl ChanstatsPlugin {
+    /// Summarizes the channel's last few days in a line.
+    async fn summary(&self, channel: &str, days: i64) -> Option<String> {
+        let oldest = Utc::today().naive_utc() - Duration::days(days - 1);
+        let mut totals = Day::default();
+        let stats = self.stats.lock().await;
+        for (_, day) in stats.get(&channel.to_lowercase())?.range(oldest ..) {
+            totals.add(day);
+        }
+        if totals.total() == 0 {
+            return None;
+        }
+
+        let ranking = |counts: &BTreeMap<String, u64>| {
+            let top: Vec<String> = top(counts, SUMMARY_TOP)
+                .iter()
+                .map(|(name, count)| format!("{} {}", name, count))
+                .collect();
+            top.join(", ")
+        };
+        let hours: Vec<String> = busiest(&totals.hours, 3)
+            .iter()
+            .map(|hour| format!("{}h", hour))
+            .collect();
+        let mut parts = vec![
+            format!(
+                "{} over {} days: {} messages",
+                channel,
+                days,
+                totals.total()
+            ),
+            format!("Most active: {}", ranking(&totals.messages)),
+            format!("Busiest hours: {} UTC", hours.join(", ")),
+        ];
+        if !totals.words.is_empty() {
+            parts.push(format!("Top words: {}", ranking(&totals.words)));
+        }
+        Some(parts.join(" 〜 "))
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for ChanstatsPlugin {
+    type Plugin = ChanstatsPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[CHANSTATS];
+    const HISTORY: bool = true;
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "chanstats";
+
+    async fn new(ctx: &PluginContext) -> Result<ChanstatsPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let keep_days = match config.get("keep-days") {
+            Some(days) => days
+                .parse()
+                .ok()
+                .filter(|days| *days > 0)
+                .ok_or_else(|| anyhow!("[Chanstats] Invalid keep-days: {}", days))?,
+            None => DEFAULT_KEEP_DAYS,
+        };
+        let stats: Stats = ctx.storage.load(DB_NAME).await?.unwrap_or_default();
+        let stats = Arc::new(Mutex::new(stats));
+        // Words aren't kept by who said them, so only the counts go
+        ctx.storage
+            .own(DB_NAME, stats.clone(), |stats: &mut Stats, nick| {
+                let mut found = false;
+                for day in stats.values_mut().flat_map(|days| days.values_mut()) {
+                    found |= day.messages.remove(nick).is_some();
+                }
+                found
+            });
+
+        let saver = Saver {
+            ctx: ctx.clone(),
+            stats: stats.clone(),
+            keep_days,
+            report_dir: config.get("report-dir").map(PathBuf::from),
+        };
+        let periodic = saver.clone();
+        ctx.scheduler
+            .every(SAVE_PERIOD, move || periodic.clone().save());
+
+        Ok(ChanstatsPlugin {
+            stats,
+            keep_days,
+            saver,
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for ChanstatsPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != CHANSTATS.name {
+            return Ok(());
+        }
+        let nick = &cmd.user.nick;
+        let mut channel = Some(cmd.reply_to.as_str()).filter(|c| irc::is_channel(c));
+        let mut days = DEFAULT_DAYS.min(self.keep_days);
+        let args = cmd.parse_args();
+        for word in args.words() {
+            if irc::is_channel(word) {
+                channel = Some(word);
+            } else {
+                match word.parse() {
+                    Ok(n) if (1 ..= self.keep_days).contains(&n) => days = n,
+                    _ => {
+                        let reply = format!(
+                            "{}: Stats go back between 1 and {} days",
+                            nick, self.keep_days
+                        );
+                        return ctx.reply(cmd, reply).await;
+                    },
+                }
+            }
+        }
+        let channel = match channel {
+            Some(channel) => channel,
+            None => {
+                let reply = ctx.usage(cmd, CHANSTATS.usage);
+                return ctx.reply(cmd, reply).await;
+            },
+        };
+
+        let reply = match self.summary(channel, days).await {
+            Some(summary) => summary,
+            None => format!("{}: No stats for {} yet", nick, channel),
+        };
+        ctx.reply(cmd, reply).await
+    }
+
+    /// Saves what was counted since the last periodic save, which would
+    /// otherwise be lost with each reconnection.
+    async fn disconnected(&self, _ctx: &PluginContext) -> Result<()> {
+        self.saver.clone().save().await;
+        Ok(())
+    }
+
+    async fn chat(&self, ctx: &PluginContext, event: &ChatEvent) -> Result<()> {
+        let (nick, channel, text) = match event.channel() {
+            Some(channel) if !event.is_ctcp => (&event.sender.nick, channel, &event.body),
+            _ => return Ok(()),
+        };
+        let key = {
+            let state = ctx.state.read().await;
+            if state.is_me(nick) {
+                return Ok(());
+            }
+            state.links.key(nick)
+        };
+
+        let at = event.time;
+        let mut stats = self.stats.lock().await;
+        let day = stats
+            .entry(channel.to_lowercase())
+            .or_default()
+            .entry(at.date().naive_utc())
+            .or_default();
+        *day.messages.entry(key).or_default() += 1;
+        day.hours[at.hour() as usize] += 1;
+        for word in words(text) {
+            *day.words.entry(word).or_default() += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+//! CVE lookups through the NVD API.
+//!
+//! An optional `api-key` from <https://nvd.nist.gov/developers/request-an-api-key>
+//! raises the API rate limits.
+
+use crate::command;
+use crate::http;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::reply::truncate;
+use anyhow::Result;
+use async_trait::async_trait;
+use log::*;
+use serde::Deserialize;
+use std::time::Duration;
+
+const CVE: command::Help = command::Help {
+    name:  "cve",
+    usage: "cve <CVE-YYYY-NNNN> - shows a vulnerability's severity, summary and affected products",
+};
+
+const API_URL: &str = "https://services.nvd.nist.gov/rest/json/cves/2.0";
+/// How long lookups are answered from the cache, CVEs rarely change
+const CACHE_FOR: Duration = Duration::from_secs(24 * 60 * 60);
+/// Longest description shown, in characters
+const MAX_DESCRIPTION: usize = 250;
+/// How many affected products to list
+const MAX_PRODUCTS: usize = 4;
+
+#[derive(Deserialize)]
+struct Results {
+    vulnerabilities: Vec<Vulnerability>,
+}
+
+#[derive(Deserialize)]
+struct Vulnerability {
+    cve: Cve,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Cve {
+    id:             String,
+    #[serde(default)]
+    vuln_status:    String,
+    #[serde(default)]
+    descriptions:   Vec<Description>,
+    #[serde(default)]
+    metrics:        Metrics,
+    #[serde(default)]
+    configurations: Vec<Configuration>,
+}
+
+#[derive(Deserialize)]
+struct Description {
+    lang:  String,
+    value: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct Metrics {
+    cvss_metric_v31: Vec<Metric>,
+    cvss_metric_v30: Vec<Metric>,
+    cvss_metric_v2:  Vec<Metric>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Metric {
+    cvss_data:     CvssData,
+    /// Only present here for CVSS v2
+    base_severity: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CvssData {
+    version:       String,
+    base_score:    f32,
+    base_severity: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Configuration {
+    #[serde(default)]
+    nodes: Vec<Node>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Node {
+    #[serde(default)]
+    cpe_match: Vec<CpeMatch>,
+}
+
+#[derive(Deserialize)]
+struct CpeMatch {
+    vulnerable: bool,
+    criteria:   String,
+}
+
+/// Normalizes `cve-2024-1234` into `CVE-2024-1234`, rejecting anything else.
+fn parse_id(id: &str) -> Option<String> {
+    let id = id.to_uppercase();
+    let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    match id.split('-').collect::<Vec<_>>()[..] {
+        ["CVE", year, number]
+            if year.len() == 4 && digits(year) && number.len() >= 4 && digits(number) =>
+        {
+            Some(id)
+        },
+        _ => None,
+    }
+}
+
+impl Cve {
+    /// The most recent CVSS version's score and severity.
+    fn severity(&self) -> Option<String> {
+        let metric = self
+            .metrics
+            .cvss_metric_v31
+            .first()
+            .or_else(|| self.metrics.cvss_metric_v30.first())
+            .or_else(|| self.metrics.cvss_metric_v2.first())?;
+        let data = &metric.cvss_data;
+        let severity = data
+            .base_severity
+            .as_ref()
+            .or(metric.base_severity.as_ref());
+        Some(match severity {
+            Some(severity) => format!(
+                "{} {:.1} (CVSS {})",
+                severity, data.base_score, data.version
+            ),
+            None => format!("{:.1} (CVSS {})", data.base_score, data.version),
+        })
+    }
+
+    /// Vulnerable products, as `vendor product` from their CPE names.
+    fn products(&self) -> Vec<String> {
+        let mut products: Vec<String> = vec![];
+        let matches = self
+            .configurations
+            .iter()
+            .flat_map(|c| &c.nodes)
+            .flat_map(|n| &n.cpe_match)
+            .filter(|m| m.vulnerable);
+        for cpe in matches {
+            // cpe:2.3:<part>:<vendor>:<product>:<version>:...
+            let fields: Vec<&str> = cpe.criteria.split(':').collect();
+            if let (Some(vendor), Some(product)) = (fields.get(3), fields.get(4)) {
+                let name = format!("{} {}", vendor, product).replace('_', " ");
+                if !products.contains(&name) {
+                    products.push(name);
+                }
+            }
+        }
+        products
+    }
+
+    fn describe(&self) -> String {
+        let mut line = self.id.clone();
+        if self.vuln_status == "Rejected" {
+            line += " 〜 Rejected";
+        }
+        if let Some(severity) = self.severity() {
+            line += &format!(" 〜 {}", severity);
+        }
+        let description = self
+            .descriptions
+            .iter()
+            .find(|d| d.lang == "en")
+            .or_else(|| self.descriptions.first());
+        if let Some(description) = description {
+            line += &format!(" 〜 {}", truncate(&description.value, MAX_DESCRIPTION));
+        }
+        let products = self.products();
+        if !products.is_empty() {
+            let mut affected = products[.. products.len().min(MAX_PRODUCTS)].join(", ");
+            if products.len() > MAX_PRODUCTS {
+                affected += &format!(" and {} more", products.len() - MAX_PRODUCTS);
+            }
+            line += &format!(" 〜 Affects: {}", affected);
+        }
+        line
+    }
+}
+
+pub struct CvePlugin {
+    api_key: Option<String>,
+    client:  http::Client,
+}
+
+impl CvePlugin {
+    async fn lookup(&self, id: &str) -> Result<Option<Cve>> {
+        let mut request = self
+            .client
+            .get(API_URL)
+            .query(&[("cveId", id)])
+            .cache(CACHE_FOR);
+        if let Some(api_key) = &self.api_key {
+            request = request.header("apiKey", api_key);
+        }
+        let results: Results = request.send().await?.error_for_status()?.json().await?;
+        Ok(results.vulnerabilities.into_iter().next().map(|v| v.cve))
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for CvePlugin {
+    type Plugin = CvePlugin;
+
+    const COMMANDS: &'static [command::Help] = &[CVE];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "cve";
+
+    async fn new(ctx: &PluginContext) -> Result<CvePlugin> {
+        Ok(CvePlugin {
+            api_key: ctx.config.as_ref().and_then(|c| c.get("api-key")).cloned(),
+            client:  ctx.http.client(),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for CvePlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != CVE.name {
+            return Ok(());
+        }
+        let id = match cmd.args.as_deref().and_then(parse_id) {
+            Some(id) => id,
+            None => {
+                let usage = ctx.usage(cmd, CVE.usage);
+                return ctx.reply(cmd, usage).await;
+            },
+        };
+
+        let reply = match self.lookup(&id).await {
+            Ok(Some(cve)) => {
+                let link = format!("https://nvd.nist.gov/vuln/detail/{}", cve.id);
+                let link = ctx.shortener.shorten(&cmd.reply_to, &link).await;
+                format!("{} 〜 {}", cve.describe(), link)
+            },
+            Ok(None) => format!("{}: No such CVE `{}`", cmd.user.nick, id),
+            Err(e) => {
+                warn!("[{}] CVE lookup for {} failed: {}", ctx.server, id, e);
+                ctx.tr(
+                    cmd,
+                    "{0}: Lookup failed, try again later",
+                    &[&cmd.user.nick],
+                )
+            },
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
@@ -0,0 +1,325 @@
+//! Reachability and latency checks timing TCP connections, since ICMP needs
+//! privileges the bot shouldn't have, along with the bot's own latency:
+//! `\ping` on its own times a PING going out through the queue replies wait
+//! in, and `\lag` tells the lag to the server.
+//!
+//! Only hosts matching the allow-list can be checked, and never private or
+//! loopback addresses, so the bot can't be used as a port scanner.
+//!
+//! Configuration:
+//! - `hosts`: space separated host patterns with `*` and `?` wildcards; nothing
+//!   is allowed by default
+//! - `ports`: space separated ports allowed for `\port`, `80 443` by default
+//! - `ping-port`: the port `\ping` connects to, 443 by default
+
+use crate::command;
+use crate::irc;
+use crate::metrics;
+use crate::net::{self, IpVersion};
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use log::*;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+const PING: command::Help = command::Help {
+    name:  "ping",
+    usage: "ping [host] - checks whether a host is reachable and how long connecting takes, or \
+            without one, how long the bot takes to get a PING to the server and back",
+};
+const PORT: command::Help = command::Help {
+    name:  "port",
+    usage: "port <host> <port> - checks whether a port is open on a host",
+};
+const LAG: command::Help = command::Help {
+    name:  "lag",
+    usage: "lag - tells the lag to the server, as last measured",
+};
+
+const DEFAULT_PORTS: &[u16] = &[80, 443];
+const DEFAULT_PING_PORT: u16 = 443;
+/// How long to wait for the connection before calling the host unreachable
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for the PONG of `\ping` before calling it lost
+const PONG_TIMEOUT: Duration = Duration::from_secs(30);
+const TOKEN_PREFIX: &str = "boton-ping-";
+
+/// How a connection attempt went.
+enum Probe {
+    Open(Duration),
+    /// The host answered, refusing the connection
+    Refused(Duration),
+    TimedOut,
+    Failed(std::io::Error),
+}
+
+/// Whether the address is on the public internet, as far as we can tell.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast())
+        },
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            let unique_local = first & 0xfe00 == 0xfc00;
+            let link_local = first & 0xffc0 == 0xfe80;
+            match ip.to_ipv4_mapped() {
+                Some(ip) => is_public(IpAddr::V4(ip)),
+                None => {
+                    !(ip.is_loopback()
+                        || ip.is_unspecified()
+                        || ip.is_multicast()
+                        || unique_local
+                        || link_local)
+                },
+            }
+        },
+    }
+}
+
+fn parse_ports(ports: &str) -> Result<Vec<u16>> {
+    ports
+        .split_whitespace()
+        .map(|port| {
+            port.parse()
+                .map_err(|_| anyhow!("[Diagnostics] Invalid port: {}", port))
+        })
+        .collect()
+}
+
+async fn probe(addr: SocketAddr) -> Probe {
+    let start = Instant::now();
+    match timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => Probe::Open(start.elapsed()),
+        Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => Probe::Refused(start.elapsed()),
+        Ok(Err(e)) => Probe::Failed(e),
+        Err(_) => Probe::TimedOut,
+    }
+}
+
+pub struct DiagnosticsPlugin {
+    hosts:     Vec<String>,
+    ports:     Vec<u16>,
+    ping_port: u16,
+    /// PINGs of `\ping` waiting for their PONG, by token
+    pongs:     Mutex<HashMap<String, oneshot::Sender<()>>>,
+    pinged:    AtomicU64,
+}
+
+impl DiagnosticsPlugin {
+    /// Times a PING sent at the priority of replies, waiting behind whatever
+    /// replies are queued, until the server answers it.
+    async fn ping_server(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        let token = format!(
+            "{}{}",
+            TOKEN_PREFIX,
+            self.pinged.fetch_add(1, Ordering::Relaxed)
+        );
+        let (tx, rx) = oneshot::channel();
+        self.pongs.lock().unwrap().insert(token.clone(), tx);
+
+        // How long the command took to get here, by the server's clock when
+        // it tells the time
+        let received = (Utc::now() - cmd.chat.time).num_milliseconds().max(0);
+        let start = Instant::now();
+        ctx.send_with(irc::Message::ping(token.as_str()), irc::Priority::Reply)
+            .await?;
+        let answered = timeout(PONG_TIMEOUT, rx).await;
+        self.pongs.lock().unwrap().remove(&token);
+
+        let reply = match answered {
+            Ok(Ok(())) => format!(
+                "{}: Pong 〜 {} ms to the server and back 〜 {} ms getting the command",
+                cmd.user.nick,
+                start.elapsed().as_millis(),
+                received
+            ),
+            _ => {
+                metrics::increment(metrics::LOST_PINGS, &ctx.server);
+                format!(
+                    "{}: The server did not answer within {}s",
+                    cmd.user.nick,
+                    PONG_TIMEOUT.as_secs()
+                )
+            },
+        };
+        ctx.reply(cmd, reply).await
+    }
+
+    fn allowed(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.hosts.iter().any(|pattern| irc::glob(pattern, &host))
+    }
+
+    /// Resolves the host to the first address worth connecting to.
+    async fn address(&self, host: &str, port: u16) -> Result<SocketAddr> {
+        let addrs = net::resolve(host, port, IpVersion::Any).await?;
+        addrs
+            .into_iter()
+            .find(|addr| is_public(addr.ip()))
+            .ok_or_else(|| anyhow!("{} has no public addresses", host))
+    }
+
+    async fn check(
+        &self,
+        ctx: &PluginContext,
+        cmd: &command::Invocation,
+        host: &str,
+        port: u16,
+    ) -> Result<()> {
+        if !self.allowed(host) {
+            let reply = format!("{}: {} isn't on the allow-list", cmd.user.nick, host);
+            return ctx.reply(cmd, reply).await;
+        }
+        let addr = match self.address(host, port).await {
+            Ok(addr) => addr,
+            Err(e) => {
+                debug!("[{}] Could not resolve {}: {}", ctx.server, host, e);
+                let reply = format!("{}: Could not resolve {}", cmd.user.nick, host);
+                return ctx.reply(cmd, reply).await;
+            },
+        };
+
+        let target = format!("{} ({})", host, addr.ip());
+        let ping = cmd.name == PING.name;
+        let reply = match probe(addr).await {
+            Probe::Open(time) if ping => format!(
+                "{} is reachable, {} ms (TCP {})",
+                target,
+                time.as_millis(),
+                port
+            ),
+            Probe::Open(time) => {
+                format!("{} port {} is open, {} ms", target, port, time.as_millis())
+            },
+            Probe::Refused(time) if ping => format!(
+                "{} is reachable, {} ms (TCP {} refused)",
+                target,
+                time.as_millis(),
+                port
+            ),
+            Probe::Refused(_) => format!("{} port {} is closed", target, port),
+            Probe::TimedOut => format!(
+                "{} did not answer within {}s (TCP {})",
+                target,
+                CONNECT_TIMEOUT.as_secs(),
+                port
+            ),
+            Probe::Failed(e) => format!("{} is unreachable (TCP {}): {}", target, port, e),
+        };
+        ctx.reply(cmd, format!("{}: {}", cmd.user.nick, reply))
+            .await
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for DiagnosticsPlugin {
+    type Plugin = DiagnosticsPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[PING, PORT, LAG];
+    const MESSAGES: Subscription = Subscription::Commands(&["PONG"]);
+    const NAME: &'static str = "diagnostics";
+
+    async fn new(ctx: &PluginContext) -> Result<DiagnosticsPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let hosts: Vec<String> = config
+            .get("hosts")
+            .map(|hosts| hosts.split_whitespace().map(str::to_lowercase).collect())
+            .unwrap_or_default();
+        if hosts.is_empty() {
+            info!(
+                "[{}] \\ping <host> and \\port need `hosts` in the diagnostics config section",
+                ctx.server
+            );
+        }
+        let ports = match config.get("ports") {
+            Some(ports) => parse_ports(ports)?,
+            None => DEFAULT_PORTS.to_vec(),
+        };
+        let ping_port = match config.get("ping-port") {
+            Some(port) => port
+                .parse()
+                .map_err(|_| anyhow!("[Diagnostics] Invalid ping-port: {}", port))?,
+            None => DEFAULT_PING_PORT,
+        };
+        Ok(DiagnosticsPlugin {
+            hosts,
+            ports,
+            ping_port,
+            pongs: Mutex::default(),
+            pinged: AtomicU64::new(1),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for DiagnosticsPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        let help = match cmd.name.as_str() {
+            "ping" => PING,
+            "port" => PORT,
+            "lag" => LAG,
+            _ => return Ok(()),
+        };
+        let args = cmd.parse_args();
+        let words: Vec<&str> = args.words().collect();
+        let (host, port) = match (help.name, &words[..]) {
+            ("ping", []) => return self.ping_server(ctx, cmd).await,
+            ("lag", _) => {
+                let reply = match ctx.state.read().await.lag.current() {
+                    Some(lag) => format!("Lag to {} is {} ms", ctx.server, lag.as_millis()),
+                    None => format!("Lag to {} hasn't been measured yet", ctx.server),
+                };
+                return ctx
+                    .reply(cmd, format!("{}: {}", cmd.user.nick, reply))
+                    .await;
+            },
+            ("ping", [host]) => (*host, Some(self.ping_port)),
+            ("port", [host, port]) => (*host, port.parse().ok()),
+            _ => {
+                let usage = ctx.usage(cmd, help.usage);
+                return ctx.reply(cmd, usage).await;
+            },
+        };
+        match port {
+            Some(port) if help.name == PING.name || self.ports.contains(&port) => {
+                self.check(ctx, cmd, host, port).await
+            },
+            _ => {
+                let allowed: Vec<String> = self.ports.iter().map(u16::to_string).collect();
+                let reply = format!(
+                    "{}: Only these ports can be checked: {}",
+                    cmd.user.nick,
+                    allowed.join(", ")
+                );
+                ctx.reply(cmd, reply).await
+            },
+        }
+    }
+
+    async fn message(&self, _ctx: &PluginContext, msg: &irc::Message) -> Result<()> {
+        // :server PONG server :token
+        let token = match msg.parameters.first() {
+            Some(token) if token.starts_with(TOKEN_PREFIX) => token,
+            _ => return Ok(()),
+        };
+        if let Some(pong) = self.pongs.lock().unwrap().remove(token) {
+            let _ = pong.send(());
+        }
+        Ok(())
+    }
+}
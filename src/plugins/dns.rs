@@ -0,0 +1,319 @@
+//! DNS lookups through the system resolver, and domain registration lookups
+//! through RDAP.
+//!
+//! Configuration:
+//! - `rdap`: base URL of the RDAP service, `https://rdap.org` by default
+//! - `lookups-per-minute`: lookups allowed across all users, 10 by default
+
+use crate::command;
+use crate::http;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::reply::truncate;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::proto::rr::RecordType;
+use hickory_resolver::TokioAsyncResolver;
+use log::*;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const DNS: command::Help = command::Help {
+    name:  "dns",
+    usage: "dns <name> [A|AAAA|MX|TXT] - shows a name's DNS records, A and AAAA by default",
+};
+const WHOIS_DOMAIN: command::Help = command::Help {
+    name:  "whois-domain",
+    usage: "whois-domain <domain> - shows a domain's registrar, dates and nameservers",
+};
+
+const DEFAULT_RDAP: &str = "https://rdap.org";
+const DEFAULT_LOOKUPS_PER_MINUTE: usize = 10;
+/// Longest reply, in characters
+const MAX_REPLY: usize = 350;
+/// How many nameservers to list
+const MAX_NAMESERVERS: usize = 4;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Domain {
+    ldh_name:    String,
+    #[serde(default)]
+    status:      Vec<String>,
+    #[serde(default)]
+    events:      Vec<Event>,
+    #[serde(default)]
+    entities:    Vec<Entity>,
+    #[serde(default)]
+    nameservers: Vec<Nameserver>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Event {
+    event_action: String,
+    event_date:   String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entity {
+    #[serde(default)]
+    roles:       Vec<String>,
+    /// jCard, as `["vcard", [[name, params, type, value], ...]]`
+    #[serde(default)]
+    vcard_array: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Nameserver {
+    ldh_name: String,
+}
+
+impl Entity {
+    /// The entity's formatted name from its jCard.
+    fn name(&self) -> Option<&str> {
+        self.vcard_array
+            .get(1)?
+            .as_array()?
+            .iter()
+            .find(|property| property.get(0).and_then(Value::as_str) == Some("fn"))?
+            .get(3)?
+            .as_str()
+    }
+}
+
+impl Domain {
+    fn event(&self, action: &str) -> Option<&str> {
+        let event = self.events.iter().find(|e| e.event_action == action)?;
+        // Only the date part of the timestamp
+        event.event_date.get(.. 10)
+    }
+
+    fn describe(&self) -> String {
+        let mut line = self.ldh_name.to_lowercase();
+        let registrar = self
+            .entities
+            .iter()
+            .find(|e| e.roles.iter().any(|r| r == "registrar"))
+            .and_then(Entity::name);
+        if let Some(registrar) = registrar {
+            line += &format!(" 〜 Registrar: {}", registrar);
+        }
+        if let Some(date) = self.event("registration") {
+            line += &format!(" 〜 Registered {}", date);
+        }
+        if let Some(date) = self.event("expiration") {
+            line += &format!(" 〜 Expires {}", date);
+        }
+        if !self.status.is_empty() {
+            line += &format!(" 〜 Status: {}", self.status.join(", "));
+        }
+        if !self.nameservers.is_empty() {
+            let nameservers: Vec<String> = self
+                .nameservers
+                .iter()
+                .take(MAX_NAMESERVERS)
+                .map(|ns| ns.ldh_name.to_lowercase())
+                .collect();
+            line += &format!(" 〜 NS: {}", nameservers.join(", "));
+        }
+        line
+    }
+}
+
+/// Allows a fixed number of lookups per minute, shared by all users, so the
+/// bot can't be used to hammer resolvers or RDAP servers.
+struct RateLimit {
+    per_minute: usize,
+    recent:     Mutex<VecDeque<Instant>>,
+}
+
+impl RateLimit {
+    async fn allow(&self) -> bool {
+        let mut recent = self.recent.lock().await;
+        let now = Instant::now();
+        while recent
+            .front()
+            .is_some_and(|t| now - *t > Duration::from_secs(60))
+        {
+            recent.pop_front();
+        }
+        if recent.len() >= self.per_minute {
+            return false;
+        }
+        recent.push_back(now);
+        true
+    }
+}
+
+pub struct DnsPlugin {
+    resolver: TokioAsyncResolver,
+    rdap:     String,
+    limit:    RateLimit,
+    client:   http::Client,
+}
+
+impl DnsPlugin {
+    async fn resolve(&self, name: &str, record_type: Option<RecordType>) -> Result<Vec<String>> {
+        let result = match record_type {
+            Some(record_type) => self
+                .resolver
+                .lookup(name, record_type)
+                .await
+                .map(|lookup| lookup.iter().map(|r| r.to_string()).collect()),
+            None => self
+                .resolver
+                .lookup_ip(name)
+                .await
+                .map(|lookup| lookup.iter().map(|ip| ip.to_string()).collect()),
+        };
+        match result {
+            Ok(records) => Ok(records),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn rdap(&self, domain: &str) -> Result<Option<Domain>> {
+        let url = format!("{}/domain/{}", self.rdap.trim_end_matches('/'), domain);
+        let response = self.client.get(&url).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    async fn dns(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        let args = cmd.parse_args();
+        let words: Vec<&str> = args.words().collect();
+        let (name, record_type) = match words[..] {
+            [name] => (name, None),
+            [name, record_type] => match record_type.to_uppercase().as_str() {
+                "A" => (name, Some(RecordType::A)),
+                "AAAA" => (name, Some(RecordType::AAAA)),
+                "MX" => (name, Some(RecordType::MX)),
+                "TXT" => (name, Some(RecordType::TXT)),
+                _ => {
+                    let reply =
+                        format!("{}: Unsupported record type {}", cmd.user.nick, record_type);
+                    return ctx.reply(cmd, reply).await;
+                },
+            },
+            _ => {
+                let usage = ctx.usage(cmd, DNS.usage);
+                return ctx.reply(cmd, usage).await;
+            },
+        };
+        if !self.limit.allow().await {
+            let reply = ctx.tr(
+                cmd,
+                "{0}: Too many lookups, try again in a minute",
+                &[&cmd.user.nick],
+            );
+            return ctx.reply(cmd, reply).await;
+        }
+
+        let reply = match self.resolve(name, record_type).await {
+            Ok(records) if records.is_empty() => {
+                format!("{}: No records for {}", cmd.user.nick, name)
+            },
+            Ok(records) => truncate(&format!("{}: {}", name, records.join(", ")), MAX_REPLY),
+            Err(e) => {
+                warn!("[{}] DNS lookup for {} failed: {}", ctx.server, name, e);
+                format!("{}: Lookup failed for {}", cmd.user.nick, name)
+            },
+        };
+        ctx.reply(cmd, reply).await
+    }
+
+    async fn whois(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        let domain = match cmd.args.as_deref() {
+            Some(domain) if domain.contains('.') && !domain.contains(char::is_whitespace) => {
+                domain.trim_end_matches('.').to_lowercase()
+            },
+            _ => {
+                let usage = ctx.usage(cmd, WHOIS_DOMAIN.usage);
+                return ctx.reply(cmd, usage).await;
+            },
+        };
+        if !self.limit.allow().await {
+            let reply = ctx.tr(
+                cmd,
+                "{0}: Too many lookups, try again in a minute",
+                &[&cmd.user.nick],
+            );
+            return ctx.reply(cmd, reply).await;
+        }
+
+        let reply = match self.rdap(&domain).await {
+            Ok(Some(info)) => truncate(&info.describe(), MAX_REPLY),
+            Ok(None) => format!("{}: No registration found for {}", cmd.user.nick, domain),
+            Err(e) => {
+                warn!("[{}] RDAP lookup for {} failed: {}", ctx.server, domain, e);
+                ctx.tr(
+                    cmd,
+                    "{0}: Lookup failed, try again later",
+                    &[&cmd.user.nick],
+                )
+            },
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for DnsPlugin {
+    type Plugin = DnsPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[DNS, WHOIS_DOMAIN];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "dns";
+
+    async fn new(ctx: &PluginContext) -> Result<DnsPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let per_minute = match config.get("lookups-per-minute") {
+            Some(limit) => limit
+                .parse()
+                .map_err(|_| anyhow!("[DNS] Invalid lookups-per-minute: {}", limit))?,
+            None => DEFAULT_LOOKUPS_PER_MINUTE,
+        };
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|e| {
+            warn!(
+                "[{}] Could not read the system resolver config, using defaults: {}",
+                ctx.server, e
+            );
+            TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
+        });
+
+        Ok(DnsPlugin {
+            resolver,
+            rdap: config
+                .get("rdap")
+                .cloned()
+                .unwrap_or_else(|| DEFAULT_RDAP.into()),
+            limit: RateLimit {
+                per_minute,
+                recent: Mutex::new(VecDeque::new()),
+            },
+            client: ctx.http.client(),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for DnsPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        match cmd.name.as_str() {
+            "dns" => self.dns(ctx, cmd).await,
+            "whois-domain" => self.whois(ctx, cmd).await,
+            _ => Ok(()),
+        }
+    }
+}
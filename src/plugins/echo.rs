@@ -1,46 +1,141 @@
-use crate::bot;
+use crate::command::{self, Invocation};
 use crate::irc;
-use crate::plugins::{Plugin, PluginBuilder};
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::state::State;
 use anyhow::Result;
 use async_trait::async_trait;
-use log::*;
-use tokio::task::JoinHandle;
 
+const ECHO: command::Help = command::Help {
+    name:  "echo",
+    usage: "echo [#channel] <text> - repeats the text, here or in a channel we're both in",
+};
+
+/// Repeats whatever it's told to. Also serves as a minimal example of a
+/// command plugin.
 pub struct EchoPlugin;
 
 #[async_trait]
 impl PluginBuilder for EchoPlugin {
     type Plugin = EchoPlugin;
 
+    const COMMANDS: &'static [command::Help] = &[ECHO];
+    const MESSAGES: Subscription = Subscription::None;
     const NAME: &'static str = "echo";
 
-    async fn new(_server: &str, _config: Option<&bot::PluginConfig>) -> Result<EchoPlugin> {
+    async fn new(_ctx: &PluginContext) -> Result<EchoPlugin> {
         Ok(EchoPlugin)
     }
 }
 
+/// Works out what to send for an `echo` invocation. Text can only be sent to
+/// another channel if both the bot and the requester are in it. On error,
+/// returns the explanation to reply with.
+fn echo(state: &State, cmd: &Invocation) -> Result<irc::Outgoing, String> {
+    let usage = format!("{}: Usage: {}{}", cmd.user.nick, cmd.prefix, ECHO.usage);
+    let args = cmd.args.as_deref().ok_or_else(|| usage.clone())?;
+    let (first, rest) = command::split_first_word(args);
+    if !irc::is_channel(first) {
+        return Ok(cmd.reply(args));
+    }
+
+    let text = rest.map(str::trim).filter(|t| !t.is_empty()).ok_or(usage)?;
+    match state.channel(first) {
+        Some(channel) if channel.has_user(&cmd.user.nick) => {
+            Ok(irc::Outgoing::privmsg(channel.name.as_str(), text))
+        },
+        _ => Err(format!("{}: We're not both in {}", cmd.user.nick, first)),
+    }
+}
+
+#[async_trait]
 impl Plugin for EchoPlugin {
-    fn spawn_task(self, mut irc: irc::IRC) -> Result<JoinHandle<Result<()>>> {
-        info!("Registering echo");
-        let handle = tokio::spawn(async move {
-            loop {
-                while let Ok(msg) = irc.received_messages.recv().await {
-                    if let irc::Command::Privmsg = msg.command {
-                        assert!(msg.parameters.len() == 1);
-                        assert!(msg.target.is_some());
-                        let user = msg.source_as_user().unwrap();
-                        let target = msg.target.unwrap();
-                        let reply = format!(
-                            "Hey {:?} thanks for saying `{}'! Much appreciated",
-                            user, msg.parameters[0]
-                        );
-                        irc.send_messages
-                            .send(irc::Message::privmsg(target, reply))
-                            .await?;
-                    }
-                }
-            }
-        });
-        Ok(handle)
+    async fn command(&self, ctx: &PluginContext, cmd: &Invocation) -> Result<()> {
+        if cmd.name != ECHO.name {
+            return Ok(());
+        }
+        let msg = echo(&*ctx.state.read().await, cmd);
+        match msg {
+            Ok(msg) => ctx.send_outgoing(msg).await,
+            Err(reply) => ctx.reply(cmd, reply).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::ChatEvent;
+    use crate::command::{Prefixes, Triggers};
+
+    fn message(source: &str, command: irc::Command, target: &str, params: &[&str]) -> irc::Message {
+        irc::Message {
+            source: Some(source.into()),
+            command,
+            target: Some(target.into()),
+            parameters: params.iter().map(|p| p.to_string()).collect(),
+            tags: Default::default(),
+        }
+    }
+
+    /// State where the bot is in #boton and #other, but alice only in #boton.
+    fn state() -> State {
+        let mut state = State {
+            nick: "boton".into(),
+            ..Default::default()
+        };
+        for (nick, channel) in &[
+            ("boton", "#boton"),
+            ("boton", "#Other"),
+            ("alice", "#boton"),
+        ] {
+            let source = format!("{}!user@host", nick);
+            state.update(&message(&source, irc::Command::Join, channel, &[]));
+        }
+        state
+    }
+
+    fn invocation(target: &str, text: &str) -> Invocation {
+        let msg = message("alice!alice@host", irc::Command::Privmsg, target, &[text]);
+        let event = ChatEvent::parse(&msg).unwrap();
+        command::parse(&event, "boton", &Prefixes::default(), &Triggers::default()).unwrap()
+    }
+
+    fn assert_privmsg(msg: irc::Outgoing, target: &str, text: &str) {
+        assert!(!msg.notice);
+        assert_eq!(msg.target, target);
+        assert_eq!(msg.text, text);
+    }
+
+    #[test]
+    fn echoes_in_the_same_channel() {
+        let msg = echo(&state(), &invocation("#boton", "\\echo hello there")).unwrap();
+        assert_privmsg(msg, "#boton", "hello there");
+    }
+
+    #[test]
+    fn echoes_privately() {
+        let msg = echo(&state(), &invocation("boton", "echo hi")).unwrap();
+        assert_privmsg(msg, "alice", "hi");
+    }
+
+    #[test]
+    fn echoes_to_shared_channel() {
+        let msg = echo(&state(), &invocation("boton", "echo #BOTON hi all")).unwrap();
+        assert_privmsg(msg, "#boton", "hi all");
+    }
+
+    #[test]
+    fn refuses_channels_not_shared() {
+        let err = echo(&state(), &invocation("#boton", "\\echo #other hi")).unwrap_err();
+        assert_eq!(err, "alice: We're not both in #other");
+        let err = echo(&state(), &invocation("#boton", "\\echo #nowhere hi")).unwrap_err();
+        assert_eq!(err, "alice: We're not both in #nowhere");
+    }
+
+    #[test]
+    fn requires_text() {
+        assert!(echo(&state(), &invocation("#boton", "\\echo")).is_err());
+        assert!(echo(&state(), &invocation("#boton", "\\echo #boton")).is_err());
+        assert!(echo(&state(), &invocation("#boton", "\\echo #boton   ")).is_err());
     }
 }
@@ -0,0 +1,398 @@
+//! Formula 1 schedule and standings from an Ergast-compatible API, with
+//! session times shown in the requester's timezone saved through `\tzset`.
+//!
+//! Configuration:
+//! - `api`: base URL of the API, `https://api.jolpi.ca/ergast/f1` by default
+//! - `announce`: space separated channels to announce race starts in
+
+use crate::command;
+use crate::http;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::prefs;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::*;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+const F1: command::Help = command::Help {
+    name:  "f1",
+    usage: "f1 next | standings [constructors] - shows the next race weekend or the championship",
+};
+
+const DEFAULT_API: &str = "https://api.jolpi.ca/ergast/f1";
+/// How often to check for an upcoming race start to announce
+const CHECK_PERIOD: Duration = Duration::from_secs(30 * 60);
+/// How long after connecting to do the first check, once channels are joined
+const FIRST_CHECK: Duration = Duration::from_secs(60);
+/// How many positions of the standings to show
+const MAX_STANDINGS: usize = 10;
+
+#[derive(Deserialize)]
+struct Response<T> {
+    #[serde(rename = "MRData")]
+    data: T,
+}
+
+#[derive(Deserialize)]
+struct RaceData {
+    #[serde(rename = "RaceTable")]
+    table: RaceTable,
+}
+
+#[derive(Deserialize)]
+struct RaceTable {
+    #[serde(rename = "Races")]
+    races: Vec<Race>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Race {
+    #[serde(rename = "season")]
+    season:            String,
+    #[serde(rename = "round")]
+    round:             String,
+    #[serde(rename = "raceName")]
+    race_name:         String,
+    circuit:           Circuit,
+    #[serde(flatten)]
+    start:             Session,
+    first_practice:    Option<Session>,
+    second_practice:   Option<Session>,
+    third_practice:    Option<Session>,
+    #[serde(alias = "SprintShootout")]
+    sprint_qualifying: Option<Session>,
+    sprint:            Option<Session>,
+    qualifying:        Option<Session>,
+}
+
+#[derive(Deserialize)]
+struct Circuit {
+    #[serde(rename = "circuitName")]
+    name:     String,
+    #[serde(rename = "Location")]
+    location: Location,
+}
+
+#[derive(Deserialize)]
+struct Location {
+    locality: String,
+    country:  String,
+}
+
+#[derive(Deserialize)]
+struct Session {
+    date: String,
+    time: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StandingsData {
+    #[serde(rename = "StandingsTable")]
+    table: StandingsTable,
+}
+
+#[derive(Deserialize)]
+struct StandingsTable {
+    #[serde(rename = "StandingsLists")]
+    lists: Vec<StandingsList>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StandingsList {
+    #[serde(rename = "season")]
+    season:                String,
+    #[serde(rename = "round")]
+    round:                 String,
+    #[serde(default)]
+    driver_standings:      Vec<DriverStanding>,
+    #[serde(default)]
+    constructor_standings: Vec<ConstructorStanding>,
+}
+
+#[derive(Deserialize)]
+struct DriverStanding {
+    position: String,
+    points:   String,
+    #[serde(rename = "Driver")]
+    driver:   Driver,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Driver {
+    code:        Option<String>,
+    family_name: String,
+}
+
+#[derive(Deserialize)]
+struct ConstructorStanding {
+    position:    String,
+    points:      String,
+    #[serde(rename = "Constructor")]
+    constructor: Constructor,
+}
+
+#[derive(Deserialize)]
+struct Constructor {
+    name: String,
+}
+
+impl Session {
+    fn start(&self) -> Option<DateTime<Utc>> {
+        let time = self.time.as_ref()?;
+        let start = DateTime::parse_from_rfc3339(&format!("{}T{}", self.date, time)).ok()?;
+        Some(start.with_timezone(&Utc))
+    }
+
+    /// The session's start in the given timezone, or just its date when
+    /// the time isn't known yet.
+    fn describe(&self, timezone: Option<Tz>) -> String {
+        match (self.start(), timezone) {
+            (Some(start), Some(timezone)) => start
+                .with_timezone(&timezone)
+                .format("%a %d %b %H:%M %Z")
+                .to_string(),
+            (Some(start), None) => start.format("%a %d %b %H:%M UTC").to_string(),
+            (None, _) => self.date.clone(),
+        }
+    }
+}
+
+impl Race {
+    fn describe(&self, timezone: Option<Tz>) -> String {
+        let mut line = format!(
+            "Round {}: {}, {} ({}, {})",
+            self.round,
+            self.race_name,
+            self.circuit.name,
+            self.circuit.location.locality,
+            self.circuit.location.country
+        );
+        let sessions = [
+            ("FP1", &self.first_practice),
+            ("FP2", &self.second_practice),
+            ("FP3", &self.third_practice),
+            ("Sprint quali", &self.sprint_qualifying),
+            ("Sprint", &self.sprint),
+            ("Quali", &self.qualifying),
+        ];
+        for (name, session) in sessions {
+            if let Some(session) = session {
+                line += &format!(" 〜 {} {}", name, session.describe(timezone));
+            }
+        }
+        format!("{} 〜 Race {}", line, self.start.describe(timezone))
+    }
+}
+
+/// Fetches a path of the API and unwraps its `MRData`.
+async fn fetch<T: DeserializeOwned>(client: &http::Client, api: &str, path: &str) -> Result<T> {
+    let response: Response<T> = client
+        .get(&format!("{}/{}", api.trim_end_matches('/'), path))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(response.data)
+}
+
+async fn next_race(client: &http::Client, api: &str) -> Result<Option<Race>> {
+    let data: RaceData = fetch(client, api, "current/next.json").await?;
+    Ok(data.table.races.into_iter().next())
+}
+
+/// Announces race starts in the configured channels.
+#[derive(Clone)]
+struct Announcer {
+    ctx:       PluginContext,
+    api:       String,
+    client:    http::Client,
+    channels:  Vec<String>,
+    /// Season and round of the last race whose start was scheduled
+    scheduled: Arc<Mutex<Option<(String, String)>>>,
+}
+
+impl Announcer {
+    /// Schedules the announcement when the next race starts before the next
+    /// check.
+    async fn check(self) {
+        let race = match next_race(&self.client, &self.api).await {
+            Ok(Some(race)) => race,
+            Ok(None) => return,
+            Err(e) => {
+                warn!(
+                    "[{}] Could not get the next F1 race: {}",
+                    self.ctx.server, e
+                );
+                return;
+            },
+        };
+        let until = match race.start.start().map(|start| start - Utc::now()) {
+            Some(until) => match until.to_std() {
+                Ok(until) if until <= CHECK_PERIOD => until,
+                _ => return,
+            },
+            None => return,
+        };
+
+        let id = (race.season.clone(), race.round.clone());
+        let mut scheduled = self.scheduled.lock().await;
+        if scheduled.as_ref() == Some(&id) {
+            return;
+        }
+        *scheduled = Some(id);
+        debug!(
+            "[{}] Announcing the {} in {:?}",
+            self.ctx.server, race.race_name, until
+        );
+
+        let (ctx, channels) = (self.ctx.clone(), self.channels.clone());
+        self.ctx.scheduler.after(until, async move {
+            let text = format!("🏁 The {} is starting now!", race.race_name);
+            for channel in &channels {
+                if let Err(e) = ctx
+                    .send_outgoing(irc::Outgoing::privmsg(channel.as_str(), text.as_str()))
+                    .await
+                {
+                    warn!("[{}] Could not announce the race: {}", ctx.server, e);
+                }
+            }
+        });
+    }
+}
+
+pub struct F1Plugin {
+    api:    String,
+    client: http::Client,
+}
+
+impl F1Plugin {
+    async fn standings(&self, constructors: bool) -> Result<String> {
+        let path = if constructors {
+            "current/constructorStandings.json"
+        } else {
+            "current/driverStandings.json"
+        };
+        let data: StandingsData = fetch(&self.client, &self.api, path).await?;
+        let list = data
+            .table
+            .lists
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("no standings yet"))?;
+
+        let positions: Vec<String> = if constructors {
+            list.constructor_standings
+                .iter()
+                .take(MAX_STANDINGS)
+                .map(|s| format!("{}. {} {}", s.position, s.constructor.name, s.points))
+                .collect()
+        } else {
+            list.driver_standings
+                .iter()
+                .take(MAX_STANDINGS)
+                .map(|s| {
+                    let name = s.driver.code.as_ref().unwrap_or(&s.driver.family_name);
+                    format!("{}. {} {}", s.position, name, s.points)
+                })
+                .collect()
+        };
+        Ok(format!(
+            "{} standings after round {}: {}",
+            list.season,
+            list.round,
+            positions.join(", ")
+        ))
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for F1Plugin {
+    type Plugin = F1Plugin;
+
+    const COMMANDS: &'static [command::Help] = &[F1];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "f1";
+
+    async fn new(ctx: &PluginContext) -> Result<F1Plugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let api = config
+            .get("api")
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_API.into());
+        let client = ctx.http.client();
+
+        let channels: Vec<String> = config
+            .get("announce")
+            .map(|channels| channels.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+        if !channels.is_empty() {
+            let announcer = Announcer {
+                ctx: ctx.clone(),
+                api: api.clone(),
+                client: client.clone(),
+                channels,
+                scheduled: Arc::new(Mutex::new(None)),
+            };
+            ctx.scheduler.after(FIRST_CHECK, announcer.clone().check());
+            ctx.scheduler
+                .every(CHECK_PERIOD, move || announcer.clone().check());
+        }
+
+        Ok(F1Plugin { api, client })
+    }
+}
+
+#[async_trait]
+impl Plugin for F1Plugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != F1.name {
+            return Ok(());
+        }
+        let args = cmd.parse_args();
+        let words: Vec<&str> = args.words().collect();
+        let reply = match words[..] {
+            ["next"] => {
+                let timezone = ctx.prefs.get(&prefs::TIMEZONE, &cmd.user.nick);
+                match next_race(&self.client, &self.api).await {
+                    Ok(Some(race)) => race.describe(timezone),
+                    Ok(None) => format!("{}: No more races this season", cmd.user.nick),
+                    Err(e) => {
+                        warn!("[{}] Could not get the next F1 race: {}", ctx.server, e);
+                        format!(
+                            "{}: Could not get the schedule, try again later",
+                            cmd.user.nick
+                        )
+                    },
+                }
+            },
+            ["standings"] | ["standings", "drivers"] | ["standings", "constructors"] => {
+                match self
+                    .standings(words.len() > 1 && words[1] == "constructors")
+                    .await
+                {
+                    Ok(standings) => standings,
+                    Err(e) => {
+                        warn!("[{}] Could not get the F1 standings: {}", ctx.server, e);
+                        format!(
+                            "{}: Could not get the standings, try again later",
+                            cmd.user.nick
+                        )
+                    },
+                }
+            },
+            _ => ctx.usage(cmd, F1.usage),
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
@@ -0,0 +1,452 @@
+//! Football scores from API-Football, configured with an `api-key` from
+//! <https://www.api-football.com>.
+//!
+//! Channels can follow teams, after which kick-offs, goals and final scores
+//! of their matches are announced there. The live scores are polled every
+//! `poll-minutes` minutes (5 by default) while any team is followed, so keep
+//! the API plan's daily quota in mind.
+
+use crate::command;
+use crate::http;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::storage::DataSet;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+const SCORE: command::Help = command::Help {
+    name:  "score",
+    usage: "score <team> | follow <team> | unfollow <team> | following - shows a team's latest \
+            match, or manages the teams announced in this channel",
+};
+
+const API_URL: &str = "https://v3.football.api-sports.io";
+const DB_NAME: &str = "football";
+pub const DATA_SET: DataSet = DataSet::new::<Follows>(DB_NAME);
+const DEFAULT_POLL_MINUTES: u64 = 5;
+
+#[derive(Deserialize)]
+struct Response<T> {
+    response: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct TeamResult {
+    team: Team,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+struct Team {
+    id:   u64,
+    name: String,
+}
+
+#[derive(Deserialize, Clone)]
+struct Fixture {
+    fixture: FixtureInfo,
+    league:  League,
+    teams:   Teams,
+    goals:   Goals,
+}
+
+#[derive(Deserialize, Clone)]
+struct FixtureInfo {
+    id:     u64,
+    date:   String,
+    status: Status,
+}
+
+#[derive(Deserialize, Clone)]
+struct Status {
+    /// Like `NS` (not started), `1H`, `HT` or `FT`
+    short:   String,
+    elapsed: Option<u32>,
+}
+
+#[derive(Deserialize, Clone)]
+struct League {
+    name: String,
+}
+
+#[derive(Deserialize, Clone)]
+struct Teams {
+    home: Team,
+    away: Team,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+struct Goals {
+    home: Option<u32>,
+    away: Option<u32>,
+}
+
+impl Fixture {
+    fn score(&self) -> String {
+        format!(
+            "{} {} - {} {}",
+            self.teams.home.name,
+            self.goals.home.unwrap_or(0),
+            self.goals.away.unwrap_or(0),
+            self.teams.away.name
+        )
+    }
+
+    fn involves(&self, team: &Team) -> bool {
+        self.teams.home.id == team.id || self.teams.away.id == team.id
+    }
+
+    fn describe(&self) -> String {
+        let status = &self.fixture.status;
+        match status.short.as_str() {
+            "NS" | "TBD" => {
+                let kickoff = self.fixture.date.replace('T', " ");
+                format!(
+                    "{} vs {} 〜 {} 〜 {} UTC",
+                    self.teams.home.name,
+                    self.teams.away.name,
+                    self.league.name,
+                    kickoff.get(.. 16).unwrap_or(&kickoff)
+                )
+            },
+            "FT" | "AET" | "PEN" => format!("{} (FT) 〜 {}", self.score(), self.league.name),
+            short => match status.elapsed {
+                Some(elapsed) => format!("{} ({}') 〜 {}", self.score(), elapsed, self.league.name),
+                None => format!("{} ({}) 〜 {}", self.score(), short, self.league.name),
+            },
+        }
+    }
+}
+
+/// Teams followed by each channel, keyed by lowercase channel name.
+type Follows = BTreeMap<String, Vec<Team>>;
+
+#[derive(Clone)]
+struct Api {
+    key:    String,
+    client: http::Client,
+}
+
+impl Api {
+    async fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<Vec<T>> {
+        let response: Response<T> = self
+            .client
+            .get(&format!("{}{}", API_URL, path))
+            .query(query)
+            .header("x-apisports-key", &self.key)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.response)
+    }
+
+    async fn find_team(&self, name: &str) -> Result<Option<Team>> {
+        let teams: Vec<TeamResult> = self.get("/teams", &[("search", name)]).await?;
+        Ok(teams.into_iter().next().map(|t| t.team))
+    }
+
+    /// Describes the team's match in progress, or else its last and next
+    /// ones.
+    async fn latest(&self, team: &Team) -> Result<Option<String>> {
+        let live: Vec<Fixture> = self.get("/fixtures", &[("live", "all")]).await?;
+        if let Some(fixture) = live.into_iter().find(|f| f.involves(team)) {
+            return Ok(Some(fixture.describe()));
+        }
+        let id = team.id.to_string();
+        let mut parts = vec![];
+        for (label, which) in [("Last", "last"), ("Next", "next")] {
+            let fixtures: Vec<Fixture> = self
+                .get("/fixtures", &[("team", &id), (which, "1")])
+                .await?;
+            if let Some(fixture) = fixtures.first() {
+                parts.push(format!("{}: {}", label, fixture.describe()));
+            }
+        }
+        Ok(Some(parts.join(" 〜 ")).filter(|line| !line.is_empty()))
+    }
+}
+
+/// Watches live matches of followed teams and announces what happens.
+#[derive(Clone)]
+struct Announcer {
+    ctx:     PluginContext,
+    api:     Api,
+    follows: Arc<Mutex<Follows>>,
+    /// Followed matches in progress, as last seen
+    live:    Arc<Mutex<HashMap<u64, Fixture>>>,
+}
+
+impl Announcer {
+    async fn announce(&self, fixture: &Fixture, text: &str) {
+        let follows = self.follows.lock().await;
+        for (channel, teams) in follows.iter() {
+            if teams.iter().any(|team| fixture.involves(team)) {
+                let msg = irc::Outgoing::privmsg(channel.as_str(), text);
+                if let Err(e) = self.ctx.send_outgoing(msg).await {
+                    warn!("[{}] Could not announce a match: {}", self.ctx.server, e);
+                }
+            }
+        }
+    }
+
+    async fn poll(self) {
+        if self.follows.lock().await.values().all(Vec::is_empty) {
+            return;
+        }
+        let fixtures: Vec<Fixture> = match self.api.get("/fixtures", &[("live", "all")]).await {
+            Ok(fixtures) => fixtures,
+            Err(e) => {
+                warn!("[{}] Could not get live matches: {}", self.ctx.server, e);
+                return;
+            },
+        };
+        let followed: Vec<Fixture> = {
+            let follows = self.follows.lock().await;
+            fixtures
+                .into_iter()
+                .filter(|f| follows.values().flatten().any(|team| f.involves(team)))
+                .collect()
+        };
+
+        let mut live = self.live.lock().await;
+        for fixture in &followed {
+            match live.get(&fixture.fixture.id) {
+                None => {
+                    let text = format!("⚽ Kick-off: {}", fixture.describe());
+                    self.announce(fixture, &text).await;
+                },
+                Some(last) if last.goals != fixture.goals => {
+                    let text = format!("⚽ Goal! {}", fixture.describe());
+                    self.announce(fixture, &text).await;
+                },
+                Some(_) => {},
+            }
+        }
+
+        // Matches no longer live are over, so announce their final score
+        let ended: Vec<u64> = live
+            .keys()
+            .filter(|id| !followed.iter().any(|f| f.fixture.id == **id))
+            .copied()
+            .collect();
+        for id in ended {
+            live.remove(&id);
+            let result: Result<Vec<Fixture>> =
+                self.api.get("/fixtures", &[("id", &id.to_string())]).await;
+            match result.map(|f| f.into_iter().next()) {
+                Ok(Some(fixture)) => {
+                    let text = format!("⚽ Full time: {}", fixture.describe());
+                    self.announce(&fixture, &text).await;
+                },
+                Ok(None) => {},
+                Err(e) => warn!("[{}] Could not get match {}: {}", self.ctx.server, id, e),
+            }
+        }
+        live.extend(followed.into_iter().map(|f| (f.fixture.id, f)));
+    }
+}
+
+pub struct FootballPlugin {
+    api:     Option<Api>,
+    follows: Arc<Mutex<Follows>>,
+}
+
+impl FootballPlugin {
+    /// Whether the user may change what's announced in the channel.
+    async fn can_manage(ctx: &PluginContext, cmd: &command::Invocation) -> bool {
+        if ctx.admins.allows(&cmd.user) {
+            return true;
+        }
+        let state = ctx.state.read().await;
+        state
+            .channel(&cmd.reply_to)
+            .is_some_and(|channel| channel.is_opped(&cmd.user.nick))
+    }
+
+    async fn follow(
+        &self,
+        ctx: &PluginContext,
+        cmd: &command::Invocation,
+        api: &Api,
+        name: &str,
+    ) -> Result<String> {
+        let team = match api.find_team(name).await? {
+            Some(team) => team,
+            None => return Ok(format!("{}: No team found for `{}`", cmd.user.nick, name)),
+        };
+        let mut follows = self.follows.lock().await;
+        let teams = follows.entry(cmd.reply_to.to_lowercase()).or_default();
+        if teams.contains(&team) {
+            return Ok(format!(
+                "{}: Already following {}",
+                cmd.user.nick, team.name
+            ));
+        }
+        let reply = format!("{}: Now following {} here", cmd.user.nick, team.name);
+        teams.push(team);
+        ctx.storage.save(DB_NAME, &*follows).await?;
+        Ok(reply)
+    }
+
+    async fn unfollow(
+        &self,
+        ctx: &PluginContext,
+        cmd: &command::Invocation,
+        name: &str,
+    ) -> Result<String> {
+        let mut follows = self.follows.lock().await;
+        let channel = cmd.reply_to.to_lowercase();
+        let teams = follows.get_mut(&channel);
+        let position = teams
+            .as_ref()
+            .and_then(|teams| teams.iter().position(|t| t.name.eq_ignore_ascii_case(name)));
+        match (teams, position) {
+            (Some(teams), Some(position)) => {
+                teams.remove(position);
+                if teams.is_empty() {
+                    follows.remove(&channel);
+                }
+            },
+            _ => return Ok(format!("{}: Not following `{}` here", cmd.user.nick, name)),
+        }
+        ctx.storage.save(DB_NAME, &*follows).await?;
+        Ok(format!(
+            "{}: No longer following {} here",
+            cmd.user.nick, name
+        ))
+    }
+
+    async fn following(&self, cmd: &command::Invocation) -> String {
+        let follows = self.follows.lock().await;
+        match follows.get(&cmd.reply_to.to_lowercase()) {
+            Some(teams) if !teams.is_empty() => {
+                let names: Vec<&str> = teams.iter().map(|t| t.name.as_str()).collect();
+                format!("{}: Following {}", cmd.user.nick, names.join(", "))
+            },
+            _ => format!("{}: Not following any teams here", cmd.user.nick),
+        }
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for FootballPlugin {
+    type Plugin = FootballPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[SCORE];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "football";
+
+    async fn new(ctx: &PluginContext) -> Result<FootballPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let follows: Follows = ctx.storage.load(DB_NAME).await?.unwrap_or_default();
+        let follows = Arc::new(Mutex::new(follows));
+        let api = match config.get("api-key") {
+            Some(key) => Api {
+                key:    key.clone(),
+                client: ctx.http.client(),
+            },
+            None => {
+                warn!(
+                    "[{}] Football scores need `api-key` in the football config section",
+                    ctx.server
+                );
+                return Ok(FootballPlugin { api: None, follows });
+            },
+        };
+
+        let poll_minutes = match config.get("poll-minutes") {
+            Some(minutes) => minutes
+                .parse()
+                .map_err(|_| anyhow!("[Football] Invalid poll-minutes: {}", minutes))?,
+            None => DEFAULT_POLL_MINUTES,
+        };
+        let announcer = Announcer {
+            ctx:     ctx.clone(),
+            api:     api.clone(),
+            follows: follows.clone(),
+            live:    Arc::new(Mutex::new(HashMap::new())),
+        };
+        ctx.scheduler
+            .every(Duration::from_secs(poll_minutes * 60), move || {
+                announcer.clone().poll()
+            });
+
+        Ok(FootballPlugin {
+            api: Some(api),
+            follows,
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for FootballPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != SCORE.name {
+            return Ok(());
+        }
+        let api = match &self.api {
+            Some(api) => api,
+            None => {
+                let reply = format!("{}: Scores aren't configured", cmd.user.nick);
+                return ctx.reply(cmd, reply).await;
+            },
+        };
+        let args = cmd.args.as_deref().unwrap_or_default();
+        let (subcommand, rest) = match args.split_once(' ') {
+            Some((subcommand, rest)) => (subcommand, rest.trim()),
+            None => (args, ""),
+        };
+
+        let reply = match subcommand {
+            "" => ctx.usage(cmd, SCORE.usage),
+            "follow" | "unfollow" | "following" if !irc::is_channel(&cmd.reply_to) => {
+                format!("{}: Teams can only be followed in channels", cmd.user.nick)
+            },
+            "following" => self.following(cmd).await,
+            "follow" | "unfollow" if rest.is_empty() => ctx.usage(cmd, SCORE.usage),
+            "follow" | "unfollow" if !FootballPlugin::can_manage(ctx, cmd).await => {
+                format!("{}: Only channel operators can do that", cmd.user.nick)
+            },
+            "follow" => match self.follow(ctx, cmd, api, rest).await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    warn!("[{}] Could not follow {:?}: {}", ctx.server, rest, e);
+                    format!(
+                        "{}: Could not follow that team, try again later",
+                        cmd.user.nick
+                    )
+                },
+            },
+            "unfollow" => self.unfollow(ctx, cmd, rest).await?,
+            _ => {
+                let result = match api.find_team(args).await {
+                    Ok(Some(team)) => api.latest(&team).await.map(Some),
+                    Ok(None) => Ok(None),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(Some(Some(matches))) => matches,
+                    Ok(Some(None)) => format!("{}: No matches found for `{}`", cmd.user.nick, args),
+                    Ok(None) => format!("{}: No team found for `{}`", cmd.user.nick, args),
+                    Err(e) => {
+                        warn!("[{}] Score lookup for {:?} failed: {}", ctx.server, args, e);
+                        ctx.tr(
+                            cmd,
+                            "{0}: Lookup failed, try again later",
+                            &[&cmd.user.nick],
+                        )
+                    },
+                }
+            },
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
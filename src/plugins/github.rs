@@ -0,0 +1,266 @@
+//! Expands GitHub references like `owner/repo#123` and GitHub URLs mentioned
+//! in messages, and searches repositories through `\gh`.
+//!
+//! An optional `token` in the config raises the API rate limits.
+
+use crate::chat::ChatEvent;
+use crate::command;
+use crate::http;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use anyhow::Result;
+use async_trait::async_trait;
+use log::*;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+const GH: command::Help = command::Help {
+    name:  "gh",
+    usage: "gh <query> - shows the best matching GitHub repository",
+};
+
+const API_URL: &str = "https://api.github.com";
+/// Most references expanded from a single message
+const MAX_REFERENCES: usize = 3;
+
+/// Something on GitHub mentioned in a message.
+#[derive(Debug, PartialEq, Eq)]
+enum Reference {
+    Repo {
+        owner: String,
+        repo:  String,
+    },
+    Issue {
+        owner:  String,
+        repo:   String,
+        number: u64,
+    },
+}
+
+#[derive(Deserialize)]
+struct Repo {
+    full_name:        String,
+    description:      Option<String>,
+    stargazers_count: u64,
+    language:         Option<String>,
+    html_url:         String,
+}
+
+#[derive(Deserialize)]
+struct Issue {
+    title:        String,
+    state:        String,
+    pull_request: Option<PullRequest>,
+}
+
+#[derive(Deserialize)]
+struct PullRequest {
+    merged_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchResults {
+    items: Vec<Repo>,
+}
+
+fn is_name(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Parses a single word as a GitHub reference: `owner/repo#123`, or the URL
+/// of a repository, issue or pull request.
+fn parse_reference(word: &str) -> Option<Reference> {
+    let word = word.trim_matches(|c: char| "()<>[],;:!?'\"".contains(c));
+    let url = ["https://github.com/", "http://github.com/", "github.com/"]
+        .iter()
+        .find_map(|prefix| word.strip_prefix(prefix));
+    if let Some(path) = url {
+        let mut parts = path.trim_end_matches('/').split('/');
+        let (owner, repo) = (parts.next()?, parts.next()?);
+        let repo = repo.trim_end_matches(".git");
+        if !is_name(owner) || !is_name(repo) {
+            return None;
+        }
+        let (owner, repo) = (owner.into(), repo.into());
+        return match (parts.next(), parts.next()) {
+            (None, _) => Some(Reference::Repo { owner, repo }),
+            (Some("issues" | "pull"), Some(number)) => Some(Reference::Issue {
+                owner,
+                repo,
+                number: number.split('#').next()?.parse().ok()?,
+            }),
+            _ => None,
+        };
+    }
+
+    let (name, number) = word.split_once('#')?;
+    let (owner, repo) = name.split_once('/')?;
+    if !is_name(owner) || !is_name(repo) {
+        return None;
+    }
+    Some(Reference::Issue {
+        owner:  owner.into(),
+        repo:   repo.into(),
+        number: number.trim_end_matches('.').parse().ok()?,
+    })
+}
+
+fn describe_repo(repo: &Repo) -> String {
+    let mut line = repo.full_name.clone();
+    if let Some(description) = repo.description.as_deref().filter(|d| !d.is_empty()) {
+        line += &format!(" 〜 {}", description);
+    }
+    if let Some(language) = &repo.language {
+        line += &format!(" 〜 {}", language);
+    }
+    format!("{} 〜 \u{2B50} {}", line, repo.stargazers_count)
+}
+
+pub struct GithubPlugin {
+    token:  Option<String>,
+    client: http::Client,
+}
+
+impl GithubPlugin {
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Option<T>> {
+        let mut request = self
+            .client
+            .get(&format!("{}{}", API_URL, path))
+            .query(query)
+            .header("Accept", "application/vnd.github+json");
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    async fn expand(&self, reference: &Reference) -> Result<Option<String>> {
+        match reference {
+            Reference::Repo { owner, repo } => {
+                let repo: Option<Repo> =
+                    self.get(&format!("/repos/{}/{}", owner, repo), &[]).await?;
+                Ok(repo.as_ref().map(describe_repo))
+            },
+            Reference::Issue {
+                owner,
+                repo,
+                number,
+            } => {
+                let path = format!("/repos/{}/{}/issues/{}", owner, repo, number);
+                let issue: Option<Issue> = self.get(&path, &[]).await?;
+                Ok(issue.map(|issue| {
+                    let (kind, state) = match &issue.pull_request {
+                        Some(pr) if pr.merged_at.is_some() => ("PR", "merged"),
+                        Some(_) => ("PR", issue.state.as_str()),
+                        None => ("Issue", issue.state.as_str()),
+                    };
+                    format!(
+                        "{}/{}#{}: {} 〜 {} {}",
+                        owner, repo, number, issue.title, kind, state
+                    )
+                }))
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for GithubPlugin {
+    type Plugin = GithubPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[GH];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "github";
+
+    async fn new(ctx: &PluginContext) -> Result<GithubPlugin> {
+        Ok(GithubPlugin {
+            token:  ctx.config.as_ref().and_then(|c| c.get("token")).cloned(),
+            client: ctx.http.client(),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for GithubPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != GH.name {
+            return Ok(());
+        }
+        let query = match cmd.args.as_deref() {
+            Some(query) => query,
+            None => {
+                let usage = ctx.usage(cmd, GH.usage);
+                return ctx.reply(cmd, usage).await;
+            },
+        };
+
+        let results: Result<Option<SearchResults>> = self
+            .get("/search/repositories", &[("q", query), ("per_page", "1")])
+            .await;
+        let reply = match results.map(|r| r.and_then(|r| r.items.into_iter().next())) {
+            Ok(Some(repo)) => {
+                let link = ctx.shortener.shorten(&cmd.reply_to, &repo.html_url).await;
+                format!("{} 〜 {}", describe_repo(&repo), link)
+            },
+            Ok(None) => format!("{}: No repositories found for `{}`", cmd.user.nick, query),
+            Err(e) => {
+                warn!(
+                    "[{}] GitHub search for {:?} failed: {}",
+                    ctx.server, query, e
+                );
+                ctx.tr(
+                    cmd,
+                    "{0}: Search failed, try again later",
+                    &[&cmd.user.nick],
+                )
+            },
+        };
+        ctx.reply(cmd, reply).await
+    }
+
+    fn claims(&self, _ctx: &PluginContext, event: &ChatEvent) -> bool {
+        !event.is_ctcp
+            && event
+                .body
+                .split_whitespace()
+                .any(|w| parse_reference(w).is_some())
+    }
+
+    async fn chat(&self, ctx: &PluginContext, event: &ChatEvent) -> Result<()> {
+        if event.is_ctcp {
+            return Ok(());
+        }
+        let reply_to = event.reply_to();
+
+        let mut references: Vec<Reference> = vec![];
+        for reference in event.body.split_whitespace().filter_map(parse_reference) {
+            if !references.contains(&reference) {
+                references.push(reference);
+            }
+        }
+        for reference in references.iter().take(MAX_REFERENCES) {
+            match self.expand(reference).await {
+                Ok(Some(line)) => {
+                    let msg = irc::Outgoing::privmsg(reply_to, line.as_str())
+                        .in_reply_to(event.msgid.as_deref());
+                    ctx.send_outgoing(msg).await?
+                },
+                Ok(None) => debug!("[{}] No such GitHub reference {:?}", ctx.server, reference),
+                Err(e) => warn!("[{}] Could not expand {:?}: {}", ctx.server, reference, e),
+            }
+        }
+        Ok(())
+    }
+}
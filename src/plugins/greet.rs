@@ -0,0 +1,159 @@
+//! Welcomes users joining a channel for the first time.
+//!
+//! Configuration:
+//! - `greet #channel`: the welcome message for the channel, where `{nick}`,
+//!   `{channel}`, `{server}` and `{users}` (how many are in the channel) get
+//!   replaced
+//! - `send-as`: `notice` (the default) or `message`, for a private message
+//! - `cooldown-minutes`: how long a host isn't greeted again after a greeting,
+//!   so users coming back under another nick aren't, 10 by default
+
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::storage::DataSet;
+use crate::template;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const DB_NAME: &str = "greet";
+pub const DATA_SET: DataSet = DataSet::new::<Greeted>(DB_NAME);
+const DEFAULT_COOLDOWN_MINUTES: u64 = 10;
+
+/// Users already greeted, by lowercase channel and lowercase nick.
+type Greeted = BTreeMap<String, BTreeSet<String>>;
+
+pub struct GreetPlugin {
+    /// Welcome message templates, by lowercase channel
+    greetings: HashMap<String, String>,
+    notice:    bool,
+    cooldown:  Duration,
+    greeted:   Arc<Mutex<Greeted>>,
+    /// When each host was last greeted
+    recent:    Mutex<HashMap<String, Instant>>,
+}
+
+impl GreetPlugin {
+    /// Whether the user should be greeted in the channel, remembering that
+    /// they were if so.
+    async fn first_join(
+        &self,
+        ctx: &PluginContext,
+        channel: &str,
+        user: &irc::User,
+    ) -> Result<bool> {
+        let mut greeted = self.greeted.lock().await;
+        if !greeted
+            .entry(channel.into())
+            .or_default()
+            .insert(user.nick.to_lowercase())
+        {
+            return Ok(false);
+        }
+        ctx.storage.save(DB_NAME, &*greeted).await?;
+
+        // A new nick from a host just greeted is most likely the same user
+        let mut recent = self.recent.lock().await;
+        recent.retain(|_, at| at.elapsed() < self.cooldown);
+        Ok(recent.insert(user.host.clone(), Instant::now()).is_none())
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for GreetPlugin {
+    type Plugin = GreetPlugin;
+
+    const MESSAGES: Subscription = Subscription::Commands(&["JOIN"]);
+    const NAME: &'static str = "greet";
+
+    async fn new(ctx: &PluginContext) -> Result<GreetPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let greetings = config
+            .iter()
+            .filter_map(|(key, template)| {
+                let channel = key.strip_prefix("greet ")?.trim();
+                Some((channel.to_lowercase(), template.clone()))
+            })
+            .collect();
+        let notice = match config.get("send-as").map(String::as_str) {
+            None | Some("notice") => true,
+            Some("message") => false,
+            Some(other) => return Err(anyhow!("[Greet] Invalid send-as: {}", other)),
+        };
+        let cooldown = match config.get("cooldown-minutes") {
+            Some(minutes) => minutes
+                .parse()
+                .map_err(|_| anyhow!("[Greet] Invalid cooldown-minutes: {}", minutes))?,
+            None => DEFAULT_COOLDOWN_MINUTES,
+        };
+
+        let greeted = Arc::new(Mutex::new(
+            ctx.storage.load(DB_NAME).await?.unwrap_or_default(),
+        ));
+        ctx.storage
+            .own(DB_NAME, greeted.clone(), |greeted: &mut Greeted, nick| {
+                let mut found = false;
+                for nicks in greeted.values_mut() {
+                    found |= nicks.remove(nick);
+                }
+                greeted.retain(|_, nicks| !nicks.is_empty());
+                found
+            });
+
+        Ok(GreetPlugin {
+            greetings,
+            notice,
+            cooldown: Duration::from_secs(cooldown * 60),
+            greeted,
+            recent: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for GreetPlugin {
+    async fn message(&self, ctx: &PluginContext, msg: &irc::Message) -> Result<()> {
+        // :nick!user@host JOIN #channel
+        let (user, channel) = match (&msg.command, msg.source_as_user(), &msg.target) {
+            (irc::Command::Join, Some(user), Some(channel)) => (user, channel),
+            _ => return Ok(()),
+        };
+        let key = channel.to_lowercase();
+        let template = match self.greetings.get(&key) {
+            Some(template) => template,
+            None => return Ok(()),
+        };
+        let users = {
+            let state = ctx.state.read().await;
+            // Users back from a netsplit were already here
+            if state.is_me(&user.nick) || state.netsplits.is_returning(&user.nick) {
+                return Ok(());
+            }
+            state.channel(channel).map_or(0, |c| c.users.len())
+        };
+        if !self.first_join(ctx, &key, &user).await? {
+            return Ok(());
+        }
+
+        info!("[{}] Greeting {} in {}", ctx.server, user.nick, channel);
+        let fields = json!({
+            "nick": user.nick,
+            "channel": channel,
+            "server": ctx.server,
+            "users": users,
+        });
+        let text = template::render(template, &fields);
+        let (nick, text) = (user.nick.as_str(), text.as_str());
+        let greeting = if self.notice {
+            irc::Outgoing::notice(nick, text)
+        } else {
+            irc::Outgoing::privmsg(nick, text)
+        };
+        ctx.send_outgoing(greeting).await
+    }
+}
@@ -0,0 +1,65 @@
+use crate::command;
+use crate::plugins::{Plugin, PluginContext};
+use crate::reply::Reply;
+use anyhow::Result;
+use async_trait::async_trait;
+
+pub const NAME: &str = "help";
+
+const HELP: command::Help = command::Help {
+    name:  "help",
+    usage: "help [command] - lists the available commands, or shows how to use one",
+};
+
+/// Lists the commands provided by all other loaded plugins.
+pub struct HelpPlugin {
+    commands: Vec<&'static command::Help>,
+}
+
+impl HelpPlugin {
+    pub fn new(mut commands: Vec<&'static command::Help>) -> Self {
+        commands.push(&HELP);
+        HelpPlugin { commands }
+    }
+
+    async fn reply(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Reply {
+        let language = ctx.i18n.language(&cmd.user.nick, &cmd.reply_to);
+        let usage = |help: &command::Help| {
+            let usage = ctx.i18n.translate(&language, help.usage);
+            format!("{}{}", cmd.prefix, usage)
+        };
+        if let Some(name) = &cmd.args {
+            let name = name.trim_start_matches(cmd.prefix.as_str()).to_lowercase();
+            return match self.commands.iter().find(|c| c.name == name) {
+                Some(help) => Reply::line(cmd, usage(help)),
+                None => Reply::line(
+                    cmd,
+                    ctx.tr(cmd, "{0}: No such command `{1}`", &[&cmd.user.nick, &name]),
+                ),
+            };
+        }
+
+        let names: Vec<String> = self
+            .commands
+            .iter()
+            .map(|c| format!("{}{}", cmd.prefix, c.name))
+            .collect();
+        let mut lines = vec![ctx.tr(
+            cmd,
+            "Commands: {0} (use {1}help <command> for details)",
+            &[&names.join(", "), &cmd.prefix],
+        )];
+        lines.extend(self.commands.iter().map(|c| usage(c)));
+        Reply::pasted(ctx, cmd, lines).await
+    }
+}
+
+#[async_trait]
+impl Plugin for HelpPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name == HELP.name {
+            self.reply(ctx, cmd).await.send(ctx).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Lets users pick the language the bot replies to them in with `\lang`,
+//! over the one configured for the channel.
+
+use crate::command;
+use crate::i18n::ENGLISH;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use anyhow::Result;
+use async_trait::async_trait;
+use log::*;
+
+const LANG: command::Help = command::Help {
+    name:  "lang",
+    usage: "lang [<language>|default] - shows or picks the language of the replies to you",
+};
+
+pub struct LangPlugin;
+
+#[async_trait]
+impl PluginBuilder for LangPlugin {
+    type Plugin = LangPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[LANG];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "lang";
+
+    async fn new(_ctx: &PluginContext) -> Result<LangPlugin> {
+        Ok(LangPlugin)
+    }
+}
+
+#[async_trait]
+impl Plugin for LangPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != LANG.name {
+            return Ok(());
+        }
+        let nick = cmd.user.nick.as_str();
+        let mut languages = vec![ENGLISH];
+        languages.extend(ctx.i18n.languages());
+        let available = languages.join(", ");
+
+        let args = cmd.parse_args();
+        let words: Vec<&str> = args.words().collect();
+        let reply = match words[..] {
+            [] => {
+                let language = ctx.i18n.language(nick, &cmd.reply_to);
+                ctx.tr(
+                    cmd,
+                    "{0}: Replying to you in {1}, out of {2}",
+                    &[nick, &language, &available],
+                )
+            },
+            ["default"] => {
+                ctx.i18n.pick(nick, None).await?;
+                ctx.tr(
+                    cmd,
+                    "{0}: Replying to you in the channel's language",
+                    &[nick],
+                )
+            },
+            [language] if languages.contains(&language) => {
+                ctx.i18n.pick(nick, Some(language)).await?;
+                debug!("[{}] {} picked {}", ctx.server, nick, language);
+                ctx.tr(cmd, "{0}: Replying to you in {1}", &[nick, language])
+            },
+            [language] => ctx.tr(
+                cmd,
+                "{0}: There's no {1} translation, only {2}",
+                &[nick, language, &available],
+            ),
+            _ => ctx.usage(cmd, LANG.usage),
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
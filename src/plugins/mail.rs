@@ -0,0 +1,456 @@
+//! Announces new mail arriving in an IMAP mailbox, like cron or monitoring
+//! notifications.
+//!
+//! Configuration:
+//! - `host`, `username` and `password`: the IMAP account, reached over TLS
+//! - `port`: 993 by default
+//! - `mailbox`: `INBOX` by default, only ever opened read-only
+//! - `channel`: where to announce new mail
+//! - `from`: space separated glob patterns for the sender addresses to
+//!   announce, like `*@example.com`, all by default
+//! - `subject`: glob pattern the subject must match, like `*backup*`, any by
+//!   default
+//! - `poll-minutes`: how often to check the mailbox, 5 by default
+
+use crate::irc;
+use crate::net;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::reply::truncate;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_native_tls::{native_tls, TlsConnector, TlsStream};
+
+const DEFAULT_PORT: u16 = 993;
+const DEFAULT_MAILBOX: &str = "INBOX";
+const DEFAULT_POLL_MINUTES: u64 = 5;
+/// How long a whole check of the mailbox may take
+const CHECK_TIMEOUT: Duration = Duration::from_secs(60);
+/// Most mails announced per check, so a flood of them doesn't flood the channel
+const MAX_MAILS: usize = 5;
+/// Longest subject announced, in characters
+const MAX_SUBJECT: usize = 300;
+
+#[derive(Clone)]
+struct Account {
+    host:     String,
+    port:     u16,
+    username: String,
+    password: String,
+    mailbox:  String,
+}
+
+/// A response line from the server, along with the literals sent in it.
+#[derive(Default)]
+struct Response {
+    line:     String,
+    literals: Vec<Vec<u8>>,
+}
+
+/// Just enough of an IMAP client to look for new mail.
+struct Session {
+    stream: BufReader<TlsStream<TcpStream>>,
+    tag:    u32,
+}
+
+impl Session {
+    async fn connect(account: &Account) -> Result<Session> {
+        let connector = TlsConnector::from(native_tls::TlsConnector::new()?);
+        let stream = net::connect(&account.host, account.port, net::IpVersion::Any).await?;
+        let stream = connector.connect(&account.host, stream).await?;
+        let mut session = Session {
+            stream: BufReader::new(stream),
+            tag:    0,
+        };
+        let greeting = session.read_response().await?;
+        if !greeting.line.starts_with("* OK") {
+            return Err(anyhow!("unexpected greeting: {}", greeting.line));
+        }
+        Ok(session)
+    }
+
+    async fn read_response(&mut self) -> Result<Response> {
+        let mut response = Response::default();
+        loop {
+            let mut line = vec![];
+            if self.stream.read_until(b'\n', &mut line).await? == 0 {
+                return Err(anyhow!("connection closed"));
+            }
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end();
+            response.line += line;
+            match literal_size(line) {
+                Some(size) => {
+                    let mut literal = vec![0; size];
+                    self.stream.read_exact(&mut literal).await?;
+                    response.literals.push(literal);
+                },
+                None => return Ok(response),
+            }
+        }
+    }
+
+    /// Sends a command, returning the untagged responses to it once it
+    /// completes successfully.
+    async fn command(&mut self, command: &str) -> Result<Vec<Response>> {
+        self.tag += 1;
+        let tag = format!("a{}", self.tag);
+        self.stream
+            .write_all(format!("{} {}\r\n", tag, command).as_bytes())
+            .await?;
+        self.stream.flush().await?;
+
+        let mut responses = vec![];
+        loop {
+            let response = self.read_response().await?;
+            match response.line.strip_prefix(&tag) {
+                Some(status) if status.trim_start().starts_with("OK") => return Ok(responses),
+                Some(status) => return Err(anyhow!("{}", status.trim())),
+                None => responses.push(response),
+            }
+        }
+    }
+}
+
+/// The size of the literal announced at the end of a line, like `{42}`.
+fn literal_size(line: &str) -> Option<usize> {
+    let start = line.strip_suffix('}')?.rfind('{')?;
+    line[start + 1 .. line.len() - 1].parse().ok()
+}
+
+/// Quotes a string for use in a command.
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The number in a response code like `[UIDNEXT 42]`.
+fn response_code(responses: &[Response], code: &str) -> Option<u32> {
+    let prefix = format!("[{} ", code);
+    responses.iter().find_map(|response| {
+        let start = response.line.find(&prefix)? + prefix.len();
+        let rest = &response.line[start ..];
+        rest[.. rest.find(']')?].parse().ok()
+    })
+}
+
+/// The number following an item in a response, like `UID 42`.
+fn item(line: &str, name: &str) -> Option<u32> {
+    let mut words = line.split([' ', '(', ')']);
+    words.find(|word| word.eq_ignore_ascii_case(name))?;
+    words.next()?.parse().ok()
+}
+
+/// A header's value, joining folded lines and decoding MIME encoded words.
+fn header(headers: &str, name: &str) -> Option<String> {
+    let mut value: Option<String> = None;
+    for line in headers.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(value) = &mut value {
+                *value += line;
+            }
+        } else if value.is_some() {
+            break;
+        } else if let Some((key, rest)) = line.split_once(':') {
+            if key.trim().eq_ignore_ascii_case(name) {
+                value = Some(rest.trim().into());
+            }
+        }
+    }
+    value.map(|value| decode_header(&value))
+}
+
+/// Decodes the `=?charset?encoding?text?=` words in a header (RFC 2047).
+fn decode_header(value: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (before, word) = rest.split_at(start);
+        match decode_word(word) {
+            Some((text, len)) => {
+                // Whitespace between encoded words isn't part of the text
+                if !after_word || !before.trim().is_empty() {
+                    decoded += before;
+                }
+                decoded += &text;
+                rest = &word[len ..];
+                after_word = true;
+            },
+            None => {
+                decoded += before;
+                decoded += "=?";
+                rest = &word[2 ..];
+                after_word = false;
+            },
+        }
+    }
+    decoded + rest
+}
+
+/// Decodes an encoded word at the start of the text, returning it along with
+/// how long it was.
+fn decode_word(word: &str) -> Option<(String, usize)> {
+    let (charset, rest) = word.strip_prefix("=?")?.split_once('?')?;
+    let (encoding, rest) = rest.split_once('?')?;
+    let end = rest.find("?=")?;
+    let bytes = match encoding {
+        "B" | "b" => base64::decode(&rest[.. end]).ok()?,
+        "Q" | "q" => {
+            let mut bytes = vec![];
+            let mut text = rest[.. end].bytes();
+            while let Some(byte) = text.next() {
+                match byte {
+                    b'_' => bytes.push(b' '),
+                    b'=' => {
+                        let hex = [text.next()?, text.next()?];
+                        bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+                    },
+                    byte => bytes.push(byte),
+                }
+            }
+            bytes
+        },
+        _ => return None,
+    };
+    let len = 2 + charset.len() + 1 + encoding.len() + 1 + end + 2;
+    // Anything besides UTF-8 is most likely Latin-1, which maps straight to
+    // the first Unicode characters
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().into_iter().map(char::from).collect(),
+    };
+    Some((text, len))
+}
+
+/// Splits a `From` header into the sender's name, if any, and address.
+fn sender(from: &str) -> (Option<String>, String) {
+    match (from.rfind('<'), from.rfind('>')) {
+        (Some(start), Some(end)) if start < end => {
+            let name = from[.. start].trim().trim_matches('"').trim();
+            let address = from[start + 1 .. end].trim().into();
+            (
+                Some(name.to_string()).filter(|name| !name.is_empty()),
+                address,
+            )
+        },
+        _ => (None, from.trim().into()),
+    }
+}
+
+/// Checks the mailbox and announces new mail.
+#[derive(Clone)]
+struct Announcer {
+    ctx:      PluginContext,
+    account:  Account,
+    channel:  String,
+    from:     Vec<String>,
+    subject:  Option<String>,
+    /// The mailbox's UIDVALIDITY and the UID the next new mail will get, or
+    /// none before the first check so old mail isn't announced
+    next_uid: Arc<Mutex<Option<(u32, u32)>>>,
+}
+
+impl Announcer {
+    fn wanted(&self, address: &str, subject: &str) -> bool {
+        let address = address.to_lowercase();
+        let from = self.from.is_empty() || self.from.iter().any(|p| irc::glob(p, &address));
+        let subject = match &self.subject {
+            Some(pattern) => irc::glob(pattern, &subject.to_lowercase()),
+            None => true,
+        };
+        from && subject
+    }
+
+    /// Fetches the senders and subjects of the mail that arrived since the
+    /// last check.
+    async fn new_mail(&self) -> Result<Vec<(String, String)>> {
+        let mut session = Session::connect(&self.account).await?;
+        let login = format!(
+            "LOGIN {} {}",
+            quote(&self.account.username),
+            quote(&self.account.password)
+        );
+        session.command(&login).await?;
+        let examined = session
+            .command(&format!("EXAMINE {}", quote(&self.account.mailbox)))
+            .await?;
+        let validity = response_code(&examined, "UIDVALIDITY")
+            .ok_or_else(|| anyhow!("no UIDVALIDITY for the mailbox"))?;
+        let uid_next = response_code(&examined, "UIDNEXT")
+            .ok_or_else(|| anyhow!("no UIDNEXT for the mailbox"))?;
+
+        let mut next_uid = self.next_uid.lock().await;
+        let since = match *next_uid {
+            Some((known_validity, since)) if known_validity == validity => since,
+            // First check, or the UIDs were reset
+            _ => {
+                *next_uid = Some((validity, uid_next));
+                return Ok(vec![]);
+            },
+        };
+
+        let search = session
+            .command(&format!("UID SEARCH UID {}:*", since))
+            .await?;
+        // The range always includes the newest mail, even if it's older
+        let uids: Vec<u32> = search
+            .iter()
+            .filter_map(|response| response.line.strip_prefix("* SEARCH"))
+            .flat_map(|uids| uids.split_whitespace().filter_map(|uid| uid.parse().ok()))
+            .filter(|uid| *uid >= since)
+            .collect();
+        let mut mails = vec![];
+        if !uids.is_empty() {
+            let uid_set: Vec<String> = uids.iter().map(u32::to_string).collect();
+            let fetched = session
+                .command(&format!(
+                    "UID FETCH {} (UID BODY.PEEK[HEADER.FIELDS (FROM SUBJECT)])",
+                    uid_set.join(",")
+                ))
+                .await?;
+            let mut fetched: Vec<(u32, String)> = fetched
+                .iter()
+                .filter_map(|response| {
+                    let uid = item(&response.line, "UID")?;
+                    let headers = String::from_utf8_lossy(response.literals.first()?);
+                    Some((uid, headers.into_owned()))
+                })
+                .collect();
+            fetched.sort();
+            for (_, headers) in fetched {
+                let from = header(&headers, "From").unwrap_or_default();
+                let subject = header(&headers, "Subject").unwrap_or_default();
+                mails.push((from, subject));
+            }
+        }
+        let newest = uids.iter().max().map_or(0, |uid| uid + 1);
+        *next_uid = Some((validity, uid_next.max(newest)));
+        drop(next_uid);
+
+        if let Err(e) = session.command("LOGOUT").await {
+            debug!("[{}] Mail logout failed: {}", self.ctx.server, e);
+        }
+        Ok(mails)
+    }
+
+    async fn poll(self) {
+        let mails = match tokio::time::timeout(CHECK_TIMEOUT, self.new_mail()).await {
+            Ok(Ok(mails)) => mails,
+            Ok(Err(e)) => {
+                warn!("[{}] Could not check for new mail: {}", self.ctx.server, e);
+                return;
+            },
+            Err(_) => {
+                warn!("[{}] Checking for new mail timed out", self.ctx.server);
+                return;
+            },
+        };
+
+        let mut lines = vec![];
+        for (from, subject) in &mails {
+            let (name, address) = sender(from);
+            if !self.wanted(&address, subject) {
+                continue;
+            }
+            let subject = if subject.is_empty() {
+                "(no subject)".into()
+            } else {
+                truncate(subject, MAX_SUBJECT)
+            };
+            lines.push(format!(
+                "📧 Mail from {}: {}",
+                name.unwrap_or(address),
+                subject
+            ));
+        }
+        if lines.len() > MAX_MAILS {
+            let more = lines.len() - MAX_MAILS;
+            lines.truncate(MAX_MAILS);
+            lines.push(format!("📧 …and {} more", more));
+        }
+        for line in lines {
+            let msg = irc::Outgoing::privmsg(self.channel.as_str(), line.as_str());
+            if let Err(e) = self.ctx.send_outgoing(msg).await {
+                warn!("[{}] Could not announce mail: {}", self.ctx.server, e);
+            }
+        }
+    }
+}
+
+pub struct MailPlugin;
+
+#[async_trait]
+impl PluginBuilder for MailPlugin {
+    type Plugin = MailPlugin;
+
+    const COMMANDS: &'static [crate::command::Help] = &[];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "mail";
+
+    async fn new(ctx: &PluginContext) -> Result<MailPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let required = ["host", "username", "password", "channel"];
+        let (host, username, password, channel) = match required.map(|key| config.get(key)) {
+            [Some(host), Some(username), Some(password), Some(channel)] => (
+                host.clone(),
+                username.clone(),
+                password.clone(),
+                channel.clone(),
+            ),
+            _ => {
+                warn!(
+                    "[{}] Mail needs `host`, `username`, `password` and `channel` in its config",
+                    ctx.server
+                );
+                return Ok(MailPlugin);
+            },
+        };
+        let port = match config.get("port") {
+            Some(port) => port
+                .parse()
+                .map_err(|_| anyhow!("[Mail] Invalid port: {}", port))?,
+            None => DEFAULT_PORT,
+        };
+        let poll_minutes = match config.get("poll-minutes") {
+            Some(minutes) => minutes
+                .parse()
+                .map_err(|_| anyhow!("[Mail] Invalid poll-minutes: {}", minutes))?,
+            None => DEFAULT_POLL_MINUTES,
+        };
+
+        let announcer = Announcer {
+            ctx: ctx.clone(),
+            account: Account {
+                host,
+                port,
+                username,
+                password,
+                mailbox: config
+                    .get("mailbox")
+                    .cloned()
+                    .unwrap_or_else(|| DEFAULT_MAILBOX.into()),
+            },
+            channel,
+            from: config
+                .get("from")
+                .map(|from| from.split_whitespace().map(str::to_lowercase).collect())
+                .unwrap_or_default(),
+            subject: config.get("subject").map(|subject| subject.to_lowercase()),
+            next_uid: Arc::new(Mutex::new(None)),
+        };
+        ctx.scheduler
+            .after(Duration::ZERO, announcer.clone().poll());
+        ctx.scheduler
+            .every(Duration::from_secs(poll_minutes * 60), move || {
+                announcer.clone().poll()
+            });
+        Ok(MailPlugin)
+    }
+}
+
+impl Plugin for MailPlugin {}
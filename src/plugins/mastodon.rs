@@ -0,0 +1,332 @@
+//! Relays new posts from Mastodon accounts and hashtags to channels, through
+//! the instances' public APIs.
+//!
+//! Configuration:
+//! - `follow #channel`: space separated accounts like `@user@instance` and
+//!   hashtags like `#tag@instance` relayed to the channel
+//! - `poll-minutes`: how often to check for new posts, 5 by default
+//! - `format post`: how to relay posts, with `{author}`, `{acct}`, `{text}`,
+//!   `{warning}` (the content warning, if any) and `{url}`, also as `format
+//!   post #channel`
+
+use crate::http;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::reply::truncate;
+use crate::template;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+const DEFAULT_POLL_MINUTES: u64 = 5;
+/// Longest post text relayed, in characters
+const MAX_TEXT: usize = 300;
+/// Most posts relayed from one source per poll, so a burst doesn't flood
+const MAX_POSTS: usize = 3;
+
+/// Something to follow on an instance.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Source {
+    Account { user: String, instance: String },
+    Hashtag { tag: String, instance: String },
+}
+
+impl Source {
+    fn parse(text: &str) -> Result<Source> {
+        let invalid = || anyhow!("[Mastodon] Invalid account or hashtag: {}", text);
+        if let Some(account) = text.strip_prefix('@') {
+            let (user, instance) = account.split_once('@').ok_or_else(invalid)?;
+            Ok(Source::Account {
+                user:     user.into(),
+                instance: instance.to_lowercase(),
+            })
+        } else if let Some(hashtag) = text.strip_prefix('#') {
+            let (tag, instance) = hashtag.split_once('@').ok_or_else(invalid)?;
+            Ok(Source::Hashtag {
+                tag:      tag.to_lowercase(),
+                instance: instance.to_lowercase(),
+            })
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Account {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    id:           String,
+    url:          Option<String>,
+    uri:          String,
+    #[serde(default)]
+    content:      String,
+    #[serde(default)]
+    spoiler_text: String,
+    account:      Author,
+}
+
+#[derive(Deserialize)]
+struct Author {
+    acct:         String,
+    #[serde(default)]
+    display_name: String,
+}
+
+impl Status {
+    fn describe(&self) -> String {
+        let author = if self.account.display_name.is_empty() {
+            format!("@{}", self.account.acct)
+        } else {
+            format!("{} (@{})", self.account.display_name, self.account.acct)
+        };
+        let link = self.url.as_ref().unwrap_or(&self.uri);
+        // Posts behind a content warning only show the warning
+        if !self.spoiler_text.is_empty() {
+            return format!("{}: [CW: {}] 〜 {}", author, self.spoiler_text.trim(), link);
+        }
+        let text = html_to_text(&self.content);
+        format!("{}: {} 〜 {}", author, truncate(&text, MAX_TEXT), link)
+    }
+
+    fn fields(&self) -> Value {
+        let author = if self.account.display_name.is_empty() {
+            &self.account.acct
+        } else {
+            &self.account.display_name
+        };
+        json!({
+            "author": author,
+            "acct": self.account.acct,
+            "text": truncate(&html_to_text(&self.content), MAX_TEXT),
+            "warning": self.spoiler_text.trim(),
+            "url": self.url.as_ref().unwrap_or(&self.uri),
+        })
+    }
+}
+
+/// Turns post HTML into a single line of text, with paragraphs and line
+/// breaks separated by ` / `.
+fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text += &rest[.. start];
+        let end = match rest[start ..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = rest[start + 1 .. end]
+            .trim_start_matches('/')
+            .to_lowercase();
+        if (tag.starts_with("br") || tag == "p")
+            && !text.trim_end().is_empty()
+            && !text.ends_with(" / ")
+        {
+            text += " / ";
+        }
+        rest = &rest[end + 1 ..];
+    }
+    text += rest;
+    decode_entities(text.trim().trim_end_matches(" /"))
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded += &rest[.. start];
+        rest = &rest[start ..];
+        let entity = rest.find(';').map(|end| (&rest[1 .. end], end));
+        let character = entity.and_then(|(name, _)| match name {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => name.strip_prefix('#')?.parse().ok(),
+                };
+                char::from_u32(code?)
+            },
+        });
+        match (character, entity) {
+            (Some(character), Some((_, end))) => {
+                decoded.push(character);
+                rest = &rest[end + 1 ..];
+            },
+            _ => {
+                decoded.push('&');
+                rest = &rest[1 ..];
+            },
+        }
+    }
+    decoded + rest
+}
+
+/// Polls the followed sources and relays new posts.
+#[derive(Clone)]
+struct Relay {
+    ctx:      PluginContext,
+    client:   http::Client,
+    /// Channels to relay each source to
+    sources:  HashMap<Source, Vec<String>>,
+    /// Account ids on their instance, looked up once
+    accounts: Arc<Mutex<HashMap<Source, String>>>,
+    /// Newest post seen from each source, or none before its first poll so
+    /// older posts aren't relayed
+    newest:   Arc<Mutex<HashMap<Source, String>>>,
+}
+
+impl Relay {
+    async fn account_id(&self, source: &Source, user: &str, instance: &str) -> Result<String> {
+        if let Some(id) = self.accounts.lock().await.get(source) {
+            return Ok(id.clone());
+        }
+        let account: Account = self
+            .client
+            .get(&format!("https://{}/api/v1/accounts/lookup", instance))
+            .query(&[("acct", user)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        self.accounts
+            .lock()
+            .await
+            .insert(source.clone(), account.id.clone());
+        Ok(account.id)
+    }
+
+    /// Posts newer than `since`, newest first.
+    async fn statuses(&self, source: &Source, since: Option<&str>) -> Result<Vec<Status>> {
+        let (url, mut query) = match source {
+            Source::Account { user, instance } => {
+                let id = self.account_id(source, user, instance).await?;
+                let url = format!("https://{}/api/v1/accounts/{}/statuses", instance, id);
+                (
+                    url,
+                    vec![("exclude_replies", "true"), ("exclude_reblogs", "true")],
+                )
+            },
+            Source::Hashtag { tag, instance } => {
+                let url = format!("https://{}/api/v1/timelines/tag/{}", instance, tag);
+                (url, vec![])
+            },
+        };
+        if let Some(since) = since {
+            query.push(("since_id", since));
+        } else {
+            query.push(("limit", "1"));
+        }
+        Ok(self
+            .client
+            .get(&url)
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn poll(self) {
+        for (source, channels) in &self.sources {
+            let since = self.newest.lock().await.get(source).cloned();
+            let statuses = match self.statuses(source, since.as_deref()).await {
+                Ok(statuses) => statuses,
+                Err(e) => {
+                    warn!("[{}] Could not check {:?}: {}", self.ctx.server, source, e);
+                    continue;
+                },
+            };
+            if let Some(newest) = statuses.first() {
+                self.newest
+                    .lock()
+                    .await
+                    .insert(source.clone(), newest.id.clone());
+            }
+            if since.is_none() {
+                continue;
+            }
+
+            let fresh = statuses.iter().take(MAX_POSTS).rev();
+            for status in fresh {
+                for channel in channels {
+                    let text = match self.ctx.format("post", channel) {
+                        Some(format) => template::render(format, &status.fields()),
+                        None => status.describe(),
+                    };
+                    let msg = irc::Outgoing::privmsg(channel.as_str(), text.as_str());
+                    if let Err(e) = self.ctx.send_outgoing(msg).await {
+                        warn!("[{}] Could not relay a post: {}", self.ctx.server, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct MastodonPlugin;
+
+#[async_trait]
+impl PluginBuilder for MastodonPlugin {
+    type Plugin = MastodonPlugin;
+
+    const COMMANDS: &'static [crate::command::Help] = &[];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "mastodon";
+
+    async fn new(ctx: &PluginContext) -> Result<MastodonPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let mut sources: HashMap<Source, Vec<String>> = HashMap::new();
+        for (key, follows) in &config {
+            if let Some(channel) = key.strip_prefix("follow ") {
+                for follow in follows.split_whitespace() {
+                    sources
+                        .entry(Source::parse(follow)?)
+                        .or_default()
+                        .push(channel.trim().into());
+                }
+            }
+        }
+        if sources.is_empty() {
+            return Ok(MastodonPlugin);
+        }
+        let poll_minutes = match config.get("poll-minutes") {
+            Some(minutes) => minutes
+                .parse()
+                .map_err(|_| anyhow!("[Mastodon] Invalid poll-minutes: {}", minutes))?,
+            None => DEFAULT_POLL_MINUTES,
+        };
+
+        let relay = Relay {
+            ctx: ctx.clone(),
+            client: ctx.http.client(),
+            sources,
+            accounts: Arc::new(Mutex::new(HashMap::new())),
+            newest: Arc::new(Mutex::new(HashMap::new())),
+        };
+        ctx.scheduler.after(Duration::ZERO, relay.clone().poll());
+        ctx.scheduler
+            .every(Duration::from_secs(poll_minutes * 60), move || {
+                relay.clone().poll()
+            });
+        Ok(MastodonPlugin)
+    }
+}
+
+impl Plugin for MastodonPlugin {}
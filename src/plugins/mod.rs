@@ -1,44 +1,798 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+use log::*;
+use std::fmt::Debug;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
 use tokio::task::JoinHandle;
 
+use crate::acl::Acl;
+use crate::awards::Awards;
+use crate::batch::Batch;
 use crate::bot;
+use crate::chat::ChatEvent;
+use crate::command::{self, Lifecycle};
+use crate::filter::Filters;
+#[cfg(feature = "http-client")]
+use crate::http::Http;
+use crate::i18n::I18n;
 use crate::irc;
+use crate::messages::Messages;
+use crate::metrics;
+use crate::notices::ServerEvent;
+use crate::ops::Ops;
+#[cfg(feature = "http-client")]
+use crate::paste::Paster;
+use crate::prefs::Prefs;
+use crate::reply::Reply;
+use crate::runtime::Runtime;
+use crate::scheduler::Scheduler;
+#[cfg(feature = "http-client")]
+use crate::shortener::Shortener;
+use crate::state;
+use crate::storage::Storage;
+use crate::tasks::{self, TaskSet};
+use crate::template;
+use crate::watchdog::{self, WatchdogConfig};
 
+pub mod admin;
+#[cfg(feature = "alertmanager")]
+pub mod alertmanager;
+#[cfg(feature = "astronomy")]
+pub mod astronomy;
+#[cfg(feature = "bans")]
+pub mod bans;
+#[cfg(feature = "chanstats")]
+pub mod chanstats;
+#[cfg(feature = "cve")]
+pub mod cve;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
+#[cfg(feature = "dns")]
+pub mod dns;
+#[cfg(feature = "echo")]
 pub mod echo;
+#[cfg(feature = "f1")]
+pub mod f1;
+#[cfg(feature = "football")]
+pub mod football;
+#[cfg(feature = "github")]
+pub mod github;
+#[cfg(feature = "greet")]
+pub mod greet;
+pub mod help;
+#[cfg(feature = "lang")]
+pub mod lang;
+#[cfg(feature = "mail")]
+pub mod mail;
+#[cfg(feature = "mastodon")]
+pub mod mastodon;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "notify")]
+pub mod notify;
+#[cfg(feature = "oper")]
+pub mod oper;
+#[cfg(feature = "packages")]
+pub mod packages;
+#[cfg(feature = "prefs")]
+pub mod prefs;
+#[cfg(feature = "process")]
+pub mod process;
+#[cfg(feature = "rank")]
+pub mod rank;
+#[cfg(feature = "recap")]
+pub mod recap;
+#[cfg(feature = "scripting")]
+pub mod script;
+#[cfg(feature = "search")]
+pub mod search;
+#[cfg(feature = "tmdb")]
+pub mod tmdb;
+#[cfg(feature = "triggers")]
+pub mod triggers;
+#[cfg(feature = "twitch")]
+pub mod twitch;
+pub mod version;
+#[cfg(feature = "wallops")]
+pub mod wallops;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "weather")]
 pub mod weather;
+#[cfg(feature = "webhook")]
+pub mod webhook;
 
 use std::collections::HashMap;
-pub async fn spawn_plugins(
+
+/// Facilities of a bot shared by all of its plugins.
+pub struct Core {
+    pub state:     state::Handle,
+    pub scheduler: Scheduler,
+    pub admins:    Acl,
+    pub owners:    Acl,
+    pub messages:  Messages,
+    pub ops:       Ops,
+    #[cfg(feature = "http-client")]
+    pub shortener: Shortener,
+    #[cfg(feature = "http-client")]
+    pub paster:    Paster,
+    #[cfg(feature = "http-client")]
+    pub http:      Http,
+    pub storage:   Storage,
+    pub runtime:   Runtime,
+    pub awards:    Awards,
+    pub i18n:      I18n,
+    pub filters:   Filters,
+    pub prefs:     Prefs,
+    pub watchdog:  WatchdogConfig,
+}
+
+impl Core {
+    /// The context of the plugin called `name`, given its section of the
+    /// configuration.
+    pub(crate) fn context(
+        &self,
+        irc: &irc::IRC,
+        name: &'static str,
+        config: Option<bot::PluginConfig>,
+    ) -> PluginContext {
+        PluginContext {
+            name,
+            server: irc.server.clone(),
+            config,
+            state: self.state.clone(),
+            storage: self.storage.clone(),
+            scheduler: self.scheduler.clone(),
+            admins: self.admins.clone(),
+            owners: self.owners.clone(),
+            messages: self.messages.clone(),
+            ops: self.ops.clone(),
+            #[cfg(feature = "http-client")]
+            shortener: self.shortener.clone(),
+            #[cfg(feature = "http-client")]
+            paster: self.paster.clone(),
+            #[cfg(feature = "http-client")]
+            http: self.http.clone(),
+            runtime: self.runtime.clone(),
+            awards: self.awards.clone(),
+            i18n: self.i18n.clone(),
+            filters: self.filters.clone(),
+            watchdog: self.watchdog.clone(),
+            prefs: self.prefs.clone(),
+            send_messages: irc.send_messages.clone(),
+        }
+    }
+}
+
+/// Builds a plugin and spawns it with its context.
+type Spawner =
+    for<'a> fn(PluginContext, &'a command::Router) -> BoxFuture<'a, Result<JoinHandle<Result<()>>>>;
+
+#[derive(Clone)]
+struct Registration {
+    name:     &'static str,
+    commands: &'static [command::Help],
+    spawn:    Spawner,
+}
+
+/// The plugins every bot loads, in order, on top of `\help` and `\version`
+/// that all of them have.
+///
+/// ```no_run
+/// # struct MyPlugin;
+/// # #[async_trait::async_trait]
+/// # impl boton::plugins::Plugin for MyPlugin {}
+/// # #[async_trait::async_trait]
+/// # impl boton::plugins::PluginBuilder for MyPlugin {
+/// #     const NAME: &'static str = "mine";
+/// #     type Plugin = MyPlugin;
+/// #     async fn new(_: &boton::plugins::PluginContext) -> anyhow::Result<MyPlugin> {
+/// #         Ok(MyPlugin)
+/// #     }
+/// # }
+/// let mut plugins = boton::plugins::Registry::bundled();
+/// plugins.add::<MyPlugin>();
+/// ```
+#[derive(Clone, Default)]
+pub struct Registry {
+    plugins: Vec<Registration>,
+}
+
+impl Registry {
+    /// No plugins besides `\help` and `\version`.
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    /// The plugins that come with boton, those it was built with.
+    pub fn bundled() -> Self {
+        let mut plugins = Registry::new();
+        plugins.add::<admin::AdminPlugin>();
+        #[cfg(feature = "bans")]
+        plugins.add::<bans::BansPlugin>();
+        #[cfg(feature = "oper")]
+        plugins.add::<oper::OperPlugin>();
+        #[cfg(feature = "greet")]
+        plugins.add::<greet::GreetPlugin>();
+        #[cfg(feature = "triggers")]
+        plugins.add::<triggers::TriggersPlugin>();
+        #[cfg(feature = "notify")]
+        plugins.add::<notify::NotifyPlugin>();
+        #[cfg(feature = "recap")]
+        plugins.add::<recap::RecapPlugin>();
+        #[cfg(feature = "chanstats")]
+        plugins.add::<chanstats::ChanstatsPlugin>();
+        #[cfg(feature = "rank")]
+        plugins.add::<rank::RankPlugin>();
+        #[cfg(feature = "lang")]
+        plugins.add::<lang::LangPlugin>();
+        #[cfg(feature = "prefs")]
+        plugins.add::<prefs::PrefsPlugin>();
+        #[cfg(feature = "echo")]
+        plugins.add::<echo::EchoPlugin>();
+        #[cfg(feature = "weather")]
+        plugins.add::<weather::WeatherPlugin>();
+        #[cfg(feature = "astronomy")]
+        plugins.add::<astronomy::AstronomyPlugin>();
+        #[cfg(feature = "f1")]
+        plugins.add::<f1::F1Plugin>();
+        #[cfg(feature = "football")]
+        plugins.add::<football::FootballPlugin>();
+        #[cfg(feature = "twitch")]
+        plugins.add::<twitch::TwitchPlugin>();
+        #[cfg(feature = "mastodon")]
+        plugins.add::<mastodon::MastodonPlugin>();
+        #[cfg(feature = "mail")]
+        plugins.add::<mail::MailPlugin>();
+        #[cfg(feature = "webhook")]
+        plugins.add::<webhook::WebhookPlugin>();
+        #[cfg(feature = "alertmanager")]
+        plugins.add::<alertmanager::AlertmanagerPlugin>();
+        #[cfg(feature = "mqtt")]
+        plugins.add::<mqtt::MqttPlugin>();
+        #[cfg(feature = "search")]
+        plugins.add::<search::SearchPlugin>();
+        #[cfg(feature = "tmdb")]
+        plugins.add::<tmdb::TmdbPlugin>();
+        #[cfg(feature = "packages")]
+        plugins.add::<packages::PackagesPlugin>();
+        #[cfg(feature = "github")]
+        plugins.add::<github::GithubPlugin>();
+        #[cfg(feature = "cve")]
+        plugins.add::<cve::CvePlugin>();
+        #[cfg(feature = "dns")]
+        plugins.add::<dns::DnsPlugin>();
+        #[cfg(feature = "diagnostics")]
+        plugins.add::<diagnostics::DiagnosticsPlugin>();
+        #[cfg(feature = "process")]
+        plugins.add::<process::ProcessPlugin>();
+        #[cfg(feature = "scripting")]
+        plugins.add::<script::ScriptPlugin>();
+        #[cfg(feature = "wallops")]
+        plugins.add::<wallops::WallopsPlugin>();
+        #[cfg(feature = "wasm")]
+        plugins.add::<wasm::WasmPlugin>();
+        plugins
+    }
+
+    /// Adds the plugin built by `B`, taking the place of any other one of the
+    /// same name.
+    pub fn add<B: PluginBuilder>(&mut self) -> &mut Self {
+        let registration = Registration {
+            name:     B::NAME,
+            commands: B::COMMANDS,
+            spawn:    |ctx, router| {
+                async move {
+                    let plugin = B::new(&ctx).await?;
+                    Ok(spawn_plugin(plugin, ctx, router, B::HISTORY, B::MESSAGES))
+                }
+                .boxed()
+            },
+        };
+        match self.plugins.iter_mut().find(|r| r.name == B::NAME) {
+            Some(existing) => *existing = registration,
+            None => self.plugins.push(registration),
+        }
+        self
+    }
+
+    /// Removes the plugin called `name`, if there.
+    pub fn remove(&mut self, name: &str) -> &mut Self {
+        self.plugins.retain(|r| r.name != name);
+        self
+    }
+
+    /// Names of the plugins, in the order they're loaded.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.plugins.iter().map(|r| r.name)
+    }
+}
+
+/// Setting of any plugin keeping it from being loaded when `false`
+const ENABLED: &str = "enabled";
+
+/// Builds and spawns every plugin of the registry not disabled in its
+/// settings, then `\help` and `\version`, returning their tasks by name.
+pub(crate) async fn spawn_plugins(
     irc: &irc::IRC,
+    router: &command::Router,
+    core: &Core,
     config: HashMap<String, bot::PluginConfig>,
+    registry: &Registry,
 ) -> Result<HashMap<String, JoinHandle<Result<()>>>> {
-    macro_rules! spawn_plugin {
-        ($p:ident, $ty:ty) => {
-            let plug = <$ty>::new(&irc.server, config.get(<$ty>::NAME)).await?;
-            let plug = plug.spawn_task(irc.clone())?;
-            $p.insert(<$ty>::NAME.into(), plug);
-        };
-    }
+    let context = |name: &'static str| core.context(irc, name, config.get(name).cloned());
 
     let mut plugins = HashMap::new();
-    // spawn_plugin!(plugins, echo::EchoPlugin);
-    spawn_plugin!(plugins, weather::WeatherPlugin);
+    let mut commands = vec![];
+    for registration in &registry.plugins {
+        let enabled = config
+            .get(registration.name)
+            .and_then(|config| config.get(ENABLED))
+            .is_none_or(|enabled| enabled != "false");
+        if !enabled {
+            info!(
+                "[{}] Not loading {}, disabled",
+                irc.server, registration.name
+            );
+            continue;
+        }
+        let handle = (registration.spawn)(context(registration.name), router).await?;
+        plugins.insert(registration.name.into(), handle);
+        commands.extend(registration.commands);
+    }
+
+    commands.push(&version::VERSION);
+    let mut names: Vec<String> = plugins.keys().cloned().collect();
+    names.extend([help::NAME.into(), version::NAME.into()]);
+    let version = version::VersionPlugin::new(names);
+    plugins.insert(
+        version::NAME.into(),
+        spawn_plugin(
+            version,
+            context(version::NAME),
+            router,
+            false,
+            Subscription::None,
+        ),
+    );
+
+    let help = help::HelpPlugin::new(commands);
+    plugins.insert(
+        help::NAME.into(),
+        spawn_plugin(help, context(help::NAME), router, false, Subscription::None),
+    );
     Ok(plugins)
 }
 
-// TODO figure out some way of managing errors from plugins
+/// Receives the next message, never returning without a receiver.
+async fn recv_subscribed(
+    messages: &mut Option<broadcast::Receiver<irc::Message>>,
+) -> Result<irc::Message, broadcast::error::RecvError> {
+    match messages {
+        Some(messages) => messages.recv().await,
+        None => future::pending().await,
+    }
+}
+
+/// Whether a message the plugin would answer was claimed by another plugin,
+/// ahead of it in claiming.
+fn claimed_by_another<P: Plugin>(plugin: &P, ctx: &PluginContext, event: &ChatEvent) -> bool {
+    event.claimed_by.as_ref().is_some_and(|by| *by != ctx.name) && plugin.claims(ctx, event)
+}
+
+/// Spawns the task feeding received messages and command invocations to a
+/// plugin's handlers, along with chat replayed from history if it wants it.
+pub(crate) fn spawn_plugin<P: Plugin>(
+    plugin: P,
+    ctx: PluginContext,
+    router: &command::Router,
+    history: bool,
+    subscription: Subscription,
+) -> JoinHandle<Result<()>> {
+    let plugin = Arc::new(plugin);
+    let ctx = Arc::new(ctx);
+    // Only sent the messages subscribed to, and none for `None`
+    let mut messages = router.subscribe_messages(subscription);
+    let mut commands = router.subscribe();
+    let mut chat = router.subscribe_chat();
+    let mut batches = router.subscribe_batches();
+    let mut notices = router.subscribe_notices();
+    let mut lifecycle = router.subscribe_lifecycle();
+    info!("[{}] Registering {}", ctx.server, ctx.name);
+    let claim = {
+        let (plugin, ctx) = (plugin.clone(), ctx.clone());
+        Box::new(move |event: &ChatEvent| plugin.claims(&ctx, event))
+    };
+    router.claims().register(ctx.name, claim);
+
+    tasks::spawn(&format!("{} {}", ctx.server, ctx.name), async move {
+        // Dropped along with this task, aborting any handlers still running
+        let mut tasks = TaskSet::new();
+        loop {
+            tokio::select! {
+                cmd = commands.recv() => match cmd {
+                    Ok(cmd) => {
+                        let (plugin, ctx) = (plugin.clone(), ctx.clone());
+                        let name = format!("{} {} {}", ctx.server, ctx.name, cmd.name);
+                        tasks.spawn(&name, async move {
+                            let handler = plugin.command(&ctx, &cmd);
+                            guard(&ctx, Some(&cmd.reply_to), &cmd, handler).await
+                        });
+                    },
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("[{}] {} skipped {} commands", ctx.server, ctx.name, n)
+                    },
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                event = chat.recv() => match event {
+                    Ok(event) if event.is_history && !history => {},
+                    Ok(event) if claimed_by_another(&*plugin, &ctx, &event) => {
+                        debug!("[{}] {} leaving {:?} to another", ctx.server, ctx.name, event.body)
+                    },
+                    Ok(event) => {
+                        let (plugin, ctx) = (plugin.clone(), ctx.clone());
+                        let name = format!("{} {} chat", ctx.server, ctx.name);
+                        tasks.spawn(&name, async move {
+                            let handler = plugin.chat(&ctx, &event);
+                            guard(&ctx, event.channel(), &event, handler).await
+                        });
+                    },
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("[{}] {} skipped {} chat messages", ctx.server, ctx.name, n)
+                    },
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                batch = batches.recv() => match batch {
+                    Ok(batch) => {
+                        let (plugin, ctx) = (plugin.clone(), ctx.clone());
+                        let name = format!("{} {} batch", ctx.server, ctx.name);
+                        tasks.spawn(&name, async move {
+                            let handler = plugin.batch(&ctx, &batch);
+                            guard(&ctx, None, &batch, handler).await
+                        });
+                    },
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("[{}] {} skipped {} batches", ctx.server, ctx.name, n)
+                    },
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                event = notices.recv() => match event {
+                    Ok(event) => {
+                        let (plugin, ctx) = (plugin.clone(), ctx.clone());
+                        let name = format!("{} {} notice", ctx.server, ctx.name);
+                        tasks.spawn(&name, async move {
+                            let handler = plugin.server(&ctx, &event);
+                            guard(&ctx, None, &event, handler).await
+                        });
+                    },
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("[{}] {} skipped {} server notices", ctx.server, ctx.name, n)
+                    },
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                event = lifecycle.recv() => match event {
+                    Ok(Lifecycle::Disconnected) | Err(broadcast::error::RecvError::Closed) => {
+                        // Run right away, as the bot only waits a moment for
+                        // plugins to stop before aborting them
+                        let handler = plugin.disconnected(&ctx);
+                        guard(&ctx, None, &Lifecycle::Disconnected, handler).await;
+                        return Ok(());
+                    },
+                    Ok(event) => {
+                        let (plugin, ctx) = (plugin.clone(), ctx.clone());
+                        let name = format!("{} {} lifecycle", ctx.server, ctx.name);
+                        tasks.spawn(&name, async move {
+                            let channel = match &event {
+                                Lifecycle::Joined(channel) => Some(channel.as_str()),
+                                _ => None,
+                            };
+                            let handler = async {
+                                match channel {
+                                    Some(channel) => plugin.joined(&ctx, channel).await,
+                                    None => plugin.connected(&ctx).await,
+                                }
+                            };
+                            guard(&ctx, channel, &event, handler).await
+                        });
+                    },
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("[{}] {} skipped {} lifecycle events", ctx.server, ctx.name, n)
+                    },
+                },
+                msg = recv_subscribed(&mut messages) => match msg {
+                    Ok(msg) => {
+                        let (plugin, ctx) = (plugin.clone(), ctx.clone());
+                        let name = format!("{} {} message", ctx.server, ctx.name);
+                        tasks.spawn(&name, async move {
+                            let handler = plugin.message(&ctx, &msg);
+                            guard(&ctx, msg.target.as_deref(), &msg, handler).await
+                        });
+                    },
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("[{}] {} skipped {} messages", ctx.server, ctx.name, n)
+                    },
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {},
+            }
+        }
+    })
+}
+
+/// Runs a handler call, logging its error if it fails. Panics are caught and
+/// logged too, so they only take down this one call and not the plugin, and
+/// calls running for too long are logged or aborted by the watchdog.
+async fn guard<F>(
+    ctx: &PluginContext,
+    channel: Option<&str>,
+    event: &(dyn Debug + Sync),
+    handler: F,
+) where
+    F: Future<Output = Result<()>>,
+{
+    let handler = AssertUnwindSafe(handler).catch_unwind();
+    let stalled = |running: Duration| {
+        warn!(
+            "[{}] {} still handling {:?} after {}s",
+            ctx.server,
+            ctx.name,
+            event,
+            running.as_secs()
+        );
+        metrics::increment(metrics::PLUGIN_STALLS, &ctx.server);
+    };
+    let result = match watchdog::watch(&ctx.watchdog, handler, stalled).await {
+        Some(result) => result,
+        None => {
+            error!(
+                "[{}] {} aborted handling {:?} after {}s",
+                ctx.server, ctx.name, event, ctx.watchdog.abort_after
+            );
+            metrics::increment(metrics::PLUGIN_ABORTS, &ctx.server);
+            return;
+        },
+    };
+    match result {
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => error!(
+            "[{}] {} failed handling {:?}: {}",
+            ctx.server, ctx.name, event, e
+        ),
+        Err(panic) => {
+            let reason = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown reason");
+            error!(
+                "[{}] {} panicked in {} handling {:?}: {}",
+                ctx.server,
+                ctx.name,
+                channel.unwrap_or("-"),
+                event,
+                reason
+            );
+            metrics::increment(metrics::PLUGIN_PANICS, &ctx.server);
+        },
+    }
+}
+
 // TODO logging and auto-respawning the plugin tasks if they die for whatever
 // reason
 
+/// Everything a plugin gets to interact with the bot, handed to its
+/// constructor and to every handler call.
+#[derive(Clone)]
+pub struct PluginContext {
+    /// Name of the plugin this context belongs to
+    pub name:      &'static str,
+    /// Name of the server the bot is connected to
+    pub server:    String,
+    /// The plugin's section of the configuration, if any
+    pub config:    Option<bot::PluginConfig>,
+    /// Current nick and channels of the bot, and recent channel messages
+    pub state:     state::Handle,
+    pub storage:   Storage,
+    pub scheduler: Scheduler,
+    /// Users allowed to use administrative commands
+    pub admins:    Acl,
+    /// Users allowed to use oper commands
+    pub owners:    Acl,
+    /// Configured quit and part messages
+    pub messages:  Messages,
+    /// Gets the bot opped to moderate channels
+    pub ops:       Ops,
+    /// Shortens URLs before posting them, when configured
+    #[cfg(feature = "http-client")]
+    pub shortener: Shortener,
+    /// Uploads long outputs, when configured
+    #[cfg(feature = "http-client")]
+    pub paster:    Paster,
+    /// HTTP client to use for any request
+    #[cfg(feature = "http-client")]
+    pub http:      Http,
+    /// Changes made through admin commands that survive restarts
+    pub runtime:   Runtime,
+    /// Points and achievements of users in each channel
+    pub awards:    Awards,
+    /// Translates replies into the language of whoever they're for
+    pub i18n:      I18n,
+    /// Where users are, their timezone and other preferences they saved
+    pub prefs:     Prefs,
+
+    filters:       Filters,
+    watchdog:      WatchdogConfig,
+    send_messages: irc::Outbox,
+}
+
+impl PluginContext {
+    /// The bot's current nick.
+    pub async fn nick(&self) -> String {
+        self.state.read().await.nick.clone()
+    }
+
+    pub async fn send(&self, msg: irc::Message) -> Result<()> {
+        self.send_with(msg, irc::Priority::Reply).await
+    }
+
+    /// Queues the message once the filters of the channel it's for, if any,
+    /// are applied. In maintenance, PRIVMSGs and NOTICEs are only logged,
+    /// except for those of the admin plugin.
+    pub async fn send_with(&self, msg: irc::Message, priority: irc::Priority) -> Result<()> {
+        if matches!(msg.command, irc::Command::Privmsg | irc::Command::Notice)
+            && self.name != admin::AdminPlugin::NAME
+            && self.runtime.maintenance().await
+        {
+            info!("[{}] In maintenance, not sending: {}", self.server, msg);
+            return Ok(());
+        }
+        let msg = self.filters.apply(msg).await;
+        self.send_messages.send_with(msg, priority).await
+    }
+
+    /// Queues the messages carrying the text, each through [`Self::send_with`]
+    /// with its priority.
+    pub async fn send_outgoing(&self, outgoing: irc::Outgoing) -> Result<()> {
+        for msg in outgoing.messages(self.send_messages.max_length()) {
+            self.send_with(msg, outgoing.priority).await?;
+        }
+        Ok(())
+    }
+
+    /// The plugin's custom format called `name` for messages sent to the
+    /// target, if configured, to fill with [`template::render`].
+    pub fn format(&self, name: &str, target: &str) -> Option<&str> {
+        template::custom(self.config.as_ref()?, name, target)
+    }
+
+    /// Translates a reply to the command into the language of its user,
+    /// filling in `{0}`, `{1}`... with the arguments.
+    pub fn tr(&self, cmd: &command::Invocation, text: &str, args: &[&str]) -> String {
+        self.i18n.tr(&cmd.user.nick, &cmd.reply_to, text, args)
+    }
+
+    /// Tells the user of the command how to use it, in their language.
+    pub fn usage(&self, cmd: &command::Invocation, usage: &str) -> String {
+        let language = self.i18n.language(&cmd.user.nick, &cmd.reply_to);
+        let usage = self.i18n.translate(&language, usage);
+        self.tr(
+            cmd,
+            "{0}: Usage: {1}{2}",
+            &[&cmd.user.nick, &cmd.prefix, usage],
+        )
+    }
+
+    /// Replies with a single line to wherever the command came from.
+    pub async fn reply<S: Into<String>>(&self, cmd: &command::Invocation, text: S) -> Result<()> {
+        Reply::line(cmd, text).send(self).await
+    }
+}
+
+/// Which messages from the server a plugin's `message` handler is called
+/// for. The router only passes on those a plugin subscribed to, so plugins
+/// not handling them, or only some, aren't woken for the rest, on busy
+/// networks with a PING or MODE every moment.
+#[derive(Debug, Clone, Copy)]
+pub enum Subscription {
+    All,
+    None,
+    /// Messages with any of these commands or numerics, like `JOIN` or
+    /// `366`
+    Commands(&'static [&'static str]),
+}
+
+impl Subscription {
+    pub fn wants(&self, msg: &irc::Message) -> bool {
+        match self {
+            Subscription::All => true,
+            Subscription::None => false,
+            Subscription::Commands(commands) => commands
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(msg.command.as_str())),
+        }
+    }
+}
+
+/// Builds a plugin once its bot connects, added to the bots through a
+/// [`Registry`].
 #[async_trait]
 pub trait PluginBuilder {
+    /// Name of the plugin, under which it finds its configuration section
     const NAME: &'static str;
-    type Plugin;
+    /// Commands handled by the plugin, listed by `\help`
+    const COMMANDS: &'static [command::Help] = &[];
+    /// Whether the plugin's `chat` also gets what was said while the bot was
+    /// away, replayed from history, like to log it or count it
+    const HISTORY: bool = false;
+    /// Which messages from the server the plugin's `message` gets, all of
+    /// them unless it says otherwise
+    const MESSAGES: Subscription = Subscription::All;
+    type Plugin: Plugin;
 
-    async fn new(server: &str, config: Option<&bot::PluginConfig>) -> Result<Self::Plugin>;
+    /// Builds the plugin for a new connection, failing which the bot
+    /// reconnects.
+    async fn new(ctx: &PluginContext) -> Result<Self::Plugin>;
 }
 
-pub trait Plugin {
-    fn spawn_task(self, irc: irc::IRC) -> Result<JoinHandle<Result<()>>>;
+/// Handles what a bot receives, each call in a task of its own.
+#[async_trait]
+pub trait Plugin: Send + Sync + 'static {
+    /// Called once the bot is registered with the server, on each
+    /// connection.
+    async fn connected(&self, _ctx: &PluginContext) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the bot has joined a channel, once its users are known.
+    async fn joined(&self, _ctx: &PluginContext, _channel: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the connection is gone, right before the plugin is
+    /// stopped, to save anything worth keeping. The bot only waits a few
+    /// seconds for it.
+    async fn disconnected(&self, _ctx: &PluginContext) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for every command addressed to the bot.
+    async fn command(&self, _ctx: &PluginContext, _cmd: &command::Invocation) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for every PRIVMSG a user sends to a channel or to the bot, and
+    /// those replayed from history if the plugin asked for them.
+    async fn chat(&self, _ctx: &PluginContext, _event: &ChatEvent) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the plugin would answer a live message, to be the only one
+    /// doing so among those that would. Asked in the order of the bot's
+    /// `priorities`, before `chat` is called, so it can't wait on anything.
+    fn claims(&self, _ctx: &PluginContext, _event: &ChatEvent) -> bool {
+        false
+    }
+
+    /// Called for every batch of messages received, once it ends. Its
+    /// messages are still passed to `message` one by one as they arrive.
+    async fn batch(&self, _ctx: &PluginContext, _batch: &Batch) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for every WALLOPS and NOTICE from a server. They're still
+    /// passed to `message` too.
+    async fn server(&self, _ctx: &PluginContext, _event: &ServerEvent) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for every message received from the server.
+    async fn message(&self, _ctx: &PluginContext, _msg: &irc::Message) -> Result<()> {
+        Ok(())
+    }
 }
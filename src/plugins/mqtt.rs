@@ -0,0 +1,221 @@
+//! Posts the messages published to MQTT topics, like home automation sensors,
+//! to channels, and lets admins publish with `\mqtt pub`.
+//!
+//! Configuration:
+//! - `host`: the broker, reached over plain TCP
+//! - `port`: 1883 by default
+//! - `username` and `password`: if the broker needs them
+//! - `subscribe <topic>`: the server and channel to post the topic's messages
+//!   to, separated by a space. The topic can have `+` and `#` wildcards.
+//! - `template <topic>`: how to format the topic's messages, like the
+//!   webhook's, with `{topic}` being the topic the message came from and
+//!   `{payload}` the whole message. Without one, `[topic] payload` is posted.
+
+use crate::command;
+use crate::irc;
+use crate::plugins::{self, Plugin, PluginBuilder, PluginContext};
+use crate::reply::truncate;
+use crate::template;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS, SubscribeFilter};
+use serde_json::Value;
+use std::time::Duration;
+
+const MQTT: command::Help = command::Help {
+    name:  "mqtt",
+    usage: "mqtt pub <topic> <payload> - publishes a message (admins only)",
+};
+
+const DEFAULT_PORT: u16 = 1883;
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// How long to wait before connecting again after losing the broker
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How many requests to the broker can be queued
+const QUEUE_SIZE: usize = 16;
+/// Longest message posted, in characters
+const MAX_MESSAGE: usize = 400;
+
+#[derive(Clone)]
+struct Subscription {
+    filter:   String,
+    channel:  String,
+    template: Option<String>,
+}
+
+impl Subscription {
+    fn format(&self, topic: &str, payload: &[u8]) -> String {
+        let text = String::from_utf8_lossy(payload);
+        let text = text.trim();
+        let template = match &self.template {
+            Some(template) => template,
+            None => return format!("[{}] {}", topic, text),
+        };
+        let mut fields = match serde_json::from_str(text) {
+            Ok(Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        };
+        fields
+            .entry("topic")
+            .or_insert_with(|| Value::String(topic.into()));
+        fields
+            .entry("payload")
+            .or_insert_with(|| Value::String(text.into()));
+        template::render(template, &Value::Object(fields))
+    }
+}
+
+/// Polls the connection to the broker, posting the messages it gets.
+async fn run(
+    ctx: PluginContext,
+    client: AsyncClient,
+    mut events: EventLoop,
+    subs: Vec<Subscription>,
+) {
+    loop {
+        match events.poll().await {
+            // The broker forgets subscriptions when reconnecting
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("[{}] Connected to the MQTT broker", ctx.server);
+                let filters = subs
+                    .iter()
+                    .map(|sub| SubscribeFilter::new(sub.filter.clone(), QoS::AtMostOnce));
+                if let Err(e) = client.try_subscribe_many(filters) {
+                    warn!("[{}] Could not subscribe to MQTT topics: {}", ctx.server, e);
+                }
+            },
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                for sub in subs
+                    .iter()
+                    .filter(|sub| rumqttc::matches(&publish.topic, &sub.filter))
+                {
+                    let text = truncate(&sub.format(&publish.topic, &publish.payload), MAX_MESSAGE);
+                    let text = text.replace(['\r', '\n'], " ");
+                    let msg = irc::Outgoing::privmsg(sub.channel.as_str(), text.as_str());
+                    if let Err(e) = ctx.send_outgoing(msg).await {
+                        warn!("[{}] Could not post an MQTT message: {}", ctx.server, e);
+                    }
+                }
+            },
+            Ok(_) => {},
+            Err(e) => {
+                warn!("[{}] MQTT connection failed: {}", ctx.server, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+            },
+        }
+    }
+}
+
+pub struct MqttPlugin {
+    client: Option<AsyncClient>,
+}
+
+#[async_trait]
+impl PluginBuilder for MqttPlugin {
+    type Plugin = MqttPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[MQTT];
+    const MESSAGES: plugins::Subscription = plugins::Subscription::None;
+    const NAME: &'static str = "mqtt";
+
+    async fn new(ctx: &PluginContext) -> Result<MqttPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let host = match config.get("host") {
+            Some(host) => host,
+            None => {
+                warn!("[{}] MQTT needs a `host` in its config section", ctx.server);
+                return Ok(MqttPlugin { client: None });
+            },
+        };
+        let port = match config.get("port") {
+            Some(port) => port
+                .parse()
+                .map_err(|_| anyhow!("[MQTT] Invalid port: {}", port))?,
+            None => DEFAULT_PORT,
+        };
+
+        let mut subs = vec![];
+        for (key, target) in &config {
+            let filter = match key.strip_prefix("subscribe ") {
+                Some(filter) => filter.trim(),
+                None => continue,
+            };
+            if !rumqttc::valid_filter(filter) {
+                return Err(anyhow!("[MQTT] Invalid topic: {}", filter));
+            }
+            let (server, channel) = match target.split_whitespace().collect::<Vec<_>>()[..] {
+                [server, channel] if irc::is_channel(channel) => (server, channel),
+                _ => return Err(anyhow!("[MQTT] Invalid target for {}: {}", filter, target)),
+            };
+            if server == ctx.server {
+                subs.push(Subscription {
+                    filter:   filter.into(),
+                    channel:  channel.into(),
+                    template: config.get(&format!("template {}", filter)).cloned(),
+                });
+            }
+        }
+
+        // Every server's connection gets its own MQTT session
+        let mut options = MqttOptions::new(format!("boton-{}", ctx.server), host, port);
+        options.set_keep_alive(KEEP_ALIVE);
+        if let (Some(username), Some(password)) = (config.get("username"), config.get("password")) {
+            options.set_credentials(username, password);
+        }
+        let (client, events) = AsyncClient::new(options, QUEUE_SIZE);
+        ctx.scheduler.after(
+            Duration::ZERO,
+            run(ctx.clone(), client.clone(), events, subs),
+        );
+
+        Ok(MqttPlugin {
+            client: Some(client),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for MqttPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != MQTT.name {
+            return Ok(());
+        }
+        if !ctx.admins.allows(&cmd.user) {
+            info!("[{}] Denied {} to {}", ctx.server, cmd.name, cmd.user);
+            let reply = ctx.tr(cmd, "{0}: You're not allowed to do that", &[&cmd.user.nick]);
+            return ctx.reply(cmd, reply).await;
+        }
+
+        // The payload is taken as typed, spaces and all
+        let args = cmd.args.as_deref().unwrap_or_default().trim();
+        let publish = args
+            .split_once(char::is_whitespace)
+            .filter(|(action, _)| *action == "pub")
+            .and_then(|(_, rest)| rest.trim_start().split_once(char::is_whitespace));
+        let reply = match (publish, &self.client) {
+            (None, _) => ctx.usage(cmd, MQTT.usage),
+            (Some(_), None) => format!("{}: MQTT isn't set up", cmd.user.nick),
+            (Some((topic, _)), Some(_)) if !rumqttc::valid_topic(topic) => {
+                format!("{}: Invalid topic {}", cmd.user.nick, topic)
+            },
+            (Some((topic, payload)), Some(client)) => {
+                let payload = payload.trim().as_bytes().to_vec();
+                match client
+                    .publish(topic, QoS::AtLeastOnce, false, payload)
+                    .await
+                {
+                    Ok(()) => {
+                        info!("[{}] {} published to {}", ctx.server, cmd.user, topic);
+                        format!("{}: Published to {}", cmd.user.nick, topic)
+                    },
+                    Err(e) => {
+                        warn!("[{}] Could not publish to {}: {}", ctx.server, topic, e);
+                        format!("{}: Could not publish, try again later", cmd.user.nick)
+                    },
+                }
+            },
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
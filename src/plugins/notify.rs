@@ -0,0 +1,257 @@
+//! Lets users know by private message when one of their keywords is said in a
+//! channel they're in while they're away, set up with `\notify add`. Quiet
+//! hours are in the timezone set with `\tzset`, UTC otherwise.
+//!
+//! Configuration:
+//! - `idle-minutes`: how long since they last spoke in the channel users count
+//!   as away, 10 by default
+
+use crate::chat::ChatEvent;
+use crate::command;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::prefs;
+use crate::reply::truncate;
+use crate::storage::DataSet;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{Timelike, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+
+const NOTIFY: command::Help = command::Help {
+    name:  "notify",
+    usage: "notify add <keyword> | del <keyword> | list | quiet <from>-<to>|off - messages you \
+            when a keyword is said while you're away, except during quiet hours like 23-7",
+};
+
+const DB_NAME: &str = "notify";
+pub const DATA_SET: DataSet = DataSet::new::<Users>(DB_NAME);
+const DEFAULT_IDLE_MINUTES: u64 = 10;
+const MAX_KEYWORDS: usize = 20;
+const MIN_KEYWORD: usize = 3;
+/// Least time between two notifications to the same user about a channel
+const NOTIFY_GAP: Duration = Duration::from_secs(60);
+/// Longest message quoted, in characters
+const MAX_QUOTE: usize = 350;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Settings {
+    /// Lowercase keywords
+    keywords: BTreeSet<String>,
+    /// Hours from which and until which not to notify
+    #[serde(default)]
+    quiet:    Option<(u32, u32)>,
+}
+
+/// Settings by user key, as linked across nick changes.
+type Users = BTreeMap<String, Settings>;
+
+/// Whether the keyword appears in the text as a whole word, both lowercase.
+fn mentions(text: &str, keyword: &str) -> bool {
+    text.match_indices(keyword).any(|(start, _)| {
+        let before = text[.. start].chars().next_back();
+        let after = text[start + keyword.len() ..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Parses quiet hours like `23-7`.
+fn parse_quiet(text: &str) -> Option<(u32, u32)> {
+    let (from, to) = text.split_once('-')?;
+    let (from, to) = (from.parse().ok()?, to.parse().ok()?);
+    (from < 24 && to < 24 && from != to).then_some((from, to))
+}
+
+fn is_quiet((from, to): (u32, u32), hour: u32) -> bool {
+    if from < to {
+        (from .. to).contains(&hour)
+    } else {
+        hour >= from || hour < to
+    }
+}
+
+pub struct NotifyPlugin {
+    idle:     Duration,
+    users:    Arc<Mutex<Users>>,
+    /// When each user was last notified about each channel, by key and
+    /// lowercase channel
+    notified: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl NotifyPlugin {
+    /// Handles `\notify` for the user, returning the reply.
+    async fn manage(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<String> {
+        let nick = &cmd.user.nick;
+        let args = cmd.parse_args();
+        let words: Vec<String> = args.words().map(str::to_lowercase).collect();
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let key = ctx.state.read().await.links.key(nick);
+        let mut users = self.users.lock().await;
+        let settings = users.entry(key).or_default();
+
+        let reply = match words[..] {
+            ["add", keyword] if keyword.chars().count() < MIN_KEYWORD => {
+                return Ok(format!(
+                    "{}: Keywords need at least {} characters",
+                    nick, MIN_KEYWORD
+                ))
+            },
+            ["add", _] if settings.keywords.len() >= MAX_KEYWORDS => {
+                return Ok(format!("{}: You have too many keywords already", nick))
+            },
+            ["add", keyword] => {
+                settings.keywords.insert(keyword.into());
+                format!("{}: Notifying you about {}", nick, keyword)
+            },
+            ["del", keyword] if !settings.keywords.remove(keyword) => {
+                return Ok(format!("{}: You have no keyword {}", nick, keyword))
+            },
+            ["del", keyword] => format!("{}: No longer notifying you about {}", nick, keyword),
+            ["list"] => {
+                let keywords: Vec<&str> = settings.keywords.iter().map(String::as_str).collect();
+                let quiet = match settings.quiet {
+                    Some((from, to)) => format!(", quiet from {}h to {}h", from, to),
+                    None => String::new(),
+                };
+                if keywords.is_empty() {
+                    return Ok(format!("{}: You have no keywords{}", nick, quiet));
+                }
+                return Ok(format!("{}: {}{}", nick, keywords.join(", "), quiet));
+            },
+            ["quiet", "off"] => {
+                settings.quiet = None;
+                format!("{}: No more quiet hours", nick)
+            },
+            ["quiet", hours] => match parse_quiet(hours) {
+                Some((from, to)) => {
+                    settings.quiet = Some((from, to));
+                    format!("{}: Quiet from {}h to {}h", nick, from, to)
+                },
+                None => return Ok(format!("{}: Quiet hours go like 23-7", nick)),
+            },
+            _ => return Ok(ctx.usage(cmd, NOTIFY.usage)),
+        };
+        users.retain(|_, settings| !settings.keywords.is_empty() || settings.quiet.is_some());
+        ctx.storage.save(DB_NAME, &*users).await?;
+        Ok(reply)
+    }
+
+    /// Whether the user is in the channel but hasn't spoken there lately.
+    async fn is_away(&self, ctx: &PluginContext, channel: &str, nick: &str) -> bool {
+        let state = ctx.state.read().await;
+        if !state.channel(channel).is_some_and(|c| c.has_user(nick)) {
+            return false;
+        }
+        let last = state.history.last_from(channel, nick);
+        !last.is_some_and(|line| {
+            SystemTime::now()
+                .duration_since(line.time)
+                .is_ok_and(|since| since < self.idle)
+        })
+    }
+
+    /// Whether the user wants no notifications at this time.
+    fn is_quiet(&self, ctx: &PluginContext, nick: &str, quiet: (u32, u32)) -> bool {
+        let hour = match ctx.prefs.get(&prefs::TIMEZONE, nick) {
+            Some(timezone) => Utc::now().with_timezone(&timezone).hour(),
+            None => Utc::now().hour(),
+        };
+        is_quiet(quiet, hour)
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for NotifyPlugin {
+    type Plugin = NotifyPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[NOTIFY];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "notify";
+
+    async fn new(ctx: &PluginContext) -> Result<NotifyPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let idle = match config.get("idle-minutes") {
+            Some(minutes) => minutes
+                .parse()
+                .map_err(|_| anyhow!("[Notify] Invalid idle-minutes: {}", minutes))?,
+            None => DEFAULT_IDLE_MINUTES,
+        };
+        let users = Arc::new(Mutex::new(
+            ctx.storage.load(DB_NAME).await?.unwrap_or_default(),
+        ));
+        ctx.storage
+            .own(DB_NAME, users.clone(), |users: &mut Users, nick| {
+                users.remove(nick).is_some()
+            });
+        Ok(NotifyPlugin {
+            idle: Duration::from_secs(idle * 60),
+            users,
+            notified: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for NotifyPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != NOTIFY.name {
+            return Ok(());
+        }
+        let reply = self.manage(ctx, cmd).await?;
+        ctx.reply(cmd, reply).await
+    }
+
+    async fn chat(&self, ctx: &PluginContext, event: &ChatEvent) -> Result<()> {
+        let (speaker, channel, text) = match event.channel() {
+            Some(channel) if !event.is_ctcp => (&event.sender.nick, channel, &event.body),
+            _ => return Ok(()),
+        };
+        let lowercase = text.to_lowercase();
+        let speaker_key = ctx.state.read().await.links.key(speaker);
+        let mentioned: Vec<(String, Option<(u32, u32)>)> = self
+            .users
+            .lock()
+            .await
+            .iter()
+            .filter(|(key, _)| **key != speaker_key)
+            .filter(|(_, settings)| settings.keywords.iter().any(|k| mentions(&lowercase, k)))
+            .map(|(key, settings)| (key.clone(), settings.quiet))
+            .collect();
+
+        for (key, quiet) in mentioned {
+            let nick = match ctx.state.read().await.links.nick(&key) {
+                Some(nick) => nick,
+                None => continue,
+            };
+            if !self.is_away(ctx, channel, &nick).await {
+                continue;
+            }
+            if let Some(quiet) = quiet {
+                if self.is_quiet(ctx, &nick, quiet) {
+                    continue;
+                }
+            }
+            {
+                let mut notified = self.notified.lock().await;
+                notified.retain(|_, at| at.elapsed() < NOTIFY_GAP);
+                if notified
+                    .insert((key, channel.to_lowercase()), Instant::now())
+                    .is_some()
+                {
+                    continue;
+                }
+            }
+
+            debug!("[{}] Notifying {} about {}", ctx.server, nick, channel);
+            let text = format!("[{}] <{}> {}", channel, speaker, truncate(text, MAX_QUOTE));
+            ctx.send_outgoing(irc::Outgoing::privmsg(nick.as_str(), text.as_str()))
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+//! Commands for bots running with an oper account, set up with `oper`, to
+//! force users into channels with `\sajoin` and disconnect them with `\kill`.
+//! Only the bot's `owners` can use them.
+
+use crate::command;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use anyhow::Result;
+use async_trait::async_trait;
+use log::*;
+
+const SAJOIN: command::Help = command::Help {
+    name:  "sajoin",
+    usage: "sajoin <nick> <#channel> - forces the user into the channel (owners only)",
+};
+const KILL: command::Help = command::Help {
+    name:  "kill",
+    usage: "kill <nick> [reason] - disconnects the user from the network (owners only)",
+};
+
+pub struct OperPlugin;
+
+#[async_trait]
+impl PluginBuilder for OperPlugin {
+    type Plugin = OperPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[SAJOIN, KILL];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "oper";
+
+    async fn new(_ctx: &PluginContext) -> Result<OperPlugin> {
+        Ok(OperPlugin)
+    }
+}
+
+#[async_trait]
+impl Plugin for OperPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != SAJOIN.name && cmd.name != KILL.name {
+            return Ok(());
+        }
+        if !ctx.owners.allows(&cmd.user) {
+            info!("[{}] Denied {} to {}", ctx.server, cmd.name, cmd.user);
+            let reply = ctx.tr(cmd, "{0}: You're not allowed to do that", &[&cmd.user.nick]);
+            return ctx.reply(cmd, reply).await;
+        }
+        if !ctx.state.read().await.is_oper() {
+            let reply = ctx.tr(cmd, "{0}: I'm not an oper here", &[&cmd.user.nick]);
+            return ctx.reply(cmd, reply).await;
+        }
+
+        let args = cmd.parse_args();
+        let msg = match (cmd.name.as_str(), args.word(0), args.word(1)) {
+            ("sajoin", Some(nick), Some(channel)) if irc::is_channel(channel) => {
+                irc::Message::sajoin(nick, channel)
+            },
+            ("kill", Some(nick), _) => {
+                let reason = match args.rest(1) {
+                    Some(reason) => reason.to_string(),
+                    None => format!("Requested by {}", cmd.user.nick),
+                };
+                irc::Message::kill(nick, reason.as_str())
+            },
+            _ => {
+                let usage = if cmd.name == SAJOIN.name {
+                    SAJOIN.usage
+                } else {
+                    KILL.usage
+                };
+                return ctx.reply(cmd, ctx.usage(cmd, usage)).await;
+            },
+        };
+        info!("[{}] {} by {}", ctx.server, msg, cmd.user);
+        // Refusals come back as numerics, logged by the bot
+        ctx.send_with(msg, irc::Priority::Control).await
+    }
+}
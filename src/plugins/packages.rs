@@ -0,0 +1,214 @@
+//! Package lookups on crates.io, PyPI and npm.
+
+use crate::command;
+use crate::http;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use reqwest::{StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const CRATE: command::Help = command::Help {
+    name:  "crate",
+    usage: "crate <name> - shows a Rust crate's latest version and docs",
+};
+const PYPI: command::Help = command::Help {
+    name:  "pypi",
+    usage: "pypi <name> - shows a Python package's latest version and docs",
+};
+const NPM: command::Help = command::Help {
+    name:  "npm",
+    usage: "npm <name> - shows an npm package's latest version and docs",
+};
+
+/// How long lookups are answered from the cache
+const CACHE_FOR: Duration = Duration::from_secs(60 * 60);
+
+/// What gets shown about a package.
+struct Package {
+    name:        String,
+    version:     String,
+    description: Option<String>,
+    link:        String,
+}
+
+#[derive(Deserialize)]
+struct CratesIo {
+    #[serde(rename = "crate")]
+    krate: Crate,
+}
+
+#[derive(Deserialize)]
+struct Crate {
+    name:               String,
+    max_stable_version: Option<String>,
+    max_version:        String,
+    description:        Option<String>,
+    documentation:      Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PyPi {
+    info: PyPiInfo,
+}
+
+#[derive(Deserialize)]
+struct PyPiInfo {
+    name:         String,
+    version:      String,
+    summary:      Option<String>,
+    docs_url:     Option<String>,
+    project_urls: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize)]
+struct Npm {
+    name:        String,
+    version:     String,
+    description: Option<String>,
+}
+
+pub struct PackagesPlugin {
+    client: http::Client,
+}
+
+impl PackagesPlugin {
+    /// Fetches `base` followed by the given path segments, which get
+    /// escaped. Returns `None` if there is no such package.
+    async fn fetch<T: DeserializeOwned>(&self, base: &str, path: &[&str]) -> Result<Option<T>> {
+        let mut url = Url::parse(base)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid base URL {}", base))?
+            .extend(path);
+        let response = self.client.get(url).cache(CACHE_FOR).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    async fn lookup(&self, registry: &str, name: &str) -> Result<Option<Package>> {
+        let package = match registry {
+            "crate" => self
+                .fetch::<CratesIo>("https://crates.io/api/v1/crates", &[name])
+                .await?
+                .map(|c| c.krate.into()),
+            "pypi" => self
+                .fetch::<PyPi>("https://pypi.org/pypi", &[name, "json"])
+                .await?
+                .map(|p| p.info.into()),
+            _ => self
+                .fetch::<Npm>("https://registry.npmjs.org", &[name, "latest"])
+                .await?
+                .map(Package::from),
+        };
+        Ok(package)
+    }
+}
+
+impl From<Crate> for Package {
+    fn from(c: Crate) -> Self {
+        let link = match c.documentation {
+            Some(documentation) => documentation,
+            None => format!("https://docs.rs/{}", c.name),
+        };
+        Package {
+            name: c.name,
+            version: c.max_stable_version.unwrap_or(c.max_version),
+            description: c.description,
+            link,
+        }
+    }
+}
+
+impl From<PyPiInfo> for Package {
+    fn from(p: PyPiInfo) -> Self {
+        let documentation = p
+            .project_urls
+            .and_then(|mut urls| urls.remove("Documentation"))
+            .or(p.docs_url);
+        let link = match documentation {
+            Some(documentation) => documentation,
+            None => format!("https://pypi.org/project/{}/", p.name),
+        };
+        Package {
+            name: p.name,
+            version: p.version,
+            description: p.summary,
+            link,
+        }
+    }
+}
+
+impl From<Npm> for Package {
+    fn from(p: Npm) -> Self {
+        Package {
+            link:        format!("https://www.npmjs.com/package/{}", p.name),
+            name:        p.name,
+            version:     p.version,
+            description: p.description,
+        }
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for PackagesPlugin {
+    type Plugin = PackagesPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[CRATE, PYPI, NPM];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "packages";
+
+    async fn new(ctx: &PluginContext) -> Result<PackagesPlugin> {
+        Ok(PackagesPlugin {
+            client: ctx.http.client(),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for PackagesPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        let help = match cmd.name.as_str() {
+            "crate" => CRATE,
+            "pypi" => PYPI,
+            "npm" => NPM,
+            _ => return Ok(()),
+        };
+        let name = match cmd.args.as_deref() {
+            Some(name) if !name.contains(char::is_whitespace) => name,
+            _ => {
+                let usage = ctx.usage(cmd, help.usage);
+                return ctx.reply(cmd, usage).await;
+            },
+        };
+
+        let reply = match self.lookup(help.name, name).await {
+            Ok(Some(package)) => {
+                let mut reply = format!("{} {}", package.name, package.version);
+                if let Some(description) = package.description.filter(|d| !d.is_empty()) {
+                    reply += &format!(" 〜 {}", description.trim());
+                }
+                let link = ctx.shortener.shorten(&cmd.reply_to, &package.link).await;
+                format!("{} 〜 {}", reply, link)
+            },
+            Ok(None) => format!("{}: No such package `{}`", cmd.user.nick, name),
+            Err(e) => {
+                warn!(
+                    "[{}] {} lookup for {} failed: {}",
+                    ctx.server, help.name, name, e
+                );
+                ctx.tr(
+                    cmd,
+                    "{0}: Lookup failed, try again later",
+                    &[&cmd.user.nick],
+                )
+            },
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
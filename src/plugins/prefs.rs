@@ -0,0 +1,144 @@
+//! Shows users the preferences they saved through other plugins, like their
+//! location or language, with `\prefs`, and lets them clear them.
+//!
+//! `\forgetme` forgets everything any plugin saved about the user, from
+//! preferences to points and keywords, and admins can have it done for any
+//! nick with `\forgetme <nick>`.
+
+use crate::command;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use anyhow::Result;
+use async_trait::async_trait;
+use log::*;
+
+const PREFS: command::Help = command::Help {
+    name:  "prefs",
+    usage: "prefs [clear [<name>]] - shows the preferences you saved, or clears one or all of them",
+};
+const FORGETME: command::Help = command::Help {
+    name:  "forgetme",
+    usage: "forgetme [nick] - forgets everything any plugin saved about you, or about the nick \
+            (admins only)",
+};
+
+/// Forgets everything saved by the nick, and by the key it's linked to if
+/// that's another, returning the names of the data sets that had something.
+async fn forget_linked(ctx: &PluginContext, target: &str) -> Result<Vec<&'static str>> {
+    let mut forgotten = ctx.storage.forget(target).await?;
+    let key = ctx.state.read().await.links.key(target);
+    if key != target.to_lowercase() {
+        forgotten.extend(ctx.storage.forget(&key).await?);
+        forgotten.sort_unstable();
+        forgotten.dedup();
+    }
+    Ok(forgotten)
+}
+
+/// Forgets everything about the nick, returning the reply.
+async fn forget(ctx: &PluginContext, cmd: &command::Invocation, target: &str) -> String {
+    let nick = cmd.user.nick.as_str();
+    let own = target.eq_ignore_ascii_case(nick);
+    match forget_linked(ctx, target).await {
+        Ok(forgotten) if forgotten.is_empty() && own => {
+            ctx.tr(cmd, "{0}: Nothing was saved about you", &[nick])
+        },
+        Ok(forgotten) if forgotten.is_empty() => {
+            ctx.tr(cmd, "{0}: Nothing was saved about {1}", &[nick, target])
+        },
+        Ok(forgotten) => {
+            let forgotten = forgotten.join(", ");
+            info!(
+                "[{}] Forgot {} in {} for {}",
+                ctx.server, target, forgotten, cmd.user
+            );
+            if own {
+                ctx.tr(
+                    cmd,
+                    "{0}: Forgot everything about you, from {1}",
+                    &[nick, &forgotten],
+                )
+            } else {
+                ctx.tr(
+                    cmd,
+                    "{0}: Forgot everything about {1}, from {2}",
+                    &[nick, target, &forgotten],
+                )
+            }
+        },
+        Err(e) => {
+            warn!("[{}] {}", ctx.server, e);
+            ctx.tr(
+                cmd,
+                "{0}: Could not forget everything, try again later",
+                &[nick],
+            )
+        },
+    }
+}
+
+pub struct PrefsPlugin;
+
+#[async_trait]
+impl PluginBuilder for PrefsPlugin {
+    type Plugin = PrefsPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[PREFS, FORGETME];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "prefs";
+
+    async fn new(_ctx: &PluginContext) -> Result<PrefsPlugin> {
+        Ok(PrefsPlugin)
+    }
+}
+
+#[async_trait]
+impl Plugin for PrefsPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        let nick = cmd.user.nick.as_str();
+        let args = cmd.parse_args();
+        let words: Vec<&str> = args.words().collect();
+        if cmd.name == FORGETME.name {
+            let reply = match words[..] {
+                [] => forget(ctx, cmd, nick).await,
+                [target] if ctx.admins.allows(&cmd.user) => forget(ctx, cmd, target).await,
+                [_] => ctx.tr(cmd, "{0}: You're not allowed to do that", &[nick]),
+                _ => ctx.usage(cmd, FORGETME.usage),
+            };
+            return ctx.reply(cmd, reply).await;
+        }
+        if cmd.name != PREFS.name {
+            return Ok(());
+        }
+        let reply = match words[..] {
+            [] => {
+                let prefs = ctx.prefs.all(nick);
+                if prefs.is_empty() {
+                    ctx.tr(cmd, "{0}: You haven't saved any preferences", &[nick])
+                } else {
+                    let prefs: Vec<String> = prefs
+                        .iter()
+                        .map(|(name, value)| format!("{}: {}", name, value))
+                        .collect();
+                    format!("{}: {}", nick, prefs.join(" 〜 "))
+                }
+            },
+            ["clear"] => {
+                if ctx.prefs.clear(nick, None).await? {
+                    info!("[{}] {} cleared their preferences", ctx.server, nick);
+                    ctx.tr(cmd, "{0}: Cleared your preferences", &[nick])
+                } else {
+                    ctx.tr(cmd, "{0}: You haven't saved any preferences", &[nick])
+                }
+            },
+            ["clear", name] => {
+                if ctx.prefs.clear(nick, Some(name)).await? {
+                    ctx.tr(cmd, "{0}: Cleared your {1}", &[nick, name])
+                } else {
+                    ctx.tr(cmd, "{0}: You haven't saved any {1}", &[nick, name])
+                }
+            },
+            _ => ctx.usage(cmd, PREFS.usage),
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
@@ -0,0 +1,255 @@
+//! Plugins running as external programs, written in any language.
+//!
+//! Every entry in this plugin's configuration maps a name to a command line,
+//! e.g. `"quotes": "python3 plugins/quotes.py"`. Each program is started when
+//! the bot connects and restarted whenever it exits.
+//!
+//! Events are written to the program's stdin as one JSON object per line:
+//! - `{"kind": "command", "name", "args", "nick", "reply_to"}`
+//! - `{"kind": "privmsg", "nick", "target", "reply_to", "text", "action"}`
+//! - `{"kind": "netsplit", "servers", "nicks"}` once users left in a netsplit,
+//!   and the same with `"netjoin"` once they're back
+//! - `{"kind": "chathistory", "target", "lines"}` for history the server
+//!   replayed, each line with `nick`, `text` and `action`
+//!
+//! The program answers by writing actions to its stdout, one JSON object per
+//! line:
+//! - `{"action": "send", "target": "#chan", "text": "hello"}`
+//! - `{"action": "log", "text": "something happened"}`
+//!
+//! Anything written to stderr ends up in the bot's own stderr.
+
+use crate::batch::{self, Batch};
+use crate::chat::ChatEvent;
+use crate::command;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::tasks;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use serde::Deserialize;
+use serde_json::json;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Instant};
+
+/// Events buffered for a program before new ones start getting dropped
+const EVENT_CHAN: usize = 64;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Something a program asked the bot to do.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Action {
+    Send { target: String, text: String },
+    Log { text: String },
+}
+
+pub struct ProcessPlugin {
+    programs: Vec<(String, mpsc::Sender<String>)>,
+}
+
+/// Keeps a program running, restarting it with an increasing delay when it
+/// exits. Stops once the plugin (and so the event channel) goes away.
+async fn supervise(
+    ctx: PluginContext,
+    name: String,
+    command_line: String,
+    mut events: mpsc::Receiver<String>,
+) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let started = Instant::now();
+        match run(&ctx, &name, &command_line, &mut events).await {
+            Ok(true) => return,
+            Ok(false) => warn!("[{}] Program {} exited", ctx.server, name),
+            Err(e) => error!("[{}] Program {} failed: {:#}", ctx.server, name, e),
+        }
+
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+        info!(
+            "[{}] Restarting program {} in {}s",
+            ctx.server,
+            name,
+            backoff.as_secs()
+        );
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Runs the program until it exits, feeding it events and carrying out its
+/// actions. Returns `true` when the event channel was closed and the program
+/// should not be restarted.
+async fn run(
+    ctx: &PluginContext,
+    name: &str,
+    command_line: &str,
+    events: &mut mpsc::Receiver<String>,
+) -> Result<bool> {
+    let mut args = command_line.split_whitespace();
+    let program = args.next().ok_or_else(|| anyhow!("empty command line"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    info!("[{}] Started program {}", ctx.server, name);
+
+    let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("no stdin"))?;
+    let stdout = child.stdout.take().ok_or_else(|| anyhow!("no stdout"))?;
+    let mut lines = BufReader::new(stdout).lines();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    stdin.write_all(event.as_bytes()).await?;
+                    stdin.write_all(b"\n").await?;
+                    stdin.flush().await?;
+                },
+                None => return Ok(true),
+            },
+            line = lines.next_line() => match line? {
+                Some(line) => match serde_json::from_str::<Action>(&line) {
+                    Ok(Action::Send { target, text }) => {
+                        ctx.send_outgoing(irc::Outgoing::privmsg(target, text).with_split(irc::Split::Lines)).await?
+                    },
+                    Ok(Action::Log { text }) => info!("[{}:{}] {}", ctx.server, name, text),
+                    Err(e) => warn!("[{}] Invalid action from {}: {}: {}", ctx.server, name, e, line),
+                },
+                None => {
+                    child.wait().await?;
+                    return Ok(false);
+                },
+            },
+        }
+    }
+}
+
+impl ProcessPlugin {
+    fn dispatch(&self, ctx: &PluginContext, event: serde_json::Value) {
+        let event = event.to_string();
+        for (name, events) in &self.programs {
+            if events.try_send(event.clone()).is_err() {
+                warn!(
+                    "[{}] Program {} is not keeping up, dropping event",
+                    ctx.server, name
+                );
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for ProcessPlugin {
+    type Plugin = ProcessPlugin;
+
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "process";
+
+    async fn new(ctx: &PluginContext) -> Result<ProcessPlugin> {
+        let mut programs = vec![];
+        for (name, command_line) in ctx.config.iter().flatten() {
+            let (send, recv) = mpsc::channel(EVENT_CHAN);
+            let task = format!("{} process {}", ctx.server, name);
+            tasks::spawn(
+                &task,
+                supervise(ctx.clone(), name.clone(), command_line.clone(), recv),
+            );
+            programs.push((name.clone(), send));
+        }
+        Ok(ProcessPlugin { programs })
+    }
+}
+
+#[async_trait]
+impl Plugin for ProcessPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        self.dispatch(
+            ctx,
+            json!({
+                "kind": "command",
+                "name": cmd.name,
+                "args": cmd.args,
+                "nick": cmd.user.nick,
+                "reply_to": cmd.reply_to,
+            }),
+        );
+        Ok(())
+    }
+
+    async fn chat(&self, ctx: &PluginContext, event: &ChatEvent) -> Result<()> {
+        if event.is_ctcp {
+            return Ok(());
+        }
+        let target = match event.channel() {
+            Some(channel) => channel.to_string(),
+            None => ctx.nick().await,
+        };
+        self.dispatch(
+            ctx,
+            json!({
+                "kind": "privmsg",
+                "nick": event.sender.nick,
+                "target": target,
+                "reply_to": event.reply_to(),
+                "text": event.body,
+                "action": event.is_action,
+            }),
+        );
+        Ok(())
+    }
+
+    async fn batch(&self, ctx: &PluginContext, batch: &Batch) -> Result<()> {
+        let event = match batch.kind.as_str() {
+            batch::NETSPLIT | batch::NETJOIN => {
+                let nicks: Vec<&str> = batch
+                    .messages
+                    .iter()
+                    .filter_map(|m| m.source_nick())
+                    .collect();
+                json!({
+                    "kind": batch.kind,
+                    "servers": batch.parameters,
+                    "nicks": nicks,
+                })
+            },
+            batch::CHATHISTORY => {
+                // Multiline messages come as nested batches
+                let multiline = batch.batches.iter().filter_map(Batch::multiline);
+                let lines: Vec<serde_json::Value> = batch
+                    .messages
+                    .iter()
+                    .cloned()
+                    .chain(multiline)
+                    .filter_map(|msg| ChatEvent::parse(&msg))
+                    .filter(|event| !event.is_ctcp)
+                    .map(|event| {
+                        json!({
+                            "nick": event.sender.nick,
+                            "text": event.body,
+                            "action": event.is_action,
+                        })
+                    })
+                    .collect();
+                json!({
+                    "kind": batch.kind,
+                    "target": batch.parameters.first(),
+                    "lines": lines,
+                })
+            },
+            _ => return Ok(()),
+        };
+        self.dispatch(ctx, event);
+        Ok(())
+    }
+}
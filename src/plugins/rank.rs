@@ -0,0 +1,213 @@
+//! Shows the points and achievements other plugins award with `\rank`,
+//! `\achievements` and `\leaderboard`, and awards the first user to speak in a
+//! channel each day, in UTC.
+//!
+//! Configuration:
+//! - `first-post-points`: points for speaking first in a day, 1 by default, and
+//!   0 to award none
+
+use crate::awards::Achievement;
+use crate::chat::ChatEvent;
+use crate::command;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::storage::DataSet;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const RANK: command::Help = command::Help {
+    name:  "rank",
+    usage: "rank [nick] - points and rank in this channel",
+};
+const ACHIEVEMENTS: command::Help = command::Help {
+    name:  "achievements",
+    usage: "achievements [nick] - achievements unlocked in this channel",
+};
+const LEADERBOARD: command::Help = command::Help {
+    name:  "leaderboard",
+    usage: "leaderboard - who has the most points in this channel",
+};
+
+const DB_NAME: &str = "first-posts";
+pub const DATA_SET: DataSet = DataSet::new::<FirstPosts>(DB_NAME);
+const DEFAULT_FIRST_POST_POINTS: i64 = 1;
+/// Users listed by `\leaderboard`
+const LEADERS: usize = 10;
+
+const EARLY_BIRD: Achievement = Achievement {
+    name:        "Early Bird",
+    description: "first to speak on a day",
+};
+const ROOSTER: Achievement = Achievement {
+    name:        "Rooster",
+    description: "first to speak on 10 days",
+};
+const DAWN_PATROL: Achievement = Achievement {
+    name:        "Dawn Patrol",
+    description: "first to speak on 50 days",
+};
+/// Achievements for speaking first, by how many days it takes
+const FIRST_POST_ACHIEVEMENTS: &[(u64, Achievement)] =
+    &[(1, EARLY_BIRD), (10, ROOSTER), (50, DAWN_PATROL)];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Channel {
+    /// Last day someone spoke first
+    day:    Option<NaiveDate>,
+    /// Days each user spoke first, by key as linked across nick changes
+    counts: BTreeMap<String, u64>,
+}
+
+/// First posts by lowercase channel.
+type FirstPosts = BTreeMap<String, Channel>;
+
+pub struct RankPlugin {
+    points: i64,
+    first:  Arc<Mutex<FirstPosts>>,
+}
+
+impl RankPlugin {
+    /// Counts the first post of the day in the channel, if this is it,
+    /// returning how many days the user spoke first.
+    async fn first_post(&self, ctx: &PluginContext, channel: &str, nick: &str) -> Result<u64> {
+        let today = Utc::today().naive_utc();
+        let key = ctx.state.read().await.links.key(nick);
+        let mut first = self.first.lock().await;
+        let posts = first.entry(channel.to_lowercase()).or_default();
+        if posts.day == Some(today) {
+            return Ok(0);
+        }
+        posts.day = Some(today);
+        let count = posts.counts.entry(key).or_default();
+        *count += 1;
+        let count = *count;
+        ctx.storage.save(DB_NAME, &*first).await?;
+        Ok(count)
+    }
+
+    async fn describe(
+        &self,
+        ctx: &PluginContext,
+        cmd: &command::Invocation,
+        channel: &str,
+    ) -> String {
+        let nick = cmd.user.nick.as_str();
+        if cmd.name == LEADERBOARD.name {
+            let leaders: Vec<String> = ctx
+                .awards
+                .leaderboard(channel, LEADERS)
+                .await
+                .iter()
+                .enumerate()
+                .map(|(i, (nick, points))| format!("{}. {} ({})", i + 1, nick, points))
+                .collect();
+            if leaders.is_empty() {
+                return format!("{}: Nobody has points here yet", nick);
+            }
+            return leaders.join(" 〜 ");
+        }
+
+        let args = cmd.parse_args();
+        let target = args.words().next().unwrap_or(nick);
+        let (rank, standing) = match ctx.awards.standing(channel, target).await {
+            Some(standing) => standing,
+            None => return format!("{}: {} has nothing here yet", nick, target),
+        };
+        if cmd.name == RANK.name {
+            return format!(
+                "{}: {} is #{} with {} points, and {} achievements",
+                nick,
+                target,
+                rank,
+                standing.points,
+                standing.achievements.len()
+            );
+        }
+        if standing.achievements.is_empty() {
+            return format!("{}: {} has no achievements here yet", nick, target);
+        }
+        let achievements: Vec<String> = standing
+            .achievements
+            .iter()
+            .map(|(name, at)| format!("{} ({})", name, at.format("%Y-%m-%d")))
+            .collect();
+        format!("{}: {} 〜 {}", nick, target, achievements.join(", "))
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for RankPlugin {
+    type Plugin = RankPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[RANK, ACHIEVEMENTS, LEADERBOARD];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "rank";
+
+    async fn new(ctx: &PluginContext) -> Result<RankPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let points = match config.get("first-post-points") {
+            Some(points) => points
+                .parse()
+                .map_err(|_| anyhow!("[Rank] Invalid first-post-points: {}", points))?,
+            None => DEFAULT_FIRST_POST_POINTS,
+        };
+        let first = Arc::new(Mutex::new(
+            ctx.storage.load(DB_NAME).await?.unwrap_or_default(),
+        ));
+        ctx.storage
+            .own(DB_NAME, first.clone(), |first: &mut FirstPosts, nick| {
+                let mut found = false;
+                for posts in first.values_mut() {
+                    found |= posts.counts.remove(nick).is_some();
+                }
+                found
+            });
+        Ok(RankPlugin { points, first })
+    }
+}
+
+#[async_trait]
+impl Plugin for RankPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if ![RANK.name, ACHIEVEMENTS.name, LEADERBOARD.name].contains(&cmd.name.as_str()) {
+            return Ok(());
+        }
+        if !irc::is_channel(&cmd.reply_to) {
+            let reply = ctx.tr(cmd, "{0}: Only works in channels", &[&cmd.user.nick]);
+            return ctx.reply(cmd, reply).await;
+        }
+        let reply = self.describe(ctx, cmd, &cmd.reply_to).await;
+        ctx.reply(cmd, reply).await
+    }
+
+    async fn chat(&self, ctx: &PluginContext, event: &ChatEvent) -> Result<()> {
+        let (nick, channel) = match event.channel() {
+            Some(channel) if !event.is_ctcp => (event.sender.nick.as_str(), channel),
+            _ => return Ok(()),
+        };
+        if ctx.state.read().await.is_me(nick) {
+            return Ok(());
+        }
+
+        let days = self.first_post(ctx, channel, nick).await?;
+        if days == 0 {
+            return Ok(());
+        }
+        debug!("[{}] {} spoke first in {}", ctx.server, nick, channel);
+        if self.points != 0 {
+            ctx.awards.add_points(channel, nick, self.points).await?;
+        }
+        for (needed, achievement) in FIRST_POST_ACHIEVEMENTS {
+            if days >= *needed {
+                ctx.awards.unlock(channel, nick, achievement).await?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+//! Keeps the messages mentioning users while they're away or gone, telling
+//! them on their return so they can have them sent with `\recap`. Users who
+//! left count as gone while their lines are still in the channel's history.
+//!
+//! Configuration:
+//! - `idle-minutes`: how long since they last spoke in the channel users count
+//!   as away, 10 by default
+
+use crate::chat::ChatEvent;
+use crate::command;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::reply::{truncate, Reply};
+use crate::storage::DataSet;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+const RECAP: command::Help = command::Help {
+    name:  "recap",
+    usage: "recap - sends you the messages that mentioned you while you were away",
+};
+
+const DB_NAME: &str = "recap";
+pub const DATA_SET: DataSet = DataSet::new::<Mentions>(DB_NAME);
+const DEFAULT_IDLE_MINUTES: u64 = 10;
+/// Most mentions kept per user, the oldest being dropped
+const MAX_MENTIONS: usize = 20;
+/// How long mentions are kept, in days
+const KEEP_DAYS: i64 = 7;
+/// Longest message kept, in characters
+const MAX_TEXT: usize = 300;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Mention {
+    channel: String,
+    from:    String,
+    text:    String,
+    at:      DateTime<Utc>,
+}
+
+/// Mentions waiting to be recapped, oldest first, by lowercase nick.
+type Mentions = BTreeMap<String, VecDeque<Mention>>;
+
+/// The nicks a message addresses or mentions, as written, like `alice` in
+/// `alice: hi` or `thanks @alice!`.
+fn mentioned(text: &str) -> impl Iterator<Item = &str> {
+    text.split_whitespace()
+        .map(|word| word.trim_start_matches('@'))
+        .map(|word| word.trim_end_matches(|c: char| ":,.!?;)".contains(c)))
+        .filter(|word| !word.is_empty())
+}
+
+/// Describes how long ago something happened, like `3h`.
+fn ago(at: DateTime<Utc>) -> String {
+    let since = Utc::now() - at;
+    if since.num_days() > 0 {
+        format!("{}d", since.num_days())
+    } else if since.num_hours() > 0 {
+        format!("{}h", since.num_hours())
+    } else {
+        format!("{}m", since.num_minutes().max(1))
+    }
+}
+
+pub struct RecapPlugin {
+    idle:     std::time::Duration,
+    mentions: Arc<Mutex<Mentions>>,
+    /// How many mentions each user was last told about, so joining several
+    /// channels doesn't tell them again
+    told:     Mutex<HashMap<String, usize>>,
+}
+
+impl RecapPlugin {
+    /// Whether mentions of the nick in the channel should be kept: they spoke
+    /// there and left since, or haven't spoken in a while.
+    async fn is_away(&self, ctx: &PluginContext, channel: &str, nick: &str) -> bool {
+        let state = ctx.state.read().await;
+        if state.is_me(nick) {
+            return false;
+        }
+        let last = state.history.last_from(channel, nick);
+        if !state.channel(channel).is_some_and(|c| c.has_user(nick)) {
+            return last.is_some();
+        }
+        !last.is_some_and(|line| {
+            SystemTime::now()
+                .duration_since(line.time)
+                .is_ok_and(|since| since < self.idle)
+        })
+    }
+
+    /// Tells users coming back how many mentions they have waiting.
+    async fn welcome_back(&self, ctx: &PluginContext, nick: &str) -> Result<()> {
+        let count = self
+            .mentions
+            .lock()
+            .await
+            .get(&nick.to_lowercase())
+            .map_or(0, VecDeque::len);
+        if count == 0 {
+            return Ok(());
+        }
+        {
+            // Users back from a netsplit are told on their next join, rather
+            // than all at once as they come back
+            let state = ctx.state.read().await;
+            if state.is_me(nick) || state.netsplits.is_returning(nick) {
+                return Ok(());
+            }
+        }
+        if self.told.lock().await.insert(nick.to_lowercase(), count) == Some(count) {
+            return Ok(());
+        }
+        let text = format!(
+            "You were mentioned {} times while away, the recap command sends you what was said",
+            count
+        );
+        ctx.send_outgoing(irc::Outgoing::notice(nick, text.as_str()))
+            .await
+    }
+
+    /// Keeps the message for the nicks it mentions that are away.
+    async fn record(
+        &self,
+        ctx: &PluginContext,
+        from: &str,
+        channel: &str,
+        text: &str,
+    ) -> Result<()> {
+        let mut away = vec![];
+        for nick in mentioned(text).map(str::to_lowercase) {
+            if away.contains(&nick) || nick.eq_ignore_ascii_case(from) {
+                continue;
+            }
+            if self.is_away(ctx, channel, &nick).await {
+                away.push(nick);
+            }
+        }
+        if away.is_empty() {
+            return Ok(());
+        }
+
+        let mention = Mention {
+            channel: channel.into(),
+            from:    from.into(),
+            text:    truncate(text, MAX_TEXT),
+            at:      Utc::now(),
+        };
+        let mut mentions = self.mentions.lock().await;
+        for nick in away {
+            debug!("[{}] Keeping a mention of {}", ctx.server, nick);
+            let kept = mentions.entry(nick).or_default();
+            if kept.len() == MAX_MENTIONS {
+                kept.pop_front();
+            }
+            kept.push_back(mention.clone());
+        }
+        let oldest = Utc::now() - Duration::days(KEEP_DAYS);
+        for kept in mentions.values_mut() {
+            kept.retain(|mention| mention.at > oldest);
+        }
+        mentions.retain(|_, kept| !kept.is_empty());
+        ctx.storage.save(DB_NAME, &*mentions).await
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for RecapPlugin {
+    type Plugin = RecapPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[RECAP];
+    const MESSAGES: Subscription = Subscription::Commands(&["JOIN"]);
+    const NAME: &'static str = "recap";
+
+    async fn new(ctx: &PluginContext) -> Result<RecapPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let idle = match config.get("idle-minutes") {
+            Some(minutes) => minutes
+                .parse()
+                .map_err(|_| anyhow!("[Recap] Invalid idle-minutes: {}", minutes))?,
+            None => DEFAULT_IDLE_MINUTES,
+        };
+        let mentions = Arc::new(Mutex::new(
+            ctx.storage.load(DB_NAME).await?.unwrap_or_default(),
+        ));
+        // Both the mentions waiting for the user and what they said to others
+        ctx.storage.own(
+            DB_NAME,
+            mentions.clone(),
+            |mentions: &mut Mentions, nick| {
+                let mut found = mentions.remove(nick).is_some();
+                for waiting in mentions.values_mut() {
+                    let before = waiting.len();
+                    waiting.retain(|mention| mention.from.to_lowercase() != nick);
+                    found |= waiting.len() != before;
+                }
+                mentions.retain(|_, waiting| !waiting.is_empty());
+                found
+            },
+        );
+        Ok(RecapPlugin {
+            idle: std::time::Duration::from_secs(idle * 60),
+            mentions,
+            told: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for RecapPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != RECAP.name {
+            return Ok(());
+        }
+        let kept = {
+            let mut mentions = self.mentions.lock().await;
+            let nick = cmd.user.nick.to_lowercase();
+            self.told.lock().await.remove(&nick);
+            let kept = mentions.remove(&nick);
+            if kept.is_some() {
+                ctx.storage.save(DB_NAME, &*mentions).await?;
+            }
+            kept.unwrap_or_default()
+        };
+        if kept.is_empty() {
+            let reply = format!("{}: Nobody mentioned you", cmd.user.nick);
+            return ctx.reply(cmd, reply).await;
+        }
+
+        let mut lines = vec![format!("{} mentions while you were away:", kept.len())];
+        lines.extend(
+            kept.iter()
+                .map(|m| format!("[{} {} ago] <{}> {}", m.channel, ago(m.at), m.from, m.text)),
+        );
+        // Sent privately, whatever channel it was asked from
+        let mut private = cmd.clone();
+        private.reply_to = cmd.user.nick.clone();
+        Reply::paginated(&private, lines).send(ctx).await
+    }
+
+    async fn chat(&self, ctx: &PluginContext, event: &ChatEvent) -> Result<()> {
+        match event.channel() {
+            Some(channel) if !event.is_ctcp => {
+                self.record(ctx, &event.sender.nick, channel, &event.body)
+                    .await
+            },
+            _ => Ok(()),
+        }
+    }
+
+    async fn message(&self, ctx: &PluginContext, msg: &irc::Message) -> Result<()> {
+        match (&msg.command, msg.source_nick()) {
+            // :nick!user@host JOIN #channel
+            (irc::Command::Join, Some(nick)) => self.welcome_back(ctx, nick).await,
+            _ => Ok(()),
+        }
+    }
+}
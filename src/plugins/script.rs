@@ -0,0 +1,275 @@
+//! User scripts written in Rhai, loaded from a directory.
+//!
+//! A script may define `on_command(bot, cmd)` and/or `on_message(bot, msg)`.
+//! `cmd` has `name`, `args`, `nick` and `reply_to`; `msg` has `nick`,
+//! `target`, `text`, `reply_to` and `action`, true for `/me`. `bot` offers
+//! `reply(text)`, `send(target, text)`, and a persistent per-script key-value
+//! store through `get(key)`, `set(key, value)` and `remove(key)`.
+
+use crate::chat::ChatEvent;
+use crate::command;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::storage::DataSet;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+
+const DB_NAME: &str = "scripts-kv";
+pub const DATA_SET: DataSet = DataSet::new::<HashMap<String, HashMap<String, String>>>(DB_NAME);
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// A script's persistent key-value store
+type Kv = Arc<Mutex<HashMap<String, String>>>;
+
+struct Script {
+    name: String,
+    ast:  AST,
+}
+
+/// What scripts get as the `bot` argument of their handlers.
+#[derive(Clone)]
+struct ScriptApi {
+    reply_to: String,
+    outgoing: Arc<Mutex<Vec<irc::Outgoing>>>,
+    kv:       Kv,
+    kv_dirty: Arc<AtomicBool>,
+}
+
+impl ScriptApi {
+    fn reply(&mut self, text: &str) {
+        let msg =
+            irc::Outgoing::privmsg(self.reply_to.as_str(), text).with_split(irc::Split::Lines);
+        self.outgoing.lock().unwrap().push(msg);
+    }
+
+    fn send(&mut self, target: &str, text: &str) {
+        let msg = irc::Outgoing::privmsg(target, text).with_split(irc::Split::Lines);
+        self.outgoing.lock().unwrap().push(msg);
+    }
+
+    fn get(&mut self, key: &str) -> Dynamic {
+        match self.kv.lock().unwrap().get(key) {
+            Some(value) => value.clone().into(),
+            None => Dynamic::UNIT,
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        self.kv.lock().unwrap().insert(key.into(), value.into());
+        self.kv_dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn remove(&mut self, key: &str) {
+        if self.kv.lock().unwrap().remove(key).is_some() {
+            self.kv_dirty.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct ScriptPlugin {
+    dir:     PathBuf,
+    engine:  Arc<Engine>,
+    scripts: RwLock<Vec<Arc<Script>>>,
+    kv:      Mutex<HashMap<String, Kv>>,
+}
+
+impl ScriptPlugin {
+    fn engine() -> Engine {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine
+            .register_type_with_name::<ScriptApi>("Bot")
+            .register_fn("reply", ScriptApi::reply)
+            .register_fn("send", ScriptApi::send)
+            .register_fn("get", ScriptApi::get)
+            .register_fn("set", ScriptApi::set)
+            .register_fn("remove", ScriptApi::remove);
+        engine
+    }
+
+    /// Compiles every `.rhai` file in the scripts directory, skipping (and
+    /// logging) the ones that fail.
+    fn load_scripts(&self) -> Result<Vec<Arc<Script>>> {
+        let mut scripts = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("rhai") {
+                continue;
+            }
+            let name = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            match self.engine.compile_file(path.clone()) {
+                Ok(ast) => {
+                    info!("Loaded script {}", name);
+                    scripts.push(Arc::new(Script { name, ast }));
+                },
+                Err(e) => error!("Could not load script {:?}: {}", path, e),
+            }
+        }
+        Ok(scripts)
+    }
+
+    fn script_kv(&self, name: &str) -> Kv {
+        self.kv
+            .lock()
+            .unwrap()
+            .entry(name.into())
+            .or_default()
+            .clone()
+    }
+
+    async fn save_kv(&self, ctx: &PluginContext) -> Result<()> {
+        let kv: HashMap<String, HashMap<String, String>> = self
+            .kv
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, kv)| (name.clone(), kv.lock().unwrap().clone()))
+            .collect();
+        ctx.storage.save(DB_NAME, &kv).await
+    }
+
+    /// Calls `function` in every script defining it.
+    async fn dispatch(
+        &self,
+        ctx: &PluginContext,
+        function: &'static str,
+        reply_to: &str,
+        event: Map,
+    ) -> Result<()> {
+        let scripts = self.scripts.read().await.clone();
+        let mut kv_dirty = false;
+        for script in scripts {
+            if !script.ast.iter_functions().any(|f| f.name == function) {
+                continue;
+            }
+            let api = ScriptApi {
+                reply_to: reply_to.into(),
+                outgoing: Arc::new(Mutex::new(vec![])),
+                kv:       self.script_kv(&script.name),
+                kv_dirty: Arc::new(AtomicBool::new(false)),
+            };
+            let (engine, event, call_api) = (self.engine.clone(), event.clone(), api.clone());
+            let script_ = script.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                engine
+                    .call_fn::<Dynamic>(
+                        &mut Scope::new(),
+                        &script_.ast,
+                        function,
+                        (call_api, event),
+                    )
+                    .map_err(|e| anyhow!("{}", e))
+            })
+            .await?;
+            if let Err(e) = result {
+                error!("[{}] Script {} failed: {}", ctx.server, script.name, e);
+            }
+
+            kv_dirty |= api.kv_dirty.load(Ordering::Relaxed);
+            let outgoing: Vec<irc::Outgoing> = api.outgoing.lock().unwrap().drain(..).collect();
+            for msg in outgoing {
+                ctx.send_outgoing(msg).await?;
+            }
+        }
+        if kv_dirty {
+            self.save_kv(ctx).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for ScriptPlugin {
+    type Plugin = ScriptPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[command::Help {
+        name:  "script",
+        usage: "script reload - reloads the scripts from disk",
+    }];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "script";
+
+    async fn new(ctx: &PluginContext) -> Result<ScriptPlugin> {
+        let dir = ctx
+            .config
+            .as_ref()
+            .and_then(|c| c.get("dir"))
+            .map_or("scripts", |d| d.as_str());
+        let kv: HashMap<String, HashMap<String, String>> =
+            ctx.storage.load(DB_NAME).await?.unwrap_or_default();
+        let plugin = ScriptPlugin {
+            dir:     dir.into(),
+            engine:  Arc::new(ScriptPlugin::engine()),
+            scripts: RwLock::new(vec![]),
+            kv:      Mutex::new(
+                kv.into_iter()
+                    .map(|(name, kv)| (name, Arc::new(Mutex::new(kv))))
+                    .collect(),
+            ),
+        };
+        match plugin.load_scripts() {
+            Ok(scripts) => *plugin.scripts.write().await = scripts,
+            Err(e) => warn!(
+                "[{}] Could not read scripts from {}: {}",
+                ctx.server, dir, e
+            ),
+        }
+        Ok(plugin)
+    }
+}
+
+#[async_trait]
+impl Plugin for ScriptPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name == "script" && cmd.args.as_deref() == Some("reload") {
+            let reply = match self.load_scripts() {
+                Ok(scripts) => {
+                    let names: Vec<String> = scripts.iter().map(|s| s.name.clone()).collect();
+                    *self.scripts.write().await = scripts;
+                    format!("Loaded {} scripts: {}", names.len(), names.join(", "))
+                },
+                Err(e) => format!("Could not reload scripts: {}", e),
+            };
+            return ctx.reply(cmd, reply).await;
+        }
+
+        let mut event = Map::new();
+        event.insert("name".into(), cmd.name.clone().into());
+        event.insert(
+            "args".into(),
+            cmd.args.clone().map_or(Dynamic::UNIT, Dynamic::from),
+        );
+        event.insert("nick".into(), cmd.user.nick.clone().into());
+        event.insert("reply_to".into(), cmd.reply_to.clone().into());
+        self.dispatch(ctx, "on_command", &cmd.reply_to, event).await
+    }
+
+    async fn chat(&self, ctx: &PluginContext, event: &ChatEvent) -> Result<()> {
+        if event.is_ctcp {
+            return Ok(());
+        }
+        let target = match event.channel() {
+            Some(channel) => channel.to_string(),
+            None => ctx.nick().await,
+        };
+        let reply_to = event.reply_to();
+
+        let mut msg = Map::new();
+        msg.insert("nick".into(), event.sender.nick.clone().into());
+        msg.insert("target".into(), target.into());
+        msg.insert("text".into(), event.body.clone().into());
+        msg.insert("reply_to".into(), reply_to.into());
+        msg.insert("action".into(), event.is_action.into());
+        self.dispatch(ctx, "on_message", reply_to, msg).await
+    }
+}
@@ -0,0 +1,242 @@
+//! Web search through DuckDuckGo's instant answers or a SearxNG instance.
+//!
+//! Configuration:
+//! - `engine`: `duckduckgo` (the default) or `searxng`
+//! - `url`: base URL of the SearxNG instance
+//! - `safesearch`: `off`, `moderate` (the default) or `strict`
+//! - `safesearch #channel`: overrides `safesearch` for one channel
+
+use crate::command;
+use crate::http;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use serde::Deserialize;
+use std::time::Duration;
+
+const SEARCH: command::Help = command::Help {
+    name:  "g",
+    usage: "g <query> - shows the top web search result",
+};
+
+const DUCKDUCKGO_URL: &str = "https://api.duckduckgo.com/";
+/// How long searches are answered from the cache
+const CACHE_FOR: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Debug)]
+enum SafeSearch {
+    Off,
+    Moderate,
+    Strict,
+}
+
+impl SafeSearch {
+    fn parse(value: &str) -> Result<SafeSearch> {
+        match value {
+            "off" => Ok(SafeSearch::Off),
+            "moderate" => Ok(SafeSearch::Moderate),
+            "strict" => Ok(SafeSearch::Strict),
+            _ => Err(anyhow!("[Search] Invalid safesearch level: {}", value)),
+        }
+    }
+}
+
+enum Engine {
+    DuckDuckGo,
+    SearxNG { url: String },
+}
+
+/// A search result worth posting.
+struct Hit {
+    title: String,
+    url:   String,
+}
+
+pub struct SearchPlugin {
+    engine:     Engine,
+    safesearch: SafeSearch,
+    /// Per-channel overrides, keyed by lowercase channel name
+    channels:   Vec<(String, SafeSearch)>,
+    client:     http::Client,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "PascalCase")]
+struct InstantAnswer {
+    heading:        String,
+    #[serde(rename = "AbstractURL")]
+    abstract_url:   String,
+    results:        Vec<Topic>,
+    related_topics: Vec<Topic>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "PascalCase")]
+struct Topic {
+    text:      String,
+    #[serde(rename = "FirstURL")]
+    first_url: String,
+}
+
+#[derive(Deserialize)]
+struct SearxResults {
+    results: Vec<SearxResult>,
+}
+
+#[derive(Deserialize)]
+struct SearxResult {
+    title: String,
+    url:   String,
+}
+
+impl SearchPlugin {
+    fn safesearch_for(&self, channel: &str) -> SafeSearch {
+        let channel = channel.to_lowercase();
+        self.channels
+            .iter()
+            .find(|(name, _)| *name == channel)
+            .map_or(self.safesearch, |(_, level)| *level)
+    }
+
+    async fn search(&self, query: &str, safesearch: SafeSearch) -> Result<Option<Hit>> {
+        match &self.engine {
+            Engine::DuckDuckGo => {
+                let kp = match safesearch {
+                    SafeSearch::Off => "-2",
+                    SafeSearch::Moderate => "-1",
+                    SafeSearch::Strict => "1",
+                };
+                let answer: InstantAnswer = self
+                    .client
+                    .get(DUCKDUCKGO_URL)
+                    .query(&[
+                        ("q", query),
+                        ("format", "json"),
+                        ("no_html", "1"),
+                        ("skip_disambig", "1"),
+                        ("kp", kp),
+                    ])
+                    .cache(CACHE_FOR)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                if !answer.abstract_url.is_empty() {
+                    return Ok(Some(Hit {
+                        title: answer.heading,
+                        url:   answer.abstract_url,
+                    }));
+                }
+                // Grouped related topics have no URL of their own
+                let topic = answer
+                    .results
+                    .into_iter()
+                    .chain(answer.related_topics)
+                    .find(|t| !t.first_url.is_empty());
+                Ok(topic.map(|t| Hit {
+                    title: t.text,
+                    url:   t.first_url,
+                }))
+            },
+            Engine::SearxNG { url } => {
+                let level = match safesearch {
+                    SafeSearch::Off => "0",
+                    SafeSearch::Moderate => "1",
+                    SafeSearch::Strict => "2",
+                };
+                let results: SearxResults = self
+                    .client
+                    .get(&format!("{}/search", url.trim_end_matches('/')))
+                    .query(&[("q", query), ("format", "json"), ("safesearch", level)])
+                    .cache(CACHE_FOR)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                Ok(results.results.into_iter().next().map(|r| Hit {
+                    title: r.title,
+                    url:   r.url,
+                }))
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for SearchPlugin {
+    type Plugin = SearchPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[SEARCH];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "search";
+
+    async fn new(ctx: &PluginContext) -> Result<SearchPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let engine = match config.get("engine").map(String::as_str) {
+            None | Some("duckduckgo") => Engine::DuckDuckGo,
+            Some("searxng") => Engine::SearxNG {
+                url: config
+                    .get("url")
+                    .ok_or_else(|| {
+                        anyhow!("[Search] SearxNG requires `url` in its config section")
+                    })?
+                    .clone(),
+            },
+            Some(engine) => return Err(anyhow!("[Search] Unknown engine: {}", engine)),
+        };
+        let safesearch = match config.get("safesearch") {
+            Some(level) => SafeSearch::parse(level)?,
+            None => SafeSearch::Moderate,
+        };
+        let mut channels = vec![];
+        for (key, level) in &config {
+            if let Some(channel) = key.strip_prefix("safesearch ") {
+                channels.push((channel.trim().to_lowercase(), SafeSearch::parse(level)?));
+            }
+        }
+
+        Ok(SearchPlugin {
+            engine,
+            safesearch,
+            channels,
+            client: ctx.http.client(),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for SearchPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != SEARCH.name {
+            return Ok(());
+        }
+        let query = match cmd.args.as_deref() {
+            Some(query) => query,
+            None => {
+                let usage = ctx.usage(cmd, SEARCH.usage);
+                return ctx.reply(cmd, usage).await;
+            },
+        };
+
+        let safesearch = self.safesearch_for(&cmd.reply_to);
+        let reply = match self.search(query, safesearch).await {
+            Ok(Some(hit)) => {
+                let url = ctx.shortener.shorten(&cmd.reply_to, &hit.url).await;
+                format!("{}: {} - {}", cmd.user.nick, hit.title, url)
+            },
+            Ok(None) => format!("{}: No results for `{}`", cmd.user.nick, query),
+            Err(e) => {
+                warn!("[{}] Search for {:?} failed: {}", ctx.server, query, e);
+                ctx.tr(
+                    cmd,
+                    "{0}: Search failed, try again later",
+                    &[&cmd.user.nick],
+                )
+            },
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
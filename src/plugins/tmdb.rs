@@ -0,0 +1,233 @@
+//! Movie and TV show lookups through The Movie Database, configured with an
+//! `api-key` from <https://www.themoviedb.org/settings/api>.
+
+use crate::command;
+use crate::http;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::reply::truncate;
+use anyhow::Result;
+use async_trait::async_trait;
+use log::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const IMDB: command::Help = command::Help {
+    name:  "imdb",
+    usage: "imdb <title> [--year=<year>] - shows a movie or show's rating, genres and plot",
+};
+
+const API_URL: &str = "https://api.themoviedb.org/3";
+/// How long searches are answered from the cache
+const CACHE_FOR: Duration = Duration::from_secs(24 * 60 * 60);
+/// Longest plot shown, in characters
+const MAX_PLOT: usize = 200;
+/// How many other titles to mention when the title is ambiguous
+const MAX_ALTERNATIVES: usize = 3;
+
+#[derive(Deserialize)]
+struct SearchResults {
+    results: Vec<Title>,
+}
+
+/// A movie or TV show, as returned by the multi search.
+#[derive(Deserialize)]
+struct Title {
+    id:           u64,
+    media_type:   String,
+    #[serde(default, alias = "name")]
+    title:        String,
+    #[serde(default, alias = "first_air_date")]
+    release_date: String,
+    #[serde(default)]
+    vote_average: f32,
+    #[serde(default)]
+    vote_count:   u64,
+    #[serde(default)]
+    genre_ids:    Vec<u64>,
+    #[serde(default)]
+    overview:     String,
+}
+
+#[derive(Deserialize)]
+struct Genres {
+    genres: Vec<Genre>,
+}
+
+#[derive(Deserialize)]
+struct Genre {
+    id:   u64,
+    name: String,
+}
+
+impl Title {
+    fn year(&self) -> Option<&str> {
+        self.release_date.get(.. 4)
+    }
+
+    fn describe(&self) -> String {
+        match self.year() {
+            Some(year) => format!("{} ({})", self.title, year),
+            None => self.title.clone(),
+        }
+    }
+}
+
+pub struct TmdbPlugin {
+    api_key: Option<String>,
+    /// Genre names for both movies and TV shows, by id
+    genres:  HashMap<u64, String>,
+    client:  http::Client,
+}
+
+impl TmdbPlugin {
+    async fn genres(client: &http::Client, api_key: &str) -> Result<HashMap<u64, String>> {
+        let mut genres = HashMap::new();
+        for kind in &["movie", "tv"] {
+            let list: Genres = client
+                .get(&format!("{}/genre/{}/list", API_URL, kind))
+                .query(&[("api_key", api_key)])
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            genres.extend(list.genres.into_iter().map(|g| (g.id, g.name)));
+        }
+        Ok(genres)
+    }
+
+    async fn search(&self, api_key: &str, query: &str) -> Result<Vec<Title>> {
+        let results: SearchResults = self
+            .client
+            .get(&format!("{}/search/multi", API_URL))
+            .query(&[("api_key", api_key), ("query", query)])
+            .cache(CACHE_FOR)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(results
+            .results
+            .into_iter()
+            .filter(|t| t.media_type == "movie" || t.media_type == "tv")
+            .collect())
+    }
+
+    /// Describes the best match, mentioning other titles with the same name
+    /// so the user can pick one by year.
+    fn describe(&self, titles: &[Title]) -> String {
+        let title = &titles[0];
+        let mut line = title.describe();
+        if title.vote_count > 0 {
+            line += &format!(" 〜 \u{2B50} {:.1}/10", title.vote_average);
+        }
+        let genres: Vec<&str> = title
+            .genre_ids
+            .iter()
+            .filter_map(|id| self.genres.get(id).map(String::as_str))
+            .collect();
+        if !genres.is_empty() {
+            line += &format!(" 〜 {}", genres.join(", "));
+        }
+        if !title.overview.is_empty() {
+            line += &format!(" 〜 {}", truncate(&title.overview, MAX_PLOT));
+        }
+
+        let alternatives: Vec<String> = titles[1 ..]
+            .iter()
+            .filter(|t| t.title.eq_ignore_ascii_case(&title.title))
+            .take(MAX_ALTERNATIVES)
+            .map(Title::describe)
+            .collect();
+        if !alternatives.is_empty() {
+            line += &format!(" 〜 Also: {}", alternatives.join(", "));
+        }
+        line
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for TmdbPlugin {
+    type Plugin = TmdbPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[IMDB];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "tmdb";
+
+    async fn new(ctx: &PluginContext) -> Result<TmdbPlugin> {
+        let api_key = ctx.config.as_ref().and_then(|c| c.get("api-key")).cloned();
+        let client = ctx.http.client();
+        let genres = match &api_key {
+            Some(api_key) => TmdbPlugin::genres(&client, api_key)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("[{}] Could not load TMDB genres: {}", ctx.server, e);
+                    HashMap::new()
+                }),
+            None => {
+                warn!(
+                    "[{}] TMDB lookups need `api-key` in the tmdb config section",
+                    ctx.server
+                );
+                HashMap::new()
+            },
+        };
+        Ok(TmdbPlugin {
+            api_key,
+            genres,
+            client,
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for TmdbPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != IMDB.name {
+            return Ok(());
+        }
+        let api_key = match &self.api_key {
+            Some(api_key) => api_key,
+            None => {
+                let reply = format!("{}: Lookups aren't configured", cmd.user.nick);
+                return ctx.reply(cmd, reply).await;
+            },
+        };
+        let args = cmd.parse_args();
+        let query = args.words().collect::<Vec<_>>().join(" ");
+        if query.is_empty() {
+            let usage = ctx.usage(cmd, IMDB.usage);
+            return ctx.reply(cmd, usage).await;
+        }
+
+        let reply = match self.search(api_key, &query).await {
+            Ok(mut titles) => {
+                if let Some(year) = args.value("year") {
+                    titles.retain(|t| t.year() == Some(year));
+                }
+                if titles.is_empty() {
+                    format!("{}: Nothing found for `{}`", cmd.user.nick, query)
+                } else {
+                    let title = &titles[0];
+                    let link = format!(
+                        "https://www.themoviedb.org/{}/{}",
+                        title.media_type, title.id
+                    );
+                    let link = ctx.shortener.shorten(&cmd.reply_to, &link).await;
+                    format!("{} 〜 {}", self.describe(&titles), link)
+                }
+            },
+            Err(e) => {
+                warn!("[{}] TMDB search for {:?} failed: {}", ctx.server, query, e);
+                ctx.tr(
+                    cmd,
+                    "{0}: Lookup failed, try again later",
+                    &[&cmd.user.nick],
+                )
+            },
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
@@ -0,0 +1,326 @@
+//! Canned responses to messages matching a regex, set up per channel by
+//! admins with `\trigger add /deploy(ed)?/ "did you update the changelog?"`.
+//! Responses can use `{nick}`, `{channel}`, and `{0}`, `{1}`... for the match
+//! and its groups.
+//!
+//! Configuration:
+//! - `cooldown-seconds`: how long a trigger stays quiet after responding,
+//!   unless given with `cooldown=<seconds>`, 60 by default
+
+use crate::chat::ChatEvent;
+use crate::command;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::reply::Reply;
+use crate::storage::DataSet;
+use crate::template;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const TRIGGER: command::Help = command::Help {
+    name:  "trigger",
+    usage: "trigger add /<regex>/[i] <response> [cooldown=<seconds>] | del <number> | list - \
+            manages this channel's triggers (admins only)",
+};
+
+const DB_NAME: &str = "triggers";
+pub const DATA_SET: DataSet = DataSet::new::<Saved>(DB_NAME);
+const DEFAULT_COOLDOWN_SECONDS: u64 = 60;
+/// Most triggers per channel
+const MAX_TRIGGERS: usize = 50;
+/// Largest compiled regex, in bytes, so triggers stay cheap to match
+const MAX_REGEX_SIZE: usize = 1 << 16;
+
+/// Triggers by lowercase channel, in the order they were added.
+type Saved = BTreeMap<String, Vec<Trigger>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Trigger {
+    /// As typed, like `/deploy(ed)?/i`
+    pattern:  String,
+    response: String,
+    /// Seconds to stay quiet after responding, or `None` for the default
+    cooldown: Option<u64>,
+}
+
+/// A trigger ready to be matched.
+struct Active {
+    trigger: Trigger,
+    regex:   Regex,
+    fired:   Option<Instant>,
+}
+
+/// Compiles a pattern like `/deploy(ed)?/i`.
+fn compile(pattern: &str) -> Result<Regex> {
+    let (regex, flags) = pattern
+        .strip_prefix('/')
+        .and_then(|p| p.rsplit_once('/'))
+        .ok_or_else(|| anyhow!("the regex must be between slashes, like /deploy(ed)?/"))?;
+    if !flags.chars().all(|c| c == 'i') {
+        return Err(anyhow!("the only flag is i, to ignore case"));
+    }
+    RegexBuilder::new(regex)
+        .case_insensitive(flags.contains('i'))
+        .size_limit(MAX_REGEX_SIZE)
+        .build()
+        .map_err(|e| anyhow!("invalid regex: {}", e))
+}
+
+pub struct TriggersPlugin {
+    cooldown: Duration,
+    /// Never held across an await, so `claims` can wait on it
+    active:   Mutex<BTreeMap<String, Vec<Active>>>,
+}
+
+impl TriggersPlugin {
+    /// Whether the trigger fired too recently to respond again.
+    fn cooling_down(&self, active: &Active) -> bool {
+        let cooldown = active
+            .trigger
+            .cooldown
+            .map_or(self.cooldown, Duration::from_secs);
+        active.fired.is_some_and(|fired| fired.elapsed() < cooldown)
+    }
+
+    async fn save(&self, ctx: &PluginContext) -> Result<()> {
+        let saved: Saved = self
+            .active
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(channel, active)| {
+                let triggers = active.iter().map(|a| a.trigger.clone()).collect();
+                (channel.clone(), triggers)
+            })
+            .collect();
+        ctx.storage.save(DB_NAME, &saved).await
+    }
+
+    /// Handles `\trigger` in the channel, returning the reply.
+    async fn manage(
+        &self,
+        ctx: &PluginContext,
+        cmd: &command::Invocation,
+        channel: &str,
+    ) -> Result<String> {
+        let args = cmd.parse_args();
+        let words: Vec<&str> = args.words().collect();
+        let nick = &cmd.user.nick;
+        match words[..] {
+            ["add", pattern, ref response @ ..] if !response.is_empty() => {
+                let regex = match compile(pattern) {
+                    Ok(regex) => regex,
+                    Err(e) => return Ok(format!("{}: {}", nick, e)),
+                };
+                let cooldown = match args.value("cooldown").map(str::parse) {
+                    Some(Ok(cooldown)) => Some(cooldown),
+                    Some(Err(_)) => return Ok(format!("{}: Invalid cooldown", nick)),
+                    None => None,
+                };
+                let number = {
+                    let mut active = self.active.lock().unwrap();
+                    let triggers = active.entry(channel.into()).or_default();
+                    if triggers.len() >= MAX_TRIGGERS {
+                        return Ok(format!("{}: Too many triggers here already", nick));
+                    }
+                    triggers.push(Active {
+                        trigger: Trigger {
+                            pattern: pattern.into(),
+                            response: response.join(" "),
+                            cooldown,
+                        },
+                        regex,
+                        fired: None,
+                    });
+                    triggers.len()
+                };
+                self.save(ctx).await?;
+                info!(
+                    "[{}] {} added trigger {} in {}",
+                    ctx.server, cmd.user, pattern, channel
+                );
+                Ok(format!("{}: Added trigger #{}", nick, number))
+            },
+            ["del", number] => {
+                let removed = {
+                    let mut active = self.active.lock().unwrap();
+                    let triggers = active.entry(channel.into()).or_default();
+                    let index = match number.trim_start_matches('#').parse::<usize>() {
+                        Ok(number) if (1 ..= triggers.len()).contains(&number) => number - 1,
+                        _ => return Ok(format!("{}: No trigger #{} here", nick, number)),
+                    };
+                    let removed = triggers.remove(index);
+                    active.retain(|_, triggers| !triggers.is_empty());
+                    removed
+                };
+                self.save(ctx).await?;
+                info!(
+                    "[{}] {} deleted trigger {} in {}",
+                    ctx.server, cmd.user, removed.trigger.pattern, channel
+                );
+                Ok(format!(
+                    "{}: Deleted trigger {}",
+                    nick, removed.trigger.pattern
+                ))
+            },
+            _ => Ok(ctx.usage(cmd, TRIGGER.usage)),
+        }
+    }
+
+    fn list(&self, channel: &str) -> Vec<String> {
+        let active = self.active.lock().unwrap();
+        let triggers = match active.get(channel) {
+            Some(triggers) => triggers,
+            None => return vec![],
+        };
+        triggers
+            .iter()
+            .enumerate()
+            .map(|(i, active)| {
+                format!(
+                    "#{} {} 〜 {}",
+                    i + 1,
+                    active.trigger.pattern,
+                    active.trigger.response
+                )
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for TriggersPlugin {
+    type Plugin = TriggersPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[TRIGGER];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "triggers";
+
+    async fn new(ctx: &PluginContext) -> Result<TriggersPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let cooldown = match config.get("cooldown-seconds") {
+            Some(seconds) => seconds
+                .parse()
+                .map_err(|_| anyhow!("[Triggers] Invalid cooldown-seconds: {}", seconds))?,
+            None => DEFAULT_COOLDOWN_SECONDS,
+        };
+
+        let saved: Saved = ctx.storage.load(DB_NAME).await?.unwrap_or_default();
+        let mut active = BTreeMap::new();
+        for (channel, triggers) in saved {
+            let mut compiled = vec![];
+            for trigger in triggers {
+                // A regex can stop compiling when the regex crate changes
+                match compile(&trigger.pattern) {
+                    Ok(regex) => compiled.push(Active {
+                        trigger,
+                        regex,
+                        fired: None,
+                    }),
+                    Err(e) => warn!(
+                        "[{}] Skipping trigger {} in {}: {}",
+                        ctx.server, trigger.pattern, channel, e
+                    ),
+                }
+            }
+            active.insert(channel, compiled);
+        }
+
+        Ok(TriggersPlugin {
+            cooldown: Duration::from_secs(cooldown),
+            active:   Mutex::new(active),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for TriggersPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != TRIGGER.name {
+            return Ok(());
+        }
+        if !ctx.admins.allows(&cmd.user) {
+            info!("[{}] Denied {} to {}", ctx.server, cmd.name, cmd.user);
+            let reply = ctx.tr(cmd, "{0}: You're not allowed to do that", &[&cmd.user.nick]);
+            return ctx.reply(cmd, reply).await;
+        }
+        if !irc::is_channel(&cmd.reply_to) {
+            let reply = ctx.tr(cmd, "{0}: Only works in channels", &[&cmd.user.nick]);
+            return ctx.reply(cmd, reply).await;
+        }
+        let channel = cmd.reply_to.to_lowercase();
+
+        if cmd.args.as_deref().map(str::trim) == Some("list") {
+            let lines = self.list(&channel);
+            if lines.is_empty() {
+                let reply = format!("{}: No triggers here", cmd.user.nick);
+                return ctx.reply(cmd, reply).await;
+            }
+            return Reply::pasted(ctx, cmd, lines).await.send(ctx).await;
+        }
+        let reply = self.manage(ctx, cmd, &channel).await?;
+        ctx.reply(cmd, reply).await
+    }
+
+    fn claims(&self, _ctx: &PluginContext, event: &ChatEvent) -> bool {
+        let channel = match event.channel() {
+            Some(channel) if !event.is_ctcp => channel,
+            _ => return false,
+        };
+        let active = self.active.lock().unwrap();
+        active.get(&channel.to_lowercase()).is_some_and(|triggers| {
+            triggers
+                .iter()
+                .any(|active| !self.cooling_down(active) && active.regex.is_match(&event.body))
+        })
+    }
+
+    async fn chat(&self, ctx: &PluginContext, event: &ChatEvent) -> Result<()> {
+        let (nick, channel, text) = match event.channel() {
+            Some(channel) if !event.is_ctcp => (event.sender.nick.as_str(), channel, &event.body),
+            _ => return Ok(()),
+        };
+        if ctx.state.read().await.is_me(nick) {
+            return Ok(());
+        }
+
+        let response = {
+            let mut active = self.active.lock().unwrap();
+            let triggers = match active.get_mut(&channel.to_lowercase()) {
+                Some(triggers) => triggers,
+                None => return Ok(()),
+            };
+            let matched = triggers.iter_mut().find_map(|active| {
+                if self.cooling_down(active) {
+                    return None;
+                }
+                let captures = active.regex.captures(text)?;
+                active.fired = Some(Instant::now());
+                Some((active.trigger.response.clone(), captures))
+            });
+            let (response, captures) = match matched {
+                Some(matched) => matched,
+                None => return Ok(()),
+            };
+
+            let mut fields = Map::new();
+            for (i, group) in captures.iter().enumerate() {
+                let group = group.map_or("", |g| g.as_str());
+                fields.insert(i.to_string(), Value::String(group.into()));
+            }
+            fields.insert("nick".into(), Value::String(nick.into()));
+            fields.insert("channel".into(), Value::String(channel.into()));
+            template::render(&response, &Value::Object(fields))
+        };
+        let msg =
+            irc::Outgoing::privmsg(channel, response.as_str()).in_reply_to(event.msgid.as_deref());
+        ctx.send_outgoing(msg).await
+    }
+}
@@ -0,0 +1,358 @@
+//! Announces when Twitch streamers go live, polling the Helix API.
+//!
+//! Configuration:
+//! - `client-id` and `client-secret`: from <https://dev.twitch.tv/console>
+//! - `poll-minutes`: how often to check the streams, 2 by default
+//! - `streams #channel`: space separated streamers announced in the channel, on
+//!   top of the ones added through `\stream add`
+//! - `format live`: how to announce streams, with `{user}`, `{login}`,
+//!   `{title}`, `{game}` and `{url}`, also as `format live #channel`
+
+use crate::command;
+use crate::http;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::storage::DataSet;
+use crate::template;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const STREAM: command::Help = command::Help {
+    name:  "stream",
+    usage: "stream add|remove <streamer> [#channel] | list [#channel] - manages the Twitch \
+            streamers announced in a channel (admins only)",
+};
+
+const TOKEN_URL: &str = "https://id.twitch.tv/oauth2/token";
+const STREAMS_URL: &str = "https://api.twitch.tv/helix/streams";
+const DB_NAME: &str = "twitch";
+pub const DATA_SET: DataSet = DataSet::new::<Streams>(DB_NAME);
+const DEFAULT_POLL_MINUTES: u64 = 2;
+/// Most streamers Helix accepts in a single request
+const MAX_LOGINS: usize = 100;
+
+/// Streamers announced in each channel, keyed by lowercase channel name.
+type Streams = BTreeMap<String, BTreeSet<String>>;
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    /// Seconds
+    expires_in:   u64,
+}
+
+#[derive(Deserialize)]
+struct StreamList {
+    data: Vec<Stream>,
+}
+
+#[derive(Deserialize)]
+struct Stream {
+    user_login: String,
+    user_name:  String,
+    #[serde(default)]
+    game_name:  String,
+    #[serde(default)]
+    title:      String,
+}
+
+impl Stream {
+    fn describe(&self) -> String {
+        let mut line = format!("🔴 {} is live", self.user_name);
+        if !self.title.is_empty() {
+            line += &format!(": {}", self.title.trim());
+        }
+        if !self.game_name.is_empty() {
+            line += &format!(" [{}]", self.game_name);
+        }
+        format!("{} 〜 https://twitch.tv/{}", line, self.user_login)
+    }
+
+    fn fields(&self) -> Value {
+        json!({
+            "user": self.user_name,
+            "login": self.user_login,
+            "title": self.title.trim(),
+            "game": self.game_name,
+            "url": format!("https://twitch.tv/{}", self.user_login),
+        })
+    }
+}
+
+/// Talks to Helix with an app access token, fetched again when it expires.
+#[derive(Clone)]
+struct Helix {
+    client_id:     String,
+    client_secret: String,
+    client:        http::Client,
+    token:         Arc<Mutex<Option<(String, Instant)>>>,
+}
+
+impl Helix {
+    async fn token(&self) -> Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some((access_token, expires)) = &*token {
+            if Instant::now() < *expires {
+                return Ok(access_token.clone());
+            }
+        }
+        let fresh: Token = self
+            .client
+            .post(TOKEN_URL)
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("grant_type", "client_credentials"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        // Renew a bit early rather than using an expired token
+        let expires = Instant::now() + Duration::from_secs(fresh.expires_in.saturating_sub(60));
+        *token = Some((fresh.access_token.clone(), expires));
+        Ok(fresh.access_token)
+    }
+
+    /// Which of the streamers are live right now.
+    async fn live(&self, logins: &[String]) -> Result<Vec<Stream>> {
+        let token = self.token().await?;
+        let mut streams = vec![];
+        for chunk in logins.chunks(MAX_LOGINS) {
+            let query: Vec<(&str, &str)> =
+                chunk.iter().map(|l| ("user_login", l.as_str())).collect();
+            let response = self
+                .client
+                .get(STREAMS_URL)
+                .query(&query)
+                .header("Client-Id", &self.client_id)
+                .bearer_auth(&token)
+                .send()
+                .await?;
+            if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+                // Revoked early, so get a new one next time
+                *self.token.lock().await = None;
+            }
+            let list: StreamList = response.error_for_status()?.json().await?;
+            streams.extend(list.data);
+        }
+        Ok(streams)
+    }
+}
+
+/// Polls the followed streamers and announces the ones going live.
+#[derive(Clone)]
+struct Announcer {
+    ctx:     PluginContext,
+    helix:   Helix,
+    streams: Arc<Mutex<Streams>>,
+    /// Streamers live at the last poll, or `None` before the first one, so
+    /// streams already live when the bot connects aren't announced
+    live:    Arc<Mutex<Option<HashSet<String>>>>,
+}
+
+impl Announcer {
+    async fn poll(self) {
+        let logins: Vec<String> = {
+            let streams = self.streams.lock().await;
+            let logins: BTreeSet<&String> = streams.values().flatten().collect();
+            logins.into_iter().cloned().collect()
+        };
+        if logins.is_empty() {
+            return;
+        }
+        let streams = match self.helix.live(&logins).await {
+            Ok(streams) => streams,
+            Err(e) => {
+                warn!(
+                    "[{}] Could not check Twitch streams: {}",
+                    self.ctx.server, e
+                );
+                return;
+            },
+        };
+
+        let mut live = self.live.lock().await;
+        let now_live: HashSet<String> = streams
+            .iter()
+            .map(|s| s.user_login.to_lowercase())
+            .collect();
+        if let Some(was_live) = &*live {
+            let channels = self.streams.lock().await;
+            for stream in streams
+                .iter()
+                .filter(|s| !was_live.contains(&s.user_login.to_lowercase()))
+            {
+                let login = stream.user_login.to_lowercase();
+                for (channel, _) in channels
+                    .iter()
+                    .filter(|(_, logins)| logins.contains(&login))
+                {
+                    let text = match self.ctx.format("live", channel) {
+                        Some(format) => template::render(format, &stream.fields()),
+                        None => stream.describe(),
+                    };
+                    let msg = irc::Outgoing::privmsg(channel.as_str(), text.as_str());
+                    if let Err(e) = self.ctx.send_outgoing(msg).await {
+                        warn!("[{}] Could not announce {}: {}", self.ctx.server, login, e);
+                    }
+                }
+            }
+        }
+        *live = Some(now_live);
+    }
+}
+
+pub struct TwitchPlugin {
+    streams: Arc<Mutex<Streams>>,
+}
+
+impl TwitchPlugin {
+    async fn change(
+        &self,
+        ctx: &PluginContext,
+        channel: String,
+        login: String,
+        add: bool,
+    ) -> Result<String> {
+        let mut streams = self.streams.lock().await;
+        let logins = streams.entry(channel.clone()).or_default();
+        let changed = if add {
+            logins.insert(login.clone())
+        } else {
+            logins.remove(&login)
+        };
+        if logins.is_empty() {
+            streams.remove(&channel);
+        }
+        if !changed {
+            return Ok(if add {
+                format!("{} is already announced in {}", login, channel)
+            } else {
+                format!("{} isn't announced in {}", login, channel)
+            });
+        }
+        ctx.storage.save(DB_NAME, &*streams).await?;
+        Ok(if add {
+            format!("Announcing {} in {}", login, channel)
+        } else {
+            format!("No longer announcing {} in {}", login, channel)
+        })
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for TwitchPlugin {
+    type Plugin = TwitchPlugin;
+
+    const COMMANDS: &'static [command::Help] = &[STREAM];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "twitch";
+
+    async fn new(ctx: &PluginContext) -> Result<TwitchPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        let mut streams: Streams = ctx.storage.load(DB_NAME).await?.unwrap_or_default();
+        for (key, logins) in &config {
+            if let Some(channel) = key.strip_prefix("streams ") {
+                streams
+                    .entry(channel.trim().to_lowercase())
+                    .or_default()
+                    .extend(logins.split_whitespace().map(str::to_lowercase));
+            }
+        }
+        let streams = Arc::new(Mutex::new(streams));
+
+        let (client_id, client_secret) =
+            match (config.get("client-id"), config.get("client-secret")) {
+                (Some(id), Some(secret)) => (id.clone(), secret.clone()),
+                _ => {
+                    warn!(
+                        "[{}] Twitch needs `client-id` and `client-secret` in its config section",
+                        ctx.server
+                    );
+                    return Ok(TwitchPlugin { streams });
+                },
+            };
+        let poll_minutes = match config.get("poll-minutes") {
+            Some(minutes) => minutes
+                .parse()
+                .map_err(|_| anyhow!("[Twitch] Invalid poll-minutes: {}", minutes))?,
+            None => DEFAULT_POLL_MINUTES,
+        };
+
+        let announcer = Announcer {
+            ctx:     ctx.clone(),
+            helix:   Helix {
+                client_id,
+                client_secret,
+                client: ctx.http.client(),
+                token: Arc::new(Mutex::new(None)),
+            },
+            streams: streams.clone(),
+            live:    Arc::new(Mutex::new(None)),
+        };
+        ctx.scheduler
+            .after(Duration::ZERO, announcer.clone().poll());
+        ctx.scheduler
+            .every(Duration::from_secs(poll_minutes * 60), move || {
+                announcer.clone().poll()
+            });
+
+        Ok(TwitchPlugin { streams })
+    }
+}
+
+#[async_trait]
+impl Plugin for TwitchPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != STREAM.name {
+            return Ok(());
+        }
+        if !ctx.admins.allows(&cmd.user) {
+            info!("[{}] Denied {} to {}", ctx.server, cmd.name, cmd.user);
+            let reply = ctx.tr(cmd, "{0}: You're not allowed to do that", &[&cmd.user.nick]);
+            return ctx.reply(cmd, reply).await;
+        }
+
+        let args = cmd.parse_args();
+        let mut words: Vec<&str> = args.words().collect();
+        // An explicit channel, or else the one the command came from
+        let channel = match words.last() {
+            Some(last) if irc::is_channel(last) => Some(words.pop().unwrap_or_default()),
+            _ => Some(cmd.reply_to.as_str()).filter(|target| irc::is_channel(target)),
+        };
+        let reply = match (&words[..], channel) {
+            (_, None) => ctx.tr(cmd, "{0}: Which channel?", &[&cmd.user.nick]),
+            (["list"], Some(channel)) => {
+                let streams = self.streams.lock().await;
+                match streams.get(&channel.to_lowercase()) {
+                    Some(logins) => {
+                        let logins: Vec<&str> = logins.iter().map(String::as_str).collect();
+                        format!(
+                            "{}: Announcing {} in {}",
+                            cmd.user.nick,
+                            logins.join(", "),
+                            channel
+                        )
+                    },
+                    None => format!("{}: No streams announced in {}", cmd.user.nick, channel),
+                }
+            },
+            ([action @ ("add" | "remove"), login], Some(channel)) => {
+                let (channel, login) = (channel.to_lowercase(), login.to_lowercase());
+                let reply = self.change(ctx, channel, login, *action == "add").await?;
+                format!("{}: {}", cmd.user.nick, reply)
+            },
+            _ => ctx.usage(cmd, STREAM.usage),
+        };
+        ctx.reply(cmd, reply).await
+    }
+}
@@ -0,0 +1,55 @@
+use crate::chat::ChatEvent;
+use crate::command;
+use crate::irc;
+use crate::plugins::{Plugin, PluginContext};
+use crate::version;
+use anyhow::Result;
+use async_trait::async_trait;
+
+pub const NAME: &str = "version";
+
+pub const VERSION: command::Help = command::Help {
+    name:  "version",
+    usage: "version - shows the bot's version and loaded plugins",
+};
+
+const CTCP_VERSION: &str = "VERSION";
+
+/// Answers `\version` and CTCP VERSION requests.
+pub struct VersionPlugin {
+    plugins: Vec<String>,
+}
+
+impl VersionPlugin {
+    pub fn new(mut plugins: Vec<String>) -> Self {
+        plugins.sort();
+        VersionPlugin { plugins }
+    }
+}
+
+#[async_trait]
+impl Plugin for VersionPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if cmd.name != VERSION.name {
+            return Ok(());
+        }
+        let reply = format!(
+            "boton {}, with plugins: {}",
+            version::describe(),
+            self.plugins.join(", ")
+        );
+        ctx.reply(cmd, reply).await
+    }
+
+    async fn chat(&self, ctx: &PluginContext, event: &ChatEvent) -> Result<()> {
+        if !event.is_ctcp || event.body != CTCP_VERSION {
+            return Ok(());
+        }
+        let reply = format!("\x01VERSION {}\x01", ctx.messages.version_reply());
+        ctx.send_outgoing(irc::Outgoing::notice(
+            event.sender.nick.as_str(),
+            reply.as_str(),
+        ))
+        .await
+    }
+}
@@ -0,0 +1,60 @@
+//! Relays WALLOPS and server notices to a channel, for bots running with an
+//! oper account. Which of them the server sends depends on the bot's user
+//! modes, set with `user_modes`, like `+ws` for both.
+//!
+//! Configuration:
+//! - `channel`: where to relay them, nothing being relayed without one
+//! - `server-notices`: `false` to only relay WALLOPS
+
+use crate::irc;
+use crate::notices::ServerEvent;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use anyhow::Result;
+use async_trait::async_trait;
+use log::*;
+
+pub struct WallopsPlugin {
+    channel: Option<String>,
+    notices: bool,
+}
+
+#[async_trait]
+impl PluginBuilder for WallopsPlugin {
+    type Plugin = WallopsPlugin;
+
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "wallops";
+
+    async fn new(ctx: &PluginContext) -> Result<WallopsPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        Ok(WallopsPlugin {
+            channel: config.get("channel").cloned(),
+            notices: config.get("server-notices").is_none_or(|n| n != "false"),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for WallopsPlugin {
+    async fn server(&self, ctx: &PluginContext, event: &ServerEvent) -> Result<()> {
+        let channel = match &self.channel {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        let text = match event {
+            ServerEvent::Wallops { from, text } => format!("[wallops] <{}> {}", from, text),
+            ServerEvent::Notice { .. } if !self.notices => return Ok(()),
+            ServerEvent::Notice { server, text } if server.is_empty() => {
+                format!("[{}] {}", ctx.server, text)
+            },
+            ServerEvent::Notice { server, text } => format!("[{}] {}", server, text),
+        };
+        // Like the notices of connecting, before any channel is joined
+        if ctx.state.read().await.channel(channel).is_none() {
+            debug!("[{}] Not in {}, dropping {:?}", ctx.server, channel, event);
+            return Ok(());
+        }
+        ctx.send_outgoing(irc::Outgoing::privmsg(channel.as_str(), text.as_str()))
+            .await
+    }
+}
@@ -0,0 +1,440 @@
+//! Plugins compiled to WebAssembly, loaded at runtime from a directory.
+//!
+//! Each module gets every command and PRIVMSG as a JSON event through its
+//! exported `on_event(ptr, len)` function, and must also export `memory` and
+//! `alloc(len) -> ptr`, used by the host to hand data back to the module.
+//!
+//! Modules can import from `boton`:
+//! - `send(target_ptr, target_len, text_ptr, text_len)`
+//! - `log(ptr, len)`
+//! - `kv_get(key_ptr, key_len) -> i64`
+//! - `kv_set(key_ptr, key_len, value_ptr, value_len)`
+//! - `kv_delete(key_ptr, key_len)`
+//! - `http_get(url_ptr, url_len) -> i64`
+//!
+//! Functions returning `i64` give back `(ptr << 32) | len` of a buffer
+//! allocated through `alloc`, or -1 when there's nothing to return.
+//!
+//! Each module can keep a limited number of keys in its storage, and values
+//! over the size limit are dropped; `kv_set` fails silently in both cases.
+
+use crate::chat::ChatEvent;
+use crate::command;
+use crate::http;
+use crate::irc;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::storage::DataSet;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::Duration;
+use log::*;
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::Path;
+use tokio::runtime;
+use tokio::sync::Mutex;
+use wasmtime::{Caller, Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+const DB_NAME: &str = "wasm-kv";
+pub const DATA_SET: DataSet = DataSet::new::<KV>(DB_NAME);
+/// Longest string a module can hand to the bot, like a message or a value to
+/// store
+const MAX_STRING: usize = 1024 * 1024;
+
+/// Resource limits applied to every event handled by a module
+#[derive(Clone)]
+struct Limits {
+    /// Fuel (roughly, instructions) a module may consume per event
+    fuel:          u64,
+    /// Maximum size of a module's linear memory, in bytes
+    memory:        usize,
+    /// Maximum number of elements in a module's tables
+    table:         usize,
+    /// Keys a module may keep in its storage
+    kv_entries:    usize,
+    /// Maximum size of a stored value, in bytes
+    kv_bytes:      usize,
+    /// HTTP requests a module may make per event
+    http_requests: usize,
+    /// Maximum size of HTTP response bodies, in bytes
+    http_bytes:    usize,
+    http_timeout:  std::time::Duration,
+}
+
+impl Limits {
+    fn store_limits(&self) -> StoreLimits {
+        StoreLimitsBuilder::new()
+            .memory_size(self.memory)
+            .table_elements(self.table)
+            .instances(1)
+            .build()
+    }
+}
+
+/// Per-module key-value storage, keyed by module name.
+type KV = HashMap<String, HashMap<String, String>>;
+
+pub struct WasmPlugin {
+    engine:      Engine,
+    modules:     Vec<(String, Module)>,
+    linker:      Linker<Host>,
+    limits:      Limits,
+    http_client: http::Client,
+    kv:          Mutex<KV>,
+}
+
+/// State available to the host functions while a module handles an event.
+struct Host {
+    module:       String,
+    runtime:      runtime::Handle,
+    http_client:  http::Client,
+    limits:       Limits,
+    store_limits: StoreLimits,
+    requests:     usize,
+    kv:           HashMap<String, String>,
+    kv_dirty:     bool,
+    outgoing:     Vec<irc::Outgoing>,
+}
+
+fn config_value<T: std::str::FromStr>(ctx: &PluginContext, key: &str, default: T) -> Result<T> {
+    match ctx.config.as_ref().and_then(|c| c.get(key)) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| anyhow!("[Wasm] Invalid value for `{}`: {}", key, value)),
+        None => Ok(default),
+    }
+}
+
+fn read_string(caller: &mut Caller<'_, Host>, ptr: i32, len: i32) -> Result<String> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|e| e.into_memory())
+        .ok_or_else(|| anyhow!("module does not export `memory`"))?;
+    // Both come from the module, so neither is trusted to make sense
+    let (ptr, len) = match (usize::try_from(ptr), usize::try_from(len)) {
+        (Ok(ptr), Ok(len)) => (ptr, len),
+        _ => return Err(anyhow!("negative pointer or length")),
+    };
+    if len > MAX_STRING {
+        return Err(anyhow!("string of {} bytes, over {}", len, MAX_STRING));
+    }
+    if ptr
+        .checked_add(len)
+        .is_none_or(|end| end > memory.data_size(&*caller))
+    {
+        return Err(anyhow!("string out of the module's memory"));
+    }
+    let mut buf = vec![0; len];
+    memory.read(&*caller, ptr, &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Copies data into a buffer allocated by the module, returning its packed
+/// pointer and length.
+fn write_bytes(caller: &mut Caller<'_, Host>, data: &[u8]) -> Result<i64> {
+    let alloc = caller
+        .get_export("alloc")
+        .and_then(|e| e.into_func())
+        .ok_or_else(|| anyhow!("module does not export `alloc`"))?
+        .typed::<i32, i32>(&*caller)?;
+    let ptr = alloc.call(&mut *caller, data.len() as i32)?;
+    let memory = caller
+        .get_export("memory")
+        .and_then(|e| e.into_memory())
+        .ok_or_else(|| anyhow!("module does not export `memory`"))?;
+    memory.write(&mut *caller, ptr as usize, data)?;
+    Ok(((ptr as u32 as i64) << 32) | data.len() as u32 as i64)
+}
+
+async fn http_get(client: &http::Client, url: &str, limits: &Limits) -> Result<Vec<u8>> {
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Err(anyhow!("only http(s) URLs are allowed"));
+    }
+    let mut response = client
+        .get(url)
+        .timeout(limits.http_timeout)
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut body = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= limits.http_bytes {
+            body.truncate(limits.http_bytes);
+            break;
+        }
+    }
+    Ok(body)
+}
+
+fn link_host_functions(linker: &mut Linker<Host>) -> Result<()> {
+    linker.func_wrap(
+        "boton",
+        "send",
+        |mut caller: Caller<'_, Host>, target: i32, target_len: i32, text: i32, text_len: i32| {
+            let target = read_string(&mut caller, target, target_len)?;
+            let text = read_string(&mut caller, text, text_len)?;
+            caller
+                .data_mut()
+                .outgoing
+                .push(irc::Outgoing::privmsg(target, text).with_split(irc::Split::Lines));
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "boton",
+        "log",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+            let text = read_string(&mut caller, ptr, len)?;
+            info!("[wasm:{}] {}", caller.data().module, text);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "boton",
+        "kv_get",
+        |mut caller: Caller<'_, Host>, key: i32, key_len: i32| {
+            let key = read_string(&mut caller, key, key_len)?;
+            match caller.data().kv.get(&key).cloned() {
+                Some(value) => write_bytes(&mut caller, value.as_bytes()),
+                None => Ok(-1),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "boton",
+        "kv_set",
+        |mut caller: Caller<'_, Host>, key: i32, key_len: i32, value: i32, value_len: i32| {
+            let key = read_string(&mut caller, key, key_len)?;
+            let value = read_string(&mut caller, value, value_len)?;
+            let host = caller.data_mut();
+            if value.len() > host.limits.kv_bytes {
+                warn!(
+                    "[wasm:{}] Not storing {}: value over {} bytes",
+                    host.module, key, host.limits.kv_bytes
+                );
+                return Ok(());
+            }
+            if !host.kv.contains_key(&key) && host.kv.len() >= host.limits.kv_entries {
+                warn!(
+                    "[wasm:{}] Not storing {}: storage limit of {} keys reached",
+                    host.module, key, host.limits.kv_entries
+                );
+                return Ok(());
+            }
+            host.kv.insert(key, value);
+            host.kv_dirty = true;
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "boton",
+        "kv_delete",
+        |mut caller: Caller<'_, Host>, key: i32, key_len: i32| {
+            let key = read_string(&mut caller, key, key_len)?;
+            let host = caller.data_mut();
+            host.kv_dirty |= host.kv.remove(&key).is_some();
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "boton",
+        "http_get",
+        |mut caller: Caller<'_, Host>, url: i32, url_len: i32| {
+            let url = read_string(&mut caller, url, url_len)?;
+            let host = caller.data_mut();
+            if host.requests >= host.limits.http_requests {
+                warn!("[wasm:{}] HTTP request limit reached", host.module);
+                return Ok(-1);
+            }
+            host.requests += 1;
+            let body = host
+                .runtime
+                .block_on(http_get(&host.http_client, &url, &host.limits));
+            match body {
+                Ok(body) => write_bytes(&mut caller, &body),
+                Err(e) => {
+                    debug!(
+                        "[wasm:{}] HTTP request to {} failed: {}",
+                        caller.data().module,
+                        url,
+                        e
+                    );
+                    Ok(-1)
+                },
+            }
+        },
+    )?;
+    Ok(())
+}
+
+impl WasmPlugin {
+    /// Runs the event through a fresh instance of every module, then sends out
+    /// whatever the modules replied with.
+    async fn dispatch(&self, ctx: &PluginContext, event: serde_json::Value) -> Result<()> {
+        let event = serde_json::to_vec(&event)?;
+        let mut kv = self.kv.lock().await;
+        let mut kv_dirty = false;
+
+        for (name, module) in &self.modules {
+            let host = Host {
+                module:       name.clone(),
+                runtime:      runtime::Handle::current(),
+                http_client:  self.http_client.clone(),
+                limits:       self.limits.clone(),
+                store_limits: self.limits.store_limits(),
+                requests:     0,
+                kv:           kv.get(name).cloned().unwrap_or_default(),
+                kv_dirty:     false,
+                outgoing:     vec![],
+            };
+            let (engine, linker, module, event) = (
+                self.engine.clone(),
+                self.linker.clone(),
+                module.clone(),
+                event.clone(),
+            );
+            let result = tokio::task::spawn_blocking(move || -> Result<Host> {
+                let mut store = Store::new(&engine, host);
+                store.set_fuel(store.data().limits.fuel)?;
+                store.limiter(|host| &mut host.store_limits);
+                let instance = linker.instantiate(&mut store, &module)?;
+                let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+                let on_event = instance.get_typed_func::<(i32, i32), ()>(&mut store, "on_event")?;
+                let memory = instance
+                    .get_memory(&mut store, "memory")
+                    .ok_or_else(|| anyhow!("module does not export `memory`"))?;
+                let ptr = alloc.call(&mut store, event.len() as i32)?;
+                memory.write(&mut store, ptr as usize, &event)?;
+                on_event.call(&mut store, (ptr, event.len() as i32))?;
+                Ok(store.into_data())
+            })
+            .await?;
+
+            match result {
+                Ok(host) => {
+                    if host.kv_dirty {
+                        kv.insert(name.clone(), host.kv);
+                        kv_dirty = true;
+                    }
+                    for msg in host.outgoing {
+                        ctx.send_outgoing(msg).await?;
+                    }
+                },
+                Err(e) => error!("[{}] Wasm module {} failed: {:#}", ctx.server, name, e),
+            }
+        }
+
+        if kv_dirty {
+            ctx.storage.save(DB_NAME, &*kv).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PluginBuilder for WasmPlugin {
+    type Plugin = WasmPlugin;
+
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "wasm";
+
+    async fn new(ctx: &PluginContext) -> Result<WasmPlugin> {
+        let dir: String = config_value(ctx, "dir", "wasm".into())?;
+        let limits = Limits {
+            fuel:          config_value(ctx, "fuel", 10_000_000)?,
+            memory:        config_value(ctx, "memory", 16 * 1024 * 1024)?,
+            table:         config_value(ctx, "table-elements", 10_000)?,
+            kv_entries:    config_value(ctx, "kv-entries", 1000)?,
+            kv_bytes:      config_value(ctx, "kv-bytes", 64 * 1024)?,
+            http_requests: config_value(ctx, "http-requests", 4)?,
+            http_bytes:    config_value(ctx, "http-bytes", 64 * 1024)?,
+            http_timeout:  Duration::seconds(config_value(ctx, "http-timeout", 10)?).to_std()?,
+        };
+
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let mut linker = Linker::new(&engine);
+        link_host_functions(&mut linker)?;
+
+        let mut modules = vec![];
+        match std::fs::read_dir(&dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let path = entry?.path();
+                    if path.extension().and_then(|ext| ext.to_str()) != Some("wasm") {
+                        continue;
+                    }
+                    let name = module_name(&path);
+                    match Module::from_file(&engine, &path) {
+                        Ok(module) => {
+                            info!("[{}] Loaded wasm module {}", ctx.server, name);
+                            modules.push((name, module));
+                        },
+                        Err(e) => error!("[{}] Could not load {:?}: {:#}", ctx.server, path, e),
+                    }
+                }
+            },
+            Err(e) => warn!(
+                "[{}] Could not read wasm directory {}: {}",
+                ctx.server, dir, e
+            ),
+        }
+
+        let http_client = ctx.http.client();
+        let kv = ctx.storage.load(DB_NAME).await?.unwrap_or_default();
+
+        Ok(WasmPlugin {
+            engine,
+            modules,
+            linker,
+            limits,
+            http_client,
+            kv: Mutex::new(kv),
+        })
+    }
+}
+
+fn module_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl Plugin for WasmPlugin {
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        if self.modules.is_empty() {
+            return Ok(());
+        }
+        let event = json!({
+            "kind": "command",
+            "name": cmd.name,
+            "args": cmd.args,
+            "nick": cmd.user.nick,
+            "reply_to": cmd.reply_to,
+        });
+        self.dispatch(ctx, event).await
+    }
+
+    async fn chat(&self, ctx: &PluginContext, event: &ChatEvent) -> Result<()> {
+        if self.modules.is_empty() || event.is_ctcp {
+            return Ok(());
+        }
+        let target = match event.channel() {
+            Some(channel) => channel.to_string(),
+            None => ctx.nick().await,
+        };
+        let event = json!({
+            "kind": "privmsg",
+            "nick": event.sender.nick,
+            "target": target,
+            "reply_to": event.reply_to(),
+            "text": event.body,
+            "action": event.is_action,
+        });
+        self.dispatch(ctx, event).await
+    }
+}
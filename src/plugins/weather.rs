@@ -1,27 +1,23 @@
-use crate::bot;
-use crate::irc;
-use crate::plugins::{Plugin, PluginBuilder};
+use crate::command;
+use crate::http;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::prefs;
+use crate::template;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use chrono::{DateTime, Duration, FixedOffset, Utc};
+use chrono::{DateTime, FixedOffset, Utc};
+use chrono_tz::Tz;
 use log::*;
-use ron::de::from_str;
-use ron::ser::to_string;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::fs::{read_to_string, File};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::RwLock;
-use tokio::task::JoinHandle;
-
-#[derive(Debug, Deserialize, Serialize, Clone)]
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone)]
 enum Speed {
     MPH,
     KMH,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Clone)]
 enum Temperature {
     Celsius,
     Fahrenheit,
@@ -31,17 +27,31 @@ type Units = (Temperature, Speed);
 const IMPERIAL: Units = (Temperature::Fahrenheit, Speed::MPH);
 const METRIC: Units = (Temperature::Celsius, Speed::KMH);
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct UserConfig {
-    location: Option<String>,
-    units:    Option<Units>,
+fn parse_units(name: &str) -> Option<Units> {
+    match name.to_lowercase().as_str() {
+        "metric" => Some(METRIC),
+        "imperial" => Some(IMPERIAL),
+        _ => None,
+    }
+}
+
+/// Units as saved in the `units` preference
+fn units_name(units: &Units) -> &'static str {
+    match units.0 {
+        Temperature::Celsius => "metric",
+        Temperature::Fahrenheit => "imperial",
+    }
 }
-type WeatherDB = RwLock<HashMap<String, UserConfig>>;
 
-#[derive(Clone)]
+/// How `\w` answers, unless configured with a `format weather` of its own or a
+/// `format weather #channel` for a channel. Besides the fields used here,
+/// there's `{icon}` and `{description}`, which make up `{conditions}`.
+const DEFAULT_FORMAT: &str = "Weather for {place}: {temperature} {unit} · {min}⌄ {max}⌃ (feels \
+                              like {feels_like}) 〜 {conditions} 〜 \u{1F4A7} {humidity}% 〜 \
+                              \u{1F4A8} {wind}";
+
 pub struct WeatherPlugin {
-    user_db:               Arc<WeatherDB>,
-    http_client:           reqwest::Client,
+    http_client:           http::Client,
     openweathermap_apikey: String,
 }
 
@@ -64,125 +74,55 @@ impl<'a> Display for OWMQuery<'a> {
     }
 }
 
-impl WeatherPlugin {
-    fn db_path(server: &str) -> String {
-        format!("data/{}-weather", server)
-    }
-
-    async fn load_db(server: &str) -> Result<WeatherDB> {
-        let db_path = WeatherPlugin::db_path(server);
-        let data = read_to_string(&db_path).await?;
-        let user_db: HashMap<String, UserConfig> = from_str(&data)?;
-        Ok(RwLock::new(user_db))
-    }
-
-    async fn save_db(&self, server: &str) -> Result<()> {
-        let db_path = WeatherPlugin::db_path(server);
-        let mut file = File::create(&db_path).await?;
-        let user_db = self.user_db.read().await;
-        let data = to_string(&*user_db)?;
-        file.write_all(data.as_bytes()).await?;
-        Ok(())
-    }
-
-    async fn get_user_config(&self, nick: &str) -> Option<UserConfig> {
-        let user_db = self.user_db.read().await;
-        user_db.get(nick).cloned()
-    }
-
-    async fn set_user_units(&self, nick: &str, units: Option<Units>) {
-        let mut user_db = self.user_db.write().await;
-        let mut delete = false;
-        if let Some(user_conf) = user_db.get_mut(nick) {
-            if units.is_none() && user_conf.location.is_none() {
-                delete = true;
-            }
-            user_conf.units = units;
-        } else if units.is_some() {
-            user_db.insert(
-                nick.into(),
-                UserConfig {
-                    location: None,
-                    units,
-                },
-            );
-        }
-        if delete {
-            user_db.remove(nick);
-        }
-    }
-
-    async fn set_user_location(&self, nick: &str, location: Option<String>) {
-        let mut user_db = self.user_db.write().await;
-        let mut delete = false;
-        if let Some(user_conf) = user_db.get_mut(nick) {
-            if location.is_none() && user_conf.units.is_none() {
-                delete = true;
-            }
-            user_conf.location = location;
-        } else if location.is_some() {
-            user_db.insert(
-                nick.into(),
-                UserConfig {
-                    units: None,
-                    location,
-                },
-            );
-        }
-        if delete {
-            user_db.remove(nick);
-        }
-    }
-}
-
 #[async_trait]
 impl PluginBuilder for WeatherPlugin {
     type Plugin = WeatherPlugin;
 
+    const COMMANDS: &'static [command::Help] = &[
+        command::Help {
+            name:  "w",
+            usage: "w [--units=metric|imperial] [city | @nick] - current weather for a city, \
+                    someone's saved location or your own",
+        },
+        command::Help {
+            name:  "t",
+            usage: "t [city | @nick] - current local time for a city or saved location",
+        },
+        command::Help {
+            name:  "wset",
+            usage: "wset [city] - saves your location, or removes it if none is given",
+        },
+        command::Help {
+            name:  "units",
+            usage: "units [metric | imperial] - saves your preferred units, or removes them",
+        },
+        command::Help {
+            name:  "tzset",
+            usage: "tzset [Area/City] - saves your timezone, or removes it if none is given",
+        },
+    ];
+    const MESSAGES: Subscription = Subscription::None;
     const NAME: &'static str = "weather";
 
-    async fn new(server: &str, config: Option<&bot::PluginConfig>) -> Result<WeatherPlugin> {
-        if config.is_none() {
+    async fn new(ctx: &PluginContext) -> Result<WeatherPlugin> {
+        if ctx.config.is_none() {
             return Err(anyhow!(
                 "Weather plugin requires `openweathermap-apikey` in its config section"
             ));
         }
-        let config = config.unwrap();
+        let config = ctx.config.as_ref().unwrap();
         // TODO get rid of these clones
         let openweathermap_apikey = config
             .get("openweathermap-apikey")
             .expect("[Weather] Missing `openweathermap-apikey`")
             .clone();
 
-        let http_client = reqwest::Client::builder()
-            .connect_timeout(Duration::seconds(10).to_std()?)
-            .connection_verbose(true)
-            .build()?;
-
-        if let Ok(user_db) = WeatherPlugin::load_db(server).await {
-            info!("[{}] Weather DB loaded successfully", server);
-            debug!("{:?}", user_db);
-            Ok(WeatherPlugin {
-                openweathermap_apikey,
-                http_client,
-                user_db: Arc::new(user_db),
-            })
-        } else {
-            warn!("[{}] Weather DB not found", server);
-            Ok(WeatherPlugin {
-                openweathermap_apikey,
-                http_client,
-                user_db: Arc::new(RwLock::new(HashMap::new())),
-            })
-        }
-    }
-}
+        let http_client = ctx.http.client();
 
-fn split_first_word(text: &str) -> (&str, Option<&str>) {
-    if let Some(space) = text.find(' ') {
-        (&text[.. space], Some(&text[space + 1 ..]))
-    } else {
-        (text, None)
+        Ok(WeatherPlugin {
+            openweathermap_apikey,
+            http_client,
+        })
     }
 }
 
@@ -357,7 +297,8 @@ impl WeatherData {
     }
 
     // TODO air pollution too?
-    fn print_data(&self, units: Option<Units>, nick: Option<String>) -> String {
+    /// The fields filled into the `weather` format.
+    fn fields(&self, units: Option<Units>, nick: Option<String>) -> Value {
         let country = self.sys.country.clone().unwrap_or_else(|| "??".into());
         let units = if let Some(units) = units {
             units
@@ -373,29 +314,36 @@ impl WeatherData {
             WeatherData::convert_temp(self.main.temp_max, &units.0),
             WeatherData::convert_temp(self.main.feels_like, &units.0),
         );
-        let temperature = format!(
-            "{:.1} {} · {:.1}⌄ {:.1}⌃ (feels like {:.1})",
-            temp, units.0, min, max, feels
-        );
-        let description = if let Some(icon) = &self.weather[0].icon {
-            let icon = WeatherData::get_icon(icon).unwrap();
-            format!(" 〜 {} {}", icon, self.weather[0].description)
+        let description = &self.weather[0].description;
+        let icon = match &self.weather[0].icon {
+            Some(icon) => WeatherData::get_icon(icon).unwrap(),
+            None => "",
+        };
+        let conditions = if icon.is_empty() {
+            description.clone()
         } else {
-            format!(" 〜 {}", self.weather[0].description)
+            format!("{} {}", icon, description)
         };
-        let humidity = format!(" 〜 \u{1F4A7} {}%", self.main.humidity);
         let wind_dir = if let Some(deg) = self.wind.deg {
             WeatherData::convert_wind_dir(deg).unwrap()
         } else {
             ""
         };
         let wind_speed = WeatherData::convert_speed(self.wind.speed, &units.1);
-        let wind = format!(" 〜 \u{1F4A8} {:.1} {}{}", wind_speed, units.1, wind_dir);
 
-        format!(
-            "Weather for {}: {}{}{}{}",
-            prefix, temperature, description, humidity, wind
-        )
+        json!({
+            "place": prefix,
+            "temperature": format!("{:.1}", temp),
+            "min": format!("{:.1}", min),
+            "max": format!("{:.1}", max),
+            "feels_like": format!("{:.1}", feels),
+            "unit": units.0.to_string(),
+            "icon": icon,
+            "description": description,
+            "conditions": conditions,
+            "humidity": self.main.humidity.to_string(),
+            "wind": format!("{:.1} {}{}", wind_speed, units.1, wind_dir),
+        })
     }
 }
 
@@ -413,231 +361,182 @@ impl WeatherPlugin {
 
 // TODO use more data and reformat stuff; remove temp_min and temp_max
 // TODO factor out the code into functions and organize stuff better
-// TODO configurable and global command prefix (for the factored privmsg
-// handling; move it out of this file) TODO convenience function for sending a
-// privmsg in IRC TODO the privmsg function should support "replying": if sent
-// to a channel, replies there, otherwise if it's a pm send it back TODO maybe
-// that can be generalized to other stuff besides privmsgs?
+#[async_trait]
 impl Plugin for WeatherPlugin {
-    fn spawn_task(self, mut irc: irc::IRC) -> Result<JoinHandle<Result<()>>> {
-        let handle = tokio::spawn(async move {
-            loop {
-                while let Ok(msg) = irc.received_messages.recv().await {
-                    if let irc::Command::Privmsg = msg.command {
-                        let plugin = self.clone();
-                        let irc = irc.clone();
-                        // TODO maybe some "standard" plugin way of spawning tasks/registering
-                        // handles so when the plugin gets cancelled/restarted they can be aborted?
-                        // doesn't really matter in the weather plugin case i believe
-                        tokio::spawn(async move {
-                            if msg.target.is_none() || msg.parameters.len() != 1 {
-                                error!("Unexpected PRIVMSG format, ignoring");
-                                return;
-                            }
-
-                            // TODO ideally this only happens if theres a command
-                            let user = if let Some(user) = msg.source_as_user() {
-                                user
-                            } else {
-                                error!("PRIVMSG without user, ignoring");
-                                return;
-                            };
-                            let target = msg.target.unwrap();
-
-                            let (cmd, msg) = split_first_word(&msg.parameters[0]);
-                            match cmd {
-                                r"\w" | r"\t" => {
-                                    let nick = user.nick.to_lowercase();
-
-                                    let user_units = if let Some(UserConfig {
-                                        location: _,
-                                        units: Some(units),
-                                    }) = plugin.get_user_config(&nick).await
-                                    {
-                                        Some(units)
-                                    } else {
-                                        None
-                                    };
-
-                                    let (query_string, target_nick) = if let Some(msg) = msg {
-                                        if let Some(target_nick) = msg.strip_prefix("@") {
-                                            let target_nick = target_nick.to_lowercase();
-                                            if let Some(UserConfig {
-                                                location: Some(user_loc),
-                                                units: _,
-                                            }) = plugin.get_user_config(&target_nick).await
-                                            {
-                                                (user_loc, Some(target_nick))
-                                            } else {
-                                                let reply = format!(
-                                                    "{}: Could not find saved weather location \
-                                                     for `{}`",
-                                                    nick, target_nick
-                                                );
-                                                irc.send_messages
-                                                    .send(irc::Message::privmsg(target, reply))
-                                                    .await
-                                                    .unwrap();
-                                                return;
-                                            }
-                                        } else {
-                                            (msg.to_owned(), None)
-                                        }
-                                    } else {
-                                        // no message, look up in user_db
-                                        if let Some(UserConfig {
-                                            location: Some(user_loc),
-                                            units: _,
-                                        }) = plugin.get_user_config(&nick).await
-                                        {
-                                            (user_loc, Some(nick.clone()))
-                                        } else {
-                                            let reply = format!(
-                                                "{}: Inform a city, or optionally set a city \
-                                                 using \\wset. Accepted formats: `city`, `city, \
-                                                 country` (ISO country code), US zip codes, \
-                                                 `id:1234` (OpenWeatherMap ID)",
-                                                nick
-                                            );
-                                            irc.send_messages
-                                                .send(irc::Message::privmsg(target, reply))
-                                                .await
-                                                .unwrap();
-                                            return;
-                                        }
-                                    };
-
-                                    let query = if let Some(id) = query_string.strip_prefix("id:") {
-                                        OWMQuery::Id(id)
-                                    } else if query_string.chars().all(|c| c.is_ascii_digit()) {
-                                        OWMQuery::USZip(&query_string)
-                                    } else {
-                                        OWMQuery::Simple(&query_string)
-                                    };
-
-                                    let weather = plugin.get_openweathermap(query).await;
-                                    let weather_data = if let Ok(data) = weather {
-                                        data
-                                    } else {
-                                        debug!(
-                                            "Weather error: query_string: {}, response: {:?}",
-                                            query_string, weather
-                                        );
-                                        let reply = format!(
-                                            "{}: Could not get weather, sorry! Maybe the query is \
-                                             invalid?",
-                                            nick
-                                        );
-                                        irc.send_messages
-                                            .send(irc::Message::privmsg(target, reply))
-                                            .await
-                                            .unwrap();
-                                        return;
-                                    };
-
-                                    if cmd == r"\w" {
-                                        let reply =
-                                            weather_data.print_data(user_units, target_nick);
-                                        irc.send_messages
-                                            .send(irc::Message::privmsg(target, reply))
-                                            .await
-                                            .unwrap();
-                                    } else if cmd == r"\t" {
-                                        let current_time = Utc::now().with_timezone(
-                                            &FixedOffset::east(weather_data.timezone),
-                                        );
-
-                                        let geoplace = if let Some(target_nick) = target_nick {
-                                            format!("for {}", target_nick)
-                                        } else {
-                                            format!(
-                                                "in {}, {}",
-                                                weather_data.name,
-                                                weather_data.sys.country.unwrap()
-                                            )
-                                        };
-                                        let reply = format!(
-                                            "The curent date and time {} is {}",
-                                            geoplace, current_time
-                                        );
-                                        irc.send_messages
-                                            .send(irc::Message::privmsg(target, reply))
-                                            .await
-                                            .unwrap();
-                                    }
-                                },
-                                r"\wset" => {
-                                    let nick = user.nick.to_lowercase();
-                                    let reply = if let Some(msg) = msg {
-                                        let reply = format!(
-                                            "{}: Updated your saved weather location to `{}`",
-                                            nick, msg
-                                        );
-                                        plugin.set_user_location(&nick, Some(msg.into())).await;
-                                        reply
-                                    } else {
-                                        let reply = format!(
-                                            "{}: Removed your saved weather location",
-                                            nick
-                                        );
-                                        plugin.set_user_location(&nick, None).await;
-                                        reply
-                                    };
-                                    irc.send_messages
-                                        .send(irc::Message::privmsg(target, reply))
-                                        .await
-                                        .unwrap();
-
-                                    plugin.save_db(&irc.server).await.unwrap();
-                                },
-                                r"\units" => {
-                                    let nick = user.nick.to_lowercase();
-                                    let reply = if let Some(msg) = msg {
-                                        let units = match msg.to_lowercase().as_str() {
-                                            "metric" => METRIC,
-                                            "imperial" => IMPERIAL,
-                                            _ => {
-                                                let reply = format!(
-                                                    "{}: Use \\units [metric|imperial] to set \
-                                                     your saved preference",
-                                                    user.nick
-                                                );
-                                                irc.send_messages
-                                                    .send(irc::Message::privmsg(target, reply))
-                                                    .await
-                                                    .unwrap();
-                                                return;
-                                            },
-                                        };
-                                        let reply = format!(
-                                            "{}: Updated your saved units preference to `{:?}`",
-                                            nick, units
-                                        );
-                                        plugin.set_user_units(&nick, Some(units)).await;
-                                        reply
-                                    } else {
-                                        let reply = format!(
-                                            "{}: Removed your saved unit preferences. Set it \
-                                             again with \\units [metric|imperial]",
-                                            nick
-                                        );
-                                        plugin.set_user_units(&nick, None).await;
-                                        reply
-                                    };
-                                    irc.send_messages
-                                        .send(irc::Message::privmsg(target, reply))
-                                        .await
-                                        .unwrap();
-
-                                    plugin.save_db(&irc.server).await.unwrap();
-                                },
-                                _ => {},
-                            }
-                        });
+    async fn command(&self, ctx: &PluginContext, cmd: &command::Invocation) -> Result<()> {
+        let nick = cmd.user.nick.to_lowercase();
+        let msg = cmd.args.as_deref();
+        match cmd.name.as_str() {
+            "w" | "t" => {
+                let args = cmd.parse_args();
+                let query = args.words().collect::<Vec<_>>().join(" ");
+                let msg = Some(query.as_str()).filter(|q| !q.is_empty());
+
+                let user_units = if let Some(units) = args.value("units") {
+                    match parse_units(units) {
+                        Some(units) => Some(units),
+                        None => {
+                            let reply = format!("{}: Units can be either metric or imperial", nick);
+                            return ctx.reply(cmd, reply).await;
+                        },
                     }
+                } else {
+                    ctx.prefs
+                        .get(&prefs::UNITS, &nick)
+                        .and_then(|units| parse_units(&units))
+                };
+
+                let (query_string, target_nick) = if let Some(msg) = msg {
+                    if let Some(target_nick) = msg.strip_prefix("@") {
+                        let target_nick = target_nick.to_lowercase();
+                        if let Some(user_loc) = ctx.prefs.get(&prefs::LOCATION, &target_nick) {
+                            (user_loc, Some(target_nick))
+                        } else {
+                            let reply = format!(
+                                "{}: Could not find saved weather location for `{}`",
+                                nick, target_nick
+                            );
+                            return ctx.reply(cmd, reply).await;
+                        }
+                    } else {
+                        (msg.to_owned(), None)
+                    }
+                } else {
+                    // no message, look up the saved location
+                    if let Some(user_loc) = ctx.prefs.get(&prefs::LOCATION, &nick) {
+                        (user_loc, Some(nick.clone()))
+                    } else {
+                        let reply = format!(
+                            "{}: Inform a city, or optionally set a city using {}wset. Accepted \
+                             formats: `city`, `city, country` (ISO country code), US zip codes, \
+                             `id:1234` (OpenWeatherMap ID)",
+                            nick, cmd.prefix
+                        );
+                        return ctx.reply(cmd, reply).await;
+                    }
+                };
+
+                let query = if let Some(id) = query_string.strip_prefix("id:") {
+                    OWMQuery::Id(id)
+                } else if query_string.chars().all(|c| c.is_ascii_digit()) {
+                    OWMQuery::USZip(&query_string)
+                } else {
+                    OWMQuery::Simple(&query_string)
+                };
+
+                let weather = self.get_openweathermap(query).await;
+                let weather_data = if let Ok(data) = weather {
+                    data
+                } else {
+                    debug!(
+                        "Weather error: query_string: {}, response: {:?}",
+                        query_string, weather
+                    );
+                    let reply = format!(
+                        "{}: Could not get weather, sorry! Maybe the query is invalid?",
+                        nick
+                    );
+                    return ctx.reply(cmd, reply).await;
+                };
+
+                if cmd.name == "w" {
+                    let format = ctx
+                        .format("weather", &cmd.reply_to)
+                        .unwrap_or(DEFAULT_FORMAT);
+                    let fields = weather_data.fields(user_units, target_nick);
+                    let reply = template::render(format, &fields);
+                    ctx.reply(cmd, reply).await?;
+                } else if cmd.name == "t" {
+                    let current_time =
+                        Utc::now().with_timezone(&FixedOffset::east(weather_data.timezone));
+
+                    let geoplace = if let Some(target_nick) = target_nick {
+                        format!("for {}", target_nick)
+                    } else {
+                        format!(
+                            "in {}, {}",
+                            weather_data.name,
+                            weather_data.sys.country.unwrap()
+                        )
+                    };
+                    let reply =
+                        format!("The curent date and time {} is {}", geoplace, current_time);
+                    ctx.reply(cmd, reply).await?;
                 }
-            }
-        });
-        Ok(handle)
+            },
+            "wset" => {
+                let reply = if let Some(msg) = msg {
+                    let reply =
+                        format!("{}: Updated your saved weather location to `{}`", nick, msg);
+                    ctx.prefs
+                        .set(&prefs::LOCATION, &nick, Some(msg.to_string()))
+                        .await?;
+                    reply
+                } else {
+                    let reply = format!("{}: Removed your saved weather location", nick);
+                    ctx.prefs.set(&prefs::LOCATION, &nick, None).await?;
+                    reply
+                };
+                ctx.reply(cmd, reply).await?;
+            },
+            "units" => {
+                let reply = if let Some(msg) = msg {
+                    let units = match parse_units(msg) {
+                        Some(units) => units,
+                        None => {
+                            let reply = format!(
+                                "{}: Use {}units [metric|imperial] to set your saved preference",
+                                cmd.user.nick, cmd.prefix
+                            );
+                            return ctx.reply(cmd, reply).await;
+                        },
+                    };
+                    let units = units_name(&units);
+                    let reply = format!(
+                        "{}: Updated your saved units preference to `{}`",
+                        nick, units
+                    );
+                    ctx.prefs
+                        .set(&prefs::UNITS, &nick, Some(units.to_string()))
+                        .await?;
+                    reply
+                } else {
+                    let reply = format!(
+                        "{}: Removed your saved unit preferences. Set it again with {}units \
+                         [metric|imperial]",
+                        nick, cmd.prefix
+                    );
+                    ctx.prefs.set(&prefs::UNITS, &nick, None).await?;
+                    reply
+                };
+                ctx.reply(cmd, reply).await?;
+            },
+            "tzset" => {
+                let reply = if let Some(msg) = msg {
+                    let timezone: Tz = match msg.parse() {
+                        Ok(timezone) => timezone,
+                        Err(_) => {
+                            let reply = format!(
+                                "{}: Unknown timezone `{}`, use a name like Europe/Berlin",
+                                cmd.user.nick, msg
+                            );
+                            return ctx.reply(cmd, reply).await;
+                        },
+                    };
+                    let reply = format!("{}: Updated your saved timezone to `{}`", nick, timezone);
+                    ctx.prefs
+                        .set(&prefs::TIMEZONE, &nick, Some(timezone))
+                        .await?;
+                    reply
+                } else {
+                    let reply = format!("{}: Removed your saved timezone", nick);
+                    ctx.prefs.set(&prefs::TIMEZONE, &nick, None).await?;
+                    reply
+                };
+                ctx.reply(cmd, reply).await?;
+            },
+            _ => {},
+        }
+        Ok(())
     }
 }
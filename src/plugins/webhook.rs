@@ -0,0 +1,110 @@
+//! Posts the webhooks received on `/hook/<token>` to a channel, so services
+//! like Grafana or CI systems can notify channels without a plugin of their
+//! own. Needs the `listener` to be configured.
+//!
+//! Configuration:
+//! - `hook <token>`: the server and channel to post to, separated by a space
+//! - `template <token>`: how to format the hook's JSON payload, with `{field}`
+//!   replaced by the payload's field of that name, and `{field.inner.0}`
+//!   reaching into objects and arrays. Without one, the payload's `text` or
+//!   `message` field is posted, or the whole body.
+
+use crate::irc;
+use crate::listener;
+use crate::plugins::{Plugin, PluginBuilder, PluginContext, Subscription};
+use crate::reply::truncate;
+use crate::template::render;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::*;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Most lines posted for a single hook
+const MAX_LINES: usize = 4;
+/// Longest line posted, in characters
+const MAX_LINE: usize = 400;
+
+struct Hook {
+    ctx:      PluginContext,
+    channel:  String,
+    template: Option<String>,
+}
+
+impl Hook {
+    fn format(&self, body: &[u8]) -> Result<String> {
+        let payload: Option<Value> = serde_json::from_slice(body).ok();
+        Ok(match (&self.template, payload) {
+            (Some(template), Some(payload)) => render(template, &payload),
+            (Some(_), None) => return Err(anyhow!("payload isn't JSON")),
+            (None, Some(payload)) => ["text", "message"]
+                .iter()
+                .find_map(|key| payload.get(key)?.as_str().map(String::from))
+                .unwrap_or_else(|| payload.to_string()),
+            (None, None) => String::from_utf8_lossy(body).into_owned(),
+        })
+    }
+
+    async fn post(&self, body: Bytes) -> Result<()> {
+        let text = self.format(&body)?;
+        let lines = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .take(MAX_LINES);
+        for line in lines {
+            let line = truncate(line, MAX_LINE);
+            self.ctx
+                .send_outgoing(irc::Outgoing::privmsg(self.channel.as_str(), line.as_str()))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+pub struct WebhookPlugin;
+
+#[async_trait]
+impl PluginBuilder for WebhookPlugin {
+    type Plugin = WebhookPlugin;
+
+    const COMMANDS: &'static [crate::command::Help] = &[];
+    const MESSAGES: Subscription = Subscription::None;
+    const NAME: &'static str = "webhook";
+
+    async fn new(ctx: &PluginContext) -> Result<WebhookPlugin> {
+        let config = ctx.config.clone().unwrap_or_default();
+        for (key, target) in &config {
+            let token = match key.strip_prefix("hook ") {
+                Some(token) => token.trim(),
+                None => continue,
+            };
+            let (server, channel) = match target.split_whitespace().collect::<Vec<_>>()[..] {
+                [server, channel] if irc::is_channel(channel) => (server, channel),
+                _ => return Err(anyhow!("[Webhook] Invalid target for a hook: {}", target)),
+            };
+            // Hooks for other servers are routed by their own connections
+            if server != ctx.server {
+                continue;
+            }
+
+            let hook = Arc::new(Hook {
+                ctx:      ctx.clone(),
+                channel:  channel.into(),
+                template: config.get(&format!("template {}", token)).cloned(),
+            });
+            debug!("[{}] Routing a webhook to {}", ctx.server, channel);
+            listener::route(
+                format!("/hook/{}", token),
+                Arc::new(move |body| {
+                    let hook = hook.clone();
+                    Box::pin(async move { hook.post(body).await })
+                }),
+            );
+        }
+        Ok(WebhookPlugin)
+    }
+}
+
+impl Plugin for WebhookPlugin {}
@@ -0,0 +1,265 @@
+//! Preferences users save for themselves, like where they are or which
+//! language they want replies in, kept in one place for every plugin to use.
+//! Users logged in to services have theirs saved by account, so they follow
+//! them across nicks, and everyone else by nick.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono_tz::Tz;
+use ron::de::from_str;
+use ron::ser::to_string;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::storage::{DataSet, Migration, Storage, UserData};
+
+const DB_NAME: &str = "prefs";
+pub const DATA_SET: DataSet = DataSet::new::<Users>(DB_NAME);
+/// Where the weather plugin kept preferences before
+const WEATHER_DB_NAME: &str = "weather";
+
+/// Moves the preferences the weather plugin kept into `prefs`.
+pub const WEATHER_MIGRATION: Migration = Migration {
+    name:    WEATHER_DB_NAME,
+    version: 1,
+    into:    DB_NAME,
+    migrate: from_weather,
+};
+/// Starts the keys of users saved by account, which nicks can't contain
+pub const ACCOUNT_PREFIX: &str = "account:";
+
+/// Where the user is, as given to `\wset`
+pub const LOCATION: Pref<String> = Pref::new("location");
+/// Units for the weather, `metric` or `imperial`
+pub const UNITS: Pref<String> = Pref::new("units");
+/// The user's timezone, like `Europe/Berlin`
+pub const TIMEZONE: Pref<Tz> = Pref::new("timezone");
+/// Language of the replies to the user, picked through `\lang`
+pub const LANGUAGE: Pref<String> = Pref::new("language");
+
+/// Preferences of a user, as text by name.
+pub type UserPrefs = BTreeMap<String, String>;
+/// Preferences of every user, by lowercase nick or by `account:` and
+/// lowercase account.
+type Users = BTreeMap<String, UserPrefs>;
+
+/// A preference, along with the type its text is parsed into.
+pub struct Pref<T> {
+    pub name: &'static str,
+    kind:     PhantomData<fn() -> T>,
+}
+
+impl<T> Pref<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Pref {
+            name,
+            kind: PhantomData,
+        }
+    }
+}
+
+/// Every user's preferences, saved whenever they change.
+#[derive(Clone)]
+pub struct Prefs {
+    storage:  Storage,
+    users:    Arc<Mutex<Users>>,
+    /// Accounts users are logged in to, by lowercase nick, as told by the
+    /// `account` tag of their messages
+    accounts: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Prefs {
+    pub async fn load(storage: Storage) -> Result<Self> {
+        let users = storage.load(DB_NAME).await?.unwrap_or_default();
+        let prefs = Prefs {
+            storage:  storage.detached(),
+            users:    Arc::new(Mutex::new(users)),
+            accounts: Arc::new(Mutex::new(HashMap::new())),
+        };
+        storage.register(DB_NAME, Arc::new(prefs.clone()));
+        Ok(prefs)
+    }
+
+    /// The key the user's preferences are saved by.
+    fn key(&self, nick: &str) -> String {
+        let nick = nick.to_lowercase();
+        match self.accounts.lock().unwrap().get(&nick) {
+            Some(account) => format!("{}{}", ACCOUNT_PREFIX, account),
+            None => nick,
+        }
+    }
+
+    /// Records which account the user is logged in to, if any, as seen on a
+    /// message of theirs. Preferences they saved by nick move to the account
+    /// unless it has some already.
+    pub async fn seen(&self, nick: &str, account: Option<&str>) -> Result<()> {
+        let nick = nick.to_lowercase();
+        let account = match account {
+            Some(account) => account.to_lowercase(),
+            None => {
+                self.accounts.lock().unwrap().remove(&nick);
+                return Ok(());
+            },
+        };
+        self.accounts
+            .lock()
+            .unwrap()
+            .insert(nick.clone(), account.clone());
+        let users = {
+            let mut users = self.users.lock().unwrap();
+            let key = format!("{}{}", ACCOUNT_PREFIX, account);
+            if users.contains_key(&key) {
+                return Ok(());
+            }
+            match users.remove(&nick) {
+                Some(prefs) => users.insert(key, prefs),
+                None => return Ok(()),
+            };
+            users.clone()
+        };
+        self.storage.save(DB_NAME, &users).await
+    }
+
+    /// Follows a user changing nicks, staying logged in.
+    pub fn renamed(&self, old: &str, new: &str) {
+        let mut accounts = self.accounts.lock().unwrap();
+        if let Some(account) = accounts.remove(&old.to_lowercase()) {
+            accounts.insert(new.to_lowercase(), account);
+        }
+    }
+
+    /// Forgets the account of a user who left the network.
+    pub fn left(&self, nick: &str) {
+        self.accounts.lock().unwrap().remove(&nick.to_lowercase());
+    }
+
+    /// The user's preference, if they saved one.
+    pub fn get<T: FromStr>(&self, pref: &Pref<T>, nick: &str) -> Option<T> {
+        let key = self.key(nick);
+        self.users
+            .lock()
+            .unwrap()
+            .get(&key)?
+            .get(pref.name)?
+            .parse()
+            .ok()
+    }
+
+    /// Saves the user's preference, or forgets it with `None`.
+    pub async fn set<T: Display>(
+        &self,
+        pref: &Pref<T>,
+        nick: &str,
+        value: Option<T>,
+    ) -> Result<()> {
+        let key = self.key(nick);
+        let users = {
+            let mut users = self.users.lock().unwrap();
+            match value {
+                Some(value) => {
+                    users
+                        .entry(key)
+                        .or_default()
+                        .insert(pref.name.into(), value.to_string());
+                },
+                None => {
+                    if let Some(prefs) = users.get_mut(&key) {
+                        prefs.remove(pref.name);
+                        if prefs.is_empty() {
+                            users.remove(&key);
+                        }
+                    }
+                },
+            }
+            users.clone()
+        };
+        self.storage.save(DB_NAME, &users).await
+    }
+
+    /// Every preference the user saved.
+    pub fn all(&self, nick: &str) -> UserPrefs {
+        let key = self.key(nick);
+        self.users
+            .lock()
+            .unwrap()
+            .get(&key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Forgets the named preference of the user, or every one with `None`.
+    /// Returns whether there was any to forget.
+    pub async fn clear(&self, nick: &str, name: Option<&str>) -> Result<bool> {
+        let key = self.key(nick);
+        let users = {
+            let mut users = self.users.lock().unwrap();
+            let prefs = match users.get_mut(&key) {
+                Some(prefs) => prefs,
+                None => return Ok(false),
+            };
+            match name {
+                Some(name) if prefs.remove(name).is_none() => return Ok(false),
+                Some(_) => {},
+                None => prefs.clear(),
+            }
+            if prefs.is_empty() {
+                users.remove(&key);
+            }
+            users.clone()
+        };
+        self.storage.save(DB_NAME, &users).await?;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl UserData for Prefs {
+    async fn forget(&self, nick: &str) -> Result<bool> {
+        self.clear(nick, None).await
+    }
+}
+
+/// Temperature scale of the units saved by the weather plugin, which tells
+/// them apart
+#[derive(Deserialize)]
+enum LegacyTemperature {
+    Celsius,
+    Fahrenheit,
+}
+
+#[derive(Deserialize)]
+struct LegacyWeather {
+    location: Option<String>,
+    units:    Option<(LegacyTemperature, IgnoredAny)>,
+    #[serde(default)]
+    timezone: Option<String>,
+}
+
+/// The preferences saved by the weather plugin, by nick as it kept them.
+fn from_weather(data: &str) -> Result<String> {
+    let mut users = Users::new();
+    let weather: HashMap<String, LegacyWeather> = from_str(data)?;
+    for (nick, saved) in weather {
+        let prefs = users.entry(nick.to_lowercase()).or_default();
+        if let Some(location) = saved.location {
+            prefs.insert(LOCATION.name.into(), location);
+        }
+        if let Some((temperature, _)) = saved.units {
+            let units = match temperature {
+                LegacyTemperature::Celsius => "metric",
+                LegacyTemperature::Fahrenheit => "imperial",
+            };
+            prefs.insert(UNITS.name.into(), units.into());
+        }
+        if let Some(timezone) = saved.timezone {
+            prefs.insert(TIMEZONE.name.into(), timezone);
+        }
+    }
+    users.retain(|_, prefs| !prefs.is_empty());
+    Ok(to_string(&users)?)
+}
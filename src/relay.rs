@@ -0,0 +1,321 @@
+//! Built-in bouncer: IRC clients connecting to the bot share its connection
+//! to the server, seeing what happens in its channels and talking through it,
+//! while plugins keep running as usual. Clients are disconnected whenever the
+//! bot reconnects.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::timeout;
+
+use crate::irc;
+use crate::state;
+use crate::tasks::TaskSet;
+
+/// Name the bot goes by as a server towards its clients
+const SERVER_NAME: &str = "boton";
+/// Messages a client sent still waiting to be sent to the server, past which
+/// the oldest are assumed to have been dropped as duplicates
+const PENDING_ECHOES: usize = 16;
+/// Longest line accepted from a client, line ending included, as on servers
+const MAX_LINE: usize = 512;
+/// How long clients get to send their password, nick and user
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where to accept clients
+#[derive(Deserialize, Clone)]
+pub struct RelayConfig {
+    /// Address and port to listen on, e.g. `127.0.0.1:6697`
+    pub address:  SocketAddr,
+    /// Password clients must send with PASS
+    pub password: String,
+}
+
+// Keeps the password out of the logged configuration
+impl fmt::Debug for RelayConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RelayConfig")
+            .field("address", &self.address)
+            .field("password", &"..")
+            .finish()
+    }
+}
+
+type Writer = BufWriter<OwnedWriteHalf>;
+
+/// Reads the lines a client sends, refusing any longer than `MAX_LINE`.
+struct Reader {
+    reader: BufReader<OwnedReadHalf>,
+    /// What was read so far of the next line
+    line:   Vec<u8>,
+}
+
+impl Reader {
+    fn new(read_half: OwnedReadHalf) -> Self {
+        Reader {
+            reader: BufReader::new(read_half),
+            line:   Vec::with_capacity(MAX_LINE),
+        }
+    }
+
+    /// The next line, without its line ending, or `None` once the client is
+    /// gone. What was read of a line is kept when cancelled, for the next
+    /// call to carry on with it.
+    async fn next_line(&mut self) -> Result<Option<String>> {
+        let limit = (MAX_LINE + 1).saturating_sub(self.line.len()) as u64;
+        (&mut self.reader)
+            .take(limit)
+            .read_until(b'\n', &mut self.line)
+            .await?;
+        if self.line.len() > MAX_LINE {
+            return Err(anyhow!("sent a line longer than {} bytes", MAX_LINE));
+        }
+        if !self.line.ends_with(b"\n") {
+            // Gone, maybe halfway through a line
+            return Ok(None);
+        }
+        let line = String::from_utf8_lossy(&self.line)
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+        self.line.clear();
+        Ok(Some(line))
+    }
+}
+
+/// Accepts clients until aborted, relaying between them and the server.
+pub async fn serve(config: RelayConfig, irc: irc::IRC, state: state::Handle) -> Result<()> {
+    let listener = TcpListener::bind(config.address).await?;
+    info!("[{}] Accepting clients on {}", irc.server, config.address);
+    let mut clients = TaskSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, address) = accepted?;
+                info!("[{}] Client connected from {}", irc.server, address);
+                let (password, irc, state) = (config.password.clone(), irc.clone(), state.clone());
+                clients.spawn(&format!("{} relay client {}", irc.server, address), async move {
+                    let server = irc.server.clone();
+                    match client(socket, &password, irc, state).await {
+                        Ok(()) => info!("[{}] Client from {} left", server, address),
+                        Err(e) => warn!("[{}] Client from {} dropped: {}", server, address, e),
+                    }
+                });
+            },
+            Some(_) = clients.join_next() => {},
+        }
+    }
+}
+
+/// Serves a client until it quits or goes away.
+async fn client(
+    socket: TcpStream,
+    password: &str,
+    irc: irc::IRC,
+    state: state::Handle,
+) -> Result<()> {
+    let (read_half, write_half) = socket.into_split();
+    let (mut lines, mut writer) = (Reader::new(read_half), BufWriter::new(write_half));
+    let registered = timeout(
+        REGISTER_TIMEOUT,
+        register(&mut lines, &mut writer, password),
+    )
+    .await
+    .map_err(|_| anyhow!("did not register within {}s", REGISTER_TIMEOUT.as_secs()))?;
+    if !registered? {
+        return Ok(());
+    }
+    // Subscribe before sending the channels, so nothing is missed in between
+    let mut received = irc.clone().received_messages;
+    let mut sent = irc.subscribe_sent();
+    welcome(&mut writer, &irc.server, &state).await?;
+
+    // What the client sent, so it doesn't get it back once sent to the server
+    let mut pending = VecDeque::new();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = match line? {
+                    Some(line) => line,
+                    None => return Ok(()),
+                };
+                let msg = match irc::Message::raw(&line) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        debug!("[{}] Ignoring client line: {}", irc.server, e);
+                        continue;
+                    },
+                };
+                match msg.command.to_string().as_str() {
+                    "PING" => pong(&mut writer, &msg).await?,
+                    // Leaving must not make the bot quit
+                    "QUIT" => return Ok(()),
+                    "PASS" | "USER" | "CAP" => {},
+                    command => {
+                        if matches!(command, "PRIVMSG" | "NOTICE") {
+                            if pending.len() == PENDING_ECHOES {
+                                pending.pop_front();
+                            }
+                            pending.push_back(msg.to_string());
+                        }
+                        irc.send_messages.send(msg).await?;
+                    },
+                }
+            },
+            msg = received.recv() => match msg {
+                Ok(msg) if is_relayed(&msg) => write(&mut writer, &msg.to_string()).await?,
+                Ok(_) => {},
+                Err(RecvError::Lagged(n)) => warn!("[{}] Client missed {} messages", irc.server, n),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            msg = sent.recv() => match msg {
+                Ok(mut msg) if matches!(msg.command.to_string().as_str(), "PRIVMSG" | "NOTICE") => {
+                    let line = msg.to_string();
+                    if let Some(own) = pending.iter().position(|sent| *sent == line) {
+                        pending.remove(own);
+                        continue;
+                    }
+                    // Show what plugins and other clients said as said by us
+                    msg.source = Some(state.read().await.nick.clone());
+                    write(&mut writer, &msg.to_string()).await?;
+                },
+                Ok(_) => {},
+                Err(RecvError::Lagged(n)) => warn!("[{}] Client missed {} messages", irc.server, n),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Waits for the client to send its NICK and USER, answering capability
+/// negotiation with no capabilities. Returns whether it registered with the
+/// right password.
+async fn register(lines: &mut Reader, writer: &mut Writer, password: &str) -> Result<bool> {
+    let (mut pass, mut nick, mut user) = (None, false, false);
+    while !(nick && user) {
+        let line = match lines.next_line().await? {
+            Some(line) => line,
+            None => return Ok(false),
+        };
+        let msg = match irc::Message::raw(&line) {
+            Ok(msg) => msg,
+            Err(_) => continue,
+        };
+        match msg.command.to_string().as_str() {
+            "PASS" => pass = msg.target,
+            "NICK" => nick = true,
+            "USER" => user = true,
+            "PING" => pong(writer, &msg).await?,
+            "QUIT" => return Ok(false),
+            "CAP" => match msg.target.as_deref() {
+                Some("LS") => write(writer, &format!(":{} CAP * LS :", SERVER_NAME)).await?,
+                Some("REQ") => {
+                    let caps = msg.parameters.join(" ");
+                    write(writer, &format!(":{} CAP * NAK :{}", SERVER_NAME, caps)).await?
+                },
+                _ => {},
+            },
+            _ => {},
+        }
+    }
+    if pass.as_deref() != Some(password) {
+        write(
+            writer,
+            &format!(":{} 464 * :Password incorrect", SERVER_NAME),
+        )
+        .await?;
+        write(writer, "ERROR :Closing link (password incorrect)").await?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Tells the client it registered as the bot, and which channels it's in
+/// along with their users.
+async fn welcome(writer: &mut Writer, server: &str, state: &state::Handle) -> Result<()> {
+    let mut lines = vec![];
+    {
+        let state = state.read().await;
+        let nick = &state.nick;
+        let (modes, symbols): (String, String) = state.modes.prefixes.iter().copied().unzip();
+        lines.push(format!(
+            ":{} 001 {} :Welcome to {}, relaying {}",
+            SERVER_NAME, nick, SERVER_NAME, server
+        ));
+        lines.push(format!(
+            ":{} 005 {} PREFIX=({}){} :are supported by this server",
+            SERVER_NAME, nick, modes, symbols
+        ));
+        lines.push(format!(
+            ":{} 422 {} :MOTD File is missing",
+            SERVER_NAME, nick
+        ));
+        for channel in state.channels.values() {
+            lines.push(format!(":{} JOIN {}", nick, channel.name));
+            let names: Vec<String> = channel
+                .users
+                .values()
+                .map(|member| {
+                    let prefix = state
+                        .modes
+                        .prefixes
+                        .iter()
+                        .find(|(mode, _)| member.modes.contains(mode))
+                        .map(|&(_, symbol)| symbol.to_string());
+                    prefix.unwrap_or_default() + member.nick.as_str()
+                })
+                .collect();
+            lines.push(format!(
+                ":{} 353 {} = {} :{}",
+                SERVER_NAME,
+                nick,
+                channel.name,
+                names.join(" ")
+            ));
+            lines.push(format!(
+                ":{} 366 {} {} :End of /NAMES list.",
+                SERVER_NAME, nick, channel.name
+            ));
+        }
+    }
+    for line in lines {
+        write(writer, &line).await?;
+    }
+    Ok(())
+}
+
+/// Whether a message from the server is passed on to clients, rather than
+/// being part of keeping the connection alive or of batches and capabilities
+/// clients weren't told about.
+fn is_relayed(msg: &irc::Message) -> bool {
+    !matches!(
+        msg.command,
+        irc::Command::Ping | irc::Command::Pong | irc::Command::Batch
+    ) && msg.command != irc::Command::Other("CAP".into())
+}
+
+async fn pong(writer: &mut Writer, ping: &irc::Message) -> Result<()> {
+    let token = ping
+        .target
+        .as_deref()
+        .ok_or_else(|| anyhow!("PING without a token"))?;
+    write(
+        writer,
+        &format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME, token),
+    )
+    .await
+}
+
+async fn write(writer: &mut Writer, line: &str) -> Result<()> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\r\n").await?;
+    writer.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use anyhow::Result;
+#[cfg(feature = "http-client")]
+use log::*;
+
+use crate::command::Invocation;
+use crate::irc;
+use crate::plugins::PluginContext;
+
+/// One or more messages answering a command invocation.
+pub struct Reply {
+    messages: Vec<irc::Outgoing>,
+}
+
+impl Reply {
+    /// A single line sent to wherever the command came from.
+    pub fn line<S: Into<String>>(invocation: &Invocation, text: S) -> Reply {
+        Reply {
+            messages: vec![invocation.reply(text)],
+        }
+    }
+
+    /// Sends the first line to wherever the command came from, and the
+    /// remaining lines privately to the requester as NOTICEs, so long outputs
+    /// don't flood the channel. Those are sent with bulk priority, behind
+    /// other replies.
+    pub fn paginated(invocation: &Invocation, lines: Vec<String>) -> Reply {
+        let private = !irc::is_channel(&invocation.reply_to);
+        let mut lines = lines.into_iter();
+        let first = match lines.next() {
+            Some(first) => first,
+            None => return Reply { messages: vec![] },
+        };
+        let overflow: Vec<String> = lines.collect();
+
+        let mut messages = Vec::with_capacity(overflow.len() + 1);
+        if private || overflow.is_empty() {
+            messages.push(invocation.reply(first));
+        } else {
+            let first = format!("{} (+{} more sent privately)", first, overflow.len());
+            messages.push(invocation.reply(first));
+        }
+        for line in overflow {
+            let msg = if private {
+                invocation.reply(line)
+            } else {
+                irc::Outgoing::notice(invocation.user.nick.clone(), line)
+            };
+            messages.push(msg.with_priority(irc::Priority::Bulk));
+        }
+        Reply { messages }
+    }
+
+    /// Uploads all the lines to the configured pastebin and answers with the
+    /// link followed by the first one, so cutting long lines keeps the link.
+    /// Falls back to [`Reply::paginated`] when no pastebin is configured,
+    /// or built without `http-client`, or when the upload fails.
+    #[cfg_attr(not(feature = "http-client"), allow(unused_variables))]
+    pub async fn pasted(ctx: &PluginContext, invocation: &Invocation, lines: Vec<String>) -> Reply {
+        #[cfg(feature = "http-client")]
+        if lines.len() >= 2 && ctx.paster.is_enabled() {
+            match ctx.paster.upload(&lines.join("\n")).await {
+                Ok(link) => {
+                    let first = format!("Full output: {} 〜 {}", link, lines[0]);
+                    return Reply::line(invocation, first);
+                },
+                Err(e) => warn!("[{}] Could not upload paste: {}", ctx.server, e),
+            }
+        }
+        Reply::paginated(invocation, lines)
+    }
+
+    pub async fn send(self, ctx: &PluginContext) -> Result<()> {
+        for msg in self.messages {
+            ctx.send_outgoing(msg).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Cuts the text at a word boundary to at most `max` characters.
+pub fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.into();
+    }
+    let cut: String = text.chars().take(max).collect();
+    let cut = match cut.rfind(' ') {
+        Some(space) => &cut[.. space],
+        None => &cut,
+    };
+    format!(
+        "{}…",
+        cut.trim_end_matches(|c: char| c.is_ascii_punctuation())
+    )
+}
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::storage::{DataSet, Storage};
+
+const DB_NAME: &str = "runtime-state";
+pub const DATA_SET: DataSet = DataSet::new::<RuntimeState>(DB_NAME);
+
+/// Changes made through admin commands while running, kept apart from the
+/// static configuration.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct RuntimeState {
+    /// Channels joined through `\join`, lowercase
+    joined:      BTreeSet<String>,
+    /// Configured channels left through `\part`, lowercase
+    parted:      BTreeSet<String>,
+    /// Keys given to `\join`, by lowercase channel
+    keys:        BTreeMap<String, String>,
+    /// Whether plugins are kept quiet, through `\maintenance`
+    maintenance: bool,
+}
+
+/// Runtime state of one bot, saved whenever it changes and reloaded on
+/// startup so it survives restarts.
+#[derive(Clone)]
+pub struct Runtime {
+    storage: Storage,
+    state:   Arc<Mutex<RuntimeState>>,
+}
+
+impl Runtime {
+    pub async fn load(storage: Storage) -> Result<Self> {
+        let state = storage.load(DB_NAME).await?.unwrap_or_default();
+        Ok(Runtime {
+            storage,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// Channels to join on connect, with their keys if they need one: the
+    /// configured ones, minus the ones left and plus the ones joined since.
+    pub async fn channels(&self, configured: &[String]) -> Vec<(String, Option<String>)> {
+        let state = self.state.lock().await;
+        let mut channels: Vec<String> = configured
+            .iter()
+            .filter(|c| !state.parted.contains(&c.to_lowercase()))
+            .cloned()
+            .collect();
+        channels.extend(
+            state
+                .joined
+                .iter()
+                .filter(|c| !configured.iter().any(|conf| conf.eq_ignore_ascii_case(c)))
+                .cloned(),
+        );
+        channels
+            .into_iter()
+            .map(|c| {
+                let key = state.keys.get(&c.to_lowercase()).cloned();
+                (c, key)
+            })
+            .collect()
+    }
+
+    pub async fn joined(&self, channel: &str, key: Option<&str>) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let channel = channel.to_lowercase();
+        state.parted.remove(&channel);
+        match key {
+            Some(key) => state.keys.insert(channel.clone(), key.into()),
+            None => state.keys.remove(&channel),
+        };
+        state.joined.insert(channel);
+        self.storage.save(DB_NAME, &*state).await
+    }
+
+    pub async fn parted(&self, channel: &str) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let channel = channel.to_lowercase();
+        state.joined.remove(&channel);
+        state.keys.remove(&channel);
+        state.parted.insert(channel);
+        self.storage.save(DB_NAME, &*state).await
+    }
+
+    /// Whether the bot is in maintenance, with plugins not saying anything.
+    pub async fn maintenance(&self) -> bool {
+        self.state.lock().await.maintenance
+    }
+
+    pub async fn set_maintenance(&self, maintenance: bool) -> Result<()> {
+        let mut state = self.state.lock().await;
+        state.maintenance = maintenance;
+        self.storage.save(DB_NAME, &*state).await
+    }
+}
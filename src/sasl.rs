@@ -0,0 +1,124 @@
+//! Logging in to the network's account with SASL PLAIN while registering, so
+//! the bot is identified before joining any channel.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{timeout_at, Instant};
+
+use crate::irc::{self, Command, Message};
+
+/// How long to wait for the server at each step of the exchange
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest AUTHENTICATE argument, longer payloads being sent in pieces
+const CHUNK_SIZE: usize = 400;
+/// Numerics ending the exchange: logged in, then the ways it can fail
+const SUCCESS: &str = "903";
+const FAILURES: &[&str] = &["902", "904", "905", "906", "908"];
+
+/// Account to log in to
+#[derive(Deserialize, Clone)]
+pub struct SaslConfig {
+    pub username: String,
+    pub password: String,
+}
+
+// Keeps the password out of the logged configuration
+impl fmt::Debug for SaslConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SaslConfig")
+            .field("username", &self.username)
+            .field("password", &"..")
+            .finish()
+    }
+}
+
+/// Runs the SASL exchange on the messages received since before the `sasl`
+/// capability was requested, answering PINGs along the way since
+/// registration isn't over yet.
+pub async fn login(
+    irc: &irc::IRC,
+    messages: &mut broadcast::Receiver<Message>,
+    config: &SaslConfig,
+) -> Result<()> {
+    // :server CAP * ACK :sasl
+    let msg = next(irc, messages, |msg| {
+        msg.command == Command::Other("CAP".into())
+            && msg
+                .parameters
+                .get(1)
+                .is_some_and(|caps| caps.split_whitespace().any(|cap| cap == "sasl"))
+    })
+    .await?;
+    if msg.parameters.first().map(String::as_str) != Some("ACK") {
+        return Err(anyhow!("the server doesn't support SASL"));
+    }
+
+    authenticate(irc, "PLAIN").await?;
+    next(irc, messages, |msg| {
+        msg.command == Command::Other("AUTHENTICATE".into()) && msg.target.as_deref() == Some("+")
+    })
+    .await?;
+    let payload = format!(
+        "{}\0{}\0{}",
+        config.username, config.username, config.password
+    );
+    let payload = base64::encode(payload);
+    for chunk in payload.as_bytes().chunks(CHUNK_SIZE) {
+        authenticate(irc, std::str::from_utf8(chunk)?).await?;
+    }
+    // A payload filling the last piece is followed by an empty one
+    if payload.len() % CHUNK_SIZE == 0 {
+        authenticate(irc, "+").await?;
+    }
+
+    let msg = next(irc, messages, |msg| {
+        let numeric = msg.command.to_string();
+        numeric == SUCCESS || FAILURES.contains(&numeric.as_str())
+    })
+    .await?;
+    if msg.command.to_string() != SUCCESS {
+        return Err(anyhow!("{:?}", msg.parameters.last()));
+    }
+    info!(
+        "[{}] Logged in as {} with SASL",
+        irc.server, config.username
+    );
+    Ok(())
+}
+
+async fn authenticate(irc: &irc::IRC, argument: &str) -> Result<()> {
+    irc.send_messages
+        .send_with(
+            Message::single_argument(Command::Other("AUTHENTICATE".into()), argument),
+            irc::Priority::Control,
+        )
+        .await
+}
+
+/// The next message `wanted`, within [`STEP_TIMEOUT`].
+async fn next(
+    irc: &irc::IRC,
+    messages: &mut broadcast::Receiver<Message>,
+    wanted: impl Fn(&Message) -> bool,
+) -> Result<Message> {
+    let deadline = Instant::now() + STEP_TIMEOUT;
+    loop {
+        let msg = match timeout_at(deadline, messages.recv()).await {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) => return Err(anyhow!("connection closed")),
+            Err(_) => return Err(anyhow!("no answer from the server")),
+        };
+        if msg.command == Command::Ping {
+            irc.send_messages
+                .send_with(msg, irc::Priority::Control)
+                .await?;
+        } else if wanted(&msg) {
+            return Ok(msg);
+        }
+    }
+}
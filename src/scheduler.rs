@@ -0,0 +1,67 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::{interval_at, sleep, Instant};
+
+use crate::tasks;
+
+/// Runs jobs later or periodically. All jobs get cancelled when the connection
+/// they belong to goes away.
+#[derive(Clone)]
+pub struct Scheduler {
+    cancel: broadcast::Sender<()>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        let (cancel, _) = broadcast::channel(1);
+        Scheduler { cancel }
+    }
+
+    fn spawn<F: Future<Output = ()> + Send + 'static>(&self, job: F) {
+        let mut cancel = self.cancel.subscribe();
+        tasks::spawn("scheduled job", async move {
+            tokio::select! {
+                _ = cancel.recv() => {},
+                _ = job => {},
+            }
+        });
+    }
+
+    /// Runs a job once, after the given delay.
+    pub fn after<F>(&self, delay: Duration, job: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn(async move {
+            sleep(delay).await;
+            job.await
+        });
+    }
+
+    /// Runs a job every `period`, starting one period from now.
+    pub fn every<F, Fut>(&self, period: Duration, mut job: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        self.spawn(async move {
+            let mut interval = interval_at(Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                job().await;
+            }
+        });
+    }
+
+    /// Cancels every pending job.
+    pub fn cancel_all(&self) {
+        let _ = self.cancel.send(());
+    }
+}
@@ -0,0 +1,166 @@
+//! Talking to network services like NickServ, which answer commands with
+//! free-form NOTICEs.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{timeout_at, Instant};
+
+use crate::irc;
+
+/// How long to wait for services to answer a command
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Logging in to services after connecting
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ServicesConfig {
+    /// NickServ password for the bot's nick
+    pub password: Option<String>,
+    /// Whether to GHOST whoever is using the bot's nick, and take it back
+    pub ghost:    bool,
+    /// Whether to activate the bot's vhost through HostServ
+    pub vhost:    bool,
+}
+
+// Keeps the password out of the logged configuration
+impl fmt::Debug for ServicesConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServicesConfig")
+            .field("password", &self.password.as_ref().map(|_| ".."))
+            .field("ghost", &self.ghost)
+            .field("vhost", &self.vhost)
+            .finish()
+    }
+}
+
+/// The NOTICEs a service replied to a command with.
+#[derive(Debug)]
+pub struct Answer {
+    pub lines:   Vec<String>,
+    /// Which of the expected texts the last line contained
+    pub matched: &'static str,
+}
+
+/// Sends a command to a service and collects its NOTICEs, until one contains
+/// any of the `expected` texts (compared case-insensitively) or the answer
+/// times out.
+pub async fn ask(
+    irc: &irc::IRC,
+    service: &str,
+    command: &str,
+    expected: &[&'static str],
+) -> Result<Answer> {
+    let mut messages = irc.clone().received_messages;
+    irc.send_messages
+        .send_outgoing(irc::Outgoing::privmsg(service, command))
+        .await?;
+
+    let deadline = Instant::now() + ANSWER_TIMEOUT;
+    let mut lines = vec![];
+    loop {
+        let msg = match timeout_at(deadline, messages.recv()).await {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) => return Err(anyhow!("connection closed")),
+            Err(_) => return Err(anyhow!("no answer from {} (got {:?})", service, lines)),
+        };
+        let from_service = msg
+            .source_nick()
+            .is_some_and(|nick| nick.eq_ignore_ascii_case(service));
+        if msg.command != irc::Command::Notice || !from_service {
+            continue;
+        }
+
+        let line = msg.parameters.first().cloned().unwrap_or_default();
+        let lowercase = line.to_lowercase();
+        lines.push(line);
+        if let Some(matched) = expected.iter().find(|e| lowercase.contains(*e)) {
+            return Ok(Answer { lines, matched });
+        }
+    }
+}
+
+/// Identifies to NickServ, first taking the nick back if `nick_taken` and
+/// ghosting is enabled, then activates the vhost if enabled.
+pub async fn login(
+    irc: irc::IRC,
+    config: ServicesConfig,
+    nick: String,
+    nick_taken: bool,
+) -> Result<()> {
+    let password = match &config.password {
+        Some(password) => password,
+        None => return Ok(()),
+    };
+
+    if nick_taken && config.ghost {
+        let ghost = format!("GHOST {} {}", nick, password);
+        let answer = ask(
+            &irc,
+            "NickServ",
+            &ghost,
+            &[
+                "ghosted",
+                "has been killed",
+                "is not online",
+                "invalid",
+                "denied",
+            ],
+        )
+        .await?;
+        if matches!(answer.matched, "invalid" | "denied") {
+            warn!(
+                "[{}] Could not ghost {}: {:?}",
+                irc.server, nick, answer.lines
+            );
+        } else {
+            info!("[{}] Ghosted {}, taking the nick back", irc.server, nick);
+            irc.send_messages
+                .send(irc::Message::nick(nick.as_str()))
+                .await?;
+        }
+    }
+
+    let identify = format!("IDENTIFY {} {}", nick, password);
+    let answer = ask(
+        &irc,
+        "NickServ",
+        &identify,
+        &[
+            "now identified",
+            "password accepted",
+            "invalid",
+            "incorrect",
+            "not registered",
+        ],
+    )
+    .await?;
+    if !matches!(answer.matched, "now identified" | "password accepted") {
+        warn!("[{}] Could not identify: {:?}", irc.server, answer.lines);
+        return Ok(());
+    }
+    info!("[{}] Identified as {}", irc.server, nick);
+
+    if config.vhost {
+        let answer = ask(
+            &irc,
+            "HostServ",
+            "ON",
+            &["activated", "not have a vhost", "no vhost"],
+        )
+        .await?;
+        if answer.matched == "activated" {
+            info!("[{}] Activated vhost", irc.server);
+        } else {
+            warn!(
+                "[{}] Could not activate vhost: {:?}",
+                irc.server, answer.lines
+            );
+        }
+    }
+    Ok(())
+}
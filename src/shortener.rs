@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::http::{self, Http};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where and when to shorten URLs before posting them
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ShortenerConfig {
+    /// Shortening service URL, with `{url}` replaced by the URL to shorten.
+    /// It must answer with the short URL as plain text. Nothing is shortened
+    /// when unset.
+    pub endpoint:   Option<String>,
+    /// Only URLs at least this long get shortened
+    pub min_length: usize,
+    /// Channels where URLs get shortened, all of them (and private messages)
+    /// when empty
+    pub channels:   Vec<String>,
+}
+
+impl Default for ShortenerConfig {
+    fn default() -> Self {
+        ShortenerConfig {
+            endpoint:   None,
+            min_length: 40,
+            channels:   vec![],
+        }
+    }
+}
+
+/// Shortens long URLs through an external service, for plugins posting links
+/// to channels where long lines get cut.
+#[derive(Clone)]
+pub struct Shortener {
+    config: ShortenerConfig,
+    client: http::Client,
+}
+
+impl Shortener {
+    pub fn new(config: ShortenerConfig, http: &Http) -> Self {
+        Shortener {
+            config,
+            client: http.client(),
+        }
+    }
+
+    fn applies_to(&self, target: &str, url: &str) -> bool {
+        url.len() >= self.config.min_length
+            && (self.config.channels.is_empty()
+                || self
+                    .config
+                    .channels
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(target)))
+    }
+
+    /// Shortens the URL if it is long enough and about to be sent to a target
+    /// where shortening is enabled. The URL is returned untouched otherwise, or
+    /// if the service fails.
+    pub async fn shorten(&self, target: &str, url: &str) -> String {
+        let endpoint = match &self.config.endpoint {
+            Some(endpoint) if self.applies_to(target, url) => endpoint,
+            _ => return url.into(),
+        };
+        match self.request(endpoint, url).await {
+            Ok(short) => short,
+            Err(e) => {
+                warn!("Could not shorten {}: {}", url, e);
+                url.into()
+            },
+        }
+    }
+
+    async fn request(&self, endpoint: &str, url: &str) -> Result<String> {
+        let request = endpoint.replace("{url}", &percent_encode(url));
+        let response = self
+            .client
+            .get(&request)
+            .timeout(TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        let short = response.text().await?.trim().to_string();
+        if !short.starts_with("http") {
+            return Err(anyhow!("unexpected response {:?}", short));
+        }
+        Ok(short)
+    }
+}
+
+/// Encodes everything but unreserved characters, for use in a query string.
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A' ..= b'Z' | b'a' ..= b'z' | b'0' ..= b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            },
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
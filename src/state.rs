@@ -0,0 +1,420 @@
+use chrono::{DateTime, TimeZone, Utc};
+use log::*;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::history::{History, HistoryConfig};
+use crate::irc;
+use crate::lag::Lag;
+use crate::links::Links;
+use crate::netsplit::Netsplits;
+
+/// Shared handle to the state of one connection.
+pub type Handle = Arc<RwLock<State>>;
+
+/// What the bot knows about its current connection, kept up to date from the
+/// messages received from the server.
+#[derive(Debug, Default)]
+pub struct State {
+    /// Our current nick
+    pub nick:       String,
+    /// Channels we are currently in, keyed by lowercase name
+    pub channels:   HashMap<String, Channel>,
+    /// Channel modes supported by the server
+    pub modes:      Modes,
+    /// Recent messages in each channel
+    pub history:    History,
+    /// Round trip time to the server
+    pub lag:        Lag,
+    /// Our user modes, like `i` or `B`
+    pub user_modes: BTreeSet<char>,
+    /// IRCv3 capabilities the server enabled, like `batch`
+    pub caps:       BTreeSet<String>,
+    /// Users lost in netsplits, or rejoining after them
+    pub netsplits:  Netsplits,
+    /// Users followed across nick changes
+    pub links:      Links,
+}
+
+/// Which channel modes the server supports, as told by RPL_ISUPPORT.
+#[derive(Debug)]
+pub struct Modes {
+    /// Member status modes and their NAMES prefixes, from highest to lowest
+    /// rank, e.g. `('o', '@')`
+    pub prefixes:  Vec<(char, char)>,
+    /// Modes that add or remove a list entry, like bans. Always take a
+    /// parameter.
+    pub list:      String,
+    /// Settings that always take a parameter, like the key
+    pub parameter: String,
+    /// Settings that only take a parameter when set, like the limit
+    pub set_only:  String,
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        Modes {
+            prefixes:  vec![('o', '@'), ('v', '+')],
+            list:      "beI".into(),
+            parameter: "k".into(),
+            set_only:  "l".into(),
+        }
+    }
+}
+
+/// A channel the bot is in.
+#[derive(Debug, Default)]
+pub struct Channel {
+    pub name:  String,
+    /// Users in the channel, keyed by lowercase nick
+    pub users: HashMap<String, Member>,
+    /// Channel settings like `m` or `k`, along with their parameter, if any
+    pub modes: HashMap<char, Option<String>>,
+    /// The ban list, fetched on join and kept up to date from then on
+    pub bans:  Vec<Ban>,
+}
+
+/// An entry of a channel's ban list.
+#[derive(Debug, Clone)]
+pub struct Ban {
+    pub mask: String,
+    /// Who set it, as told by the server
+    pub by:   Option<String>,
+    /// When it was set
+    pub at:   Option<DateTime<Utc>>,
+}
+
+/// A user in a channel.
+#[derive(Debug)]
+pub struct Member {
+    pub nick:  String,
+    /// Status modes the user has in the channel, like `o` or `v`
+    pub modes: BTreeSet<char>,
+}
+
+impl State {
+    pub fn new<S: Into<String>>(nick: S, history: &HistoryConfig) -> Handle {
+        Arc::new(RwLock::new(State {
+            nick:       nick.into(),
+            channels:   HashMap::new(),
+            modes:      Modes::default(),
+            history:    History::new(history),
+            lag:        Lag::default(),
+            user_modes: BTreeSet::new(),
+            caps:       BTreeSet::new(),
+            netsplits:  Netsplits::default(),
+            links:      Links::default(),
+        }))
+    }
+
+    pub fn is_me(&self, nick: &str) -> bool {
+        self.nick.eq_ignore_ascii_case(nick)
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&Channel> {
+        self.channels.get(&name.to_lowercase())
+    }
+
+    /// Whether the bot is an IRC operator, local or global.
+    pub fn is_oper(&self) -> bool {
+        self.user_modes.contains(&'o') || self.user_modes.contains(&'O')
+    }
+
+    /// Whether the bot has operator status (or higher) in the channel.
+    pub fn bot_has_op(&self, channel: &str) -> bool {
+        self.channel(channel)
+            .is_some_and(|c| c.is_opped(&self.nick))
+    }
+
+    /// Updates the state according to a message received from the server.
+    pub fn update(&mut self, msg: &irc::Message) {
+        match msg.command {
+            irc::Command::RplWelcome => {
+                if let Some(nick) = &msg.target {
+                    self.nick = nick.clone();
+                }
+                self.channels.clear();
+                self.modes = Modes::default();
+                self.user_modes.clear();
+                self.links.clear();
+            },
+            irc::Command::RplYoureOper => {
+                // Servers also set +o, but not all of them say so
+                self.user_modes.insert('o');
+            },
+            irc::Command::RplISupport => {
+                // :server 005 ournick PREFIX=(ov)@+ CHANMODES=b,k,l,imnpst :are supported
+                for token in &msg.parameters {
+                    self.modes.update(token);
+                }
+            },
+            irc::Command::Nick => {
+                if let (Some(old), Some(new)) = (msg.source_nick(), &msg.target) {
+                    if self.is_me(old) {
+                        debug!("Our nick changed to {}", new);
+                        self.nick = new.clone();
+                    }
+                    for channel in self.channels.values_mut() {
+                        if let Some(mut member) = channel.users.remove(&old.to_lowercase()) {
+                            member.nick = new.clone();
+                            channel.users.insert(new.to_lowercase(), member);
+                        }
+                    }
+                    self.links.renamed(old, new);
+                }
+            },
+            irc::Command::Mode => {
+                // :nick!user@host MODE #channel +o-v alice bob
+                // :ournick MODE ournick :+iw
+                if let Some(target) = &msg.target {
+                    if let Some(channel) = self.channels.get_mut(&target.to_lowercase()) {
+                        channel.apply_modes(&self.modes, &msg.parameters, msg.source_nick());
+                    } else if self.is_me(target) {
+                        if let Some(changes) = msg.parameters.first() {
+                            self.apply_user_modes(changes);
+                        }
+                    }
+                }
+            },
+            irc::Command::Join => {
+                if let (Some(nick), Some(name)) = (msg.source_nick(), &msg.target) {
+                    if self.is_me(nick) {
+                        self.channels.insert(
+                            name.to_lowercase(),
+                            Channel {
+                                name: name.clone(),
+                                ..Default::default()
+                            },
+                        );
+                    }
+                    if let Some(channel) = self.channels.get_mut(&name.to_lowercase()) {
+                        channel.add_user(nick);
+                    }
+                    self.netsplits.join(nick);
+                }
+            },
+            irc::Command::Part => {
+                if let (Some(nick), Some(name)) = (msg.source_nick(), &msg.target) {
+                    self.remove_from(name, nick);
+                }
+            },
+            irc::Command::Kick => {
+                if let (Some(name), Some(nick)) = (&msg.target, msg.parameters.first()) {
+                    self.remove_from(name, nick);
+                }
+            },
+            irc::Command::Quit => {
+                if let Some(nick) = msg.source_nick() {
+                    for channel in self.channels.values_mut() {
+                        channel.users.remove(&nick.to_lowercase());
+                    }
+                    self.links.left(nick);
+                }
+                self.netsplits.quit(msg);
+            },
+            irc::Command::RplNamReply => {
+                // :server 353 ournick = #channel :nick1 @nick2 @+nick3
+                if let (Some(name), Some(names)) = (msg.parameters.get(1), msg.parameters.get(2)) {
+                    let supported = &self.modes;
+                    if let Some(channel) = self.channels.get_mut(&name.to_lowercase()) {
+                        for name in names.split_whitespace() {
+                            let nick = name.trim_start_matches(|c| supported.mode_for(c).is_some());
+                            let modes = name[.. name.len() - nick.len()]
+                                .chars()
+                                .filter_map(|c| supported.mode_for(c));
+                            channel.add_user(nick);
+                            if let Some(member) = channel.users.get_mut(&nick.to_lowercase()) {
+                                member.modes.extend(modes);
+                            }
+                        }
+                    }
+                }
+            },
+            irc::Command::RplBanList => {
+                // :server 367 ournick #channel mask setter 1600000000
+                if let (Some(name), Some(mask)) = (msg.parameters.first(), msg.parameters.get(1)) {
+                    if let Some(channel) = self.channels.get_mut(&name.to_lowercase()) {
+                        let at = msg.parameters.get(3).and_then(|at| at.parse().ok());
+                        channel.add_ban(Ban {
+                            mask: mask.clone(),
+                            by:   msg.parameters.get(2).cloned(),
+                            at:   at.and_then(|at| Utc.timestamp_opt(at, 0).single()),
+                        });
+                    }
+                }
+            },
+            irc::Command::Privmsg => self.history.record(msg),
+            irc::Command::Other(ref command) if command == "CAP" => {
+                // :server CAP ournick ACK :multi-prefix batch
+                if let (Some("ACK"), Some(caps)) = (
+                    msg.parameters.first().map(String::as_str),
+                    msg.parameters.get(1),
+                ) {
+                    self.caps.extend(caps.split_whitespace().map(String::from));
+                }
+            },
+            irc::Command::Pong => {
+                // :server PONG server :token
+                if let Some(token) = msg.parameters.first() {
+                    self.lag.pong(token);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Applies a change like `+iw-x` to our user modes.
+    fn apply_user_modes(&mut self, changes: &str) {
+        let mut adding = true;
+        for mode in changes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ if adding => {
+                    self.user_modes.insert(mode);
+                },
+                _ => {
+                    self.user_modes.remove(&mode);
+                },
+            }
+        }
+    }
+
+    fn remove_from(&mut self, channel: &str, nick: &str) {
+        if self.is_me(nick) {
+            self.channels.remove(&channel.to_lowercase());
+            self.history.forget(channel);
+        } else if let Some(channel) = self.channels.get_mut(&channel.to_lowercase()) {
+            channel.users.remove(&nick.to_lowercase());
+        }
+    }
+}
+
+impl Modes {
+    /// Status mode for a NAMES prefix like `@`.
+    fn mode_for(&self, prefix: char) -> Option<char> {
+        self.prefixes
+            .iter()
+            .find(|(_, p)| *p == prefix)
+            .map(|(mode, _)| *mode)
+    }
+
+    fn is_status(&self, mode: char) -> bool {
+        self.prefixes.iter().any(|(m, _)| *m == mode)
+    }
+
+    /// Updates the supported modes from a single RPL_ISUPPORT token.
+    fn update(&mut self, token: &str) {
+        if let Some(prefix) = token.strip_prefix("PREFIX=") {
+            // (qaohv)~&@%+
+            if let Some((modes, prefixes)) =
+                prefix.strip_prefix('(').and_then(|p| p.split_once(')'))
+            {
+                self.prefixes = modes.chars().zip(prefixes.chars()).collect();
+            }
+        } else if let Some(chanmodes) = token.strip_prefix("CHANMODES=") {
+            let mut kinds = chanmodes.split(',');
+            self.list = kinds.next().unwrap_or_default().into();
+            self.parameter = kinds.next().unwrap_or_default().into();
+            self.set_only = kinds.next().unwrap_or_default().into();
+        }
+    }
+}
+
+/// Status modes that allow managing the channel: op, admin and owner
+const OP_MODES: &[char] = &['o', 'a', 'q'];
+
+impl Channel {
+    /// Whether the user has operator status (or higher) in the channel.
+    pub fn is_opped(&self, nick: &str) -> bool {
+        self.users
+            .get(&nick.to_lowercase())
+            .is_some_and(|m| m.modes.iter().any(|mode| OP_MODES.contains(mode)))
+    }
+
+    pub fn has_user(&self, nick: &str) -> bool {
+        self.users.contains_key(&nick.to_lowercase())
+    }
+
+    /// The ban with the mask, compared case-insensitively.
+    pub fn ban(&self, mask: &str) -> Option<&Ban> {
+        self.bans.iter().find(|b| b.mask.eq_ignore_ascii_case(mask))
+    }
+
+    fn add_ban(&mut self, ban: Ban) {
+        if self.ban(&ban.mask).is_none() {
+            self.bans.push(ban);
+        }
+    }
+
+    fn add_user(&mut self, nick: &str) {
+        self.users
+            .entry(nick.to_lowercase())
+            .or_insert_with(|| Member {
+                nick:  nick.to_string(),
+                modes: BTreeSet::new(),
+            });
+    }
+
+    /// Applies a MODE change like `+o-v alice bob`, made by `by`, to the
+    /// channel.
+    fn apply_modes(&mut self, supported: &Modes, parameters: &[String], by: Option<&str>) {
+        let (changes, mut args) = match parameters.split_first() {
+            Some((changes, args)) => (changes, args.iter()),
+            None => return,
+        };
+        let mut adding = true;
+        for mode in changes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ if supported.is_status(mode) => {
+                    let nick = match args.next() {
+                        Some(nick) => nick,
+                        None => return,
+                    };
+                    if let Some(member) = self.users.get_mut(&nick.to_lowercase()) {
+                        if adding {
+                            member.modes.insert(mode);
+                        } else {
+                            member.modes.remove(&mode);
+                        }
+                    }
+                },
+                'b' => {
+                    let mask = match args.next() {
+                        Some(mask) => mask,
+                        None => return,
+                    };
+                    if adding {
+                        self.add_ban(Ban {
+                            mask: mask.clone(),
+                            by:   by.map(String::from),
+                            at:   Some(Utc::now()),
+                        });
+                    } else {
+                        self.bans.retain(|b| !b.mask.eq_ignore_ascii_case(mask));
+                    }
+                },
+                _ if supported.list.contains(mode) => {
+                    args.next();
+                },
+                _ => {
+                    let takes_arg = supported.parameter.contains(mode)
+                        || (adding && supported.set_only.contains(mode));
+                    let arg = if takes_arg {
+                        args.next().cloned()
+                    } else {
+                        None
+                    };
+                    if adding {
+                        self.modes.insert(mode, arg);
+                    } else {
+                        self.modes.remove(&mode);
+                    }
+                },
+            }
+        }
+    }
+}
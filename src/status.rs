@@ -0,0 +1,137 @@
+//! Connection status of every server, for reporting through `\status` along
+//! with HTTP providers in trouble.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[cfg(feature = "http-client")]
+use crate::http;
+use crate::irc;
+use crate::messages::format_duration;
+use crate::metrics;
+use crate::runtime::Runtime;
+use crate::state;
+
+static SERVERS: Mutex<BTreeMap<String, Server>> = Mutex::new(BTreeMap::new());
+
+/// Where a server's connection is at
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Phase {
+    Connecting,
+    /// Registered with the server and able to talk
+    Connected,
+    Disconnected,
+}
+
+#[derive(Clone)]
+struct Server {
+    phase:      Phase,
+    since:      Instant,
+    /// State, outgoing queues and runtime state of the current connection,
+    /// if any
+    connection: Option<(state::Handle, irc::Outbox, Runtime)>,
+}
+
+/// Records the server moving to a new phase. Leaving the connected phase
+/// forgets about the connection.
+pub fn set(server: &str, phase: Phase) {
+    let mut servers = SERVERS.lock().unwrap();
+    let entry = servers.entry(server.into()).or_insert_with(|| Server {
+        phase,
+        since: Instant::now(),
+        connection: None,
+    });
+    if entry.phase != phase {
+        entry.phase = phase;
+        entry.since = Instant::now();
+    }
+    if phase == Phase::Disconnected {
+        entry.connection = None;
+    }
+}
+
+/// Records the state, outgoing queues and runtime state of the server's new
+/// connection.
+pub fn attach(server: &str, state: state::Handle, outbox: irc::Outbox, runtime: Runtime) {
+    let mut servers = SERVERS.lock().unwrap();
+    if let Some(entry) = servers.get_mut(server) {
+        entry.connection = Some((state, outbox, runtime));
+    }
+}
+
+/// Every server's phase, along with how long it has been in it.
+pub fn phases() -> Vec<(String, Phase, Duration)> {
+    SERVERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, server)| (name.clone(), server.phase, server.since.elapsed()))
+        .collect()
+}
+
+/// One line per server describing its connection and the counters that went
+/// up, like malformed lines and plugin panics, then one per HTTP
+/// provider failing or over budget.
+pub async fn report() -> Vec<String> {
+    let servers: Vec<(String, Server)> = SERVERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, server)| (name.clone(), server.clone()))
+        .collect();
+
+    let mut lines = vec![];
+    for (name, server) in servers {
+        let phase = match server.phase {
+            Phase::Connecting => "connecting",
+            Phase::Connected => "connected",
+            Phase::Disconnected => "disconnected",
+        };
+        let mut line = format!(
+            "{}: {} for {}",
+            name,
+            phase,
+            format_duration(server.since.elapsed())
+        );
+        if let Some((state, outbox, runtime)) = server.connection {
+            let state = state.read().await;
+            let lag = match state.lag.current() {
+                Some(lag) => format!("{}ms", lag.as_millis()),
+                None => "unknown".into(),
+            };
+            line += &format!(" as {}", state.nick);
+            if !state.user_modes.is_empty() {
+                let modes: String = state.user_modes.iter().collect();
+                line += &format!(" (+{})", modes);
+            }
+            let mut channels: Vec<&str> =
+                state.channels.values().map(|c| c.name.as_str()).collect();
+            channels.sort_unstable();
+            line += &format!(
+                ", lag {}, in {} channels ({}), queued {} control/{} reply/{} bulk",
+                lag,
+                channels.len(),
+                channels.join(", "),
+                outbox.queued(irc::Priority::Control),
+                outbox.queued(irc::Priority::Reply),
+                outbox.queued(irc::Priority::Bulk),
+            );
+            if runtime.maintenance().await {
+                line += ", in maintenance";
+            }
+        }
+        let counters: Vec<String> = metrics::counters(&name)
+            .into_iter()
+            .map(|(counter, count)| format!("{} {}", count, counter))
+            .collect();
+        if !counters.is_empty() {
+            line += &format!(", counted {}", counters.join(", "));
+        }
+        lines.push(line);
+    }
+    #[cfg(feature = "http-client")]
+    lines.extend(http::report());
+    lines
+}
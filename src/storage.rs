@@ -0,0 +1,908 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::*;
+use ron::de::from_str;
+use ron::ser::to_string;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use tokio::fs::{create_dir_all, read_to_string, File};
+use tokio::io::AsyncWriteExt;
+
+/// Data set keeping the version of every other data set's format
+const VERSIONS: &str = "versions";
+/// Starts encrypted data sets and backups, followed by the salt, nonce,
+/// ciphertext and tag in base64
+const ENCRYPTED_PREFIX: &str = "boton-aes256gcm-pbkdf2:";
+const SALT_LEN: usize = 16;
+/// Rounds of PBKDF2-HMAC-SHA256 turning the storage key into AES-256 keys
+const PBKDF2_ITERATIONS: usize = 600_000;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// A change to the format of a data set, taking it from the version before to
+/// its own.
+pub struct Migration {
+    /// Data set it applies to, like `weather`
+    pub name:    &'static str,
+    /// Version of the format once migrated, starting at 1
+    pub version: u32,
+    /// Data set the migrated data is saved as: `name` itself, or the one it
+    /// moved to, which is left alone if it has data already
+    pub into:    &'static str,
+    /// Rewrites the data set, given as RON
+    pub migrate: fn(&str) -> Result<String>,
+}
+
+/// Every migration, in order of version for each data set. A plugin changing
+/// the format of its data adds one here, rather than having to read every
+/// format it ever had.
+const MIGRATIONS: &[Migration] = &[crate::prefs::WEATHER_MIGRATION];
+
+/// A data set whose format is known, so it can be exported as JSON and
+/// imported back, checking it along the way.
+pub struct DataSet {
+    pub name:  &'static str,
+    to_json:   fn(&str) -> Result<serde_json::Value>,
+    from_json: fn(serde_json::Value) -> Result<String>,
+}
+
+impl DataSet {
+    /// The data set with the given name, saved as a `T`.
+    pub const fn new<T: Serialize + DeserializeOwned>(name: &'static str) -> DataSet {
+        DataSet {
+            name,
+            to_json: |data| Ok(serde_json::to_value(from_str::<T>(data)?)?),
+            from_json: |value| Ok(to_string(&serde_json::from_value::<T>(value)?)?),
+        }
+    }
+}
+
+/// Where plugin data is kept
+#[derive(Deserialize, Clone)]
+// Backends left out of the build are still parsed, to tell what's missing
+#[cfg_attr(not(all(feature = "sqlite", feature = "redis")), allow(dead_code))]
+pub enum StorageConfig {
+    /// One RON file per data set in a directory
+    File {
+        #[serde(default = "default_dir")]
+        dir: String,
+    },
+    /// A single SQLite database, when built with the `sqlite` feature
+    Sqlite { path: String },
+    /// A Redis server, when built with the `redis` feature, so several
+    /// instances of the bot can share their data. Keys start with `prefix`,
+    /// `boton:` by default.
+    Redis {
+        url:    String,
+        #[serde(default = "default_prefix")]
+        prefix: String,
+    },
+}
+
+fn default_dir() -> String {
+    "data".into()
+}
+
+fn default_prefix() -> String {
+    "boton:".into()
+}
+
+// Keeps passwords in Redis URLs out of the logged configuration
+impl Debug for StorageConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StorageConfig::File { dir } => f.debug_struct("File").field("dir", dir).finish(),
+            StorageConfig::Sqlite { path } => f.debug_struct("Sqlite").field("path", path).finish(),
+            StorageConfig::Redis { url, prefix } => f
+                .debug_struct("Redis")
+                .field("url", &redact_userinfo(url))
+                .field("prefix", prefix)
+                .finish(),
+        }
+    }
+}
+
+/// The URL without the user and password before its host, if any.
+fn redact_userinfo(url: &str) -> String {
+    let (scheme, rest) = match url.split_once("://") {
+        Some(parts) => parts,
+        None => return url.into(),
+    };
+    let authority = &rest[.. rest.find('/').unwrap_or(rest.len())];
+    match authority.rfind('@') {
+        Some(at) => format!("{}://..@{}", scheme, &rest[at + 1 ..]),
+        None => url.into(),
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig::File { dir: default_dir() }
+    }
+}
+
+/// Where the key encrypting plugin data comes from. Any text works as a key,
+/// the longer and more random the better.
+#[derive(Deserialize, Clone)]
+pub enum KeyConfig {
+    /// The key itself
+    Key(String),
+    /// Environment variable holding the key, to keep it out of the config
+    Env(String),
+}
+
+// Keeps the key out of the logged configuration
+impl Debug for KeyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeyConfig::Key(_) => f.write_str("Key(..)"),
+            KeyConfig::Env(var) => f.debug_tuple("Env").field(var).finish(),
+        }
+    }
+}
+
+impl KeyConfig {
+    /// The configured key, ready to encrypt and decrypt with.
+    pub async fn derive(&self) -> Result<StorageKey> {
+        let key = match self {
+            KeyConfig::Key(key) => key.clone(),
+            KeyConfig::Env(var) => std::env::var(var)
+                .map_err(|e| anyhow!("could not read the storage key from {}: {}", var, e))?,
+        };
+        if key.is_empty() {
+            return Err(anyhow!("the storage key is empty"));
+        }
+        StorageKey::new(key).await
+    }
+}
+
+/// The storage key, stretched with PBKDF2 into an AES-256 key for each salt.
+/// What's encrypted gets the salt picked when the key was read, stored along
+/// with it, and decrypting anything else derives the key for its own salt.
+#[derive(Clone)]
+pub struct StorageKey {
+    passphrase: String,
+    salt:       [u8; SALT_LEN],
+    key:        [u8; 32],
+    /// Keys derived for the salts of what was decrypted so far, as deriving
+    /// them is made slow on purpose
+    derived:    Arc<std::sync::Mutex<HashMap<Vec<u8>, [u8; 32]>>>,
+}
+
+impl StorageKey {
+    async fn new(passphrase: String) -> Result<StorageKey> {
+        let mut salt = [0; SALT_LEN];
+        crypto::random(&mut salt)?;
+        let key = stretch(&passphrase, &salt).await?;
+        Ok(StorageKey {
+            passphrase,
+            salt,
+            key,
+            derived: Arc::default(),
+        })
+    }
+
+    /// The AES-256 key for data encrypted with the salt.
+    async fn salted(&self, salt: &[u8]) -> Result<[u8; 32]> {
+        if salt == self.salt {
+            return Ok(self.key);
+        }
+        let derived = self.derived.lock().unwrap().get(salt).copied();
+        if let Some(key) = derived {
+            return Ok(key);
+        }
+        let key = stretch(&self.passphrase, salt).await?;
+        self.derived.lock().unwrap().insert(salt.to_vec(), key);
+        Ok(key)
+    }
+}
+
+/// Derives the AES-256 key for the salt, away from the async tasks as that
+/// takes a while.
+async fn stretch(passphrase: &str, salt: &[u8]) -> Result<[u8; 32]> {
+    let (passphrase, salt) = (passphrase.to_string(), salt.to_vec());
+    tokio::task::spawn_blocking(move || {
+        crypto::pbkdf2(passphrase.as_bytes(), &salt, PBKDF2_ITERATIONS)
+    })
+    .await?
+}
+
+/// Keeps data sets, serialized as RON, by key.
+#[async_trait]
+pub trait Backend: Debug + Send + Sync {
+    /// The data saved under the key, or `None` if it was never saved.
+    async fn get(&self, key: &str) -> Result<Option<String>>;
+
+    async fn set(&self, key: &str, data: &str) -> Result<()>;
+}
+
+/// Something keeping data about users, which forgets everything it has about
+/// one of them when they ask for it.
+#[async_trait]
+pub trait UserData: Send + Sync {
+    /// Forgets the user with the lowercase nick, saving the data set, and
+    /// returns whether there was anything to forget.
+    async fn forget(&self, nick: &str) -> Result<bool>;
+}
+
+/// A data set kept in memory by its owner and saved as a whole.
+struct Owned<T> {
+    storage: Storage,
+    name:    &'static str,
+    data:    Arc<tokio::sync::Mutex<T>>,
+    forget:  fn(&mut T, &str) -> bool,
+}
+
+#[async_trait]
+impl<T: Serialize + Send + Sync + 'static> UserData for Owned<T> {
+    async fn forget(&self, nick: &str) -> Result<bool> {
+        let mut data = self.data.lock().await;
+        if !(self.forget)(&mut data, nick) {
+            return Ok(false);
+        }
+        self.storage.save(self.name, &*data).await?;
+        Ok(true)
+    }
+}
+
+/// Opens the configured backend, shared by every server, encrypting data
+/// with the key if given.
+pub async fn open(config: &StorageConfig, key: Option<StorageKey>) -> Result<Arc<dyn Backend>> {
+    let backend = open_backend(config).await?;
+    Ok(match key {
+        Some(key) => Arc::new(Encrypted {
+            inner: backend,
+            key,
+        }),
+        None => backend,
+    })
+}
+
+async fn open_backend(config: &StorageConfig) -> Result<Arc<dyn Backend>> {
+    Ok(match config {
+        StorageConfig::File { dir } => Arc::new(Files { dir: dir.clone() }),
+        #[cfg(feature = "sqlite")]
+        StorageConfig::Sqlite { path } => Arc::new(sqlite_db::Sqlite::open(path).await?),
+        #[cfg(feature = "redis")]
+        StorageConfig::Redis { url, prefix } => Arc::new(redis_db::Redis::open(url, prefix).await?),
+        #[allow(unreachable_patterns)]
+        other => return Err(anyhow!("built without the feature for {:?}", other)),
+    })
+}
+
+#[derive(Debug)]
+struct Files {
+    dir: String,
+}
+
+#[async_trait]
+impl Backend for Files {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        match read_to_string(format!("{}/{}", self.dir, key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn set(&self, key: &str, data: &str) -> Result<()> {
+        create_dir_all(&self.dir).await?;
+        let mut file = File::create(format!("{}/{}", self.dir, key)).await?;
+        file.write_all(data.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+/// Encrypts data sets with AES-256-GCM before passing them on to another
+/// backend, and decrypts them back. Data sets saved before encryption was
+/// set up are read as they are, and encrypted when next saved.
+struct Encrypted {
+    inner: Arc<dyn Backend>,
+    key:   StorageKey,
+}
+
+impl Debug for Encrypted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Encrypted")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl Backend for Encrypted {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.inner.get(key).await? {
+            // The key is authenticated too, so data sets can't be swapped around
+            Some(data) if is_sealed(&data) => Ok(Some(unseal(&self.key, key, &data).await?)),
+            data => Ok(data),
+        }
+    }
+
+    async fn set(&self, key: &str, data: &str) -> Result<()> {
+        self.inner.set(key, &seal(&self.key, key, data)?).await
+    }
+}
+
+/// Whether the text was encrypted by `seal`.
+pub fn is_sealed(text: &str) -> bool {
+    text.starts_with(ENCRYPTED_PREFIX)
+}
+
+/// Encrypts the text with AES-256-GCM, authenticating the label along with
+/// it, which has to be the same to decrypt it.
+pub fn seal(key: &StorageKey, label: &str, text: &str) -> Result<String> {
+    let mut nonce = [0; NONCE_LEN];
+    crypto::random(&mut nonce)?;
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = crypto::encrypt(
+        &key.key,
+        &nonce,
+        label.as_bytes(),
+        text.as_bytes(),
+        &mut tag,
+    )?;
+    let sealed = [&key.salt[..], &nonce, &ciphertext, &tag].concat();
+    Ok(format!("{}{}", ENCRYPTED_PREFIX, base64::encode(sealed)))
+}
+
+/// Decrypts text encrypted by `seal` with the same key and label.
+pub async fn unseal(key: &StorageKey, label: &str, text: &str) -> Result<String> {
+    let sealed = base64::decode(text.trim_start_matches(ENCRYPTED_PREFIX))?;
+    if sealed.len() < SALT_LEN + NONCE_LEN + TAG_LEN {
+        return Err(anyhow!("{} is too short to be encrypted data", label));
+    }
+    let (salt, sealed) = sealed.split_at(SALT_LEN);
+    let aes_key = key.salted(salt).await?;
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let plaintext = crypto::decrypt(&aes_key, nonce, label.as_bytes(), ciphertext, tag)
+        .map_err(|_| anyhow!("could not decrypt {}, is the storage key right?", label))?;
+    Ok(String::from_utf8(plaintext)?)
+}
+
+/// AES-256-GCM, and PBKDF2 to derive its keys, from OpenSSL.
+#[cfg(feature = "encryption")]
+mod crypto {
+    use anyhow::Result;
+    use openssl::hash::MessageDigest;
+    use openssl::symm::Cipher;
+
+    pub fn pbkdf2(pass: &[u8], salt: &[u8], iterations: usize) -> Result<[u8; 32]> {
+        let mut key = [0; 32];
+        openssl::pkcs5::pbkdf2_hmac(pass, salt, iterations, MessageDigest::sha256(), &mut key)?;
+        Ok(key)
+    }
+
+    pub fn random(buf: &mut [u8]) -> Result<()> {
+        Ok(openssl::rand::rand_bytes(buf)?)
+    }
+
+    pub fn encrypt(
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        data: &[u8],
+        tag: &mut [u8],
+    ) -> Result<Vec<u8>> {
+        let cipher = Cipher::aes_256_gcm();
+        Ok(openssl::symm::encrypt_aead(
+            cipher,
+            key,
+            Some(nonce),
+            aad,
+            data,
+            tag,
+        )?)
+    }
+
+    pub fn decrypt(
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        data: &[u8],
+        tag: &[u8],
+    ) -> Result<Vec<u8>> {
+        let cipher = Cipher::aes_256_gcm();
+        Ok(openssl::symm::decrypt_aead(
+            cipher,
+            key,
+            Some(nonce),
+            aad,
+            data,
+            tag,
+        )?)
+    }
+}
+
+/// Built without encryption, so a storage key can't be used.
+#[cfg(not(feature = "encryption"))]
+mod crypto {
+    use anyhow::{anyhow, Result};
+
+    const MISSING: &str = "built without the encryption feature, needed for storage_key";
+
+    pub fn pbkdf2(_pass: &[u8], _salt: &[u8], _iterations: usize) -> Result<[u8; 32]> {
+        Err(anyhow!(MISSING))
+    }
+
+    pub fn random(_buf: &mut [u8]) -> Result<()> {
+        Err(anyhow!(MISSING))
+    }
+
+    pub fn encrypt(
+        _key: &[u8],
+        _nonce: &[u8],
+        _aad: &[u8],
+        _data: &[u8],
+        _tag: &mut [u8],
+    ) -> Result<Vec<u8>> {
+        Err(anyhow!(MISSING))
+    }
+
+    pub fn decrypt(
+        _key: &[u8],
+        _nonce: &[u8],
+        _aad: &[u8],
+        _data: &[u8],
+        _tag: &[u8],
+    ) -> Result<Vec<u8>> {
+        Err(anyhow!(MISSING))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite_db {
+    use super::Backend;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use rusqlite::{params, Connection, OptionalExtension};
+    use std::sync::{Arc, Mutex};
+    use tokio::task::spawn_blocking;
+
+    #[derive(Debug)]
+    pub struct Sqlite {
+        db: Arc<Mutex<Connection>>,
+    }
+
+    impl Sqlite {
+        pub async fn open(path: &str) -> Result<Sqlite> {
+            let path = path.to_string();
+            let db = spawn_blocking(move || -> Result<Connection> {
+                let db = Connection::open(path)?;
+                db.execute(
+                    "CREATE TABLE IF NOT EXISTS data (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+                    [],
+                )?;
+                Ok(db)
+            })
+            .await??;
+            Ok(Sqlite {
+                db: Arc::new(Mutex::new(db)),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl Backend for Sqlite {
+        async fn get(&self, key: &str) -> Result<Option<String>> {
+            let (db, key) = (self.db.clone(), key.to_string());
+            spawn_blocking(move || {
+                let db = db.lock().unwrap();
+                let value = db
+                    .query_row("SELECT value FROM data WHERE key = ?1", [key], |row| {
+                        row.get(0)
+                    })
+                    .optional()?;
+                Ok(value)
+            })
+            .await?
+        }
+
+        async fn set(&self, key: &str, data: &str) -> Result<()> {
+            let (db, key, data) = (self.db.clone(), key.to_string(), data.to_string());
+            spawn_blocking(move || {
+                let db = db.lock().unwrap();
+                db.execute(
+                    "INSERT INTO data (key, value) VALUES (?1, ?2) ON CONFLICT (key) DO UPDATE \
+                     SET value = excluded.value",
+                    params![key, data],
+                )?;
+                Ok(())
+            })
+            .await?
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+mod redis_db {
+    use super::Backend;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use redis::aio::MultiplexedConnection;
+    use redis::AsyncCommands;
+
+    pub struct Redis {
+        connection: MultiplexedConnection,
+        prefix:     String,
+    }
+
+    impl std::fmt::Debug for Redis {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Redis")
+                .field("prefix", &self.prefix)
+                .finish()
+        }
+    }
+
+    impl Redis {
+        pub async fn open(url: &str, prefix: &str) -> Result<Redis> {
+            let client = redis::Client::open(url)?;
+            Ok(Redis {
+                connection: client.get_multiplexed_async_connection().await?,
+                prefix:     prefix.into(),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl Backend for Redis {
+        async fn get(&self, key: &str) -> Result<Option<String>> {
+            let mut connection = self.connection.clone();
+            Ok(connection.get(format!("{}{}", self.prefix, key)).await?)
+        }
+
+        async fn set(&self, key: &str, data: &str) -> Result<()> {
+            let mut connection = self.connection.clone();
+            connection
+                .set::<_, _, ()>(format!("{}{}", self.prefix, key), data)
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Owners of data about users, with the names of their data sets.
+type Owners = Vec<(&'static str, Arc<dyn UserData>)>;
+
+/// The data set as saved, unless it's encrypted, which means no key was
+/// configured to decrypt it.
+fn readable<'a>(name: &str, data: &'a str) -> Result<&'a str> {
+    if is_sealed(data) {
+        return Err(anyhow!(
+            "{} data is encrypted, but no storage key is configured",
+            name
+        ));
+    }
+    Ok(data)
+}
+
+/// Persistent per-server storage for plugin data, kept in whichever backend
+/// is configured.
+#[derive(Clone)]
+pub struct Storage {
+    server:  String,
+    backend: Arc<dyn Backend>,
+    /// What keeps data about users, by the name of its data set
+    owners:  Arc<std::sync::Mutex<Owners>>,
+}
+
+impl Debug for Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Storage")
+            .field("server", &self.server)
+            .field("backend", &self.backend)
+            .finish()
+    }
+}
+
+impl Storage {
+    pub fn new<S: Into<String>>(server: S, backend: Arc<dyn Backend>) -> Self {
+        Storage {
+            server: server.into(),
+            backend,
+            owners: Default::default(),
+        }
+    }
+
+    /// Name of the server whose data this is.
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    /// The same storage without the owners registered with it, for them to
+    /// save through without keeping each other alive.
+    pub fn detached(&self) -> Storage {
+        Storage::new(self.server.clone(), self.backend.clone())
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}-{}", self.server, name)
+    }
+
+    /// Loads the named data set, or `None` if it was never saved.
+    pub async fn load<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        match self.backend.get(&self.key(name)).await? {
+            Some(data) => Ok(Some(from_str(readable(name, &data)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn save<T: Serialize>(&self, name: &str, data: &T) -> Result<()> {
+        let data = to_string(data)?;
+        self.backend.set(&self.key(name), &data).await
+    }
+
+    /// Registers the owner of a data set with data about users, so they can
+    /// have it forgotten.
+    pub fn register(&self, name: &'static str, owner: Arc<dyn UserData>) {
+        self.owners.lock().unwrap().push((name, owner));
+    }
+
+    /// Registers a data set kept in memory, whose user data `forget` removes
+    /// given a lowercase nick, returning whether there was any.
+    pub fn own<T: Serialize + Send + Sync + 'static>(
+        &self,
+        name: &'static str,
+        data: Arc<tokio::sync::Mutex<T>>,
+        forget: fn(&mut T, &str) -> bool,
+    ) {
+        let owned = Owned {
+            storage: self.detached(),
+            name,
+            data,
+            forget,
+        };
+        self.register(name, Arc::new(owned));
+    }
+
+    /// Forgets everything saved about the user, returning the names of the
+    /// data sets that had something. Goes through every data set even if
+    /// some fail, failing afterwards.
+    pub async fn forget(&self, nick: &str) -> Result<Vec<&'static str>> {
+        let nick = nick.to_lowercase();
+        let owners = self.owners.lock().unwrap().clone();
+        let (mut forgotten, mut failed) = (vec![], vec![]);
+        for (name, owner) in owners {
+            match owner.forget(&nick).await {
+                Ok(true) => forgotten.push(name),
+                Ok(false) => {},
+                Err(e) => {
+                    warn!(
+                        "[{}] Could not forget {} in {}: {}",
+                        self.server, nick, name, e
+                    );
+                    failed.push(name);
+                },
+            }
+        }
+        if !failed.is_empty() {
+            return Err(anyhow!(
+                "could not forget {} in {}",
+                nick,
+                failed.join(", ")
+            ));
+        }
+        Ok(forgotten)
+    }
+
+    /// Loads the data set as JSON, or `None` if it was never saved.
+    pub async fn export(&self, data_set: &DataSet) -> Result<Option<serde_json::Value>> {
+        match self.backend.get(&self.key(data_set.name)).await? {
+            Some(data) => (data_set.to_json)(readable(data_set.name, &data)?)
+                .map(Some)
+                .map_err(|e| anyhow!("invalid {} data: {}", data_set.name, e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Saves the data sets from JSON, replacing what was saved before. Saves
+    /// nothing unless every one of them is valid.
+    pub async fn import(&self, data: Vec<(&DataSet, serde_json::Value)>) -> Result<()> {
+        let mut converted = vec![];
+        for (data_set, value) in data {
+            let data = (data_set.from_json)(value)
+                .map_err(|e| anyhow!("invalid {} data: {}", data_set.name, e))?;
+            converted.push((data_set.name, data));
+        }
+        for (name, data) in converted {
+            self.backend.set(&self.key(name), &data).await?;
+        }
+        Ok(())
+    }
+
+    /// Brings every data set to the latest version of its format. Fails if
+    /// one is newer than this build knows about, rather than risk
+    /// misreading it.
+    pub async fn migrate(&self) -> Result<()> {
+        self.migrate_with(MIGRATIONS).await
+    }
+
+    async fn migrate_with(&self, migrations: &[Migration]) -> Result<()> {
+        let mut versions: BTreeMap<String, u32> = self.load(VERSIONS).await?.unwrap_or_default();
+        let latest = |name: &str| {
+            migrations
+                .iter()
+                .filter(|m| m.name == name)
+                .map(|m| m.version)
+                .max()
+                .unwrap_or(0)
+        };
+        for (name, version) in &versions {
+            if *version > latest(name) {
+                return Err(anyhow!(
+                    "[{}] {} data is at version {}, but this build only knows up to {}",
+                    self.server,
+                    name,
+                    version,
+                    latest(name)
+                ));
+            }
+        }
+
+        let mut changed = false;
+        for migration in migrations {
+            let version = versions.entry(migration.name.into()).or_default();
+            if migration.version <= *version {
+                continue;
+            }
+            changed = true;
+            // Data sets saved from now on already have the latest format
+            let data = match self.backend.get(&self.key(migration.name)).await? {
+                Some(data) => data,
+                None => {
+                    *version = latest(migration.name);
+                    continue;
+                },
+            };
+            info!(
+                "[{}] Migrating {} data to version {}",
+                self.server, migration.name, migration.version
+            );
+            let data = (migration.migrate)(readable(migration.name, &data)?).map_err(|e| {
+                anyhow!(
+                    "[{}] Could not migrate {} data to version {}: {}",
+                    self.server,
+                    migration.name,
+                    migration.version,
+                    e
+                )
+            })?;
+            let into = self.key(migration.into);
+            if migration.into != migration.name && self.backend.get(&into).await?.is_some() {
+                warn!(
+                    "[{}] Not moving {} data into {}, which has some already",
+                    self.server, migration.name, migration.into
+                );
+            } else {
+                self.backend.set(&into, &data).await?;
+            }
+            *version = migration.version;
+        }
+        if changed {
+            self.save(VERSIONS, &versions).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Keeps data sets in memory
+    #[derive(Debug, Default)]
+    struct Memory(Mutex<HashMap<String, String>>);
+
+    #[async_trait]
+    impl Backend for Memory {
+        async fn get(&self, key: &str) -> Result<Option<String>> {
+            Ok(self.0.lock().unwrap().get(key).cloned())
+        }
+
+        async fn set(&self, key: &str, data: &str) -> Result<()> {
+            self.0.lock().unwrap().insert(key.into(), data.into());
+            Ok(())
+        }
+    }
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            name:    "notes",
+            version: 1,
+            into:    "notes",
+            migrate: |data| Ok(format!("{} v1", data)),
+        },
+        Migration {
+            name:    "notes",
+            version: 2,
+            into:    "notes",
+            migrate: |data| Ok(format!("{} v2", data)),
+        },
+    ];
+
+    async fn storage(data: &[(&str, &str)]) -> Storage {
+        let storage = Storage::new("test", Arc::new(Memory::default()));
+        for (name, data) in data {
+            storage.backend.set(&storage.key(name), data).await.unwrap();
+        }
+        storage
+    }
+
+    async fn raw(storage: &Storage, name: &str) -> Option<String> {
+        storage.backend.get(&storage.key(name)).await.unwrap()
+    }
+
+    async fn versions(storage: &Storage) -> BTreeMap<String, u32> {
+        storage.load(VERSIONS).await.unwrap().unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn runs_pending_migrations_in_order() {
+        let storage = storage(&[("notes", "v0")]).await;
+        storage.migrate_with(MIGRATIONS).await.unwrap();
+        assert_eq!(raw(&storage, "notes").await.as_deref(), Some("v0 v1 v2"));
+        assert_eq!(
+            versions(&storage).await,
+            BTreeMap::from([("notes".into(), 2)])
+        );
+
+        // Nothing left to run
+        storage.migrate_with(MIGRATIONS).await.unwrap();
+        assert_eq!(raw(&storage, "notes").await.as_deref(), Some("v0 v1 v2"));
+    }
+
+    #[tokio::test]
+    async fn runs_only_migrations_past_the_recorded_version() {
+        let storage = storage(&[("notes", "v1"), (VERSIONS, "{\"notes\":1}")]).await;
+        storage.migrate_with(MIGRATIONS).await.unwrap();
+        assert_eq!(raw(&storage, "notes").await.as_deref(), Some("v1 v2"));
+        assert_eq!(
+            versions(&storage).await,
+            BTreeMap::from([("notes".into(), 2)])
+        );
+    }
+
+    #[tokio::test]
+    async fn records_the_latest_version_of_missing_data_sets() {
+        let storage = storage(&[]).await;
+        storage.migrate_with(MIGRATIONS).await.unwrap();
+        assert_eq!(raw(&storage, "notes").await, None);
+        assert_eq!(
+            versions(&storage).await,
+            BTreeMap::from([("notes".into(), 2)])
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_versions_newer_than_known() {
+        let storage = storage(&[("notes", "v3"), (VERSIONS, "{\"notes\":3}")]).await;
+        assert!(storage.migrate_with(MIGRATIONS).await.is_err());
+        assert_eq!(raw(&storage, "notes").await.as_deref(), Some("v3"));
+    }
+
+    #[tokio::test]
+    async fn moves_weather_preferences_into_prefs() {
+        let weather = "{\"Alice\":(location:Some(\"Lisbon\"),units:Some((Fahrenheit,Mph))),\"bob\"\
+                       :(location:None,units:None)}";
+        let storage = storage(&[("weather", weather)]).await;
+        storage.migrate().await.unwrap();
+        let prefs: BTreeMap<String, BTreeMap<String, String>> =
+            storage.load("prefs").await.unwrap().unwrap();
+        let alice = BTreeMap::from([
+            ("location".into(), "Lisbon".into()),
+            ("units".into(), "imperial".into()),
+        ]);
+        assert_eq!(prefs, BTreeMap::from([("alice".into(), alice)]));
+        assert_eq!(
+            versions(&storage).await,
+            BTreeMap::from([("weather".into(), 1)])
+        );
+    }
+}
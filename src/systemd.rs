@@ -0,0 +1,87 @@
+//! Keeps systemd up to date when running as a `Type=notify` service: ready
+//! once every bot is registered, a status line summing up the connections,
+//! and watchdog pings for as long as the connections look healthy. Enabled by
+//! `Type=notify` in the service's unit, and the watchdog by `WatchdogSec=`.
+
+use log::*;
+use sd_notify::NotifyState;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::interval;
+
+use crate::status::{self, Phase};
+
+/// How often to update the status when there's no watchdog to ping sooner
+const STATUS_PERIOD: Duration = Duration::from_secs(10);
+/// How long a server can go without a connection before the watchdog stops
+/// being pinged, so systemd restarts the whole bot
+const STUCK_AFTER: Duration = Duration::from_secs(15 * 60);
+
+fn notify(states: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        warn!("Could not notify systemd: {}", e);
+    }
+}
+
+/// Notifies systemd about the `servers` bots until `shutdown` is set. Does
+/// nothing when not started by systemd.
+pub async fn run(servers: usize, mut shutdown: watch::Receiver<bool>) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    let mut usec = 0;
+    let watchdog = sd_notify::watchdog_enabled(false, &mut usec);
+    // Pinging twice per watchdog period leaves room for a late ping
+    let period = if watchdog {
+        STATUS_PERIOD.min(Duration::from_micros(usec) / 2)
+    } else {
+        STATUS_PERIOD
+    };
+    info!("Notifying systemd every {:?}", period);
+
+    let mut ticks = interval(period);
+    let mut ready = false;
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {},
+            changed = shutdown.changed() => {
+                if changed.is_err() || *shutdown.borrow() {
+                    notify(&[NotifyState::Stopping]);
+                    return;
+                }
+                continue;
+            },
+        }
+
+        let phases = status::phases();
+        let connected = phases
+            .iter()
+            .filter(|(_, phase, _)| *phase == Phase::Connected)
+            .count();
+        let stuck: Vec<&str> = phases
+            .iter()
+            .filter(|(_, phase, since)| *phase != Phase::Connected && *since > STUCK_AFTER)
+            .map(|(name, _, _)| name.as_str())
+            .collect();
+        let mut line = format!("{}/{} servers connected", connected, servers);
+        if !stuck.is_empty() {
+            line += &format!(", stuck: {}", stuck.join(", "));
+        }
+
+        let mut states = vec![NotifyState::Status(&line)];
+        if !ready && connected == servers {
+            ready = true;
+            info!("All servers connected, notifying systemd");
+            states.push(NotifyState::Ready);
+        }
+        if watchdog && stuck.is_empty() {
+            states.push(NotifyState::Watchdog);
+        } else if watchdog {
+            warn!(
+                "Not pinging the systemd watchdog, stuck: {}",
+                stuck.join(", ")
+            );
+        }
+        notify(&states);
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::Result;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+
+/// Serves tokio-console on the address, to see what every task is up to.
+/// Needs the `console` feature and `--cfg tokio_unstable`.
+pub fn serve_console(address: SocketAddr) -> Result<()> {
+    #[cfg(all(feature = "console", tokio_unstable))]
+    {
+        console_subscriber::ConsoleLayer::builder()
+            .server_addr(address)
+            .init();
+        Ok(())
+    }
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    Err(anyhow::anyhow!(
+        "can't serve tokio-console on {}, built without the console feature or tokio_unstable",
+        address
+    ))
+}
+
+/// Spawns a task, named so tokio-console can tell which one it is when built
+/// to serve it.
+pub fn spawn<F>(name: &str, task: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "console", tokio_unstable))]
+    return tokio::task::Builder::new()
+        .name(name)
+        .spawn(task)
+        .expect("could not spawn task");
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::spawn(task)
+    }
+}
+
+/// The in-flight tasks belonging to a single owner, e.g. the handler calls of
+/// a plugin. Every task still running is aborted when the set is dropped, so
+/// restarting or shutting down the owner doesn't leak them.
+#[derive(Default)]
+pub struct TaskSet {
+    tasks: JoinSet<()>,
+}
+
+impl TaskSet {
+    pub fn new() -> Self {
+        TaskSet {
+            tasks: JoinSet::new(),
+        }
+    }
+
+    /// Spawns a task in the set, named like with `spawn`.
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&mut self, name: &str, task: F) {
+        #[cfg(all(feature = "console", tokio_unstable))]
+        self.tasks
+            .build_task()
+            .name(name)
+            .spawn(task)
+            .expect("could not spawn task");
+        #[cfg(not(all(feature = "console", tokio_unstable)))]
+        {
+            let _ = name;
+            self.tasks.spawn(task);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Waits for the next task to finish. Returns `None` if there are no tasks
+    /// left.
+    pub async fn join_next(&mut self) -> Option<Result<(), JoinError>> {
+        self.tasks.join_next().await
+    }
+}
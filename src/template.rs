@@ -0,0 +1,60 @@
+//! Message formats with `{field}` placeholders, filled from JSON values, so
+//! plugins can let their output be customized from the configuration.
+//!
+//! Plugins taking custom formats read them from their configuration through
+//! [`PluginContext::format`](crate::plugins::PluginContext::format): a
+//! `format <name>` setting applies everywhere, and `format <name> #channel`
+//! only to messages sent to the channel.
+
+use serde_json::Value;
+
+use crate::bot::PluginConfig;
+
+/// The value at a dotted path in the fields, empty when missing.
+fn field(fields: &Value, path: &str) -> String {
+    let mut value = fields;
+    for key in path.split('.') {
+        let inner = match value {
+            Value::Object(object) => object.get(key),
+            Value::Array(array) => key.parse().ok().and_then(|i: usize| array.get(i)),
+            _ => None,
+        };
+        value = match inner {
+            Some(inner) => inner,
+            None => return String::new(),
+        };
+    }
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// Replaces every `{path}` in the template by that field, with paths like
+/// `{field.inner.0}` reaching into objects and arrays.
+pub fn render(template: &str, fields: &Value) -> String {
+    let mut text = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start ..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        text += &rest[.. start];
+        text += &field(fields, rest[start + 1 .. end].trim());
+        rest = &rest[end + 1 ..];
+    }
+    text + rest
+}
+
+/// The format called `name` in the plugin configuration for messages sent to
+/// the target, preferring one set for the target alone.
+pub fn custom<'a>(config: &'a PluginConfig, name: &str, target: &str) -> Option<&'a str> {
+    let for_target = config.iter().find_map(|(key, format)| {
+        let (format_name, for_target) = key.strip_prefix("format ")?.trim().split_once(' ')?;
+        (format_name == name && for_target.trim().eq_ignore_ascii_case(target))
+            .then_some(format.as_str())
+    });
+    for_target.or_else(|| config.get(&format!("format {}", name)).map(String::as_str))
+}
@@ -0,0 +1,12 @@
+/// Version of the crate
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Commit the bot was built from, if known
+pub const GIT_HASH: Option<&str> = option_env!("BOTON_GIT_HASH");
+
+/// The version along with the commit, e.g. `0.1.0 (1a2b3c4)`.
+pub fn describe() -> String {
+    match GIT_HASH {
+        Some(hash) => format!("{} ({})", VERSION, hash),
+        None => VERSION.into(),
+    }
+}
@@ -0,0 +1,66 @@
+//! Keeps an eye on plugin handlers, so one stuck on a request that never
+//! finishes gets noticed, and dropped if configured to, rather than piling up
+//! silently.
+
+use serde::Deserialize;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+
+/// When handlers are considered stuck
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    /// Seconds a handler runs before it's logged as stuck, 0 to never log
+    pub warn_after:  u64,
+    /// Seconds a handler runs before it's aborted, 0 to let it run
+    pub abort_after: u64,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            warn_after:  30,
+            abort_after: 0,
+        }
+    }
+}
+
+/// Runs the handler under watch, calling `stalled` with how long it has been
+/// running once it goes past `warn_after`. Returns its output, or `None` if
+/// it ran past `abort_after` and was dropped.
+pub async fn watch<F: Future>(
+    config: &WatchdogConfig,
+    handler: F,
+    stalled: impl FnOnce(Duration),
+) -> Option<F::Output> {
+    let started = Instant::now();
+    let after = |secs| (secs > 0).then(|| started + Duration::from_secs(secs));
+    let (mut warn_at, abort_at) = (after(config.warn_after), after(config.abort_after));
+    let mut stalled = Some(stalled);
+    tokio::pin!(handler);
+    loop {
+        let deadline = match (warn_at, abort_at) {
+            (Some(warn), Some(abort)) => Some(warn.min(abort)),
+            (warn, abort) => warn.or(abort),
+        };
+        let deadline = async move {
+            match deadline {
+                Some(deadline) => sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            output = &mut handler => return Some(output),
+            _ = deadline => {
+                if abort_at.is_some_and(|abort| Instant::now() >= abort) {
+                    return None;
+                }
+                if let Some(stalled) = stalled.take() {
+                    stalled(started.elapsed());
+                }
+                warn_at = None;
+            },
+        }
+    }
+}